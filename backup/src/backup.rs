@@ -1,21 +1,103 @@
 //! Encrypted backup logic.
 
 use crate::backup_crypto::*;
+use crate::btime::*;
+use crate::cancel::*;
 use crate::crypto::*;
+use crate::dedup::*;
+use crate::header::*;
+use crate::index::*;
+use crate::manifest::*;
+use crate::memory::*;
+use crate::options::*;
+use crate::progress::*;
+use crate::special::*;
 use crate::types::*;
 use crate::util::*;
+use crate::xattrs::*;
+use filetime::FileTime;
 use glob::Pattern;
-use log::info;
-use std::collections::HashSet;
+use log::{info, warn};
+use regex::Regex;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR_STR};
+use std::rc::Rc;
 use std::str;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-/// Checks if a path is excluded based on a list of globs.
-fn glob_excluded(path: impl AsRef<Path>, exclude_globs: &[Pattern]) -> bool {
-    for glob in exclude_globs {
-        if glob.matches_path(path.as_ref()) {
+/// Paths to special pseudo-filesystems whose contents are generated by the
+/// operating system and should never be backed up. Only the roots of these
+/// are rejected, since other filesystems, such as removable drives, can be
+/// mounted within some of them.
+#[cfg(unix)]
+const PSEUDO_FILESYSTEM_PATHS: &[&str] = &["/proc", "/sys", "/dev"];
+
+/// Paths to special pseudo-filesystems whose contents are generated by the
+/// operating system and should never be backed up.
+#[cfg(not(unix))]
+const PSEUDO_FILESYSTEM_PATHS: &[&str] = &[];
+
+/// The name, within the temporary directory, that the decrypted archive and
+/// progress file are named after when restoring to the filesystem root.
+const ROOT_STAGING_NAME: &str = "encrypted-backup-restore";
+
+/// The most entries extracted between writes of the extraction progress file.
+const PROGRESS_WRITE_ENTRIES: usize = 256;
+
+/// The longest time between writes of the extraction progress file.
+const PROGRESS_WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the first of a list of globs that excludes a path, if any. Globs
+/// are matched against a lossy conversion of paths that are not valid UTF-8,
+/// rather than never matching them.
+fn excluding_glob(path: impl AsRef<Path>, exclude_globs: &[Pattern]) -> Option<&Pattern> {
+    let path = path.as_ref().to_string_lossy();
+
+    exclude_globs.iter().find(|glob| glob.matches(&path))
+}
+
+/// Returns the first of a list of regular expressions that excludes an
+/// archive-relative path, if any. The path is matched with `/` as the
+/// separator on all platforms.
+fn excluding_regex<'a>(relative_path: &Path, exclude_regex: &'a [Regex]) -> Option<&'a Regex> {
+    if exclude_regex.is_empty() {
+        return None;
+    }
+
+    let path_str = relative_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    exclude_regex.iter().find(|regex| regex.is_match(&path_str))
+}
+
+/// Checks if a directory entry is hidden. Entries whose names begin with `.`
+/// are hidden on all platforms, and entries with the hidden attribute are
+/// also hidden on Windows.
+fn is_hidden(entry: &fs::DirEntry) -> bool {
+    if entry.file_name().as_encoded_bytes().starts_with(b".") {
+        return true;
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+
+        /// The Windows hidden file attribute.
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+        if entry
+            .metadata()
+            .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+        {
             return true;
         }
     }
@@ -24,38 +106,109 @@ fn glob_excluded(path: impl AsRef<Path>, exclude_globs: &[Pattern]) -> bool {
 }
 
 /// Gets the last component of a path.
-fn last_path_component(path: &Path) -> BackupResult<&str> {
+fn last_path_component(path: &Path) -> BackupResult<&OsStr> {
     Ok(path
         .components()
-        .last()
+        .next_back()
         .ok_or_else(|| BackupError::InvalidIncludePath(path.to_path_buf()))?
-        .as_os_str()
-        .to_str()
-        .unwrap())
+        .as_os_str())
+}
+
+/// Returns the name an include path is stored under when storing absolute
+/// paths: its full absolute path, without the root or any drive prefix.
+fn absolute_include_name(path: &Path) -> BackupResult<PathBuf> {
+    let name = std::path::absolute(path)?
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect::<PathBuf>();
+
+    if name.as_os_str().is_empty() {
+        return Err(BackupError::InvalidIncludePath(path.to_path_buf()));
+    }
+
+    Ok(name)
+}
+
+/// Returns the name an include path is stored under in the archive.
+fn include_name(path: &Path, path_mode: PathMode) -> BackupResult<PathBuf> {
+    match path_mode {
+        PathMode::Basename => last_path_component(path).map(PathBuf::from),
+        PathMode::Absolute => absolute_include_name(path),
+    }
 }
 
 /// Checks that there are no duplicate names in the paths included in a backup.
-fn validate_no_duplicate_include_names(include_paths: &[impl AsRef<Path>]) -> BackupResult<()> {
-    // Use a HashSet for quick lookups
-    let mut include_set = HashSet::new();
+fn validate_no_duplicate_include_names(
+    include_paths: &[impl AsRef<Path>],
+    path_mode: PathMode,
+) -> BackupResult<()> {
+    // Use a HashMap for quick lookups, remembering the path each name came from
+    let mut include_names = HashMap::new();
 
     // Check all include paths for duplicates
     for include_path in include_paths {
-        // The "name" of the include path is determined by the last component of its path
-        let include_name = last_path_component(include_path.as_ref())?;
+        // The "name" of the include path is determined by the last component
+        // of its path, or by the whole path when storing absolute paths
+        let include_path = include_path.as_ref();
+        let include_name = include_name(include_path, path_mode)?;
 
-        // If an include path with the same name is already in the set, then we have a duplicate
-        if include_set.contains(&include_name) {
-            return Err(BackupError::DuplicateIncludeName(include_name.to_owned()));
+        // If an include path with the same name has already been seen, then we have a duplicate
+        if let Some(first) = include_names.insert(include_name.clone(), include_path) {
+            return Err(BackupError::DuplicateIncludeName {
+                name: include_name.display().to_string(),
+                first: first.to_path_buf(),
+                second: include_path.to_path_buf(),
+            });
         }
-
-        include_set.insert(include_name);
     }
 
     // No duplicates
     Ok(())
 }
 
+/// Checks whether a canonical path is the root of a filesystem or of a
+/// pseudo-filesystem.
+fn is_dangerous_include_path(canonical_path: &Path) -> bool {
+    // A path with no parent is the root of a filesystem
+    canonical_path.parent().is_none()
+        || PSEUDO_FILESYSTEM_PATHS
+            .iter()
+            .any(|pseudo_path| canonical_path == Path::new(pseudo_path))
+}
+
+/// Checks that no include path is the root of a filesystem or of a
+/// pseudo-filesystem.
+fn validate_no_dangerous_include_paths(include_paths: &[impl AsRef<Path>]) -> BackupResult<()> {
+    for include_path in include_paths {
+        let include_path = include_path.as_ref();
+        let canonical_path = fs::canonicalize(include_path)
+            .map_err(|_| BackupError::InvalidIncludePath(include_path.to_path_buf()))?;
+
+        if is_dangerous_include_path(&canonical_path) {
+            return Err(BackupError::DangerousIncludePath(
+                include_path.to_path_buf(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that a directory exists and is writable.
+fn validate_writable_dir(path: impl AsRef<Path>) -> BackupResult<()> {
+    let path = path.as_ref();
+
+    if !path.is_dir() {
+        return Err(BackupError::InvalidTempDir(path.to_path_buf()));
+    }
+
+    // Probe writability by creating and removing a file
+    let probe_path = path.join(format!(".backup-write-probe-{}", std::process::id()));
+    File::create_new(&probe_path)
+        .and_then(|_| fs::remove_file(&probe_path))
+        .map_err(|_| BackupError::InvalidTempDir(path.to_path_buf()))
+}
+
 /// Checks that a path does not already exist.
 fn validate_path_does_not_exist(path: impl AsRef<Path>, path_type: PathType) -> BackupResult<()> {
     if path.as_ref().exists() {
@@ -81,300 +234,3422 @@ fn validate_path_does_not_exist(path: impl AsRef<Path>, path_type: PathType) ->
     }
 }
 
-/// Appends files to a tar archive recursively.
-fn append_to_archive<T: Write>(
-    archive: &mut tar::Builder<T>,
-    include_path: impl AsRef<Path>,
-    exclude_globs: &[Pattern],
-    relative_path: impl AsRef<Path>,
-) -> io::Result<()> {
-    if !glob_excluded(&relative_path, exclude_globs) {
-        if include_path.as_ref().is_dir() {
-            // Append the directory itself (this is necessary because if the directory is empty, it will not be appended to the archive)
-            match archive.append_path_with_name(&include_path, &relative_path) {
-                Ok(()) => Ok(()),
-                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
-                Err(e) => Err(e),
-            }?;
+/// Exclusion patterns read from an ignore file, scoped to the directory in
+/// which the file was found.
+struct LocalIgnore {
+    /// The archive-relative path of the directory containing the ignore file.
+    base: PathBuf,
+    /// The patterns in the ignore file.
+    patterns: Vec<Pattern>,
+}
 
-            // Read the list of entries in the directory
-            let entries = match fs::read_dir(&include_path) {
-                Ok(val) => Ok(val),
-                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
-                Err(e) => Err(e),
-            }?;
+/// Reads the patterns from an ignore file. Returns `None` if the file does not
+/// exist or cannot be read due to permissions.
+fn read_ignore_file(path: impl AsRef<Path>) -> BackupResult<Option<Vec<Pattern>>> {
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    };
 
-            // Iterate over all entries that did not throw errors
-            for entry in entries.into_iter().filter_map(Result::ok) {
-                let entry_path = include_path
-                    .as_ref()
-                    .join(entry.file_name().to_str().unwrap());
-                let entry_relative_path = relative_path
-                    .as_ref()
-                    .join(entry.file_name().to_str().unwrap());
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            Pattern::new(line).map_err(|_| {
+                BackupError::InvalidIgnorePattern(path.as_ref().to_path_buf(), line.to_owned())
+            })
+        })
+        .collect::<BackupResult<Vec<_>>>()
+        .map(Some)
+}
 
-                // Recursively call this function for the current directory entry to add all of its contents to the archive
-                append_to_archive(archive, &entry_path, exclude_globs, &entry_relative_path)?;
-            }
-        } else if include_path.as_ref().is_file() {
-            // Add the current file entry to the archive
-            match archive.append_path_with_name(include_path, relative_path) {
-                Ok(()) => Ok(()),
-                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
-                Err(e) => Err(e),
-            }?;
+/// State maintained while walking the include paths.
+struct ArchiveContext<'a> {
+    /// Globs to exclude from the backup.
+    exclude_globs: &'a [Pattern],
+    /// Additional backup options.
+    options: &'a BackupOptions,
+    /// The canonical paths of the backup output file and any file it will
+    /// replace, which are always excluded. Empty if the backup is not being
+    /// written to a file.
+    output_paths: Vec<PathBuf>,
+    /// The active set of directory-local ignore patterns, pushed when entering
+    /// a directory with an ignore file and popped when leaving it.
+    local_ignores: Vec<LocalIgnore>,
+    /// The archive-relative paths of files with multiple hard links, keyed by
+    /// their device and inode numbers.
+    hard_links: HashMap<(u64, u64), PathBuf>,
+    /// Paths that could not be read, when continuing past errors.
+    skipped: Vec<SkippedPath>,
+    /// The device number of the include path being walked, if staying on
+    /// one filesystem.
+    root_device: Option<u64>,
+    /// The archive path of the include path being walked.
+    include_name: PathBuf,
+    /// Limits how many files and directories are held open at once.
+    open_files: OpenFileLimiter,
+    /// The number of entries appended to the archive.
+    entries: usize,
+    /// The hashes of the files appended to the archive.
+    manifest: Manifest,
+    /// The files appended to the archive by content, if deduplicating.
+    dedup: DedupTable,
+    /// The number of files stored as duplicates of earlier ones.
+    duplicates: usize,
+    /// The entries appended to the archive, if an index is being stored.
+    index: ArchiveIndex,
+    /// The number of bytes written to the archive so far.
+    archive_position: Rc<Cell<u64>>,
+    /// The offset into the archive at which the next entry begins.
+    entry_offset: u64,
+}
+
+impl<'a> ArchiveContext<'a> {
+    /// Creates a new archive context.
+    fn new(
+        exclude_globs: &'a [Pattern],
+        options: &'a BackupOptions,
+        output_paths: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            exclude_globs,
+            options,
+            output_paths,
+            local_ignores: Vec::new(),
+            hard_links: HashMap::new(),
+            skipped: Vec::new(),
+            root_device: None,
+            include_name: PathBuf::new(),
+            open_files: OpenFileLimiter::new(options.max_open_files),
+            entries: 0,
+            manifest: Manifest::default(),
+            dedup: DedupTable::default(),
+            duplicates: 0,
+            index: if options.indexed {
+                ArchiveIndex::with_offsets()
+            } else {
+                ArchiveIndex::default()
+            },
+            archive_position: Rc::new(Cell::new(0)),
+            entry_offset: 0,
         }
     }
 
-    Ok(())
-}
-
-/// Backs up and encrypts a set of paths.
-///
-/// # Errors
-///
-/// This will return an error if validation fails, or if any operation involved
-/// in the backup fails.
-pub fn backup(
-    include_paths: &[impl AsRef<Path>],
-    exclude_globs: &[Pattern],
-    output_path: impl AsRef<Path>,
-    password: &str,
-    chunk_size: usize,
-    pool_size: u8,
-) -> BackupResult<PathBuf> {
-    info!("Validating backup");
+    /// Creates a tar header for an entry with the given metadata, with its
+    /// ownership replaced if requested.
+    fn entry_header(&self, metadata: &fs::Metadata) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(metadata);
 
-    // Make sure there are no include directories with the same name
-    validate_no_duplicate_include_names(include_paths)?;
+        if let Some((uid, gid)) = self.options.owner_override {
+            header.set_uid(uid.into());
+            header.set_gid(gid.into());
+        }
 
-    // Make sure output file does not already exist
-    validate_path_does_not_exist(&output_path, PathType::Any)?;
+        header
+    }
 
-    // Validate include paths and get their names
-    let include_paths_with_names = include_paths.iter().try_fold(
-        Vec::new(),
-        |mut include_paths_with_names, include_path| {
-            include_paths_with_names.push((
-                include_path.as_ref(),
-                last_path_component(include_path.as_ref())?,
-            ));
-            Ok::<_, BackupError>(include_paths_with_names)
-        },
-    )?;
+    /// Records an entry that was appended to the archive. Nothing else is
+    /// written between entries, so each one begins where the last recorded
+    /// entry ended.
+    fn record_entry(&mut self, name: &Path, kind: EntryKind, size: u64) {
+        self.entries += 1;
 
-    info!("Beginning backup");
+        if self.options.index_mode() != IndexMode::None {
+            self.index.push(name, kind, size, self.entry_offset);
+        }
 
-    // Create the tar archive
-    let tar_path = tmp_file_for(&output_path);
-    let tar_file = File::create_new(&tar_path)?;
-    let mut archive = tar::Builder::new(tar_file);
+        self.entry_offset = self.archive_position.get();
+    }
 
-    // Add each include path to the archive
-    for (include_path, include_name) in include_paths_with_names {
-        info!("Backing up '{}'", include_path.display());
+    /// Records the filesystem of an include path about to be walked, if
+    /// staying on one filesystem.
+    #[cfg(unix)]
+    fn enter_include_path(&mut self, include_path: &Path) {
+        use std::os::unix::fs::MetadataExt;
 
-        append_to_archive(
-            &mut archive,
-            include_path,
-            exclude_globs,
-            Path::new(&include_name),
-        )?;
+        self.root_device = if self.options.one_file_system {
+            fs::metadata(include_path)
+                .ok()
+                .map(|metadata| metadata.dev())
+        } else {
+            None
+        };
     }
 
-    // Close the archive
-    archive.finish()?;
+    /// Records the filesystem of an include path about to be walked, if
+    /// staying on one filesystem.
+    #[cfg(not(unix))]
+    #[allow(clippy::unused_self)]
+    const fn enter_include_path(&mut self, _include_path: &Path) {}
 
-    info!("Encrypting backup");
+    /// Checks if a path is on a different filesystem from the include path
+    /// being walked, when staying on one filesystem.
+    #[cfg(unix)]
+    fn crosses_filesystem(&self, path: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
 
-    // Turn the password into a 256-bit key used for encryption
-    let key = password_to_key(password);
+        self.root_device.is_some_and(|root_device| {
+            fs::metadata(path).is_ok_and(|metadata| metadata.dev() != root_device)
+        })
+    }
 
-    // Read and encrypt the tar archive
-    encrypt_backup(&tar_path, &output_path, key, chunk_size, pool_size)?;
+    /// Checks if a path is on a different filesystem from the include path
+    /// being walked, when staying on one filesystem. Device numbers are not
+    /// available here, so on Windows, reparse points are treated as crossing
+    /// into another filesystem.
+    #[cfg(not(unix))]
+    fn crosses_filesystem(&self, path: &Path) -> bool {
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::MetadataExt;
 
-    // Delete temporary tar file
-    fs::remove_file(tar_path)?;
+            /// The Windows reparse point file attribute.
+            const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
 
-    info!("Backup complete");
+            if self.options.one_file_system {
+                return fs::symlink_metadata(path).is_ok_and(|metadata| {
+                    metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0
+                });
+            }
+        }
 
-    // Return the output file path
-    Ok(output_path.as_ref().to_path_buf())
-}
+        let _ = path;
+        false
+    }
 
-/// Extracts an encrypted backup.
-///
-/// # Errors
-///
-/// This will return an error if validation fails, or if any operation involved
-/// in the extraction fails.
-pub fn extract(
-    path: impl AsRef<Path>,
-    output_path: impl AsRef<Path>,
-    password: &str,
-    pool_size: u8,
-) -> BackupResult<PathBuf> {
-    info!("Validating extraction");
+    /// Handles an error reading a path. Paths that cannot be read due to
+    /// insufficient permissions are skipped with a warning, unless such
+    /// warnings are silenced. Running out of file descriptors
+    /// is always an error, since every path after it would fail too. Other
+    /// errors are returned, unless continuing past errors, in which case the
+    /// path is recorded as skipped.
+    fn skip(&mut self, path: &Path, error: io::Error) -> BackupResult<()> {
+        if error.kind() == io::ErrorKind::PermissionDenied {
+            if !self.options.quiet_permission_denied {
+                warn!("Skipping '{}': permission denied", path.display());
+            }
 
-    // Make sure output directory does not already exist
-    validate_path_does_not_exist(&output_path, PathType::Any)?;
+            return Ok(());
+        }
 
-    info!("Decrypting backup");
+        if is_too_many_open_files(&error) {
+            return Err(BackupError::TooManyOpenFiles(path.to_path_buf()));
+        }
 
-    // Turn the password into a 256-bit key used for encryption
-    let key = password_to_key(password);
+        if !self.options.continue_on_error {
+            return Err(error.into());
+        }
 
-    // Decrypt the backup
-    let (tar_path, tar_file) = decrypt_backup(&path, key, pool_size)?;
+        warn!("Skipping '{}': {error}", path.display());
+        self.skipped.push(SkippedPath {
+            path: path.to_path_buf(),
+            error: error.to_string(),
+        });
 
-    info!("Extracting decrypted backup");
+        Ok(())
+    }
 
-    // Extract the tar file
-    let mut archive = tar::Archive::new(tar_file);
-    archive.unpack(&output_path)?;
+    /// Gets the device and inode numbers identifying a file with multiple
+    /// hard links, if hard links are being preserved.
+    #[cfg(unix)]
+    fn hard_link_id(&self, metadata: &fs::Metadata) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
 
-    // Delete temporary tar file
-    fs::remove_file(tar_path)?;
+        (!self.options.dereference_hardlinks && metadata.nlink() > 1)
+            .then(|| (metadata.dev(), metadata.ino()))
+    }
 
-    info!("Extraction complete");
+    /// Gets the device and inode numbers identifying a file with multiple
+    /// hard links, if hard links are being preserved.
+    #[cfg(not(unix))]
+    #[allow(clippy::unused_self)]
+    const fn hard_link_id(&self, _metadata: &fs::Metadata) -> Option<(u64, u64)> {
+        None
+    }
 
-    // Return the output directory path
-    Ok(output_path.as_ref().to_path_buf())
-}
+    /// Checks if a file has the same contents as its copy in the reference
+    /// directory of a delta backup, recording it as unchanged if so. A file
+    /// that cannot be compared counts as changed, so that it is backed up.
+    fn unchanged_from_reference(&mut self, path: &Path, name: &Path, size: u64) -> bool {
+        let Some(reference_dir) = &self.options.reference_dir else {
+            return false;
+        };
+        let reference_path = reference_dir.join(name);
 
-/// Gets the chunk size of a given backup file.
-///
-/// # Errors
-///
-/// This will return an error if an IO operation fails.
-pub fn backup_chunk_size(backup_path: impl AsRef<Path>) -> io::Result<usize> {
-    get_chunk_size(backup_path)
-}
+        // Only files of the same size are worth hashing
+        if !fs::symlink_metadata(&reference_path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.len() == size)
+        {
+            return false;
+        }
 
-/// Backup tests.
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::{DirEntry, File};
+        // The two files are hashed one after the other
+        let _permit = self.open_files.acquire();
 
-    fn non_existent_temp_file() -> PathBuf {
-        let temp_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
-        let path = temp_path.to_path_buf();
-        temp_path.close().unwrap();
-        path
+        match (hash_file(path), hash_file(&reference_path)) {
+            (Ok(hash), Ok(reference_hash)) if hash == reference_hash => {
+                self.manifest.push_unchanged(name, hash);
+                true
+            }
+            _ => false,
+        }
     }
 
-    fn io_error<T, E>(err: E) -> io::Result<T>
-    where
-        E: Into<Box<dyn std::error::Error + Send + Sync>>,
-    {
-        Err(io::Error::new(io::ErrorKind::Other, err))
+    /// Checks if a path refers to the backup output file.
+    fn is_output_file(&self, path: &Path) -> bool {
+        // Compare file names first to avoid canonicalizing every path
+        self.output_paths.iter().any(|output_path| {
+            path.file_name() == output_path.file_name()
+                && fs::canonicalize(path).is_ok_and(|path| &path == output_path)
+        })
     }
 
-    fn keep_entry(entry: &DirEntry, ignore_dir_names: &[&str], ignore_file_names: &[&str]) -> bool {
-        let entry_file_name = entry.file_name();
-        let entry_name = entry_file_name.to_str().unwrap();
+    /// Deactivates the ignore files of any directories that do not contain the
+    /// given archive-relative path, since the walk has moved on from them.
+    fn leave_directories(&mut self, relative_path: &Path) {
+        while self
+            .local_ignores
+            .last()
+            .is_some_and(|local_ignore| !relative_path.starts_with(&local_ignore.base))
+        {
+            self.local_ignores.pop();
+        }
+    }
 
-        if entry.file_type().unwrap().is_dir() && ignore_dir_names.contains(&entry_name) {
-            return false;
+    /// Checks if an archive-relative path is excluded by the global exclude
+    /// globs or regexes, or by an active directory-local ignore file. Returns
+    /// a description of the first pattern that excludes it, if any.
+    fn exclusion(&self, relative_path: &Path) -> Option<String> {
+        if let Some(glob) = excluding_glob(relative_path, self.exclude_globs) {
+            return Some(format!("glob '{glob}'"));
         }
 
-        if entry.file_type().unwrap().is_file() && ignore_file_names.contains(&entry_name) {
-            return false;
+        if let Some(regex) = excluding_regex(relative_path, &self.options.exclude_regex) {
+            return Some(format!("regex '{regex}'"));
         }
 
-        true
+        self.local_ignores.iter().find_map(|local_ignore| {
+            let local_path = relative_path.strip_prefix(&local_ignore.base).ok()?;
+            let glob = excluding_glob(local_path, &local_ignore.patterns)?;
+
+            Some(format!(
+                "pattern '{glob}' in '{}'",
+                local_ignore.base.join(BACKUP_IGNORE_FILE_NAME).display()
+            ))
+        })
     }
 
-    fn verify_identical_trees(
-        a: impl AsRef<Path>,
-        b: impl AsRef<Path>,
-        check_root_name: bool,
-        ignore_dir_names: &[&str],
-        ignore_file_names: &[&str],
-    ) -> io::Result<()> {
-        let a = a.as_ref();
-        let b = b.as_ref();
+    /// Checks if an archive-relative path is excluded, logging the pattern
+    /// that excluded it if excluded paths are being listed.
+    fn excluded(&self, relative_path: &Path) -> bool {
+        let Some(exclusion) = self.exclusion(relative_path) else {
+            return false;
+        };
 
-        if a.is_file() && b.is_file() {
-            let a_name = a.file_name().unwrap().to_str().unwrap();
-            let b_name = b.file_name().unwrap().to_str().unwrap();
+        if self.options.list_excluded {
+            info!("Excluded '{}' by {exclusion}", relative_path.display());
+        }
 
-            if !(ignore_file_names.contains(&a_name) || ignore_dir_names.contains(&b_name)) {
-                if check_root_name && a_name != b_name {
-                    return io_error(format!(
-                        "mismatched file names: {} != {}",
-                        a.display(),
-                        b.display()
-                    ));
-                }
+        true
+    }
 
-                let a_contents = fs::read(a)?;
-                let b_contents = fs::read(b)?;
+    /// Checks if a directory entry is excluded for being hidden, logging it
+    /// if excluded paths are being listed. `relative_path` is the
+    /// archive-relative path of the directory containing it.
+    fn excluded_as_hidden(&self, entry: &fs::DirEntry, relative_path: &Path) -> bool {
+        let excluded = self.options.exclude_hidden && is_hidden(entry);
 
-                if a_contents != b_contents {
-                    return io_error(format!(
-                        "mismatched file contents: {} != {}",
-                        a.display(),
-                        b.display()
-                    ));
-                }
-            }
-        } else if a.is_dir() && b.is_dir() {
-            let a_name = a.file_name().unwrap().to_str().unwrap();
-            let b_name = b.file_name().unwrap().to_str().unwrap();
+        if excluded && self.options.list_excluded {
+            info!(
+                "Excluded '{}' as hidden",
+                relative_path.join(entry.file_name()).display()
+            );
+        }
 
-            if !(ignore_file_names.contains(&a_name) || ignore_dir_names.contains(&b_name)) {
-                if check_root_name && a_name != b_name {
-                    return io_error(format!(
-                        "mismatched directory names: {} != {}",
-                        a.display(),
-                        b.display()
-                    ));
-                }
+        excluded
+    }
+}
 
-                let a_entries = fs::read_dir(a)?.collect::<Result<Vec<_>, _>>()?;
-                let a_entries = a_entries
-                    .iter()
-                    .filter(|entry| keep_entry(entry, ignore_dir_names, ignore_file_names))
-                    .collect::<Vec<_>>();
-                let b_entries = fs::read_dir(b)?.collect::<Result<Vec<_>, _>>()?;
-                let b_entries = b_entries
-                    .iter()
-                    .filter(|entry| keep_entry(entry, ignore_dir_names, ignore_file_names))
-                    .collect::<Vec<_>>();
+/// Appends the contents of a file to a tar archive.
+///
+/// The entry header records the size of the file when it is opened. If the
+/// file changes size while it is being read, as log files often do, its
+/// contents are truncated or padded with zeros to match that size, so the
+/// archive stays well formed, and a warning is logged. The same is done if
+/// reading the file fails part way through, and the error is then handled as
+/// the context sees fit.
+fn append_file<T: Write>(
+    archive: &mut tar::Builder<T>,
+    context: &mut ArchiveContext,
+    file: File,
+    metadata: &fs::Metadata,
+    path: &Path,
+    name: &Path,
+) -> BackupResult<()> {
+    let mut header = context.entry_header(metadata);
 
-                if a_entries.len() != b_entries.len() {
-                    return io_error(format!(
-                        "different number of contents: {} != {}",
-                        a.display(),
-                        b.display()
-                    ));
-                }
+    let mut reader = SizedReader::new(file, metadata.len());
+    let mut hashing_reader = HashingReader::new(&mut reader);
+    archive.append_data(&mut header, name, &mut hashing_reader)?;
+    let hash = hashing_reader.finish();
+    context.manifest.push(name, hash);
 
-                for i in 0..a_entries.len() {
-                    verify_identical_trees(
-                        a_entries[i].path(),
-                        b_entries[i].path(),
-                        true,
-                        ignore_dir_names,
-                        ignore_file_names,
-                    )?;
-                }
-            }
-        } else {
-            return io_error(format!(
-                "file type mismatch: {} != {}",
-                a.display(),
-                b.display()
-            ));
-        }
+    if context.options.dedup {
+        context.dedup.insert(name, metadata.len(), hash);
+    }
 
-        Ok(())
+    if let Some(e) = reader.take_error() {
+        context.skip(path, e)?;
+    } else if reader.padding() > 0 {
+        warn!(
+            "'{}' shrank while being backed up, so only part of it was captured and the rest was padded with {} zero bytes",
+            path.display(),
+            reader.padding()
+        );
+    } else if reader.truncated()? {
+        warn!(
+            "'{}' grew while being backed up, so only its first {} bytes were captured",
+            path.display(),
+            metadata.len()
+        );
     }
 
-    #[test]
-    fn test_backup() {
+    Ok(())
+}
+
+/// Opens a file to be archived, returning it along with its metadata.
+/// Directories are not opened.
+fn open_entry(path: &Path) -> io::Result<Option<(File, fs::Metadata)>> {
+    if path.is_dir() {
+        return Ok(None);
+    }
+
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    Ok(Some((file, metadata)))
+}
+
+/// Appends a PAX header holding the extended attributes and creation time of
+/// a path, if they are being preserved, along with any extra record given.
+/// Nothing is appended if there is nothing to record.
+fn append_attributes<T: Write>(
+    archive: &mut tar::Builder<T>,
+    context: &ArchiveContext,
+    path: &Path,
+    extra: Option<(String, Vec<u8>)>,
+) -> io::Result<()> {
+    let mut extensions = Vec::new();
+
+    if context.options.preserve_xattrs {
+        extensions.extend(read_xattrs(path));
+    }
+
+    if context.options.preserve_btime {
+        extensions.extend(read_btime(path));
+    }
+
+    extensions.extend(extra);
+
+    archive.append_pax_extensions(
+        extensions
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice())),
+    )
+}
+
+/// Appends a single file or directory to a tar archive, preceded by its
+/// extended attributes and creation time if they are being preserved.
+///
+/// The entry is opened before anything is written, so a path that cannot be
+/// read is handled by the context without leaving a partial entry behind.
+/// Returns whether the entry was appended. Any error returned comes from
+/// writing the archive.
+fn append_entry<T: Write>(
+    archive: &mut tar::Builder<T>,
+    context: &mut ArchiveContext,
+    path: &Path,
+    name: &Path,
+) -> BackupResult<bool> {
+    // The file stays open until it has been appended
+    let _permit = context.open_files.acquire();
+
+    // The attributes apply to whichever entry follows them, so a file is
+    // opened before they are appended, in case it cannot be read
+    let file = match open_entry(path) {
+        Ok(file) => file,
+        Err(e) => {
+            context.skip(path, e)?;
+            return Ok(false);
+        }
+    };
+
+    append_attributes(archive, context, path, None)?;
+
+    if let Some((file, metadata)) = file {
+        append_file(archive, context, file, &metadata, path, name)?;
+        context.record_entry(name, EntryKind::File, metadata.len());
+    } else {
+        let mut header = context.entry_header(&fs::metadata(path)?);
+        header.set_size(0);
+        archive.append_data(&mut header, name, io::empty())?;
+        context.record_entry(name, EntryKind::Directory, 0);
+    }
+
+    Ok(true)
+}
+
+/// Rewrites an absolute symlink target within an include path to be relative
+/// to the link, which is at the given archive-relative path. Returns `None`
+/// if the target is relative already or lies outside the include path.
+fn relativize_symlink_target(root: &Path, relative_path: &Path, target: &Path) -> Option<PathBuf> {
+    let within_root = target.strip_prefix(root).ok()?;
+
+    // The first component of the archive-relative path is the include path
+    // itself, and the last is the link
+    let depth = relative_path.components().count().saturating_sub(2);
+    let relative_target = iter::repeat_n(Path::new(".."), depth)
+        .chain(iter::once(within_root))
+        .collect::<PathBuf>();
+
+    if relative_target.as_os_str().is_empty() {
+        Some(PathBuf::from("."))
+    } else {
+        Some(relative_target)
+    }
+}
+
+/// Appends a symbolic link to a tar archive as a link, rather than following
+/// it. Returns whether the link was appended.
+fn append_symlink<T: Write>(
+    archive: &mut tar::Builder<T>,
+    context: &mut ArchiveContext,
+    root: Option<&Path>,
+    path: &Path,
+    name: &Path,
+) -> BackupResult<bool> {
+    let (metadata, target) = match fs::symlink_metadata(path)
+        .and_then(|metadata| fs::read_link(path).map(|target| (metadata, target)))
+    {
+        Ok(link) => link,
+        Err(e) => {
+            context.skip(path, e)?;
+            return Ok(false);
+        }
+    };
+
+    let target = match context.options.symlink_target {
+        SymlinkTarget::RelativizeWithinRoot if target.is_absolute() => root
+            .and_then(|root| {
+                // Only the last component of the include path's name is
+                // counted, in case it is stored under its absolute path
+                let name_parent = context
+                    .include_name
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""));
+                let name = name.strip_prefix(name_parent).ok()?;
+                relativize_symlink_target(root, name, &target)
+            })
+            .unwrap_or_else(|| {
+                warn!(
+                    "Symlink '{}' points outside of the backup, to '{}'",
+                    path.display(),
+                    target.display()
+                );
+                target
+            }),
+        _ => target,
+    };
+
+    let mut header = context.entry_header(&metadata);
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    archive.append_link(&mut header, name, target)?;
+
+    context.record_entry(name, EntryKind::Symlink, 0);
+    Ok(true)
+}
+
+/// Appends a regular file to a tar archive, as a hard link if another link
+/// to it has already been archived, or as a duplicate if a file with the same
+/// contents has been and duplicates are being detected. Files that a delta
+/// backup can take from its reference directory are left out.
+fn append_regular_file<T: Write>(
+    archive: &mut tar::Builder<T>,
+    context: &mut ArchiveContext,
+    path: &Path,
+    name: PathBuf,
+) -> BackupResult<()> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return context.skip(path, e),
+    };
+    let hard_link_id = context.hard_link_id(&metadata);
+
+    if let Some(link_target) = hard_link_id.and_then(|id| context.hard_links.get(&id)) {
+        // Add a hard link to the previously archived copy of this file
+        let mut header = context.entry_header(&metadata);
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        archive.append_link(&mut header, &name, link_target)?;
+        context.record_entry(&name, EntryKind::HardLink, 0);
+    } else if context.unchanged_from_reference(path, &name, metadata.len()) {
+        // The reference directory already holds the file
+    } else if let Some((original, hash)) =
+        context
+            .dedup
+            .find(path, metadata.len(), &context.open_files)
+    {
+        // Refer to the first copy of the contents instead of storing them
+        append_attributes(archive, context, path, Some(duplicate_extension()))?;
+        let mut header = context.entry_header(&metadata);
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        archive.append_link(&mut header, &name, original)?;
+        context.manifest.push(&name, hash);
+        context.duplicates += 1;
+        context.record_entry(&name, EntryKind::File, metadata.len());
+    } else if append_entry(archive, context, path, &name)? {
+        // Remember where the file was stored, so that other links to it can refer to it
+        if let Some(id) = hard_link_id {
+            context.hard_links.insert(id, name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends a special file, such as a FIFO or device node, to a tar archive as
+/// an entry of its own type, if special files are being stored. Otherwise, or
+/// if it cannot be stored, it is skipped and a warning is logged.
+fn append_special_file<T: Write>(
+    archive: &mut tar::Builder<T>,
+    context: &mut ArchiveContext,
+    path: &Path,
+    name: &Path,
+    metadata: &fs::Metadata,
+) -> BackupResult<()> {
+    let entry_type = match (context.options.special_files, special_entry_type(metadata)) {
+        (SpecialFileMode::Store, Some(entry_type)) => entry_type,
+        (SpecialFileMode::Store, None) => {
+            warn!(
+                "Skipping special file '{}', which cannot be stored",
+                path.display()
+            );
+            return Ok(());
+        }
+        (SpecialFileMode::Skip, _) => {
+            warn!("Skipping special file '{}'", path.display());
+            return Ok(());
+        }
+    };
+
+    let mut header = context.entry_header(metadata);
+    header.set_entry_type(entry_type);
+    header.set_size(0);
+    set_device_numbers(&mut header, metadata)?;
+    archive.append_data(&mut header, name, io::empty())?;
+
+    context.record_entry(name, EntryKind::Special, 0);
+    Ok(())
+}
+
+/// Appends files to a tar archive, descending into directories.
+///
+/// Directories are walked with an explicit worklist rather than recursion, so
+/// that arbitrarily deep trees cannot overflow the stack. Entries are still
+/// archived depth first, in the order they are read from each directory.
+fn append_to_archive<T: Write>(
+    archive: &mut tar::Builder<T>,
+    context: &mut ArchiveContext,
+    include_path: impl AsRef<Path>,
+    relative_path: impl AsRef<Path>,
+) -> BackupResult<()> {
+    let root = include_path.as_ref();
+    let mut worklist = vec![(root.to_path_buf(), relative_path.as_ref().to_path_buf())];
+    context.enter_include_path(root);
+    context.include_name = relative_path.as_ref().to_path_buf();
+
+    // Symlink targets are compared against the resolved include path
+    let canonical_root = (context.options.symlink_target == SymlinkTarget::RelativizeWithinRoot)
+        .then(|| fs::canonicalize(root).ok())
+        .flatten();
+
+    while let Some((include_path, relative_path)) = worklist.pop() {
+        // Deactivate the ignore files of directories that have been left
+        context.leave_directories(&relative_path);
+
+        if context.excluded(&relative_path) {
+            continue;
+        }
+
+        // Store symlinks inside the include path as links, if requested
+        if context.options.symlink_target != SymlinkTarget::Follow
+            && include_path != root
+            && include_path.is_symlink()
+        {
+            append_symlink(
+                archive,
+                context,
+                canonical_root.as_deref(),
+                &include_path,
+                &relative_path,
+            )?;
+            continue;
+        }
+
+        if include_path.is_dir() {
+            // Append the directory itself (this is necessary because if the directory is empty, it will not be appended to the archive)
+            if !append_entry(archive, context, &include_path, &relative_path)? {
+                continue;
+            }
+
+            // The directory's ignore file is read before the directory is
+            // opened, so that the two are never open at once
+            let ignore_patterns = if context.options.follow_backupignore {
+                let _permit = context.open_files.acquire();
+                read_ignore_file(include_path.join(BACKUP_IGNORE_FILE_NAME))?
+            } else {
+                None
+            };
+
+            // Read the list of entries in the directory, which stays open
+            // until they have all been queued
+            let permit = context.open_files.acquire();
+            let entries = match fs::read_dir(&include_path) {
+                Ok(val) => val,
+                Err(e) => {
+                    context.skip(&include_path, e)?;
+                    continue;
+                }
+            };
+
+            // Activate the directory's ignore file for its subtree
+            if let Some(patterns) = ignore_patterns {
+                context.local_ignores.push(LocalIgnore {
+                    base: relative_path.clone(),
+                    patterns,
+                });
+            }
+
+            // Queue all entries that did not throw errors
+            let mut children = entries
+                .into_iter()
+                .filter_map(Result::ok)
+                // Hidden include paths are never skipped, since they are not
+                // reached through here
+                .filter(|entry| !context.excluded_as_hidden(entry, &relative_path))
+                .map(|entry| {
+                    (
+                        include_path.join(entry.file_name()),
+                        relative_path.join(entry.file_name()),
+                    )
+                })
+                // Leave out mount points and anything else on another
+                // filesystem, if requested
+                .filter(|(path, _)| {
+                    let crosses = context.crosses_filesystem(path);
+
+                    if crosses {
+                        info!("Skipping '{}' on another filesystem", path.display());
+                    }
+
+                    !crosses
+                })
+                .collect::<Vec<_>>();
+            drop(permit);
+
+            // Entries are read in whatever order the filesystem returns them,
+            // which can differ between otherwise identical trees
+            if context.options.deterministic {
+                children.sort();
+            }
+
+            // The worklist is a stack, so push the entries in reverse to
+            // archive them in order
+            worklist.extend(children.into_iter().rev());
+        } else if context.is_output_file(&include_path) {
+            // Never include the backup in itself
+            info!("Skipping backup output file '{}'", include_path.display());
+        } else if include_path.is_file() {
+            append_regular_file(archive, context, &include_path, relative_path)?;
+        } else if let Ok(metadata) = fs::metadata(&include_path) {
+            // Anything else that exists is a special file
+            append_special_file(archive, context, &include_path, &relative_path, &metadata)?;
+        }
+    }
+
+    // Deactivate any ignore files still active for this include path
+    context.local_ignores.clear();
+
+    Ok(())
+}
+
+/// Reads the index of the last successfully extracted entry from a progress
+/// file, if one exists.
+fn read_extraction_progress(progress_path: impl AsRef<Path>) -> BackupResult<Option<usize>> {
+    match fs::read_to_string(progress_path) {
+        Ok(contents) => Ok(contents.trim().parse().ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Records the index of the last successfully extracted entry in a progress
+/// file.
+fn write_extraction_progress(progress_path: impl AsRef<Path>, index: usize) -> io::Result<()> {
+    fs::write(progress_path, index.to_string())
+}
+
+/// Records extraction progress in a progress file without rewriting it for
+/// every entry. The file is written at most every [`PROGRESS_WRITE_ENTRIES`]
+/// entries or [`PROGRESS_WRITE_INTERVAL`], and the latest progress is written
+/// when the recorder is dropped, so that an extraction that fails or is
+/// cancelled can still resume from the last entry it extracted.
+struct ExtractionProgress<'a> {
+    /// The path of the progress file.
+    path: &'a Path,
+    /// The index of the last entry extracted, if any.
+    last_index: Option<usize>,
+    /// The number of entries extracted since the file was last written.
+    unwritten: usize,
+    /// When the file was last written, or the recorder created.
+    written_at: Instant,
+}
+
+impl<'a> ExtractionProgress<'a> {
+    /// Creates a recorder that writes to the given progress file.
+    fn new(path: &'a Path) -> Self {
+        Self {
+            path,
+            last_index: None,
+            unwritten: 0,
+            written_at: Instant::now(),
+        }
+    }
+
+    /// Records that the entry at the given index has been extracted.
+    fn record(&mut self, index: usize) -> io::Result<()> {
+        self.last_index = Some(index);
+        self.unwritten += 1;
+
+        if self.unwritten >= PROGRESS_WRITE_ENTRIES
+            || self.written_at.elapsed() >= PROGRESS_WRITE_INTERVAL
+        {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes any progress recorded since the file was last written.
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(index) = self.last_index.filter(|_| self.unwritten > 0) {
+            write_extraction_progress(self.path, index)?;
+            self.unwritten = 0;
+            self.written_at = Instant::now();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ExtractionProgress<'_> {
+    fn drop(&mut self) {
+        _ = self.flush();
+    }
+}
+
+/// Checks whether an archive entry has already been extracted to the given
+/// path by comparing the size and modification time of the file on disk to
+/// the entry header. The size of a duplicate is that of `duplicate_source`,
+/// the file holding the contents it copies. Other hard links are considered
+/// extracted if the link exists.
+fn entry_already_extracted<R: Read>(
+    entry: &tar::Entry<'_, R>,
+    dst: &Path,
+    duplicate_source: Option<&Path>,
+) -> io::Result<bool> {
+    let size = match (entry.header().entry_type(), duplicate_source) {
+        (_, Some(source)) => fs::metadata(source)?.len(),
+        (tar::EntryType::Regular, None) => entry.size(),
+        (tar::EntryType::Link, None) => return Ok(dst.exists()),
+        _ => return Ok(false),
+    };
+
+    let Ok(metadata) = fs::metadata(dst) else {
+        return Ok(false);
+    };
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    Ok(metadata.is_file() && metadata.len() == size && mtime == entry.header().mtime()?)
+}
+
+/// Checks whether the file at the path an archive entry would be extracted
+/// to is at least as new as the entry, so that it is kept when only
+/// overwriting older files. Nothing is kept in place of a directory entry.
+fn existing_entry_is_newer<R: Read>(entry: &tar::Entry<'_, R>, dst: &Path) -> io::Result<bool> {
+    if entry.header().entry_type() == tar::EntryType::Directory {
+        return Ok(false);
+    }
+
+    let Ok(metadata) = fs::symlink_metadata(dst) else {
+        return Ok(false);
+    };
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    Ok(mtime >= entry.header().mtime()?)
+}
+
+/// Where an archive entry is extracted to, given the files already in the
+/// output directory.
+enum Destination {
+    /// The path the entry is at in the archive, after stripping components.
+    Original(PathBuf),
+    /// A new path, since a different file is already at the original one.
+    Renamed(PathBuf),
+    /// Nowhere, since the file already at its path is kept.
+    Kept,
+}
+
+/// Decides where an archive entry is extracted to when the output directory
+/// may already have files in it, following the overwrite mode and the
+/// conflict policy. `duplicate_source` is the file holding the contents of a
+/// duplicate.
+fn resolve_destination<R: Read>(
+    entry: &tar::Entry<'_, R>,
+    output_path: &Path,
+    relative_path: PathBuf,
+    duplicate_source: Option<&Path>,
+    options: &ExtractOptions,
+) -> BackupResult<Destination> {
+    let dst = output_path.join(&relative_path);
+
+    if options.overwrite == OverwriteMode::Never
+        || entry.header().entry_type() == tar::EntryType::Directory
+        || fs::symlink_metadata(&dst).is_err()
+    {
+        return Ok(Destination::Original(relative_path));
+    }
+
+    if options.overwrite == OverwriteMode::OnlyNewer && existing_entry_is_newer(entry, &dst)? {
+        return Ok(Destination::Kept);
+    }
+
+    match options.conflict {
+        ConflictPolicy::Overwrite => Ok(Destination::Original(relative_path)),
+        // Files that already match the entry are not conflicts
+        _ if entry_already_extracted(entry, &dst, duplicate_source)? => Ok(Destination::Kept),
+        ConflictPolicy::Skip => Ok(Destination::Kept),
+        ConflictPolicy::Rename => Ok(Destination::Renamed(unused_renamed_path(
+            output_path,
+            &relative_path,
+        ))),
+        ConflictPolicy::Fail => Err(BackupError::ExtractConflict(dst)),
+    }
+}
+
+/// Where the contents of an archive entry come from when it is unpacked.
+enum Contents {
+    /// The entry itself.
+    Entry,
+    /// The file at the given path, for a duplicate whose first copy was
+    /// extracted.
+    Duplicate(PathBuf),
+    /// Nowhere, for a duplicate whose first copy was not extracted.
+    Missing,
+}
+
+impl Contents {
+    /// Returns the file a duplicate is copied from, if the entry is one.
+    fn duplicate_source(&self) -> Option<&Path> {
+        match self {
+            Self::Duplicate(source) => Some(source),
+            Self::Entry | Self::Missing => None,
+        }
+    }
+}
+
+/// Where the contents of each regular file in an archive are once its entry
+/// has been handled, so that its duplicates can be copied from wherever the
+/// contents ended up rather than from its path in the archive.
+struct DuplicateSources {
+    /// The directory the archive is extracted to.
+    output_path: PathBuf,
+    /// The path holding the contents of each file, keyed by the archive path
+    /// of the file.
+    paths: HashMap<PathBuf, PathBuf>,
+    /// The temporary copies of files that were kept out of the output
+    /// directory, which are removed once extraction finishes.
+    staged: Vec<PartialFileGuard>,
+}
+
+impl DuplicateSources {
+    /// Creates an empty set of sources for extraction to a directory.
+    fn new(output_path: &Path) -> Self {
+        Self {
+            output_path: output_path.to_path_buf(),
+            paths: HashMap::new(),
+            staged: Vec::new(),
+        }
+    }
+
+    /// Records that the contents of an entry were written to the given path
+    /// within the output directory.
+    fn record<R: Read>(
+        &mut self,
+        entry: &tar::Entry<'_, R>,
+        relative_path: &Path,
+    ) -> io::Result<()> {
+        if entry.header().entry_type() == tar::EntryType::Regular {
+            self.paths.insert(
+                entry.path()?.into_owned(),
+                self.output_path.join(relative_path),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Records the contents of an entry that is not extracted, since the file
+    /// already at its path is kept. Unless that file matches the entry, the
+    /// entry is unpacked to a temporary file next to it, so that duplicates
+    /// of the entry can still be copied.
+    fn record_kept<R: Read>(
+        &mut self,
+        entry: &mut tar::Entry<'_, R>,
+        relative_path: &Path,
+    ) -> io::Result<()> {
+        // Empty files are never stored as duplicates
+        if entry.header().entry_type() != tar::EntryType::Regular || entry.size() == 0 {
+            return Ok(());
+        }
+
+        if entry_already_extracted(entry, &self.output_path.join(relative_path), None)? {
+            return self.record(entry, relative_path);
+        }
+
+        let mut staging_path = tmp_file_for(relative_path);
+        while fs::symlink_metadata(self.output_path.join(&staging_path)).is_ok() {
+            staging_path = tmp_file_for(staging_path);
+        }
+
+        self.staged
+            .push(PartialFileGuard::new(self.output_path.join(&staging_path)));
+
+        if unpack_entry_to(entry, &self.output_path, &staging_path, 0)? {
+            self.record(entry, &staging_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns where the contents of an entry come from. A duplicate whose
+    /// first copy was not extracted is skipped with a warning.
+    fn contents<R: Read>(&self, entry: &mut tar::Entry<'_, R>) -> io::Result<Contents> {
+        if !is_duplicate(entry) {
+            return Ok(Contents::Entry);
+        }
+
+        let source = entry
+            .link_name()?
+            .and_then(|original| self.paths.get(original.as_ref()).cloned());
+
+        Ok(if let Some(source) = source {
+            Contents::Duplicate(source)
+        } else {
+            warn!(
+                "Skipping '{}': the file it duplicates was not extracted",
+                entry.path()?.display()
+            );
+            Contents::Missing
+        })
+    }
+}
+
+/// Returns the first path formed by adding a numeric suffix to the name of
+/// the given path, before any extension, that nothing in the output
+/// directory is at.
+fn unused_renamed_path(output_path: &Path, relative_path: &Path) -> PathBuf {
+    let stem = relative_path.file_stem().unwrap_or_default();
+    let extension = relative_path.extension();
+
+    (1..=usize::MAX)
+        .map(|suffix| {
+            let mut name = stem.to_owned();
+            name.push(format!(".{suffix}"));
+
+            if let Some(extension) = extension {
+                name.push(".");
+                name.push(extension);
+            }
+
+            relative_path.with_file_name(name)
+        })
+        .find(|path| fs::symlink_metadata(output_path.join(path)).is_err())
+        .unwrap()
+}
+
+/// Removes the given number of leading components from an archive path.
+/// Returns `None` if no components are left, or if the path leads out of the
+/// directory it is extracted to.
+fn strip_path_components(path: &Path, strip_components: usize) -> Option<PathBuf> {
+    let mut normal_components = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normal_components.push(part),
+            Component::ParentDir => return None,
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+
+    let stripped = normal_components
+        .into_iter()
+        .skip(strip_components)
+        .collect::<PathBuf>();
+
+    (!stripped.as_os_str().is_empty()).then_some(stripped)
+}
+
+/// Unpacks an archive entry to a path within the output directory other than
+/// its own, making sure that the path does not lead out of the directory
+/// through a link. Hard links are pointed at the stripped path of their
+/// target, which may not lead out of the directory either. Returns whether
+/// the entry was unpacked.
+fn unpack_entry_to<R: Read>(
+    entry: &mut tar::Entry<R>,
+    output_path: &Path,
+    relative_path: &Path,
+    strip_components: usize,
+) -> io::Result<bool> {
+    let dst = output_path.join(relative_path);
+    let canonical_output_path = fs::canonicalize(output_path)?;
+
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+
+        if !fs::canonicalize(parent)?.starts_with(&canonical_output_path) {
+            return Ok(false);
+        }
+    }
+
+    if entry.header().entry_type() != tar::EntryType::Link {
+        entry.unpack(&dst)?;
+        return Ok(true);
+    }
+
+    let Some(target) = entry
+        .link_name()?
+        .and_then(|target| strip_path_components(&target, strip_components))
+    else {
+        return Ok(false);
+    };
+
+    let target = output_path.join(target);
+
+    if !fs::canonicalize(&target).is_ok_and(|target| target.starts_with(&canonical_output_path)) {
+        return Ok(false);
+    }
+
+    fs::hard_link(target, &dst)?;
+    Ok(true)
+}
+
+/// Unpacks a duplicate file to a path within the output directory, by copying
+/// `src`, a file within the output directory holding the contents of the
+/// first copy. Neither path may lead out of the output directory through a
+/// link. Returns whether the entry was unpacked.
+fn unpack_duplicate<R: Read>(
+    entry: &tar::Entry<R>,
+    output_path: &Path,
+    relative_path: &Path,
+    src: &Path,
+) -> io::Result<bool> {
+    let dst = output_path.join(relative_path);
+    let canonical_output_path = fs::canonicalize(output_path)?;
+
+    if !fs::canonicalize(&src).is_ok_and(|src| src.starts_with(&canonical_output_path))
+        || !src.is_file()
+    {
+        return Ok(false);
+    }
+
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+
+        if !fs::canonicalize(parent)?.starts_with(&canonical_output_path) {
+            return Ok(false);
+        }
+    }
+
+    copy_duplicate(src, &dst, entry.header())?;
+    Ok(true)
+}
+
+/// Unpacks a special file to a path within the output directory, which may
+/// not be reached through a link leading out of it. A special file that
+/// cannot be created, such as a device node when not running as root, is
+/// skipped with a warning. Returns whether the entry was unpacked.
+fn unpack_special<R: Read>(
+    entry: &tar::Entry<R>,
+    output_path: &Path,
+    relative_path: &Path,
+) -> io::Result<bool> {
+    let dst = output_path.join(relative_path);
+
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+
+        if !fs::canonicalize(parent)?.starts_with(fs::canonicalize(output_path)?) {
+            return Ok(false);
+        }
+    }
+
+    match create_special(&dst, entry.header()) {
+        Ok(()) => Ok(true),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+            ) =>
+        {
+            warn!("Skipping special file '{}': {e}", dst.display());
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Unpacks a single archive entry to the given path within the output
+/// directory, restoring its extended attributes and creation time if they are
+/// being preserved. A duplicate is copied from `duplicate_source`. Returns
+/// whether the entry was unpacked.
+fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    output_path: &Path,
+    relative_path: &Path,
+    duplicate_source: Option<&Path>,
+    options: &ExtractOptions,
+) -> BackupResult<bool> {
+    // Attributes are only restored to regular files, duplicates and
+    // directories, since setting them on a link would set them on its target
+    // instead
+    let has_attributes = duplicate_source.is_some()
+        || matches!(
+            entry.header().entry_type(),
+            tar::EntryType::Regular | tar::EntryType::Directory
+        );
+    let xattrs = if options.preserve_xattrs && has_attributes {
+        entry_xattrs(entry)
+    } else {
+        Vec::new()
+    };
+    let btime = if options.preserve_btime && has_attributes {
+        entry_btime(entry)
+    } else {
+        None
+    };
+
+    // Entries extracted to the path they have in the archive are unpacked
+    // with the checks `tar` makes against leaving the output directory
+    let unpacked = if let Some(source) = duplicate_source {
+        unpack_duplicate(entry, output_path, relative_path, source)?
+    } else if is_special(entry.header().entry_type()) {
+        unpack_special(entry, output_path, relative_path)?
+    } else if entry.path()? == relative_path {
+        entry.unpack_in(output_path)?
+    } else {
+        unpack_entry_to(entry, output_path, relative_path, options.strip_components)?
+    };
+
+    if unpacked && !xattrs.is_empty() {
+        write_xattrs(&output_path.join(relative_path), &xattrs);
+    }
+
+    if let Some(btime) = btime.filter(|_| unpacked) {
+        write_btime(&output_path.join(relative_path), btime);
+    }
+
+    Ok(unpacked)
+}
+
+/// Restores the modification time of an unpacked directory. Unlike files,
+/// tar leaves directories with the time they were extracted at, and the
+/// time must be set after their contents are written, since writing them
+/// changes it. A time that cannot be set is skipped with a warning.
+fn restore_directory_mtime(path: &Path, header: &tar::Header) {
+    let Ok(mtime) = header.mtime() else {
+        return;
+    };
+    let mtime = FileTime::from_unix_time(i64::try_from(mtime).unwrap_or(i64::MAX), 0);
+
+    if let Err(e) = filetime::set_file_mtime(path, mtime) {
+        warn!(
+            "Failed to restore the modification time of '{}': {e}",
+            path.display()
+        );
+    }
+}
+
+/// Records the archive path of an entry extracted to a path with components
+/// stripped, failing if an entry has already been extracted there, unless
+/// both are directories, which can be merged.
+fn check_stripped_path(
+    stripped_paths: &mut HashMap<PathBuf, (PathBuf, bool)>,
+    path: PathBuf,
+    relative_path: &Path,
+    is_dir: bool,
+) -> BackupResult<()> {
+    if let Some((first, first_is_dir)) = stripped_paths.get(relative_path) {
+        if !(is_dir && *first_is_dir) {
+            return Err(BackupError::StrippedPathCollision {
+                path: relative_path.to_path_buf(),
+                first: first.clone(),
+                second: path,
+            });
+        }
+    } else {
+        stripped_paths.insert(relative_path.to_path_buf(), (path, is_dir));
+    }
+
+    Ok(())
+}
+
+/// Unpacks a tar archive one entry at a time, recording progress in a sidecar
+/// file as entries are written. If `resume_index` is provided, entries up
+/// to and including that index are skipped when they are verified to already
+/// exist on disk.
+fn unpack_archive<R: Read>(
+    archive: &mut tar::Archive<R>,
+    output_path: impl AsRef<Path>,
+    progress_path: impl AsRef<Path>,
+    resume_index: Option<usize>,
+    options: &ExtractOptions,
+) -> BackupResult<()> {
+    let output_path = output_path.as_ref();
+    fs::create_dir_all(output_path)?;
+
+    // Directories are unpacked last so that their permissions do not
+    // interfere with the extraction of their contents, mirroring
+    // `tar::Archive::unpack`
+    let mut directories = Vec::new();
+
+    // The archive path of the entry extracted to each path, and whether it is
+    // a directory, to catch entries that collide once components are stripped
+    let mut stripped_paths = HashMap::<PathBuf, (PathBuf, bool)>::new();
+
+    // The file hashes recorded at the end of the archive, if any
+    let mut manifest = None;
+
+    // The archive paths of files that were not extracted to their original
+    // paths, along with where they were extracted to instead, if anywhere
+    let mut redirected_paths = HashMap::new();
+
+    let mut duplicate_sources = DuplicateSources::new(output_path);
+    let mut progress = ExtractionProgress::new(progress_path.as_ref());
+
+    for (index, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+
+        if entry.header().entry_type().is_pax_global_extensions() {
+            manifest = Manifest::from_entry(&mut entry)?.or(manifest);
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        let is_dir = entry.header().entry_type() == tar::EntryType::Directory;
+
+        let relative_path = if options.strip_components == 0 {
+            path
+        } else {
+            let Some(relative_path) = strip_path_components(&path, options.strip_components) else {
+                continue;
+            };
+
+            check_stripped_path(&mut stripped_paths, path, &relative_path, is_dir)?;
+            relative_path
+        };
+
+        if is_dir {
+            directories.push((entry, relative_path));
+            continue;
+        }
+
+        let contents = duplicate_sources.contents(&mut entry)?;
+        if matches!(contents, Contents::Missing) {
+            redirected_paths.insert(entry.path()?.into_owned(), None);
+            continue;
+        }
+
+        if resume_index.is_some_and(|last_index| index <= last_index)
+            && entry_already_extracted(
+                &entry,
+                &output_path.join(&relative_path),
+                contents.duplicate_source(),
+            )?
+        {
+            duplicate_sources.record(&entry, &relative_path)?;
+            continue;
+        }
+
+        let original_path = relative_path.clone();
+        let relative_path = match resolve_destination(
+            &entry,
+            output_path,
+            relative_path,
+            contents.duplicate_source(),
+            options,
+        )? {
+            Destination::Original(relative_path) => relative_path,
+            Destination::Renamed(relative_path) => {
+                redirected_paths.insert(entry.path()?.into_owned(), Some(relative_path.clone()));
+                relative_path
+            }
+            Destination::Kept => {
+                redirected_paths.insert(entry.path()?.into_owned(), None);
+                duplicate_sources.record_kept(&mut entry, &original_path)?;
+                continue;
+            }
+        };
+
+        if unpack_entry(
+            &mut entry,
+            output_path,
+            &relative_path,
+            contents.duplicate_source(),
+            options,
+        )? {
+            duplicate_sources.record(&entry, &relative_path)?;
+        }
+        progress.record(index)?;
+    }
+
+    progress.flush()?;
+
+    directories.sort_by(|(a, _), (b, _)| b.path_bytes().cmp(&a.path_bytes()));
+    // Directories are unpacked deepest first, so setting the time of one
+    // never changes the time of a directory that has already been restored
+    for (mut directory, relative_path) in directories {
+        if unpack_entry(&mut directory, output_path, &relative_path, None, options)? {
+            restore_directory_mtime(&output_path.join(&relative_path), directory.header());
+        }
+    }
+
+    if options.verify_on_extract {
+        verify_extracted_files(
+            output_path,
+            manifest.as_ref(),
+            options.strip_components,
+            &redirected_paths,
+            options.overwrite != OverwriteMode::Never,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Checks the hash of each extracted file against the manifest recorded when
+/// the backup was created. Files extracted somewhere other than their
+/// original paths are checked where they were extracted to, and those that
+/// were kept instead of being extracted are not checked. If `check_unchanged`
+/// is set, the files a delta backup left out are checked too, since they
+/// should already be in the output directory.
+fn verify_extracted_files(
+    output_path: &Path,
+    manifest: Option<&Manifest>,
+    strip_components: usize,
+    redirected_paths: &HashMap<PathBuf, Option<PathBuf>>,
+    check_unchanged: bool,
+) -> BackupResult<()> {
+    let Some(manifest) = manifest else {
+        warn!("The backup has no file hashes, so extracted files cannot be verified");
+        return Ok(());
+    };
+
+    info!("Verifying extracted files");
+
+    for (path, hash) in manifest.files() {
+        let relative_path = match redirected_paths.get(path) {
+            Some(Some(relative_path)) => relative_path.clone(),
+            Some(None) => continue,
+            // Files left out by stripping path components were not extracted
+            None => match strip_path_components(path, strip_components) {
+                Some(relative_path) => relative_path,
+                None => continue,
+            },
+        };
+        let dst = output_path.join(relative_path);
+
+        if hash_file(&dst)? != *hash {
+            return Err(BackupError::FileHashMismatch { path: dst });
+        }
+    }
+
+    if check_unchanged {
+        for (path, hash) in manifest.unchanged_files() {
+            let Some(relative_path) = strip_path_components(path, strip_components) else {
+                continue;
+            };
+            let dst = output_path.join(relative_path);
+
+            // A missing file does not match either
+            if !hash_file(&dst).is_ok_and(|dst_hash| dst_hash == *hash) {
+                return Err(BackupError::FileHashMismatch { path: dst });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Backs up and encrypts a set of paths.
+///
+/// # Errors
+///
+/// This will return an error if validation fails, or if any operation involved
+/// in the backup fails.
+pub fn backup(
+    include_paths: &[impl AsRef<Path>],
+    exclude_globs: &[Pattern],
+    output_path: impl AsRef<Path>,
+    password: &str,
+    chunk_size: usize,
+    pool_size: u8,
+    options: &BackupOptions,
+) -> BackupResult<BackupStats> {
+    backup_with_secret(
+        include_paths,
+        exclude_globs,
+        output_path,
+        Secret::Password(password),
+        chunk_size,
+        pool_size,
+        options,
+    )
+}
+
+/// Backs up and encrypts a set of paths with a key instead of a password.
+///
+/// The key is used as is, without any key derivation, so it must be a
+/// uniformly random secret, such as one from a hardware token or key
+/// management system. Any additional passwords in the options can also open
+/// the backup.
+///
+/// # Errors
+///
+/// This will return an error if validation fails, or if any operation involved
+/// in the backup fails.
+pub fn backup_with_key(
+    include_paths: &[impl AsRef<Path>],
+    exclude_globs: &[Pattern],
+    output_path: impl AsRef<Path>,
+    key: [u8; AES_KEY_SIZE],
+    chunk_size: usize,
+    pool_size: u8,
+    options: &BackupOptions,
+) -> BackupResult<BackupStats> {
+    backup_with_secret(
+        include_paths,
+        exclude_globs,
+        output_path,
+        Secret::Key(&key),
+        chunk_size,
+        pool_size,
+        options,
+    )
+}
+
+/// Validates the include paths and options of a backup, returning each include
+/// path along with the name it is stored under.
+pub fn validate_backup<'a>(
+    include_paths: &'a [impl AsRef<Path>],
+    options: &BackupOptions,
+) -> BackupResult<Vec<(&'a Path, PathBuf)>> {
+    // Make sure there are no include directories with the same name
+    validate_no_duplicate_include_names(include_paths, options.path_mode)?;
+
+    // Make sure nothing dangerous is included unless explicitly allowed
+    if !options.allow_root {
+        validate_no_dangerous_include_paths(include_paths)?;
+    }
+
+    // Make sure the key derivation parameters are safe
+    options.kdf_params.validate()?;
+
+    // Make sure the compression level is supported
+    if let Some(level) = options.compression_level {
+        if !COMPRESSION_LEVELS.contains(&level) {
+            return Err(BackupError::InvalidCompressionLevel(level));
+        }
+
+        if options.indexed {
+            return Err(BackupError::IncompatibleOptions(
+                "an indexed backup cannot be compressed".to_owned(),
+            ));
+        }
+    }
+
+    // Make sure there is a directory to compare a delta backup against
+    if let Some(reference_dir) = &options.reference_dir {
+        if !reference_dir.is_dir() {
+            return Err(BackupError::InvalidReferenceDir(reference_dir.clone()));
+        }
+    }
+
+    // Make sure the recovery key can be used before anything is written
+    if let Some(public_key) = &options.recovery_public_key {
+        if options.deterministic {
+            return Err(BackupError::IncompatibleOptions(
+                "a deterministic backup cannot have a recovery key".to_owned(),
+            ));
+        }
+
+        recovery_key_agreement(public_key)?;
+    }
+
+    // Make sure the checksum can be computed
+    if !options.checksum_algorithm.is_supported() {
+        return Err(BackupError::UnsupportedChecksumAlgorithm(
+            options.checksum_algorithm,
+        ));
+    }
+
+    // Validate include paths and get their names
+    let include_paths_with_names = include_paths.iter().try_fold(
+        Vec::new(),
+        |mut include_paths_with_names, include_path| {
+            include_paths_with_names.push((
+                include_path.as_ref(),
+                include_name(include_path.as_ref(), options.path_mode)?,
+            ));
+            Ok::<_, BackupError>(include_paths_with_names)
+        },
+    )?;
+
+    if cfg!(not(unix)) && !options.dereference_hardlinks {
+        warn!("Hard link detection is not supported on this platform, so hard linked files will be stored as copies");
+    }
+
+    if options.path_mode == PathMode::Absolute {
+        warn!("Storing absolute paths. Restoring this backup to the filesystem root will overwrite the original files");
+    }
+
+    Ok(include_paths_with_names)
+}
+
+/// Backs up and encrypts a set of paths, writing the backup to the given
+/// destination instead of a file.
+///
+/// The destination is only ever written to sequentially, so it can be a
+/// network stream or an upload to remote storage. The path in the returned
+/// statistics is empty.
+///
+/// # Errors
+///
+/// This will return an error if validation fails, or if any operation involved
+/// in the backup fails, including writing to the destination.
+pub fn encrypt_backup_to<W: Write + Send>(
+    include_paths: &[impl AsRef<Path>],
+    exclude_globs: &[Pattern],
+    dest: W,
+    password: &str,
+    chunk_size: usize,
+    pool_size: u8,
+    options: &BackupOptions,
+) -> BackupResult<BackupStats> {
+    info!("Validating backup");
+
+    let include_paths_with_names = validate_backup(include_paths, options)?;
+
+    write_backup(
+        &include_paths_with_names,
+        exclude_globs,
+        dest,
+        Vec::new(),
+        Secret::Password(password),
+        chunk_size,
+        pool_size,
+        options,
+    )
+}
+
+/// Backs up and encrypts a set of paths to a file, so that the backup can be
+/// opened by the given secret.
+fn backup_with_secret(
+    include_paths: &[impl AsRef<Path>],
+    exclude_globs: &[Pattern],
+    output_path: impl AsRef<Path>,
+    secret: Secret,
+    chunk_size: usize,
+    pool_size: u8,
+    options: &BackupOptions,
+) -> BackupResult<BackupStats> {
+    info!("Validating backup");
+
+    let include_paths_with_names = validate_backup(include_paths, options)?;
+
+    // Make sure output file does not already exist, unless it is to be
+    // replaced
+    if options.overwrite {
+        validate_path_does_not_exist(&output_path, PathType::Directory)?;
+    } else {
+        validate_path_does_not_exist(&output_path, PathType::Any)?;
+    }
+
+    // A backup that replaces an existing file is written alongside it first,
+    // and only renamed over it once complete
+    let replaced_path = options
+        .overwrite
+        .then(|| fs::canonicalize(&output_path).ok())
+        .flatten();
+    let write_path = if options.overwrite {
+        tmp_file_for(&output_path)
+    } else {
+        output_path.as_ref().to_path_buf()
+    };
+
+    // Create the output file, which is removed again unless the backup
+    // completes
+    let output_file = File::create_new(&write_path)?;
+    let output_guard = PartialFileGuard::new(&write_path);
+    let output_paths = iter::once(fs::canonicalize(&write_path)?)
+        .chain(replaced_path)
+        .collect();
+
+    let stats = write_backup(
+        &include_paths_with_names,
+        exclude_globs,
+        output_file,
+        output_paths,
+        secret,
+        chunk_size,
+        pool_size,
+        options,
+    )?;
+
+    // Read the backup back to make sure it was written correctly
+    if options.verify_after_write {
+        info!("Verifying written backup");
+
+        if let Err(e) =
+            verify_with_secret(&write_path, secret, pool_size, &VerifyOptions::default())
+        {
+            // The backup was written in full, so it is only removed if asked
+            if !(options.remove_unverified_output || options.overwrite) {
+                output_guard.complete();
+            }
+
+            return Err(BackupError::VerificationFailed(Box::new(e)));
+        }
+    }
+
+    // Make sure the backup has reached the storage device before reporting
+    // that it is complete
+    if options.durable {
+        sync_file(&write_path)?;
+    }
+
+    if options.overwrite {
+        fs::rename(&write_path, &output_path)?;
+    }
+
+    if options.durable {
+        sync_parent_dir(&output_path)?;
+    }
+
+    output_guard.complete();
+
+    // Return the output file path and statistics
+    Ok(BackupStats {
+        path: output_path.as_ref().to_path_buf(),
+        ..stats
+    })
+}
+
+/// Writes a tar archive of a set of validated include paths, returning the
+/// writer once the archive has been closed, along with any paths that were
+/// skipped and an index of the entries, if one is being stored. An archive
+/// with no entries is an error, since it almost always means the exclusions
+/// were broader than intended.
+fn write_archive<T: Write>(
+    dest: T,
+    include_paths_with_names: &[(&Path, PathBuf)],
+    exclude_globs: &[Pattern],
+    options: &BackupOptions,
+    output_paths: Vec<PathBuf>,
+) -> BackupResult<(T, Vec<SkippedPath>, ArchiveIndex)> {
+    let mut context = ArchiveContext::new(exclude_globs, options, output_paths);
+    let mut archive = tar::Builder::new(PositionWriter::new(
+        dest,
+        Rc::clone(&context.archive_position),
+    ));
+
+    // Add each include path to the archive
+    for (include_path, include_name) in include_paths_with_names {
+        info!("Backing up '{}'", include_path.display());
+
+        append_to_archive(&mut archive, &mut context, include_path, include_name)?;
+    }
+
+    if context.entries == 0 {
+        return Err(BackupError::EmptyBackup);
+    }
+
+    if options.reference_dir.is_some() {
+        info!(
+            "Left out {} files unchanged from the reference directory",
+            context.manifest.unchanged_files().count()
+        );
+    }
+
+    if options.dedup {
+        info!(
+            "Stored {} duplicate files as references to their first copies",
+            context.duplicates
+        );
+    }
+
+    // Record the hash of every file at the end of the archive
+    context.manifest.append_to(&mut archive)?;
+
+    // Close the archive
+    Ok((
+        archive.into_inner()?.into_inner(),
+        context.skipped,
+        context.index,
+    ))
+}
+
+/// Backs up and encrypts a set of validated include paths to a writer, so that
+/// the backup can be opened by the given secret. If the writer is a file, its
+/// canonical path must be provided so that the backup does not include itself,
+/// along with that of any file it will replace.
+#[allow(clippy::too_many_arguments)]
+fn write_backup<W: Write + Send>(
+    include_paths_with_names: &[(&Path, PathBuf)],
+    exclude_globs: &[Pattern],
+    dest: W,
+    output_paths: Vec<PathBuf>,
+    secret: Secret,
+    chunk_size: usize,
+    pool_size: u8,
+    options: &BackupOptions,
+) -> BackupResult<BackupStats> {
+    info!("Beginning backup");
+
+    let start = Instant::now();
+    let mut dest = CountingWriter::new(dest);
+
+    // Generate a random key to encrypt the backup with, and write a header
+    // with a copy of it wrapped by each password. Deterministic backups
+    // derive the key from the first password instead.
+    let secrets = iter::once(secret)
+        .chain(
+            options
+                .additional_passwords
+                .iter()
+                .map(|password| Secret::Password(password)),
+        )
+        .collect::<Vec<_>>();
+    let password_kdf = Kdf::Argon2(options.kdf_params);
+    let (mut header, key, nonce_mode) = if options.deterministic {
+        let (header, key) =
+            BackupHeader::new_deterministic(&secrets, chunk_size as u64, password_kdf)?;
+        (header, key, NonceMode::Derived)
+    } else {
+        let key = random_key();
+        let mut header = BackupHeader::new(key, &secrets, chunk_size as u64, password_kdf)?;
+        if let Some(public_key) = &options.recovery_public_key {
+            header.add_recovery_slot(public_key, key)?;
+        }
+        (header, key, NonceMode::Random)
+    };
+    header.checksum_algorithm = options.checksum_algorithm;
+    header.compressed = options.compression_level.is_some();
+    header.index = options.index_mode();
+    header.index_offsets = options.indexed;
+    header.chunk_keys = options.chunk_keys;
+    header.seal(key)?;
+    header.write(&mut dest)?;
+
+    // Build the tar archive, encrypting it in chunks as it is written
+    let mut payload = ChecksumWriter::new(&mut dest, header.checksum_algorithm)?;
+    let mut skipped = Vec::new();
+    let mut index = ArchiveIndex::default();
+    let archive_size = encrypt_stream(
+        &mut payload,
+        PayloadKey::new(key, header.chunk_keys),
+        chunk_size,
+        pool_size,
+        options.adaptive_memory.then_some(MEMORY_LIMIT),
+        nonce_mode,
+        |encryptor| {
+            let writer = ProgressWriter::new(
+                encryptor,
+                options.progress.clone(),
+                ProgressStage::Archiving,
+                None,
+                options.cancel.clone(),
+                options.pause.clone(),
+            );
+
+            // Compress the archive before it is encrypted, if requested
+            if let Some(level) = options.compression_level {
+                let encoder = zstd::Encoder::new(writer, level)?;
+                let (encoder, archive_skipped, archive_index) = write_archive(
+                    encoder,
+                    include_paths_with_names,
+                    exclude_globs,
+                    options,
+                    output_paths,
+                )?;
+                encoder.finish()?.finish();
+                skipped = archive_skipped;
+                index = archive_index;
+            } else {
+                let (writer, archive_skipped, archive_index) = write_archive(
+                    writer,
+                    include_paths_with_names,
+                    exclude_globs,
+                    options,
+                    output_paths,
+                )?;
+                writer.finish();
+                skipped = archive_skipped;
+                index = archive_index;
+            }
+
+            Ok(())
+        },
+    )
+    .map_err(|e| {
+        if is_cancelled(options.cancel.as_ref()) {
+            BackupError::Cancelled
+        } else {
+            e
+        }
+    })?;
+
+    // Mark the end of the payload, store the index after it if requested,
+    // and record the checksum of both
+    let index = match options.index_mode() {
+        IndexMode::None => None,
+        mode => Some(index.seal(mode, key, nonce_mode)?),
+    };
+    write_checksum_trailer(payload, index.as_deref())?;
+
+    info!("Backup complete");
+
+    Ok(BackupStats {
+        path: PathBuf::new(),
+        archive_size,
+        output_size: dest.bytes_written(),
+        elapsed: start.elapsed(),
+        skipped,
+    })
+}
+
+/// Removes a temporary file, wiping its contents first if requested.
+fn remove_tmp_file(path: impl AsRef<Path>, secure_delete: bool) -> io::Result<()> {
+    if secure_delete {
+        info!("Securely deleting {}", path.as_ref().display());
+        remove_file_securely(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Extracts an encrypted backup.
+///
+/// # Errors
+///
+/// This will return an error if validation fails, or if any operation involved
+/// in the extraction fails.
+pub fn extract(
+    path: impl AsRef<Path>,
+    output_path: impl AsRef<Path>,
+    password: &str,
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<PathBuf> {
+    extract_with_secret(
+        path,
+        output_path,
+        Secret::Password(password),
+        pool_size,
+        options,
+    )
+}
+
+/// Extracts an encrypted backup using a key provided by the caller instead of
+/// a password. This opens backups created with [`backup_with_key`].
+///
+/// # Errors
+///
+/// This will return an error if validation fails, or if any operation involved
+/// in the extraction fails.
+pub fn extract_with_key(
+    path: impl AsRef<Path>,
+    output_path: impl AsRef<Path>,
+    key: [u8; AES_KEY_SIZE],
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<PathBuf> {
+    extract_with_secret(path, output_path, Secret::Key(&key), pool_size, options)
+}
+
+/// Extracts an encrypted backup using a recovery private key instead of a
+/// password. This opens backups created with the matching public key as
+/// [`BackupOptions::recovery_public_key`].
+///
+/// # Errors
+///
+/// This will return an error if validation fails, or if any operation involved
+/// in the extraction fails.
+pub fn extract_with_recovery_key(
+    path: impl AsRef<Path>,
+    output_path: impl AsRef<Path>,
+    recovery_key: [u8; RECOVERY_KEY_SIZE],
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<PathBuf> {
+    extract_with_secret(
+        path,
+        output_path,
+        Secret::RecoveryKey(&recovery_key),
+        pool_size,
+        options,
+    )
+}
+
+/// Extracts an encrypted backup read from the given source instead of a file.
+///
+/// The source is only ever read sequentially, so it can be a network stream
+/// or a download from remote storage. The decrypted archive is staged in a
+/// temporary file next to the output directory, or in the configured
+/// temporary directory.
+///
+/// # Errors
+///
+/// This will return an error if validation fails, or if any operation involved
+/// in the extraction fails, including reading from the source.
+pub fn decrypt_backup_from<R: Read + Send>(
+    src: R,
+    output_path: impl AsRef<Path>,
+    password: &str,
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<PathBuf> {
+    read_backup(
+        src,
+        None,
+        output_path,
+        Secret::Password(password),
+        pool_size,
+        options,
+    )
+}
+
+/// Extracts an encrypted backup file, opening it with the given secret.
+fn extract_with_secret(
+    path: impl AsRef<Path>,
+    output_path: impl AsRef<Path>,
+    secret: Secret,
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<PathBuf> {
+    let src = File::open(&path)?;
+    let src_size = src.metadata()?.len();
+
+    read_backup(src, Some(src_size), output_path, secret, pool_size, options)
+}
+
+/// Extracts an encrypted backup from a reader, opening it with the given
+/// secret. The size of the backup is used to report progress, if known.
+fn read_backup<R: Read + Send>(
+    src: R,
+    src_size: Option<u64>,
+    output_path: impl AsRef<Path>,
+    secret: Secret,
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<PathBuf> {
+    info!("Validating extraction");
+
+    // When restoring to the root, nothing is staged next to the output
+    // directory, since that would place it in the root too
+    let root_path = Path::new(MAIN_SEPARATOR_STR);
+    let (output_path, staging_path, temp_dir) = if options.restore_to_root {
+        warn!("Restoring backup to the filesystem root, overwriting any existing files at the original paths");
+        let temp_dir = options.temp_dir.clone().unwrap_or_else(env::temp_dir);
+        let staging_path = temp_dir.join(ROOT_STAGING_NAME);
+        (root_path, staging_path, Some(temp_dir))
+    } else {
+        let output_path = output_path.as_ref();
+        (
+            output_path,
+            output_path.to_path_buf(),
+            options.temp_dir.clone(),
+        )
+    };
+
+    // Check for progress from a previous extraction attempt
+    let progress_path = progress_file_for(&staging_path);
+    let resume_index = if options.resume {
+        read_extraction_progress(&progress_path)?
+    } else {
+        None
+    };
+
+    if options.atomic && !options.restore_to_root && options.overwrite != OverwriteMode::Never {
+        return Err(BackupError::IncompatibleOptions(
+            "an atomic extraction cannot overwrite an existing directory".to_owned(),
+        ));
+    }
+
+    if options.conflict != ConflictPolicy::default() && options.overwrite == OverwriteMode::Never {
+        return Err(BackupError::IncompatibleOptions(
+            "a conflict policy needs an overwrite mode that extracts into an existing directory"
+                .to_owned(),
+        ));
+    }
+
+    // Make sure output directory does not already exist, unless resuming,
+    // overwriting or restoring to the root, which always exists
+    if resume_index.is_some() {
+        info!("Resuming previous extraction");
+    } else if !options.restore_to_root && options.overwrite == OverwriteMode::Never {
+        validate_path_does_not_exist(output_path, PathType::Any)?;
+    }
+
+    // A staged extraction is unpacked next to the output directory, and only
+    // moved into place once it succeeds
+    let unpack_path = if options.atomic && !options.restore_to_root {
+        let partial_path = partial_dir_for(output_path);
+
+        if resume_index.is_none() {
+            validate_path_does_not_exist(&partial_path, PathType::Any)?;
+        }
+
+        partial_path
+    } else {
+        output_path.to_path_buf()
+    };
+
+    // Make sure the temporary directory is usable
+    if let Some(temp_dir) = &temp_dir {
+        validate_writable_dir(temp_dir)?;
+    }
+
+    // Remove the decrypted file left behind by an interrupted extraction
+    let tar_path = tmp_file_in(temp_dir.as_deref(), &staging_path);
+
+    if options.resume && tar_path.is_file() {
+        remove_tmp_file(&tar_path, options.secure_delete)?;
+    }
+
+    info!("Decrypting backup");
+
+    // Read the header and unwrap the key used for encryption
+    let backup = open_backup_stream(src, secret)?;
+
+    // Decrypt and unpack the backup, cleaning up after it if it is cancelled,
+    // or if it fails while staged
+    if let Err(e) = unpack_backup(
+        backup,
+        src_size,
+        &tar_path,
+        &unpack_path,
+        &progress_path,
+        resume_index,
+        pool_size,
+        options,
+    ) {
+        let cancelled = is_cancelled(options.cancel.as_ref());
+
+        if cancelled {
+            info!("Extraction cancelled, removing partial output");
+        }
+
+        // The decrypted archive is only kept to resume from, since it would
+        // otherwise stand in the way of extracting again
+        if (cancelled || !options.resume) && tar_path.exists() {
+            remove_tmp_file(&tar_path, options.secure_delete)?;
+        }
+
+        // Output that can be resumed from is kept, and the root or an
+        // existing directory being overwritten is never removed
+        if (cancelled || options.atomic)
+            && resume_index.is_none()
+            && !options.resume
+            && !options.restore_to_root
+            && options.overwrite == OverwriteMode::Never
+        {
+            match fs::remove_dir_all(&unpack_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        return Err(if cancelled { BackupError::Cancelled } else { e });
+    }
+
+    // Move a staged extraction into place
+    if unpack_path != output_path {
+        fs::rename(&unpack_path, output_path)?;
+    }
+
+    // Delete temporary tar file and progress file
+    remove_tmp_file(&tar_path, options.secure_delete)?;
+
+    if progress_path.exists() {
+        fs::remove_file(progress_path)?;
+    }
+
+    info!("Extraction complete");
+
+    // Return the output directory path
+    Ok(output_path.to_path_buf())
+}
+
+/// Decrypts an opened backup to a temporary tar file, then unpacks it into
+/// the output directory.
+#[allow(clippy::too_many_arguments)]
+fn unpack_backup<R: Read + Send>(
+    backup: OpenedBackup<PayloadReader<R>>,
+    src_size: Option<u64>,
+    tar_path: &Path,
+    output_path: &Path,
+    progress_path: &Path,
+    resume_index: Option<usize>,
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<()> {
+    // Decrypt the backup
+    let mut reader = ProgressReader::new(
+        backup.payload,
+        options.progress.clone(),
+        ProgressStage::Decrypting,
+        src_size,
+        options.cancel.clone(),
+        options.pause.clone(),
+    );
+    let tar_file = decrypt_backup(
+        &mut reader,
+        tar_path,
+        backup.key,
+        pool_size,
+        options.adaptive_memory.then_some(MEMORY_LIMIT),
+        backup.end_marker,
+    )?;
+    reader.finish();
+
+    info!("Extracting decrypted backup");
+
+    // Extract the tar file, decompressing it first if necessary
+    let tar_size = tar_file.metadata()?.len();
+    let mut tar_reader = ProgressReader::new(
+        tar_file,
+        options.progress.clone(),
+        ProgressStage::Unpacking,
+        Some(tar_size),
+        options.cancel.clone(),
+        options.pause.clone(),
+    );
+
+    if backup.compressed {
+        let mut archive = tar::Archive::new(zstd::Decoder::new(&mut tar_reader)?);
+        unpack_archive(
+            &mut archive,
+            output_path,
+            progress_path,
+            resume_index,
+            options,
+        )?;
+    } else {
+        let mut archive = tar::Archive::new(&mut tar_reader);
+        unpack_archive(
+            &mut archive,
+            output_path,
+            progress_path,
+            resume_index,
+            options,
+        )?;
+    }
+
+    tar_reader.finish();
+
+    Ok(())
+}
+
+/// Replaces the header of a backup file, leaving its encrypted payload
+/// untouched. `payload_offset` is the size of the header being replaced.
+///
+/// The new header and the payload are written to a temporary file which then
+/// replaces the backup, so that a failure part way through cannot leave the
+/// backup with a torn header.
+fn replace_header(
+    path: impl AsRef<Path>,
+    file: &mut File,
+    payload_offset: u64,
+    header: &BackupHeader,
+) -> BackupResult<()> {
+    let tmp_path = tmp_file_for(&path);
+    let mut tmp_file = File::create_new(&tmp_path)?;
+
+    let copy_result = (|| {
+        header.write(&mut tmp_file)?;
+        file.seek(SeekFrom::Start(payload_offset))?;
+        io::copy(file, &mut tmp_file)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    })();
+
+    if copy_result.is_err() {
+        _ = fs::remove_file(&tmp_path);
+    }
+
+    Ok(copy_result?)
+}
+
+/// Rewrites a backup from before checksums were introduced with a new header,
+/// adding the end marker and checksum trailer its payload lacks. The
+/// encrypted sections of the payload are copied as they are.
+fn add_checksum_trailer(
+    path: impl AsRef<Path>,
+    file: &mut File,
+    payload_offset: u64,
+    header: &BackupHeader,
+) -> BackupResult<()> {
+    let tmp_path = tmp_file_for(&path);
+    let mut tmp_file = File::create_new(&tmp_path)?;
+
+    let copy_result = (|| {
+        header.write(&mut tmp_file)?;
+        file.seek(SeekFrom::Start(payload_offset))?;
+
+        let mut src = io::BufReader::new(&mut *file);
+        let mut dest =
+            ChecksumWriter::new(io::BufWriter::new(&mut tmp_file), header.checksum_algorithm)?;
+
+        while let Some(section) = read_section(&mut src)? {
+            if section.is_empty() {
+                break;
+            }
+
+            write_section(&mut dest, &section)?;
+        }
+
+        write_checksum_trailer(dest, None)?
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+
+        BackupResult::Ok(())
+    })();
+
+    if copy_result.is_err() {
+        _ = fs::remove_file(&tmp_path);
+    }
+
+    copy_result
+}
+
+/// Migrates a backup from before headers were introduced to a format with a
+/// header, opened by the new password.
+///
+/// Such backups are encrypted directly with the key derived from the
+/// password, so the payload is re-encrypted under a new random data key.
+/// Otherwise anyone who knew the old password could still decrypt the payload
+/// after the password was changed. Unless `upgrade_format` is set, the
+/// payload is written without a checksum trailer, as it was before.
+fn migrate_legacy_backup(
+    path: impl AsRef<Path>,
+    file: &mut File,
+    old_password: &str,
+    new_password: &str,
+    upgrade_format: bool,
+) -> BackupResult<()> {
+    info!("Migrating backup to the current format");
+
+    // Make sure the password is correct by decrypting the first section
+    let old_key = password_to_key(old_password);
+    let chunk_size = match read_section(&mut *file)? {
+        Some(data) => aes_decrypt(old_key, &data)?.len() as u64,
+        None => 0,
+    };
+
+    let data_key = random_key();
+    let mut header = BackupHeader::new(
+        data_key,
+        &[Secret::Password(new_password)],
+        chunk_size,
+        Kdf::default(),
+    )?;
+
+    if !upgrade_format {
+        header.version = CHECKSUMLESS_FORMAT_VERSION;
+    }
+
+    let tmp_path = tmp_file_for(&path);
+    let mut tmp_file = File::create_new(&tmp_path)?;
+
+    let copy_result = (|| {
+        header.write(&mut tmp_file)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut src = io::BufReader::new(&mut *file);
+        let mut dest =
+            ChecksumWriter::new(io::BufWriter::new(&mut tmp_file), header.checksum_algorithm)?;
+
+        while let Some(section) = read_section(&mut src)? {
+            if section.is_empty() {
+                break;
+            }
+
+            let data = aes_decrypt(old_key, &section)?;
+            write_section(&mut dest, &aes_encrypt(data_key, &data)?)?;
+        }
+
+        let dest = if header.has_checksum() {
+            write_checksum_trailer(dest, None)?
+        } else {
+            dest.finish().0
+        };
+        dest.into_inner().map_err(io::IntoInnerError::into_error)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+
+        BackupResult::Ok(())
+    })();
+
+    if copy_result.is_err() {
+        _ = fs::remove_file(&tmp_path);
+    }
+
+    copy_result
+}
+
+/// State carried across the backups being merged into one.
+#[derive(Default)]
+struct MergeContext {
+    /// The position and path of the backup each top-level name was first
+    /// seen in.
+    names: HashMap<PathBuf, (usize, PathBuf)>,
+    /// The file hashes from the manifests of every backup merged so far.
+    manifest: Manifest,
+    /// The number of entries copied so far.
+    entries: usize,
+}
+
+/// Copies the entries of a decrypted archive into the merged archive, along
+/// with their PAX attributes. The manifest at the end of the archive is
+/// collected rather than copied, so that the merged archive ends with a
+/// single manifest covering every backup.
+fn copy_archive_entries<R: Read, T: Write>(
+    src: R,
+    archive: &mut tar::Builder<T>,
+    context: &mut MergeContext,
+    backup_index: usize,
+    backup_path: &Path,
+) -> BackupResult<()> {
+    let mut src_archive = tar::Archive::new(src);
+
+    for entry in src_archive.entries()? {
+        let mut entry = entry?;
+
+        if entry.header().entry_type().is_pax_global_extensions() {
+            if let Some(manifest) = Manifest::from_entry(&mut entry)? {
+                for (path, hash) in manifest.files() {
+                    context.manifest.push(path, *hash);
+                }
+            }
+
+            continue;
+        }
+
+        // Entries may share a top-level name with others from the same
+        // backup, but not with those from another one
+        let path = entry.path()?.into_owned();
+        let name = path
+            .components()
+            .find_map(|component| match component {
+                Component::Normal(part) => Some(PathBuf::from(part)),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        match context.names.get(&name) {
+            Some((first_index, first)) if *first_index != backup_index => {
+                return Err(BackupError::DuplicateIncludeName {
+                    name: name.display().to_string(),
+                    first: first.clone(),
+                    second: backup_path.to_path_buf(),
+                });
+            }
+            Some(_) => {}
+            None => {
+                context
+                    .names
+                    .insert(name, (backup_index, backup_path.to_path_buf()));
+            }
+        }
+
+        // Paths are written again by the builder, so only the other
+        // attributes are carried over
+        let mut extensions = Vec::new();
+
+        if let Some(pax_extensions) = entry.pax_extensions()? {
+            for extension in pax_extensions {
+                let extension = extension?;
+
+                if let Ok(key) = extension.key() {
+                    if !matches!(key, "path" | "linkpath") {
+                        extensions.push((key.to_owned(), extension.value_bytes().to_vec()));
+                    }
+                }
+            }
+        }
+
+        archive.append_pax_extensions(
+            extensions
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice())),
+        )?;
+
+        let mut header = entry.header().clone();
+
+        match header.entry_type() {
+            tar::EntryType::Symlink | tar::EntryType::Link => {
+                let target = entry.link_name()?.unwrap_or_default().into_owned();
+                archive.append_link(&mut header, &path, target)?;
+            }
+            _ => archive.append_data(&mut header, &path, &mut entry)?,
+        }
+
+        context.entries += 1;
+    }
+
+    Ok(())
+}
+
+/// Combines several encrypted backups into one new backup, which is
+/// encrypted with the same password the backups are opened with.
+///
+/// Each backup is decrypted in turn and the entries of its archive are
+/// copied into the new backup, in order, so nothing is staged on disk. Every
+/// top-level name, such as the name of each include path, must only appear
+/// in one of the backups, or the merge fails and the partially written
+/// backup is removed. The manifests of file hashes are combined, so files
+/// from every backup can still be verified when the merged backup is
+/// extracted. The new backup uses the chunk size of the first backup, and
+/// is neither compressed nor indexed.
+///
+/// # Errors
+///
+/// This will return an error if the output path already exists, if any
+/// backup cannot be opened with the password or fails to decrypt, if two
+/// backups share a top-level name, or if the backups have no entries.
+pub fn merge(
+    backups: &[impl AsRef<Path>],
+    output_path: impl AsRef<Path>,
+    password: &str,
+    pool_size: u8,
+) -> BackupResult<BackupStats> {
+    info!("Validating merge");
+
+    let output_path = output_path.as_ref();
+    validate_path_does_not_exist(output_path, PathType::Any)?;
+    let chunk_size = match backups.first() {
+        Some(first) => get_chunk_size(first)?,
+        None => return Err(BackupError::EmptyBackup),
+    };
+
+    let output_file = File::create_new(output_path)?;
+    let output_guard = PartialFileGuard::new(output_path);
+
+    info!("Beginning merge");
+
+    let start = Instant::now();
+    let mut dest = CountingWriter::new(output_file);
+    let secret = Secret::Password(password);
+    let key = random_key();
+    let header = BackupHeader::new(key, &[secret], chunk_size as u64, Kdf::default())?;
+    header.write(&mut dest)?;
+
+    let mut payload = ChecksumWriter::new(&mut dest, header.checksum_algorithm)?;
+    let archive_size = encrypt_stream(
+        &mut payload,
+        PayloadKey::new(key, false),
+        chunk_size,
+        pool_size,
+        None,
+        NonceMode::Random,
+        |encryptor| {
+            let mut archive = tar::Builder::new(encryptor);
+            let mut context = MergeContext::default();
+
+            for (backup_index, backup_path) in backups.iter().enumerate() {
+                let backup_path = backup_path.as_ref();
+                info!("Merging '{}'", backup_path.display());
+
+                let mut backup = open_backup(backup_path, secret)?;
+                let compressed = backup.compressed;
+                decrypt_reader(
+                    &mut backup.payload,
+                    backup.key,
+                    pool_size,
+                    backup.end_marker,
+                    |reader| {
+                        if compressed {
+                            let decoder = zstd::Decoder::new(reader)?;
+                            copy_archive_entries(
+                                decoder,
+                                &mut archive,
+                                &mut context,
+                                backup_index,
+                                backup_path,
+                            )
+                        } else {
+                            copy_archive_entries(
+                                reader,
+                                &mut archive,
+                                &mut context,
+                                backup_index,
+                                backup_path,
+                            )
+                        }
+                    },
+                )?;
+            }
+
+            if context.entries == 0 {
+                return Err(BackupError::EmptyBackup);
+            }
+
+            context.manifest.append_to(&mut archive)?;
+            archive.into_inner()?;
+
+            Ok(())
+        },
+    )?;
+    write_checksum_trailer(payload, None)?;
+
+    sync_file(output_path)?;
+    sync_parent_dir(output_path)?;
+    output_guard.complete();
+
+    info!("Merge complete");
+
+    Ok(BackupStats {
+        path: output_path.to_path_buf(),
+        archive_size,
+        output_size: dest.bytes_written(),
+        elapsed: start.elapsed(),
+        skipped: Vec::new(),
+    })
+}
+
+/// Changes one of the passwords that can open a backup.
+///
+/// Only the copy of the data key wrapped by the old password is replaced, so
+/// the payload is not re-encrypted. Other passwords that can open the backup
+/// are unaffected. Backups created before headers were introduced are
+/// migrated to a format with a header in the process, which re-encrypts the
+/// payload under a new data key. The old and new passwords may be the same,
+/// in which case such a backup is only migrated, or upgraded if
+/// [`ChangePasswordOptions::upgrade_format`] is set.
+///
+/// # Errors
+///
+/// This will return an error if the old password cannot open the backup, if
+/// the backup is deterministic, if the header fails authentication while
+/// being upgraded, or if any IO operation fails.
+pub fn change_password(
+    path: impl AsRef<Path>,
+    old_password: &str,
+    new_password: &str,
+    options: &ChangePasswordOptions,
+) -> BackupResult<()> {
+    info!("Changing backup password");
+
+    let mut file = File::options().read(true).write(true).open(&path)?;
+
+    let existing_header = BackupHeader::read(&mut file)?;
+    let payload_offset = file.stream_position()?;
+    let had_checksum = existing_header
+        .as_ref()
+        .is_some_and(BackupHeader::has_checksum);
+
+    let Some(mut header) = existing_header else {
+        migrate_legacy_backup(
+            &path,
+            &mut file,
+            old_password,
+            new_password,
+            options.upgrade_format,
+        )?;
+        info!("Password changed");
+
+        return Ok(());
+    };
+
+    let kdf = options.upgrade_format.then(Kdf::default);
+    let data_key = header.replace_password(old_password, new_password, kdf)?;
+
+    if options.upgrade_format && header.version < FORMAT_VERSION {
+        info!(
+            "Upgrading backup from format version {} to {FORMAT_VERSION}",
+            header.version
+        );
+        header.upgrade(data_key)?;
+    }
+
+    if header.has_checksum() && !had_checksum {
+        add_checksum_trailer(&path, &mut file, payload_offset, &header)?;
+    } else {
+        replace_header(&path, &mut file, payload_offset, &header)?;
+    }
+
+    info!("Password changed");
+
+    Ok(())
+}
+
+/// Verifies the checksum of an encrypted backup without decrypting it.
+///
+/// This does not need the password, and is much faster than [`verify`], but it
+/// only detects corruption of the backup, not whether it can be opened.
+///
+/// # Errors
+///
+/// This will return an error if the backup cannot be read, if it was created
+/// before checksums were introduced, or if the checksum does not match.
+pub fn verify_checksum(path: impl AsRef<Path>) -> BackupResult<()> {
+    info!("Verifying backup checksum");
+
+    let mut file = File::open(&path)?;
+
+    let checksum_algorithm = match BackupHeader::read(&mut file)? {
+        Some(header) if header.has_checksum() => header.checksum_algorithm,
+        _ => return Err(BackupError::MissingChecksum),
+    };
+
+    // The checksum is stored at the very end, and covers everything between
+    // the header and itself
+    let payload_offset = file.stream_position()?;
+    let checksum_offset = file
+        .metadata()?
+        .len()
+        .checked_sub(CHECKSUM_SIZE as u64)
+        .filter(|&offset| offset >= payload_offset)
+        .ok_or(BackupError::ChecksumMismatch)?;
+
+    let mut expected_checksum = [0u8; CHECKSUM_SIZE];
+    file.seek(SeekFrom::Start(checksum_offset))?;
+    file.read_exact(&mut expected_checksum)?;
+
+    file.seek(SeekFrom::Start(payload_offset))?;
+    let mut hasher = ChecksumWriter::new(io::sink(), checksum_algorithm)?;
+    io::copy(
+        &mut (&mut file).take(checksum_offset - payload_offset),
+        &mut hasher,
+    )?;
+    let (_, checksum) = hasher.finish();
+
+    if checksum != expected_checksum {
+        return Err(BackupError::ChecksumMismatch);
+    }
+
+    info!("Checksum verified");
+
+    Ok(())
+}
+
+/// Verifies an encrypted backup without extracting it.
+///
+/// The backup is decrypted in full with the given password. Unless only
+/// statistics are requested, the archive within it is read through to the end
+/// as well.
+///
+/// # Errors
+///
+/// This will return an error if the backup cannot be read, if any part of it
+/// fails to decrypt, or if the archive within it is malformed.
+pub fn verify(
+    path: impl AsRef<Path>,
+    password: &str,
+    pool_size: u8,
+    options: &VerifyOptions,
+) -> BackupResult<BackupStats> {
+    verify_with_secret(path, Secret::Password(password), pool_size, options)
+}
+
+/// Reads every entry of a tar archive through to the end, discarding the
+/// contents.
+fn read_archive(src: impl Read) -> BackupResult<()> {
+    let mut archive = tar::Archive::new(src);
+
+    for entry in archive.entries()? {
+        io::copy(&mut entry?, &mut io::sink())?;
+    }
+
+    Ok(())
+}
+
+/// Verifies an encrypted backup, opening it with the given secret.
+fn verify_with_secret(
+    path: impl AsRef<Path>,
+    secret: Secret,
+    pool_size: u8,
+    options: &VerifyOptions,
+) -> BackupResult<BackupStats> {
+    info!("Verifying backup");
+
+    let start = Instant::now();
+
+    // Read the header and unwrap the key used for encryption
+    let mut backup = open_backup(&path, secret)?;
+    let (key, compressed, end_marker) = (backup.key, backup.compressed, backup.end_marker);
+
+    // Decrypt the backup, discarding the decrypted data
+    let archive_size = if options.stats_only {
+        decrypt_stream(
+            &mut backup.payload,
+            key,
+            pool_size,
+            None,
+            end_marker,
+            |_| Ok(()),
+        )?
+    } else {
+        decrypt_reader(&mut backup.payload, key, pool_size, end_marker, |reader| {
+            if compressed {
+                read_archive(zstd::Decoder::new(reader)?)
+            } else {
+                read_archive(reader)
+            }
+        })?
+    };
+
+    let output_size = backup.payload.metadata()?.len();
+
+    info!("Verification complete");
+
+    Ok(BackupStats {
+        path: path.as_ref().to_path_buf(),
+        archive_size,
+        output_size,
+        elapsed: start.elapsed(),
+        skipped: Vec::new(),
+    })
+}
+
+/// Checks whether a password opens an encrypted backup, without decrypting
+/// its payload.
+///
+/// Only the data key is unwrapped from the header, so this takes about as
+/// long as deriving a key from the password. Backups without a header are
+/// encrypted directly with the password, so the first chunk is decrypted
+/// instead, and a corrupted first chunk is indistinguishable from an
+/// incorrect password.
+///
+/// # Errors
+///
+/// This will return an error if the backup cannot be read or its header is
+/// malformed or fails authentication.
+pub fn check_password(path: impl AsRef<Path>, password: &str) -> BackupResult<bool> {
+    let mut file = File::open(path)?;
+
+    if let Some(header) = BackupHeader::read(&mut file)? {
+        return match header.unwrap_key(Secret::Password(password)) {
+            Ok(_) => Ok(true),
+            Err(BackupError::IncorrectPassword) => Ok(false),
+            Err(e) => Err(e),
+        };
+    }
+
+    let section = read_section(&mut file)?.ok_or(BackupError::TruncatedBackup)?;
+
+    Ok(aes_decrypt(password_to_key(password), &section).is_ok())
+}
+
+/// Gets the chunk size of a given backup file.
+///
+/// # Errors
+///
+/// This will return an error if an IO operation fails or the backup header is
+/// invalid.
+pub fn backup_chunk_size(backup_path: impl AsRef<Path>) -> BackupResult<usize> {
+    get_chunk_size(backup_path)
+}
+
+/// Reads information about an encrypted backup from its header, without the
+/// password and without reading the payload.
+///
+/// # Errors
+///
+/// This will return an error if the backup cannot be read or its header is
+/// malformed.
+pub fn backup_info(backup_path: impl AsRef<Path>) -> BackupResult<BackupInfo> {
+    let backup_path = backup_path.as_ref();
+    let mut file = File::open(backup_path)?;
+    let size = file.metadata()?.len();
+
+    let Some(header) = BackupHeader::read(&mut file)? else {
+        // Without a header, a backup begins with the size of its first
+        // encrypted chunk, which must fit within the file
+        let chunk_size = get_chunk_size(backup_path)? as u64;
+
+        if chunk_size == 0 || chunk_size + LEN_SIZE as u64 > size {
+            return Err(BackupError::InvalidHeader(
+                "not a recognizable backup file".to_owned(),
+            ));
+        }
+
+        return Ok(BackupInfo {
+            path: backup_path.to_path_buf(),
+            format_version: None,
+            chunk_size,
+            key_slots: 1,
+            checksum_algorithm: None,
+            compressed: false,
+            created: None,
+            tool_version: None,
+            index: IndexMode::None,
+            size,
+        });
+    };
+
+    Ok(BackupInfo {
+        path: backup_path.to_path_buf(),
+        format_version: Some(header.version),
+        chunk_size: header.chunk_size,
+        key_slots: header.slots.len(),
+        checksum_algorithm: header.has_checksum().then_some(header.checksum_algorithm),
+        compressed: header.compressed,
+        created: header.has_metadata().then_some(header.created),
+        tool_version: header.has_metadata().then_some(header.tool_version),
+        index: header.index,
+        size,
+    })
+}
+
+/// Lists the contents of an encrypted backup from its index, without
+/// decrypting the payload.
+///
+/// A plain index can be listed without the password. If the password is
+/// given, the index is authenticated with it first, so that tampering is
+/// detected. An encrypted index always requires the password.
+///
+/// # Errors
+///
+/// This will return an error if the backup cannot be read, if it has no
+/// index, if the index is encrypted and no password is given, if the
+/// password is incorrect, or if the index is malformed or fails
+/// authentication.
+pub fn list(path: impl AsRef<Path>, password: Option<&str>) -> BackupResult<Vec<IndexEntry>> {
+    let mut file = File::open(&path)?;
+
+    let header = match BackupHeader::read(&mut file)? {
+        Some(header) if header.index != IndexMode::None => header,
+        _ => return Err(BackupError::MissingIndex),
+    };
+    let payload_offset = file.stream_position()?;
+
+    let key = password
+        .map(|password| header.unwrap_key(Secret::Password(password)))
+        .transpose()?;
+    let data = read_index_section(&mut file, payload_offset)?;
+
+    Ok(ArchiveIndex::open(&data, header.index, key, header.index_offsets)?.into_entries())
+}
+
+/// Returns whether an archive path matches any of the given globs.
+fn matches_any(patterns: &[Pattern], path: &Path) -> bool {
+    patterns.iter().any(|pattern| pattern.matches_path(path))
+}
+
+/// Unpacks an entry extracted without the rest of the archive, restoring the
+/// modification time of directories. Directories should be unpacked after
+/// their contents, deepest first. Returns whether the entry was unpacked.
+fn unpack_single_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    output_path: &Path,
+    duplicate_sources: &mut DuplicateSources,
+    options: &ExtractOptions,
+) -> BackupResult<bool> {
+    let path = entry.path()?.into_owned();
+    let relative_path = if options.strip_components == 0 {
+        path
+    } else {
+        let Some(relative_path) = strip_path_components(&path, options.strip_components) else {
+            return Ok(false);
+        };
+        relative_path
+    };
+
+    let contents = duplicate_sources.contents(entry)?;
+    if matches!(contents, Contents::Missing) {
+        return Ok(false);
+    }
+
+    let original_path = relative_path.clone();
+    let relative_path = match resolve_destination(
+        entry,
+        output_path,
+        relative_path,
+        contents.duplicate_source(),
+        options,
+    )? {
+        Destination::Original(relative_path) | Destination::Renamed(relative_path) => relative_path,
+        Destination::Kept => {
+            duplicate_sources.record_kept(entry, &original_path)?;
+            return Ok(false);
+        }
+    };
+
+    let unpacked = unpack_entry(
+        entry,
+        output_path,
+        &relative_path,
+        contents.duplicate_source(),
+        options,
+    )?;
+
+    if unpacked {
+        duplicate_sources.record(entry, &relative_path)?;
+    }
+
+    if unpacked && entry.header().entry_type() == tar::EntryType::Directory {
+        restore_directory_mtime(&output_path.join(&relative_path), entry.header());
+    }
+
+    Ok(unpacked)
+}
+
+/// Extracts the matching entries of a backup whose index records where each
+/// entry begins, decrypting only the chunks that hold them.
+fn extract_indexed(
+    file: &mut File,
+    header: &BackupHeader,
+    key: [u8; AES_KEY_SIZE],
+    output_path: &Path,
+    patterns: &[Pattern],
+    options: &ExtractOptions,
+) -> BackupResult<usize> {
+    let payload_offset = file.stream_position()?;
+    let data = read_index_section(file, payload_offset)?;
+    let entries = ArchiveIndex::open(&data, header.index, Some(key), true)?
+        .into_entries_with_offsets()
+        .ok_or(BackupError::MissingIndex)?;
+
+    // Directories are unpacked after everything else, deepest first, so that
+    // unpacking their contents does not change their modification times
+    let (directories, others): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .filter(|(entry, _)| matches_any(patterns, &entry.path))
+        .partition(|(entry, _)| entry.kind == EntryKind::Directory);
+    let mut extracted = 0;
+    let mut duplicate_sources = DuplicateSources::new(output_path);
+
+    let key = PayloadKey::new(key, header.chunk_keys);
+
+    for (index_entry, offset) in others.into_iter().chain(directories.into_iter().rev()) {
+        let reader = OffsetReader::new(file, payload_offset, header.chunk_size, key, offset)?;
+        let mut archive = tar::Archive::new(reader);
+        let mismatch = || BackupError::InvalidIndex("entry offset does not match".to_owned());
+        let mut entry = archive.entries()?.next().ok_or_else(mismatch)??;
+
+        if entry.path()? != index_entry.path {
+            return Err(mismatch());
+        }
+
+        if unpack_single_entry(&mut entry, output_path, &mut duplicate_sources, options)? {
+            extracted += 1;
+        }
+    }
+
+    Ok(extracted)
+}
+
+/// Unpacks the matching entries of a tar archive read from start to end.
+fn unpack_matching<R: Read>(
+    src: R,
+    output_path: &Path,
+    patterns: &[Pattern],
+    options: &ExtractOptions,
+) -> BackupResult<usize> {
+    let mut archive = tar::Archive::new(src);
+    let mut directories = Vec::new();
+    let mut extracted = 0;
+    let mut duplicate_sources = DuplicateSources::new(output_path);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if entry.header().entry_type().is_pax_global_extensions()
+            || !matches_any(patterns, &entry.path()?)
+        {
+            continue;
+        }
+
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            directories.push(entry);
+        } else if unpack_single_entry(&mut entry, output_path, &mut duplicate_sources, options)? {
+            extracted += 1;
+        }
+    }
+
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut directory in directories {
+        if unpack_single_entry(&mut directory, output_path, &mut duplicate_sources, options)? {
+            extracted += 1;
+        }
+    }
+
+    Ok(extracted)
+}
+
+/// Extracts the entries of an encrypted backup whose paths match any of the
+/// given globs, returning the number of entries extracted.
+///
+/// Globs are matched against the path of each entry within the backup, which
+/// begins with the name of its include path. In backups made with
+/// [`BackupOptions::indexed`], matching entries are looked up in the index
+/// and only the chunks holding them are decrypted, so extracting a single
+/// file takes time proportional to its size rather than that of the whole
+/// backup. Other backups are decrypted in full, skipping the entries that do
+/// not match.
+///
+/// The output directory is created if it does not exist, and files already at
+/// the paths of matching entries are handled as the overwrite mode and the
+/// conflict policy direct. Only the extraction options that control how each
+/// entry is unpacked apply: extended attributes, creation times, stripped
+/// path components, overwriting and conflicts. Matching hard links, and files
+/// stored as duplicates by [`BackupOptions::dedup`], are only restored if the
+/// files they refer to are extracted too.
+///
+/// # Errors
+///
+/// This will return an error if the backup cannot be read or opened with the
+/// password, if its index is malformed, or if any matching entry fails to
+/// decrypt or unpack.
+pub fn extract_matching(
+    path: impl AsRef<Path>,
+    output_path: impl AsRef<Path>,
+    password: &str,
+    patterns: &[Pattern],
+    pool_size: u8,
+    options: &ExtractOptions,
+) -> BackupResult<usize> {
+    let output_path = output_path.as_ref();
+    fs::create_dir_all(output_path)?;
+
+    let mut file = File::open(&path)?;
+
+    if let Some(header) = BackupHeader::read(&mut file)? {
+        if header.index_offsets && !header.compressed {
+            info!("Extracting matching entries using the index");
+
+            let key = header.unwrap_key(Secret::Password(password))?;
+            return extract_indexed(&mut file, &header, key, output_path, patterns, options);
+        }
+    }
+
+    info!("Extracting matching entries");
+
+    let mut backup = open_backup(&path, Secret::Password(password))?;
+    let compressed = backup.compressed;
+    let mut extracted = 0;
+
+    decrypt_reader(
+        &mut backup.payload,
+        backup.key,
+        pool_size,
+        backup.end_marker,
+        |reader| {
+            extracted = if compressed {
+                unpack_matching(zstd::Decoder::new(reader)?, output_path, patterns, options)?
+            } else {
+                unpack_matching(reader, output_path, patterns, options)?
+            };
+            Ok(())
+        },
+    )?;
+
+    Ok(extracted)
+}
+
+/// Backup tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, RngCore, SeedableRng};
+    use std::fs::{DirEntry, File};
+    use std::num::NonZeroUsize;
+
+    fn non_existent_temp_file() -> PathBuf {
+        let temp_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let path = temp_path.to_path_buf();
+        temp_path.close().unwrap();
+        path
+    }
+
+    fn io_error<T, E>(err: E) -> io::Result<T>
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Err(io::Error::other(err))
+    }
+
+    fn keep_entry(entry: &DirEntry, ignore_dir_names: &[&str], ignore_file_names: &[&str]) -> bool {
+        let entry_file_name = entry.file_name();
+        let entry_name = &*entry_file_name.to_string_lossy();
+
+        if entry.file_type().unwrap().is_dir() && ignore_dir_names.contains(&entry_name) {
+            return false;
+        }
+
+        if entry.file_type().unwrap().is_file() && ignore_file_names.contains(&entry_name) {
+            return false;
+        }
+
+        true
+    }
+
+    fn verify_identical_trees(
+        a: impl AsRef<Path>,
+        b: impl AsRef<Path>,
+        check_root_name: bool,
+        ignore_dir_names: &[&str],
+        ignore_file_names: &[&str],
+    ) -> io::Result<()> {
+        let a = a.as_ref();
+        let b = b.as_ref();
+
+        if a.is_file() && b.is_file() {
+            let a_name = a.file_name().unwrap();
+            let b_name = b.file_name().unwrap();
+
+            if !(ignore_file_names.contains(&&*a_name.to_string_lossy())
+                || ignore_dir_names.contains(&&*b_name.to_string_lossy()))
+            {
+                if check_root_name && a_name != b_name {
+                    return io_error(format!(
+                        "mismatched file names: {} != {}",
+                        a.display(),
+                        b.display()
+                    ));
+                }
+
+                let a_contents = fs::read(a)?;
+                let b_contents = fs::read(b)?;
+
+                if a_contents != b_contents {
+                    return io_error(format!(
+                        "mismatched file contents: {} != {}",
+                        a.display(),
+                        b.display()
+                    ));
+                }
+            }
+        } else if a.is_dir() && b.is_dir() {
+            let a_name = a.file_name().unwrap();
+            let b_name = b.file_name().unwrap();
+
+            if !(ignore_file_names.contains(&&*a_name.to_string_lossy())
+                || ignore_dir_names.contains(&&*b_name.to_string_lossy()))
+            {
+                if check_root_name && a_name != b_name {
+                    return io_error(format!(
+                        "mismatched directory names: {} != {}",
+                        a.display(),
+                        b.display()
+                    ));
+                }
+
+                // Directory listing order is not guaranteed to match, so
+                // entries are compared by name
+                let mut a_entries = fs::read_dir(a)?.collect::<Result<Vec<_>, _>>()?;
+                a_entries.sort_by_key(DirEntry::file_name);
+                let a_entries = a_entries
+                    .iter()
+                    .filter(|entry| keep_entry(entry, ignore_dir_names, ignore_file_names))
+                    .collect::<Vec<_>>();
+                let mut b_entries = fs::read_dir(b)?.collect::<Result<Vec<_>, _>>()?;
+                b_entries.sort_by_key(DirEntry::file_name);
+                let b_entries = b_entries
+                    .iter()
+                    .filter(|entry| keep_entry(entry, ignore_dir_names, ignore_file_names))
+                    .collect::<Vec<_>>();
+
+                if a_entries.len() != b_entries.len() {
+                    return io_error(format!(
+                        "different number of contents: {} != {}",
+                        a.display(),
+                        b.display()
+                    ));
+                }
+
+                for i in 0..a_entries.len() {
+                    verify_identical_trees(
+                        a_entries[i].path(),
+                        b_entries[i].path(),
+                        true,
+                        ignore_dir_names,
+                        ignore_file_names,
+                    )?;
+                }
+            }
+        } else {
+            return io_error(format!(
+                "file type mismatch: {} != {}",
+                a.display(),
+                b.display()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Awkward names for the files and directories of random trees. Each is
+    /// given a unique suffix, so they never collide.
+    const ODD_NAMES: &[&str] = &[
+        "file",
+        "with space",
+        " leading space",
+        "trailing.dot.",
+        ".hidden",
+        "-dash",
+        "100%",
+        "ünïcödé",
+        "日本語",
+        "emoji 🦀",
+        "a=b;c,d",
+        "#hash",
+        "'quote'",
+        "UPPER",
+        #[cfg(unix)]
+        "new\nline",
+        #[cfg(unix)]
+        "back\\slash",
+    ];
+
+    /// Generates a random tree of files and directories under the given path,
+    /// which must not exist yet. File sizes are chosen around multiples of
+    /// the chunk size, so that they span chunk boundaries, and empty files and
+    /// directories are common.
+    fn random_tree(rng: &mut impl Rng, path: &Path, chunk_size: usize, depth: usize) {
+        fs::create_dir(path).unwrap();
+
+        let num_entries = rng.gen_range(0..=6);
+
+        for i in 0..num_entries {
+            let odd_name = ODD_NAMES[rng.gen_range(0..ODD_NAMES.len())];
+            let entry_path = path.join(format!("{odd_name}{i}"));
+
+            if depth > 0 && rng.gen_bool(0.3) {
+                random_tree(rng, &entry_path, chunk_size, depth - 1);
+            } else {
+                let size = match rng.gen_range(0..4) {
+                    0 => 0,
+                    1 => rng.gen_range(1..chunk_size * 4),
+                    _ => {
+                        (rng.gen_range(1..=3) * chunk_size + rng.gen_range(0..3)).saturating_sub(1)
+                    }
+                };
+                let mut contents = vec![0u8; size];
+                rng.fill_bytes(&mut contents);
+                fs::write(&entry_path, contents).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_backup_round_trip_fuzz() {
+        const ITERATIONS: u64 = 24;
+        const CHUNK_SIZES: &[usize] = &[1, 7, 512, 1000, 4096, 1 << 16];
+        const POOL_SIZES: &[u8] = &[1, 2, 3, 16];
+
+        // The seed is part of every failure message, so that a failure can be
+        // reproduced
+        let seed = rand::thread_rng().next_u64();
+
+        for iteration in 0..ITERATIONS {
+            let mut rng = StdRng::seed_from_u64(seed.wrapping_add(iteration));
+            let chunk_size = CHUNK_SIZES[rng.gen_range(0..CHUNK_SIZES.len())];
+            let pool_size = POOL_SIZES[rng.gen_range(0..POOL_SIZES.len())];
+            let compression_level = rng.gen_bool(0.5).then_some(1);
+            let key = random_key();
+
+            let src_path = non_existent_temp_file();
+            let backup_output_path = non_existent_temp_file();
+            let extract_output_path = non_existent_temp_file();
+            let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+            random_tree(&mut rng, &src_path, chunk_size.min(4096), 3);
+
+            // An empty tree cannot be backed up, so make sure there is
+            // something in it
+            File::create(src_path.join("empty")).unwrap();
+
+            let context = format!(
+                "seed {seed}, iteration {iteration}, chunk size {chunk_size}, pool size {pool_size}, compression {compression_level:?}"
+            );
+            backup_with_key(
+                &[&src_path],
+                &[],
+                &backup_output_path,
+                key,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    compression_level,
+                    ..Default::default()
+                },
+            )
+            .expect(&context);
+            extract_with_key(
+                &backup_output_path,
+                &extract_output_path,
+                key,
+                pool_size,
+                &ExtractOptions {
+                    verify_on_extract: true,
+                    ..Default::default()
+                },
+            )
+            .expect(&context);
+
+            verify_identical_trees(&src_path, &extract_output_root, true, &[], &[])
+                .expect(&context);
+
+            fs::remove_dir_all(&src_path).unwrap();
+            fs::remove_file(&backup_output_path).unwrap();
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_backup() {
         let root = project_root::get_project_root().unwrap();
         let include_paths = [&root];
         let exclude_globs = [Pattern::new("**/target").unwrap()];
@@ -382,18 +3657,3945 @@ mod tests {
         let ignore_file_names = [];
         let backup_output_path = non_existent_temp_file();
         let extract_output_path = non_existent_temp_file();
-        let extract_output_root = extract_output_path.join("encrypted-backup");
+        let extract_output_root = extract_output_path.join("encrypted-backup");
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        verify_identical_trees(
+            &root,
+            &extract_output_root,
+            false,
+            &ignore_dir_names,
+            &ignore_file_names,
+        )
+        .unwrap();
+
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_empty() {
+        let src_path = non_existent_temp_file();
+        let empty_file = src_path.join("empty_file.txt");
+        let empty_dir = src_path.join("empty_dir");
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let ignore_dir_names = [];
+        let ignore_file_names = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            File::create(&empty_file).unwrap();
+            fs::create_dir(&empty_dir).unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        verify_identical_trees(
+            &src_path,
+            &extract_output_root,
+            false,
+            &ignore_dir_names,
+            &ignore_file_names,
+        )
+        .unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_resume() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let progress_path = progress_file_for(&extract_output_path);
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("first.txt"), "first file").unwrap();
+            fs::write(src_path.join("second.txt"), "second file").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                resume: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(!progress_path.exists());
+
+        // Simulate an extraction that was interrupted after writing every
+        // entry, but where one file was only partially written
+        fs::write(extract_output_root.join("second.txt"), "sec").unwrap();
+        write_extraction_progress(&progress_path, usize::MAX).unwrap();
+
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                resume: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(!progress_path.exists());
+
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_extraction_progress() {
+        let progress_path = non_existent_temp_file();
+        let read_progress = || read_extraction_progress(&progress_path).unwrap();
+
+        {
+            let mut progress = ExtractionProgress::new(&progress_path);
+
+            // The file is not written for every entry
+            for index in 0..PROGRESS_WRITE_ENTRIES - 1 {
+                progress.record(index).unwrap();
+            }
+            assert_eq!(read_progress(), None);
+
+            // It is written once enough entries have been extracted
+            progress.record(PROGRESS_WRITE_ENTRIES - 1).unwrap();
+            assert_eq!(read_progress(), Some(PROGRESS_WRITE_ENTRIES - 1));
+            progress.record(PROGRESS_WRITE_ENTRIES).unwrap();
+            assert_eq!(read_progress(), Some(PROGRESS_WRITE_ENTRIES - 1));
+
+            // And it is written after the interval, however few entries have
+            // been extracted
+            std::thread::sleep(PROGRESS_WRITE_INTERVAL);
+            progress.record(PROGRESS_WRITE_ENTRIES + 1).unwrap();
+            assert_eq!(read_progress(), Some(PROGRESS_WRITE_ENTRIES + 1));
+            progress.record(PROGRESS_WRITE_ENTRIES + 2).unwrap();
+        }
+
+        // The latest progress is written when extraction stops, such as when
+        // it fails
+        assert_eq!(read_progress(), Some(PROGRESS_WRITE_ENTRIES + 2));
+
+        fs::remove_file(&progress_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_overwrite() {
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let extract_with = |overwrite, atomic| {
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions {
+                    overwrite,
+                    atomic,
+                    verify_on_extract: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("newer.txt"), "backed up").unwrap();
+            fs::write(src_path.join("older.txt"), "backed up").unwrap();
+        }
+
+        backup(
+            &[&src_path],
+            &[],
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract_with(OverwriteMode::Never, false).unwrap();
+
+        // Change one extracted file after the backup, and one before it
+        let now = FileTime::now();
+        let newer_path = extract_root.join("newer.txt");
+        let older_path = extract_root.join("older.txt");
+        fs::write(&newer_path, "changed").unwrap();
+        filetime::set_file_mtime(
+            &newer_path,
+            FileTime::from_unix_time(now.unix_seconds() + 60, 0),
+        )
+        .unwrap();
+        fs::write(&older_path, "stale").unwrap();
+        filetime::set_file_mtime(&older_path, FileTime::from_unix_time(0, 0)).unwrap();
+
+        // The output directory must not exist by default
+        assert!(matches!(
+            extract_with(OverwriteMode::Never, false).unwrap_err(),
+            BackupError::PathAlreadyExists(_)
+        ));
+        assert!(matches!(
+            extract_with(OverwriteMode::OnlyNewer, true).unwrap_err(),
+            BackupError::IncompatibleOptions(_)
+        ));
+
+        // Only older files are replaced, and kept files are not verified
+        extract_with(OverwriteMode::OnlyNewer, false).unwrap();
+        assert_eq!(fs::read_to_string(&newer_path).unwrap(), "changed");
+        assert_eq!(fs::read_to_string(&older_path).unwrap(), "backed up");
+
+        // Every file is replaced when always overwriting
+        extract_with(OverwriteMode::Always, false).unwrap();
+        verify_identical_trees(&src_path, &extract_root, true, &[], &[]).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_conflict() {
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let extract_with = |overwrite, conflict| {
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions {
+                    overwrite,
+                    conflict,
+                    verify_on_extract: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("changed.txt"), "backed up").unwrap();
+            fs::write(src_path.join("unchanged"), "backed up").unwrap();
+        }
+
+        backup(
+            &[&src_path],
+            &[],
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract_with(OverwriteMode::Never, ConflictPolicy::default()).unwrap();
+        let changed_path = extract_root.join("changed.txt");
+        fs::write(&changed_path, "changed").unwrap();
+
+        // A conflict policy needs the output directory to be allowed to exist
+        assert!(matches!(
+            extract_with(OverwriteMode::Never, ConflictPolicy::Skip).unwrap_err(),
+            BackupError::IncompatibleOptions(_)
+        ));
+
+        // Only the changed file conflicts
+        assert!(matches!(
+            extract_with(OverwriteMode::Always, ConflictPolicy::Fail).unwrap_err(),
+            BackupError::ExtractConflict(path) if path == changed_path
+        ));
+
+        // Skipped files are left as they are, and not verified
+        extract_with(OverwriteMode::Always, ConflictPolicy::Skip).unwrap();
+        assert_eq!(fs::read_to_string(&changed_path).unwrap(), "changed");
+
+        // Renamed files are extracted alongside the existing ones, and
+        // verified at their new paths
+        extract_with(OverwriteMode::Always, ConflictPolicy::Rename).unwrap();
+        assert_eq!(fs::read_to_string(&changed_path).unwrap(), "changed");
+        assert_eq!(
+            fs::read_to_string(extract_root.join("changed.1.txt")).unwrap(),
+            "backed up"
+        );
+        assert!(!extract_root.join("unchanged.1").exists());
+
+        // A second rename picks the next unused suffix
+        extract_with(OverwriteMode::Always, ConflictPolicy::Rename).unwrap();
+        assert_eq!(
+            fs::read_to_string(extract_root.join("changed.2.txt")).unwrap(),
+            "backed up"
+        );
+
+        // Overwriting replaces the changed file
+        extract_with(OverwriteMode::Always, ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(fs::read_to_string(&changed_path).unwrap(), "backed up");
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_dedup_conflict() {
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let extract_with = |overwrite, conflict| {
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions {
+                    overwrite,
+                    conflict,
+                    verify_on_extract: overwrite != OverwriteMode::OnlyNewer,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        };
+        let file_names = || {
+            let mut names = fs::read_dir(&extract_root)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("a.txt"), "backed up").unwrap();
+            fs::write(src_path.join("b.txt"), "backed up").unwrap();
+        }
+
+        backup(
+            &[&src_path],
+            &[],
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                dedup: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Either file may be the one stored in full, so each is made to
+        // conflict in turn
+        for (name, other_name, renamed_name) in
+            [("a.txt", "b.txt", "a.1.txt"), ("b.txt", "a.txt", "b.1.txt")]
+        {
+            let existing_path = extract_root.join(name);
+            let other_path = extract_root.join(other_name);
+            let reset = |mtime: FileTime| {
+                if extract_output_path.exists() {
+                    fs::remove_dir_all(&extract_output_path).unwrap();
+                }
+
+                fs::create_dir_all(&extract_root).unwrap();
+                fs::write(&existing_path, "changed").unwrap();
+                filetime::set_file_mtime(&existing_path, mtime).unwrap();
+            };
+
+            // A skipped file is left as it is, and the other copy is still
+            // extracted with the backed up contents
+            reset(FileTime::now());
+            extract_with(OverwriteMode::Always, ConflictPolicy::Skip);
+            assert_eq!(fs::read_to_string(&existing_path).unwrap(), "changed");
+            assert_eq!(fs::read_to_string(&other_path).unwrap(), "backed up");
+            assert_eq!(file_names(), ["a.txt", "b.txt"]);
+
+            // A renamed file is extracted alongside the existing one, and the
+            // other copy is extracted to its original path
+            reset(FileTime::now());
+            extract_with(OverwriteMode::Always, ConflictPolicy::Rename);
+            assert_eq!(fs::read_to_string(&existing_path).unwrap(), "changed");
+            assert_eq!(
+                fs::read_to_string(extract_root.join(renamed_name)).unwrap(),
+                "backed up"
+            );
+            assert_eq!(fs::read_to_string(&other_path).unwrap(), "backed up");
+            assert_eq!(file_names().len(), 3);
+
+            // A file newer than the backup is kept when only replacing older
+            // files
+            reset(FileTime::from_unix_time(
+                FileTime::now().unix_seconds() + 60,
+                0,
+            ));
+            extract_with(OverwriteMode::OnlyNewer, ConflictPolicy::default());
+            assert_eq!(fs::read_to_string(&existing_path).unwrap(), "changed");
+            assert_eq!(fs::read_to_string(&other_path).unwrap(), "backed up");
+            assert_eq!(file_names(), ["a.txt", "b.txt"]);
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_atomic() {
+        let src_path1 = non_existent_temp_file();
+        let src_path2 = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let partial_path = partial_dir_for(&extract_output_path);
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let extract_options = ExtractOptions {
+            atomic: true,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir_all(&src_path1).unwrap();
+            fs::create_dir_all(&src_path2).unwrap();
+            fs::write(src_path1.join("file1.txt"), "file 1").unwrap();
+            fs::write(src_path2.join("file2.txt"), "file 2").unwrap();
+        }
+
+        // A staged extraction is moved into place once it succeeds
+        backup(
+            &[&src_path1, &src_path2],
+            &[],
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &extract_options,
+        )
+        .unwrap();
+        verify_identical_trees(
+            &src_path1,
+            extract_output_path.join(src_path1.file_name().unwrap()),
+            true,
+            &[],
+            &[],
+        )
+        .unwrap();
+        assert!(!partial_path.exists());
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        // A staged extraction that fails part way through leaves nothing
+        // behind. Stripping the include directories makes the second file
+        // collide with the first after it has been unpacked
+        fs::write(src_path2.join("file1.txt"), "other file 1").unwrap();
+        backup(
+            &[&src_path1, &src_path2],
+            &[],
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        let result = extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                strip_components: 1,
+                ..extract_options
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(BackupError::StrippedPathCollision { .. })
+        ));
+        assert!(!extract_output_path.exists());
+        assert!(!partial_path.exists());
+
+        fs::remove_dir_all(&src_path1).unwrap();
+        fs::remove_dir_all(&src_path2).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("small.txt"), "Hello, verification!").unwrap();
+            fs::write(src_path.join("large.bin"), vec![7u8; chunk_size * 10 + 3]).unwrap();
+        }
+
+        let stats = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        for stats_only in [false, true] {
+            let verify_stats = verify(
+                &backup_output_path,
+                password,
+                pool_size,
+                &VerifyOptions { stats_only },
+            )
+            .unwrap();
+            assert_eq!(verify_stats.archive_size, stats.archive_size);
+            assert_eq!(verify_stats.output_size, stats.output_size);
+        }
+
+        // An incorrect password is reported as such
+        let err = verify(
+            &backup_output_path,
+            "wrong password",
+            pool_size,
+            &VerifyOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::IncorrectPassword));
+
+        // A corrupted backup fails to authenticate, in either mode
+        let mut corrupted = fs::read(&backup_output_path).unwrap();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0xFF;
+        fs::write(&backup_output_path, corrupted).unwrap();
+
+        for stats_only in [false, true] {
+            let err = verify(
+                &backup_output_path,
+                password,
+                pool_size,
+                &VerifyOptions { stats_only },
+            )
+            .unwrap_err();
+            assert!(matches!(err, BackupError::CryptoError(_)));
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "x".repeat(10_000)).unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        verify_checksum(&backup_output_path).unwrap();
+
+        let original = fs::read(&backup_output_path).unwrap();
+
+        // A flipped bit in the payload is detected
+        let mut corrupted = original.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0x01;
+        fs::write(&backup_output_path, corrupted).unwrap();
+        let err = verify_checksum(&backup_output_path).unwrap_err();
+        assert!(matches!(err, BackupError::ChecksumMismatch));
+
+        // So is truncation, even at a section boundary
+        for len in [
+            original.len() - 1,
+            original.len() - CHECKSUM_SIZE - LEN_SIZE,
+        ] {
+            fs::write(&backup_output_path, &original[..len]).unwrap();
+            let err = verify_checksum(&backup_output_path).unwrap_err();
+            assert!(matches!(err, BackupError::ChecksumMismatch));
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_tampered() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.bin"), vec![42u8; chunk_size * 10]).unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        // A single modified byte of ciphertext fails authentication, rather
+        // than being extracted as corrupted data
+        let mut tampered = fs::read(&backup_output_path).unwrap();
+        let middle = tampered.len() / 2;
+        tampered[middle] ^= 0x01;
+        fs::write(&backup_output_path, tampered).unwrap();
+
+        let err = extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::CryptoError(_)));
+        assert!(!extract_output_path.exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_chunk_keys() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let name = PathBuf::from(src_path.file_name().unwrap());
+        let mut rng = StdRng::seed_from_u64(1172);
+        let mut first = vec![0; chunk_size * 5 + 3];
+        rng.fill_bytes(&mut first);
+        let mut second = vec![0; chunk_size * 2 + 5];
+        rng.fill_bytes(&mut second);
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("first.bin"), &first).unwrap();
+            fs::write(src_path.join("second.bin"), &second).unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                chunk_keys: true,
+                indexed: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // The scheme is recorded in the header, so nothing else is needed to
+        // open the backup
+        let mut file = File::open(&backup_output_path).unwrap();
+        assert!(BackupHeader::read(&mut file).unwrap().unwrap().chunk_keys);
+        verify(
+            &backup_output_path,
+            password,
+            pool_size,
+            &VerifyOptions::default(),
+        )
+        .unwrap();
+
+        let extract_output_path = non_existent_temp_file();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read(extract_output_path.join(&name).join("first.bin")).unwrap(),
+            first
+        );
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        // Entries found through the index are decrypted starting partway
+        // through the payload, with the keys of the chunks they start in.
+        // Whichever file is archived second starts several chunks in.
+        let extract_output_path = non_existent_temp_file();
+        let extracted = extract_matching(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            &[Pattern::new("*/*.bin").unwrap()],
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(extracted, 2);
+        assert_eq!(
+            fs::read(extract_output_path.join(&name).join("first.bin")).unwrap(),
+            first
+        );
+        assert_eq!(
+            fs::read(extract_output_path.join(&name).join("second.bin")).unwrap(),
+            second
+        );
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_truncated() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "x".repeat(10_000)).unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        // Cut the backup off right after its last encrypted section, so
+        // only the end marker and checksum are missing
+        let original = fs::read(&backup_output_path).unwrap();
+        let truncated = &original[..original.len() - CHECKSUM_SIZE - LEN_SIZE];
+        fs::write(&backup_output_path, truncated).unwrap();
+
+        let err = verify(
+            &backup_output_path,
+            password,
+            pool_size,
+            &VerifyOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::TruncatedBackup));
+
+        let err = decrypt_backup_from(
+            truncated,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::TruncatedBackup));
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        _ = fs::remove_dir_all(&extract_output_path);
+    }
+
+    #[test]
+    fn test_backup_unsupported_format_version() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "file").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        // Pretend the backup was written by a newer version of the tool
+        let mut bytes = fs::read(&backup_output_path).unwrap();
+        bytes[MAGIC.len()] = FORMAT_VERSION + 1;
+        fs::write(&backup_output_path, &bytes).unwrap();
+
+        let err = extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            BackupError::UnsupportedFormatVersion(version) if version == FORMAT_VERSION + 1
+        ));
+        assert!(!extract_output_path.exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_checksum_algorithm() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, checksum!").unwrap();
+        }
+
+        for checksum_algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3] {
+            let backup_output_path = non_existent_temp_file();
+            let result = backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    checksum_algorithm,
+                    ..Default::default()
+                },
+            );
+
+            if !checksum_algorithm.is_supported() {
+                assert!(matches!(
+                    result.unwrap_err(),
+                    BackupError::UnsupportedChecksumAlgorithm(_)
+                ));
+                assert!(!backup_output_path.exists());
+                continue;
+            }
+
+            result.unwrap();
+            let header = BackupHeader::read(&mut File::open(&backup_output_path).unwrap())
+                .unwrap()
+                .unwrap();
+            assert_eq!(header.checksum_algorithm, checksum_algorithm);
+            verify_checksum(&backup_output_path).unwrap();
+
+            fs::remove_file(&backup_output_path).unwrap();
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_verify_after_write() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "x".repeat(10_000)).unwrap();
+        }
+
+        // A good write verifies
+        let backup_output_path = non_existent_temp_file();
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                verify_after_write: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+
+        // Simulate a bad write by corrupting the header once it is on disk,
+        // from within the progress callback
+        for remove_unverified_output in [false, true] {
+            let backup_output_path = non_existent_temp_file();
+            let corrupted = Arc::new(AtomicBool::new(false));
+            let progress = {
+                let backup_output_path = backup_output_path.clone();
+                ProgressHandler::new(move |_| {
+                    if !corrupted.swap(true, Ordering::SeqCst) {
+                        // This falls within the wrapped key of the only key
+                        // slot
+                        let mut file = File::options()
+                            .write(true)
+                            .open(&backup_output_path)
+                            .unwrap();
+                        file.seek(SeekFrom::Start(60)).unwrap();
+                        file.write_all(&[0]).unwrap();
+                    }
+                })
+            };
+
+            let err = backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    verify_after_write: true,
+                    remove_unverified_output,
+                    progress: Some(progress),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+            assert!(matches!(
+                err,
+                BackupError::VerificationFailed(ref e) if matches!(**e, BackupError::IncorrectPassword)
+            ));
+            assert_eq!(backup_output_path.exists(), !remove_unverified_output);
+
+            if backup_output_path.exists() {
+                fs::remove_file(&backup_output_path).unwrap();
+            }
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_compressed() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let uncompressed_output_path = non_existent_temp_file();
+        let compressed_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join("sub")).unwrap();
+            fs::write(
+                src_path.join("repetitive.txt"),
+                "compress me ".repeat(10_000),
+            )
+            .unwrap();
+            fs::write(src_path.join("sub").join("small.txt"), "Hello, zstd!").unwrap();
+        }
+
+        // Out of range levels are rejected before anything is written
+        for compression_level in [0, 20] {
+            let err = backup(
+                &include_paths,
+                &exclude_globs,
+                &compressed_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    compression_level: Some(compression_level),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+            assert!(matches!(err, BackupError::InvalidCompressionLevel(_)));
+            assert!(!compressed_output_path.exists());
+        }
+
+        let uncompressed_stats = backup(
+            &include_paths,
+            &exclude_globs,
+            &uncompressed_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        let compressed_stats = backup(
+            &include_paths,
+            &exclude_globs,
+            &compressed_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                compression_level: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(compressed_stats.output_size * 10 < uncompressed_stats.output_size);
+
+        verify(
+            &compressed_output_path,
+            password,
+            pool_size,
+            &VerifyOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &compressed_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&uncompressed_output_path).unwrap();
+        fs::remove_file(&compressed_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_info() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, info!").unwrap();
+        }
+
+        let stats = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                compression_level: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let info = backup_info(&backup_output_path).unwrap();
+        assert_eq!(info.path, backup_output_path);
+        assert_eq!(info.format_version, Some(FORMAT_VERSION));
+        assert_eq!(info.chunk_size, chunk_size as u64);
+        assert_eq!(info.key_slots, 1);
+        assert_eq!(info.checksum_algorithm, Some(ChecksumAlgorithm::Sha256));
+        assert!(info.compressed);
+        assert_eq!(info.tool_version.as_deref(), Some(TOOL_VERSION));
+        assert_eq!(info.size, stats.output_size);
+
+        let created = info.created.unwrap();
+        let elapsed = chrono::Utc::now() - created;
+        assert!(elapsed >= chrono::TimeDelta::zero());
+        assert!(elapsed < chrono::TimeDelta::minutes(5));
+
+        assert!(matches!(
+            backup_info(non_existent_temp_file()),
+            Err(BackupError::IoError(_))
+        ));
+
+        // Files that are not backups are rejected rather than read as
+        // backups from before headers were introduced
+        let not_backup_path = src_path.join("file.txt");
+        assert!(matches!(
+            backup_info(&not_backup_path),
+            Err(BackupError::InvalidHeader(_))
+        ));
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_deterministic() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_paths = [
+            non_existent_temp_file(),
+            non_existent_temp_file(),
+            non_existent_temp_file(),
+        ];
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let options = BackupOptions {
+            additional_passwords: vec!["hunter22".to_owned()],
+            deterministic: true,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir_all(src_path.join("sub")).unwrap();
+            for i in 0..10 {
+                fs::write(src_path.join(format!("file{i}.txt")), "x".repeat(i * 500)).unwrap();
+            }
+            fs::write(src_path.join("sub").join("nested.txt"), "Hello, again!").unwrap();
+        }
+
+        for (backup_output_path, password) in
+            backup_output_paths
+                .iter()
+                .zip(["password123", "password123", "password124"])
+        {
+            backup(
+                &include_paths,
+                &exclude_globs,
+                backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &options,
+            )
+            .unwrap();
+        }
+
+        // The same files and password produce the same bytes, and a different
+        // password does not
+        let backups = backup_output_paths
+            .iter()
+            .map(|path| fs::read(path).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(backups[0], backups[1]);
+        assert_ne!(backups[0], backups[2]);
+
+        // The password of a deterministic backup cannot be changed, since the
+        // data key is derived from the first password, and the backup is left
+        // as it was
+        for password in ["password123", "hunter22"] {
+            assert!(matches!(
+                change_password(
+                    &backup_output_paths[0],
+                    password,
+                    "hunter23",
+                    &ChangePasswordOptions::default(),
+                )
+                .unwrap_err(),
+                BackupError::DeterministicPasswordChange
+            ));
+        }
+        assert_eq!(fs::read(&backup_output_paths[0]).unwrap(), backups[0]);
+
+        // Either password still opens the backup
+        for password in ["password123", "hunter22"] {
+            extract(
+                &backup_output_paths[0],
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+        for backup_output_path in &backup_output_paths {
+            fs::remove_file(backup_output_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_backup_with_key() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let key = random_key();
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, raw key!").unwrap();
+        }
+
+        backup_with_key(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            key,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                additional_passwords: vec!["password123".to_owned()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // A different key does not open the backup
+        let err = extract_with_key(
+            &backup_output_path,
+            &extract_output_path,
+            random_key(),
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::IncorrectPassword));
+        assert!(!extract_output_path.exists());
+
+        extract_with_key(
+            &backup_output_path,
+            &extract_output_path,
+            key,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        // The additional password opens the backup too
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            "password123",
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_recovery_key() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let (private_key, public_key) = generate_recovery_key();
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, recovery key!").unwrap();
+        }
+
+        // A recovery key cannot be combined with a deterministic backup, nor
+        // can a public key of low order be used
+        for (recovery_public_key, deterministic) in
+            [(public_key, true), ([0; RECOVERY_KEY_SIZE], false)]
+        {
+            let err = backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    recovery_public_key: Some(recovery_public_key),
+                    deterministic,
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+            assert!(matches!(
+                err,
+                BackupError::IncompatibleOptions(_) | BackupError::InvalidRecoveryKey
+            ));
+            assert!(!backup_output_path.exists());
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                recovery_public_key: Some(public_key),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // A different recovery key does not open the backup
+        let err = extract_with_recovery_key(
+            &backup_output_path,
+            &extract_output_path,
+            generate_recovery_key().0,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::IncorrectPassword));
+        assert!(!extract_output_path.exists());
+
+        extract_with_recovery_key(
+            &backup_output_path,
+            &extract_output_path,
+            private_key,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        // The password still opens the backup
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_dedup() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let name = PathBuf::from(src_path.file_name().unwrap());
+        let mut rng = StdRng::seed_from_u64(1174);
+        let mut repeated = vec![0; chunk_size * 8];
+        rng.fill_bytes(&mut repeated);
+        let mut same_size = vec![0; chunk_size * 8];
+        rng.fill_bytes(&mut same_size);
+
+        {
+            fs::create_dir_all(src_path.join("a/b")).unwrap();
+            fs::write(src_path.join("first.bin"), &repeated).unwrap();
+            fs::write(src_path.join("a/copy.bin"), &repeated).unwrap();
+            fs::write(src_path.join("a/b/copy.bin"), &repeated).unwrap();
+            fs::write(src_path.join("same_size.bin"), &same_size).unwrap();
+            fs::write(src_path.join("empty1.txt"), "").unwrap();
+            fs::write(src_path.join("empty2.txt"), "").unwrap();
+        }
+
+        let backup_with = |dedup| {
+            let backup_output_path = non_existent_temp_file();
+            let options = BackupOptions {
+                dedup,
+                indexed: true,
+                ..Default::default()
+            };
+            let stats = backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &options,
+            )
+            .unwrap();
+            (backup_output_path, stats.output_size)
+        };
+        let (plain_path, plain_size) = backup_with(false);
+        let (dedup_path, dedup_size) = backup_with(true);
+
+        // Each copy is stored once, rather than three times
+        assert!(dedup_size + 2 * repeated.len() as u64 <= plain_size + 2048);
+        let list_entry = |path: &str| {
+            list(&dedup_path, Some(password))
+                .unwrap()
+                .into_iter()
+                .find(|entry| entry.path == name.join(path))
+                .unwrap()
+        };
+        assert_eq!(list_entry("a/copy.bin").kind, EntryKind::File);
+        assert_eq!(list_entry("a/copy.bin").size, repeated.len() as u64);
+
+        let extract_output_path = non_existent_temp_file();
+        let extract_options = ExtractOptions {
+            verify_on_extract: true,
+            ..Default::default()
+        };
+        extract(
+            &dedup_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &extract_options,
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, extract_output_path.join(&name), true, &[], &[]).unwrap();
+
+        // Copies are written separately rather than linked to the first one
+        let copy_path = extract_output_path.join(&name).join("a/b/copy.bin");
+        fs::write(&copy_path, "Changed").unwrap();
+        assert_eq!(
+            fs::read(extract_output_path.join(&name).join("first.bin")).unwrap(),
+            repeated
+        );
+
+        // A copy is only restored alongside the file it refers to
+        let patterns = [
+            Pattern::new("*/first.bin").unwrap(),
+            Pattern::new("*/a/*").unwrap(),
+        ];
+        for (patterns, restored) in [(&patterns[..], true), (&patterns[1..], false)] {
+            let matching_output_path = non_existent_temp_file();
+            extract_matching(
+                &dedup_path,
+                &matching_output_path,
+                password,
+                patterns,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            let matching_copy_path = matching_output_path.join(&name).join("a/copy.bin");
+            assert_eq!(
+                fs::read(matching_copy_path).ok(),
+                restored.then(|| repeated.clone())
+            );
+            fs::remove_dir_all(&matching_output_path).unwrap();
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&plain_path).unwrap();
+        fs::remove_file(&dedup_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_special_files() {
+        use nix::sys::stat::Mode;
+        use nix::unistd::mkfifo;
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+        use std::os::unix::net::UnixListener;
+
+        let src_path = non_existent_temp_file();
+        let fifo_path = non_existent_temp_file();
+        let include_paths = [&src_path, &fifo_path];
+        let exclude_globs = [];
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let name = PathBuf::from(src_path.file_name().unwrap());
+        let fifo_name = PathBuf::from(fifo_path.file_name().unwrap());
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, special files!").unwrap();
+            mkfifo(&src_path.join("fifo"), Mode::from_bits_truncate(0o640)).unwrap();
+            UnixListener::bind(src_path.join("socket")).unwrap();
+            mkfifo(&fifo_path, Mode::from_bits_truncate(0o600)).unwrap();
+        }
+
+        let backup_and_extract = |special_files| {
+            let backup_output_path = non_existent_temp_file();
+            let extract_output_path = non_existent_temp_file();
+            let options = BackupOptions {
+                special_files,
+                ..Default::default()
+            };
+            backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &options,
+            )
+            .unwrap();
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            fs::remove_file(&backup_output_path).unwrap();
+            extract_output_path
+        };
+        let file_type = |path: PathBuf| fs::symlink_metadata(path).ok().map(|m| m.file_type());
+
+        // Special files are left out unless they are being stored
+        let skipped_path = backup_and_extract(SpecialFileMode::Skip);
+        assert!(skipped_path.join(&name).join("file.txt").is_file());
+        assert!(file_type(skipped_path.join(&name).join("fifo")).is_none());
+        assert!(file_type(skipped_path.join(&fifo_name)).is_none());
+
+        // Sockets cannot be stored, even when other special files are
+        let stored_path = backup_and_extract(SpecialFileMode::Store);
+        let fifo_metadata = fs::symlink_metadata(stored_path.join(&name).join("fifo")).unwrap();
+        assert!(fifo_metadata.file_type().is_fifo());
+        assert_eq!(fifo_metadata.permissions().mode() & 0o777, 0o640);
+        assert!(file_type(stored_path.join(&fifo_name)).is_some_and(|t| t.is_fifo()));
+        assert!(file_type(stored_path.join(&name).join("socket")).is_none());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&fifo_path).unwrap();
+        fs::remove_dir_all(&skipped_path).unwrap();
+        fs::remove_dir_all(&stored_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_delta() {
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let delta_output_path = non_existent_temp_file();
+        let reference_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let name = src_path.file_name().unwrap();
+        let password = "password123";
+        let pool_size = 16;
+        let backup_to = |output_path: &Path, options: &BackupOptions| {
+            backup(
+                &[&src_path],
+                &[],
+                output_path,
+                password,
+                1024,
+                pool_size,
+                options,
+            )
+        };
+        let extract_to = |backup_path: &Path, output_path: &Path, overwrite| {
+            extract(
+                backup_path,
+                output_path,
+                password,
+                pool_size,
+                &ExtractOptions {
+                    overwrite,
+                    verify_on_extract: true,
+                    ..Default::default()
+                },
+            )
+        };
+        let delta_options = BackupOptions {
+            reference_dir: Some(reference_path.clone()),
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("same.txt"), "same").unwrap();
+            fs::write(src_path.join("touched.txt"), "touched").unwrap();
+            fs::write(src_path.join("changed.txt"), "old").unwrap();
+        }
+
+        // The reference directory must exist
+        assert!(matches!(
+            backup_to(&delta_output_path, &delta_options).unwrap_err(),
+            BackupError::InvalidReferenceDir(_)
+        ));
+
+        backup_to(&backup_output_path, &BackupOptions::default()).unwrap();
+        extract_to(&backup_output_path, &reference_path, OverwriteMode::Never).unwrap();
+
+        // Touching a file without changing it does not make it part of the
+        // delta, unlike changing or adding one
+        filetime::set_file_mtime(
+            src_path.join("touched.txt"),
+            FileTime::from_unix_time(FileTime::now().unix_seconds() + 60, 0),
+        )
+        .unwrap();
+        fs::write(src_path.join("changed.txt"), "new contents").unwrap();
+        fs::write(src_path.join("added.txt"), "added").unwrap();
+
+        backup_to(&delta_output_path, &delta_options).unwrap();
+        extract_to(
+            &delta_output_path,
+            &extract_output_path,
+            OverwriteMode::Never,
+        )
+        .unwrap();
+        let mut delta_files = fs::read_dir(extract_output_path.join(name))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        delta_files.sort();
+        assert_eq!(delta_files, ["added.txt", "changed.txt"]);
+
+        // Applying the delta over a reference that has changed since fails
+        // verification
+        let same_path = reference_path.join(name).join("same.txt");
+        fs::write(&same_path, "different").unwrap();
+        assert!(matches!(
+            extract_to(&delta_output_path, &reference_path, OverwriteMode::Always).unwrap_err(),
+            BackupError::FileHashMismatch { path } if path == same_path
+        ));
+
+        // Applying it over the reference restores the full tree
+        fs::write(&same_path, "same").unwrap();
+        extract_to(&delta_output_path, &reference_path, OverwriteMode::Always).unwrap();
+        verify_identical_trees(&src_path, &reference_path.join(name), false, &[], &[]).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_file(&delta_output_path).unwrap();
+        fs::remove_dir_all(&reference_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_to_stream() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "x".repeat(10_000)).unwrap();
+        }
+
+        // Neither a `Vec` writer nor a slice reader can seek
+        let mut data = Vec::new();
+        let stats = encrypt_backup_to(
+            &include_paths,
+            &exclude_globs,
+            &mut data,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.path, PathBuf::new());
+        assert_eq!(stats.output_size, data.len() as u64);
+
+        let err = decrypt_backup_from(
+            data.as_slice(),
+            &extract_output_path,
+            "wrong password",
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::IncorrectPassword));
+
+        decrypt_backup_from(
+            data.as_slice(),
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+        assert!(!tmp_file_for(&extract_output_path).exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_multiple_passwords() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let passwords = ["password123", "correct horse", "battery staple"];
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("shared.txt"), "Hello, shared custody!").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            passwords[0],
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                additional_passwords: passwords[1..].iter().map(|&p| p.to_owned()).collect(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Any one of the passwords opens the backup
+        for password in passwords {
+            let extract_output_path = non_existent_temp_file();
+            let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        }
+
+        // Any other password does not
+        let extract_output_path = non_existent_temp_file();
+        let err = extract(
+            &backup_output_path,
+            &extract_output_path,
+            "password124",
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::IncorrectPassword));
+        assert!(!extract_output_path.exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_kdf_params() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let kdf_params = Argon2Params {
+            memory_kib: Argon2Params::MIN_MEMORY_KIB,
+            iterations: 3,
+            parallelism: 2,
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, Argon2!").unwrap();
+        }
+
+        // Unsafe parameters are rejected before anything is written
+        let err = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                kdf_params: Argon2Params {
+                    memory_kib: 1024,
+                    ..kdf_params
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::InvalidKdfParams(_)));
+        assert!(!backup_output_path.exists());
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                kdf_params,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // The parameters are stored in the header and used for extraction
+        let header = BackupHeader::read(&mut File::open(&backup_output_path).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.slots[0].kdf, Kdf::Argon2(kdf_params));
+
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    /// Writes a backup in the format used before headers were introduced,
+    /// encrypted directly with the password.
+    fn write_legacy_backup(
+        src_path: &Path,
+        backup_path: &Path,
+        password: &str,
+        chunk_size: usize,
+        pool_size: u8,
+    ) {
+        let mut tar_bytes = Vec::new();
+        let mut archive = tar::Builder::new(&mut tar_bytes);
+        archive
+            .append_dir_all(src_path.file_name().unwrap(), src_path)
+            .unwrap();
+        archive.into_inner().unwrap();
+
+        let mut legacy_file = File::create_new(backup_path).unwrap();
+        encrypt_stream(
+            &mut legacy_file,
+            PayloadKey::new(password_to_key(password), false),
+            chunk_size,
+            pool_size,
+            None,
+            NonceMode::Random,
+            |encryptor| Ok(encryptor.write_all(&tar_bytes)?),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_change_password() {
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        let assert_extracts = |password: &str| {
+            let extract_output_path = non_existent_temp_file();
+            let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        };
+        let assert_rejected = |password: &str| {
+            let err = verify(
+                &backup_output_path,
+                password,
+                pool_size,
+                &VerifyOptions::default(),
+            )
+            .unwrap_err();
+            assert!(matches!(err, BackupError::IncorrectPassword));
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), vec![3u8; chunk_size * 4]).unwrap();
+        }
+
+        // Create a backup in the format used before headers were introduced,
+        // encrypted directly with the password
+        write_legacy_backup(
+            &src_path,
+            &backup_output_path,
+            "password123",
+            chunk_size,
+            pool_size,
+        );
+
+        assert_extracts("password123");
+
+        // An incorrect old password leaves the backup untouched
+        let legacy_backup = fs::read(&backup_output_path).unwrap();
+        assert!(change_password(
+            &backup_output_path,
+            "password124",
+            "hunter22",
+            &ChangePasswordOptions::default()
+        )
+        .is_err());
+        assert_eq!(fs::read(&backup_output_path).unwrap(), legacy_backup);
+        assert!(!tmp_file_for(&backup_output_path).exists());
+
+        // Changing the password migrates the backup to the current format
+        change_password(
+            &backup_output_path,
+            "password123",
+            "hunter22",
+            &ChangePasswordOptions::default(),
+        )
+        .unwrap();
+        assert!(fs::read(&backup_output_path).unwrap().starts_with(MAGIC));
+        assert_eq!(backup_chunk_size(&backup_output_path).unwrap(), chunk_size);
+        assert!(matches!(
+            verify_checksum(&backup_output_path).unwrap_err(),
+            BackupError::MissingChecksum
+        ));
+        assert_extracts("hunter22");
+        assert_rejected("password123");
+
+        // The payload is no longer encrypted with the old password's key
+        {
+            let mut file = File::open(&backup_output_path).unwrap();
+            BackupHeader::read(&mut file).unwrap().unwrap();
+            let section = read_section(&mut file).unwrap().unwrap();
+            assert!(aes_decrypt(password_to_key("password123"), &section).is_err());
+        }
+
+        // Changing it again only replaces the header
+        let size_before = fs::metadata(&backup_output_path).unwrap().len();
+        change_password(
+            &backup_output_path,
+            "hunter22",
+            "hunter23",
+            &ChangePasswordOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::metadata(&backup_output_path).unwrap().len(),
+            size_before
+        );
+        assert_extracts("hunter23");
+        assert_rejected("hunter22");
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_change_password_upgrade_format() {
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        let assert_extracts = |password: &str| {
+            let extract_output_path = non_existent_temp_file();
+            let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), vec![3u8; chunk_size * 4]).unwrap();
+        }
+
+        write_legacy_backup(
+            &src_path,
+            &backup_output_path,
+            "password123",
+            chunk_size,
+            pool_size,
+        );
+
+        // Migrating without upgrading leaves the backup without a checksum
+        change_password(
+            &backup_output_path,
+            "password123",
+            "hunter23",
+            &ChangePasswordOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            backup_info(&backup_output_path).unwrap().format_version,
+            Some(CHECKSUMLESS_FORMAT_VERSION)
+        );
+
+        // Upgrading adds the checksum trailer and brings the header to the
+        // current format version
+        let upgrade_options = ChangePasswordOptions {
+            upgrade_format: true,
+        };
+        change_password(
+            &backup_output_path,
+            "hunter23",
+            "hunter24",
+            &upgrade_options,
+        )
+        .unwrap();
+        assert!(!tmp_file_for(&backup_output_path).exists());
+        verify_checksum(&backup_output_path).unwrap();
+        let info = backup_info(&backup_output_path).unwrap();
+        assert_eq!(info.format_version, Some(FORMAT_VERSION));
+        assert_eq!(info.chunk_size, chunk_size as u64);
+        assert_extracts("hunter24");
+        assert!(matches!(
+            verify(
+                &backup_output_path,
+                "hunter23",
+                pool_size,
+                &VerifyOptions::default()
+            ),
+            Err(BackupError::IncorrectPassword)
+        ));
+
+        // Upgrading a backup that is already current only replaces the key
+        // slot
+        let size_before = fs::metadata(&backup_output_path).unwrap().len();
+        change_password(
+            &backup_output_path,
+            "hunter24",
+            "hunter24",
+            &upgrade_options,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::metadata(&backup_output_path).unwrap().len(),
+            size_before
+        );
+        verify_checksum(&backup_output_path).unwrap();
+        assert_extracts("hunter24");
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let src_path1 = non_existent_temp_file();
+        let src_path2 = non_existent_temp_file();
+        let backup_path1 = non_existent_temp_file();
+        let backup_path2 = non_existent_temp_file();
+        let merged_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path1.join("nested")).unwrap();
+            fs::write(src_path1.join("nested").join("file1.txt"), "file 1").unwrap();
+            fs::create_dir(&src_path2).unwrap();
+            fs::write(src_path2.join("file2.txt"), "file 2".repeat(1000)).unwrap();
+        }
+
+        // Backups with different chunk sizes and compression can be merged
+        backup(
+            &[&src_path1],
+            &[],
+            &backup_path1,
+            password,
+            1024,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        backup(
+            &[&src_path2],
+            &[],
+            &backup_path2,
+            password,
+            4096,
+            pool_size,
+            &BackupOptions {
+                compression_level: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let stats = merge(
+            &[&backup_path1, &backup_path2],
+            &merged_path,
+            password,
+            pool_size,
+        )
+        .unwrap();
+        assert_eq!(stats.path, merged_path);
+        assert_eq!(backup_chunk_size(&merged_path).unwrap(), 1024);
+        verify_checksum(&merged_path).unwrap();
+
+        extract(
+            &merged_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                verify_on_extract: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for src_path in [&src_path1, &src_path2] {
+            verify_identical_trees(
+                src_path,
+                extract_output_path.join(src_path.file_name().unwrap()),
+                true,
+                &[],
+                &[],
+            )
+            .unwrap();
+        }
+        fs::remove_file(&merged_path).unwrap();
+
+        // Backups cannot share top-level names or be opened with another
+        // password, and nothing is left behind when merging fails
+        assert!(matches!(
+            merge(
+                &[&backup_path1, &backup_path1],
+                &merged_path,
+                password,
+                pool_size
+            )
+            .unwrap_err(),
+            BackupError::DuplicateIncludeName { .. }
+        ));
+        assert!(!merged_path.exists());
+        assert!(matches!(
+            merge(&[&backup_path1], &merged_path, "password124", pool_size).unwrap_err(),
+            BackupError::IncorrectPassword
+        ));
+        assert!(!merged_path.exists());
+        assert!(matches!(
+            merge(&[] as &[&Path], &merged_path, password, pool_size).unwrap_err(),
+            BackupError::EmptyBackup
+        ));
+
+        fs::remove_dir_all(&src_path1).unwrap();
+        fs::remove_dir_all(&src_path2).unwrap();
+        fs::remove_file(&backup_path1).unwrap();
+        fs::remove_file(&backup_path2).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_check_password() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, password check!").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            "password123",
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                additional_passwords: vec!["correct horse".to_owned()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(check_password(&backup_output_path, "password123").unwrap());
+        assert!(check_password(&backup_output_path, "correct horse").unwrap());
+        assert!(!check_password(&backup_output_path, "password124").unwrap());
+
+        // Backups without a header are checked against their first chunk
+        fs::remove_file(&backup_output_path).unwrap();
+        write_legacy_backup(
+            &src_path,
+            &backup_output_path,
+            "password123",
+            chunk_size,
+            pool_size,
+        );
+        assert!(check_password(&backup_output_path, "password123").unwrap());
+        assert!(!check_password(&backup_output_path, "password124").unwrap());
+
+        // Files that are not backups are errors, not incorrect passwords
+        fs::write(&backup_output_path, "").unwrap();
+        assert!(check_password(&backup_output_path, "password123").is_err());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_temp_dir() {
+        let src_path = non_existent_temp_file();
+        let src_file = src_path.join("file.txt");
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let temp_dir = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(&src_file, "Hello, temporary directory!").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        // A missing temporary directory is rejected
+        let err = extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                temp_dir: Some(temp_dir.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::InvalidTempDir(_)));
+        assert!(!extract_output_path.exists());
+
+        fs::create_dir(&temp_dir).unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                temp_dir: Some(temp_dir.clone()),
+                secure_delete: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert!(!tmp_file_for(&backup_output_path).exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+        fs::remove_dir(&temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_backup_ignore_file() {
+        let src_path = non_existent_temp_file();
+        let sub_path = src_path.join("sub");
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let options = BackupOptions {
+            follow_backupignore: true,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir_all(sub_path.join("deeper")).unwrap();
+            fs::write(src_path.join("keep.log"), "kept").unwrap();
+            fs::write(sub_path.join(BACKUP_IGNORE_FILE_NAME), "# Logs\n\n*.log\n").unwrap();
+            fs::write(sub_path.join("skip.log"), "skipped").unwrap();
+            fs::write(sub_path.join("keep.txt"), "kept").unwrap();
+            fs::write(sub_path.join("deeper").join("skip.log"), "skipped").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &options,
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        assert!(extract_output_root.join("keep.log").is_file());
+        assert!(extract_output_root.join("sub").join("keep.txt").is_file());
+        assert!(extract_output_root
+            .join("sub")
+            .join(BACKUP_IGNORE_FILE_NAME)
+            .is_file());
+        assert!(!extract_output_root.join("sub").join("skip.log").exists());
+        assert!(!extract_output_root
+            .join("sub")
+            .join("deeper")
+            .join("skip.log")
+            .exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_exclude_regex() {
+        let src_path = non_existent_temp_file();
+        let src_name = src_path.file_name().unwrap().to_str().unwrap().to_owned();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(&src_name);
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join("sub").join("deeper")).unwrap();
+            fs::write(src_path.join("notes.txt"), "kept").unwrap();
+            fs::write(src_path.join("release-1.2.3.tar"), "skipped").unwrap();
+            fs::write(src_path.join("sub").join("skip"), "skipped").unwrap();
+            fs::write(src_path.join("sub").join("deeper").join("skip"), "kept").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                exclude_regex: vec![
+                    Regex::new(r"\d+\.\d+\.\d+").unwrap(),
+                    // Paths are matched with forward slashes on all platforms
+                    Regex::new(&format!("^{}/sub/skip$", regex::escape(&src_name))).unwrap(),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        assert!(extract_output_root.join("notes.txt").is_file());
+        assert!(!extract_output_root.join("release-1.2.3.tar").exists());
+        assert!(!extract_output_root.join("sub").join("skip").exists());
+        assert!(extract_output_root
+            .join("sub")
+            .join("deeper")
+            .join("skip")
+            .is_file());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_excluding_pattern() {
+        let globs = [
+            Pattern::new("*.log").unwrap(),
+            Pattern::new("src/*").unwrap(),
+        ];
+        let regexes = [Regex::new(r"^a/\w").unwrap(), Regex::new(r"\w/c$").unwrap()];
+
+        // The first matching pattern is reported
+        assert_eq!(
+            excluding_glob("src/debug.log", &globs).map(Pattern::as_str),
+            Some("*.log")
+        );
+        assert_eq!(
+            excluding_glob("src/main.rs", &globs).map(Pattern::as_str),
+            Some("src/*")
+        );
+        assert!(excluding_glob("main.rs", &globs).is_none());
+        assert_eq!(
+            excluding_regex(&Path::new("x").join("b").join("c"), &regexes).map(Regex::as_str),
+            Some(r"\w/c$")
+        );
+        assert_eq!(
+            excluding_regex(&Path::new("a").join("b").join("c"), &regexes).map(Regex::as_str),
+            Some(r"^a/\w")
+        );
+        assert!(excluding_regex(Path::new("b"), &regexes).is_none());
+        assert!(excluding_regex(Path::new("b"), &[]).is_none());
+    }
+
+    #[test]
+    fn test_backup_exclude_hidden() {
+        let src_path = non_existent_temp_file();
+        let hidden_include_path = {
+            let path = non_existent_temp_file();
+            let name = format!(".{}", path.file_name().unwrap().to_str().unwrap());
+            path.with_file_name(name)
+        };
+        let include_paths = [&src_path, &hidden_include_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let extract_hidden_root =
+            extract_output_path.join(hidden_include_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join("sub")).unwrap();
+            fs::create_dir_all(src_path.join(".hidden_dir")).unwrap();
+            fs::write(src_path.join("visible.txt"), "kept").unwrap();
+            fs::write(src_path.join(".hidden"), "skipped").unwrap();
+            fs::write(src_path.join(".hidden_dir").join("inner.txt"), "skipped").unwrap();
+            fs::write(src_path.join("sub").join(".env"), "skipped").unwrap();
+            fs::write(src_path.join("sub").join("visible.txt"), "kept").unwrap();
+            fs::create_dir(&hidden_include_path).unwrap();
+            fs::write(hidden_include_path.join("config"), "kept").unwrap();
+            fs::write(hidden_include_path.join(".lock"), "skipped").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                exclude_hidden: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        assert!(extract_output_root.join("visible.txt").is_file());
+        assert!(extract_output_root
+            .join("sub")
+            .join("visible.txt")
+            .is_file());
+        assert!(!extract_output_root.join(".hidden").exists());
+        assert!(!extract_output_root.join(".hidden_dir").exists());
+        assert!(!extract_output_root.join("sub").join(".env").exists());
+
+        // A hidden include path is still backed up, minus its hidden contents
+        assert!(extract_hidden_root.join("config").is_file());
+        assert!(!extract_hidden_root.join(".lock").exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&hidden_include_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_root_rejected() {
+        let root = Path::new("/");
+        let include_paths = [root];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        let err = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap_err();
+
+        assert!(matches!(err, BackupError::DangerousIncludePath(path) if path == root));
+        assert!(!backup_output_path.exists());
+    }
+
+    #[test]
+    fn test_dangerous_include_paths() {
+        assert!(is_dangerous_include_path(Path::new("/")));
+
+        #[cfg(unix)]
+        {
+            assert!(is_dangerous_include_path(Path::new("/proc")));
+            assert!(is_dangerous_include_path(Path::new("/sys")));
+            assert!(is_dangerous_include_path(Path::new("/dev")));
+
+            // Removable drives are mounted under `/run/media` on some
+            // distributions
+            assert!(!is_dangerous_include_path(Path::new(
+                "/run/media/user/drive"
+            )));
+            assert!(!is_dangerous_include_path(Path::new("/run")));
+            assert!(!is_dangerous_include_path(Path::new("/home/user")));
+        }
+    }
+
+    #[test]
+    fn test_backup_duplicate_include_names() {
+        let first = non_existent_temp_file();
+        let second = non_existent_temp_file().join(first.file_name().unwrap());
+        let include_paths = [&first, &second];
+        let backup_output_path = non_existent_temp_file();
+
+        {
+            fs::create_dir(&first).unwrap();
+            fs::create_dir_all(&second).unwrap();
+        }
+
+        let err = backup(
+            &include_paths,
+            &[],
+            &backup_output_path,
+            "password123",
+            1024,
+            16,
+            &BackupOptions::default(),
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            &err,
+            BackupError::DuplicateIncludeName { name, first: err_first, second: err_second }
+                if name == first.file_name().unwrap().to_str().unwrap()
+                    && err_first == &first
+                    && err_second == &second
+        ));
+        assert!(!backup_output_path.exists());
+
+        fs::remove_dir_all(&first).unwrap();
+        fs::remove_dir_all(second.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_backup_everything_excluded() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs =
+            [Pattern::new(src_path.file_name().unwrap().to_str().unwrap()).unwrap()];
+        let backup_output_path = non_existent_temp_file();
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(
+                src_path.join("file.txt"),
+                "excluded along with its directory",
+            )
+            .unwrap();
+        }
+
+        // An include path covered entirely by an exclude glob leaves nothing
+        // to back up
+        let err = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            "password123",
+            1024,
+            16,
+            &BackupOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::EmptyBackup));
+        assert_eq!(err.kind(), "empty-backup");
+
+        // The partly written output is removed
+        assert!(!backup_output_path.exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_output_inside_include_path() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_name = "backup.ebk";
+        let backup_output_path = src_path.join(backup_output_name);
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "file contents").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        assert!(!extract_output_root.join(backup_output_name).exists());
+        verify_identical_trees(
+            &src_path,
+            &extract_output_root,
+            false,
+            &[],
+            &[backup_output_name],
+        )
+        .unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_overwrite() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_name = "backup.ebk";
+        let backup_output_path = src_path.join(backup_output_name);
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let overwrite_options = BackupOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "old contents").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+
+        // An existing backup is only replaced when requested
+        fs::write(src_path.join("file.txt"), "new contents").unwrap();
+        let err = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::PathAlreadyExists(_)));
+
+        let stats = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &overwrite_options,
+        )
+        .unwrap();
+        assert_eq!(stats.path, backup_output_path);
+        assert!(!tmp_file_for(&backup_output_path).exists());
+
+        // Neither the new backup nor the one it replaced is included
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        assert!(!extract_output_root.join(backup_output_name).exists());
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join("file.txt")).unwrap(),
+            "new contents"
+        );
+
+        // Directories are never replaced
+        let err = backup(
+            &include_paths,
+            &exclude_globs,
+            &extract_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &overwrite_options,
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::PathAlreadyExists(_)));
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_continue_on_error() {
+        let path = Path::new("unreadable.txt");
+        let error = || io::Error::other("read failed");
+        let permission_error = || io::Error::from(io::ErrorKind::PermissionDenied);
+
+        // Errors fail the backup by default, except for permission errors
+        let options = BackupOptions::default();
+        let mut context = ArchiveContext::new(&[], &options, Vec::new());
+        assert!(context.skip(path, error()).is_err());
+        assert!(context.skip(path, permission_error()).is_ok());
+        assert!(context.skipped.is_empty());
+
+        // Errors are recorded when continuing past them
+        let options = BackupOptions {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let mut context = ArchiveContext::new(&[], &options, Vec::new());
+        context.skip(path, error()).unwrap();
+        context.skip(path, permission_error()).unwrap();
+        assert_eq!(
+            context.skipped,
+            vec![SkippedPath {
+                path: path.to_path_buf(),
+                error: "read failed".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_backup_max_open_files() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let options = BackupOptions {
+            max_open_files: NonZeroUsize::new(1),
+            continue_on_error: true,
+            dedup: true,
+            follow_backupignore: true,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir_all(src_path.join("dir1").join("dir2")).unwrap();
+            fs::write(src_path.join("file1.txt"), "file 1").unwrap();
+            fs::write(src_path.join("dir1").join("file2.txt"), "file 2").unwrap();
+            fs::write(
+                src_path.join("dir1").join("dir2").join("file3.txt"),
+                "file 3",
+            )
+            .unwrap();
+            fs::write(src_path.join("dir1").join("copy.txt"), "file 2").unwrap();
+            fs::write(src_path.join("dir1").join("ignored.log"), "log").unwrap();
+            fs::write(
+                src_path.join("dir1").join(BACKUP_IGNORE_FILE_NAME),
+                "*.log\n",
+            )
+            .unwrap();
+        }
+
+        // Running out of file descriptors fails the backup with a clear
+        // error, even when continuing past other errors
+        let mut context = ArchiveContext::new(&[], &options, Vec::new());
+        #[cfg(unix)]
+        let error = io::Error::from_raw_os_error(24);
+        #[cfg(windows)]
+        let error = io::Error::from_raw_os_error(4);
+        #[cfg(any(unix, windows))]
+        assert!(matches!(
+            context.skip(&src_path, error),
+            Err(BackupError::TooManyOpenFiles(path)) if path == src_path
+        ));
+
+        // A single open file at a time is enough to walk nested directories
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &options,
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(
+                extract_output_root
+                    .join("dir1")
+                    .join("dir2")
+                    .join("file3.txt")
+            )
+            .unwrap(),
+            "file 3"
+        );
+
+        // Ignore files and the files hashed to find duplicates are opened
+        // within the limit too
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join("dir1").join("copy.txt")).unwrap(),
+            "file 2"
+        );
+        assert!(!extract_output_root
+            .join("dir1")
+            .join("ignored.log")
+            .exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_strip_components() {
+        let src_path1 = non_existent_temp_file();
+        let src_path2 = non_existent_temp_file();
+        let include_paths = [&src_path1, &src_path2];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let extract_options = ExtractOptions {
+            strip_components: 1,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir_all(src_path1.join("shared")).unwrap();
+            fs::create_dir_all(src_path2.join("shared").join("nested")).unwrap();
+            fs::write(src_path1.join("file1.txt"), "file 1").unwrap();
+            fs::write(src_path1.join("shared").join("file2.txt"), "file 2").unwrap();
+            fs::write(
+                src_path2.join("shared").join("nested").join("file3.txt"),
+                "file 3",
+            )
+            .unwrap();
+        }
+
+        // The contents of both include paths are merged into the output
+        // directory, including directories they have in common
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &extract_options,
+        )
+        .unwrap();
+        assert!(!extract_output_path
+            .join(src_path1.file_name().unwrap())
+            .exists());
+        assert_eq!(
+            fs::read_to_string(extract_output_path.join("file1.txt")).unwrap(),
+            "file 1"
+        );
+        assert_eq!(
+            fs::read_to_string(extract_output_path.join("shared").join("file2.txt")).unwrap(),
+            "file 2"
+        );
+        assert_eq!(
+            fs::read_to_string(
+                extract_output_path
+                    .join("shared")
+                    .join("nested")
+                    .join("file3.txt")
+            )
+            .unwrap(),
+            "file 3"
+        );
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        // Files that would be extracted to the same path fail the extraction
+        fs::write(src_path2.join("file1.txt"), "other file 1").unwrap();
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        let result = extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &extract_options,
+        );
+        assert!(matches!(
+            result,
+            Err(BackupError::StrippedPathCollision { path, .. }) if path == Path::new("file1.txt")
+        ));
+
+        fs::remove_dir_all(&src_path1).unwrap();
+        fs::remove_dir_all(&src_path2).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        if extract_output_path.exists() {
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unpack_stripped_hard_link_through_symlink() {
+        use std::os::unix::fs::MetadataExt;
+
+        let outside_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let progress_path = non_existent_temp_file();
+        let extract_options = ExtractOptions {
+            strip_components: 1,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir(&outside_path).unwrap();
+            fs::write(outside_path.join("key"), "secret").unwrap();
+        }
+
+        // A symlink leading out of the output directory, followed by a hard
+        // link whose stripped target passes through it
+        let mut tar_bytes = Vec::new();
+        let mut archive = tar::Builder::new(&mut tar_bytes);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        archive
+            .append_link(&mut header, "top/a", &outside_path)
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        archive
+            .append_link(&mut header, "top/b", "top/a/key")
+            .unwrap();
+        archive.into_inner().unwrap();
+
+        // The hard link is skipped rather than linked to the outside file
+        unpack_archive(
+            &mut tar::Archive::new(tar_bytes.as_slice()),
+            &extract_output_path,
+            &progress_path,
+            None,
+            &extract_options,
+        )
+        .unwrap();
+        assert!(!extract_output_path.join("b").exists());
+        assert_eq!(fs::metadata(outside_path.join("key")).unwrap().nlink(), 1);
+        assert_eq!(
+            fs::read_to_string(outside_path.join("key")).unwrap(),
+            "secret"
+        );
+
+        fs::remove_dir_all(&outside_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+        if progress_path.exists() {
+            fs::remove_file(&progress_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_backup_verify_on_extract() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let src_name = Path::new(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join("dir")).unwrap();
+            fs::write(src_path.join("file1.txt"), "file 1").unwrap();
+            fs::write(src_path.join("dir").join("file2.txt"), "x".repeat(5000)).unwrap();
+        }
+
+        // Every file matches the hash recorded for it
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                verify_on_extract: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // A file that changes after being written no longer matches
+        let mut manifest = Manifest::default();
+        for name in [Path::new("file1.txt"), &Path::new("dir").join("file2.txt")] {
+            manifest.push(
+                &src_name.join(name),
+                hash_file(&src_path.join(name)).unwrap(),
+            );
+        }
+        verify_extracted_files(
+            &extract_output_path,
+            Some(&manifest),
+            0,
+            &HashMap::new(),
+            false,
+        )
+        .unwrap();
+        let corrupted_path = extract_output_path
+            .join(src_name)
+            .join("dir")
+            .join("file2.txt");
+        fs::write(&corrupted_path, "y".repeat(5000)).unwrap();
+        assert!(matches!(
+            verify_extracted_files(&extract_output_path, Some(&manifest), 0, &HashMap::new(), false),
+            Err(BackupError::FileHashMismatch { path }) if path == corrupted_path
+        ));
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_absolute_paths() {
+        let src_path = non_existent_temp_file();
+        let other_path = non_existent_temp_file();
+        let include_paths = [src_path.join("data"), other_path.join("data")];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let options = BackupOptions {
+            path_mode: PathMode::Absolute,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir_all(src_path.join("data").join("dir")).unwrap();
+            fs::create_dir_all(other_path.join("data")).unwrap();
+            fs::write(
+                src_path.join("data").join("dir").join("file1.txt"),
+                "file 1",
+            )
+            .unwrap();
+            fs::write(other_path.join("data").join("file2.txt"), "file 2").unwrap();
+        }
+
+        // Include paths with the same name no longer collide
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &options,
+        )
+        .unwrap();
+
+        // Extracting elsewhere recreates the full paths inside the output
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        let src_name = absolute_include_name(&src_path).unwrap();
+        let other_name = absolute_include_name(&other_path).unwrap();
+        assert!(src_name.is_relative());
+        assert_eq!(
+            fs::read_to_string(
+                extract_output_path
+                    .join(&src_name)
+                    .join("data")
+                    .join("dir")
+                    .join("file1.txt")
+            )
+            .unwrap(),
+            "file 1"
+        );
+        assert_eq!(
+            fs::read_to_string(
+                extract_output_path
+                    .join(&other_name)
+                    .join("data")
+                    .join("file2.txt")
+            )
+            .unwrap(),
+            "file 2"
+        );
+
+        // Restoring to the root puts everything back where it came from
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&other_path).unwrap();
+        let restored_path = extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                restore_to_root: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(restored_path, Path::new(MAIN_SEPARATOR_STR));
+        assert_eq!(
+            fs::read_to_string(src_path.join("data").join("dir").join("file1.txt")).unwrap(),
+            "file 1"
+        );
+        assert_eq!(
+            fs::read_to_string(other_path.join("data").join("file2.txt")).unwrap(),
+            "file 2"
+        );
+        assert!(!progress_file_for(env::temp_dir().join(ROOT_STAGING_NAME)).exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&other_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_growing_file() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let options = BackupOptions {
+            deterministic: true,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("growing.log"), "first line\n".repeat(10_000)).unwrap();
+            fs::write(
+                src_path.join("later.txt"),
+                "archived after the growing file",
+            )
+            .unwrap();
+        }
+
+        // Keep appending to the log file while it is backed up
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let done = Arc::clone(&done);
+            let log_path = src_path.join("growing.log");
+            thread::spawn(move || {
+                let mut log = fs::OpenOptions::new().append(true).open(log_path).unwrap();
+                while !done.load(Ordering::Relaxed) {
+                    log.write_all(b"another line\n").unwrap();
+                }
+            })
+        };
+
+        let result = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &options,
+        );
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        result.unwrap();
+
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        // Whatever was captured of the log file is a prefix of its contents,
+        // and the rest of the archive is intact
+        let log_contents = fs::read(src_path.join("growing.log")).unwrap();
+        let captured_contents = fs::read(extract_output_root.join("growing.log")).unwrap();
+        assert!(captured_contents.len() >= "first line\n".len() * 10_000);
+        assert!(log_contents.starts_with(&captured_contents));
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join("later.txt")).unwrap(),
+            "archived after the growing file"
+        );
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_one_file_system() {
+        use std::os::unix::fs::{symlink, MetadataExt};
+
+        // A symlink to a directory on another filesystem stands in for a
+        // mount point, since following it crosses filesystems the same way
+        let other_fs_path = Path::new("/dev/shm");
+        let src_path = non_existent_temp_file();
+        if !other_fs_path.is_dir()
+            || fs::metadata(other_fs_path).unwrap().dev()
+                == fs::metadata(src_path.parent().unwrap()).unwrap().dev()
+        {
+            return;
+        }
+
+        let other_fs_dir = other_fs_path.join(src_path.file_name().unwrap());
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let options = BackupOptions {
+            one_file_system: true,
+            ..Default::default()
+        };
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::create_dir(src_path.join("sub")).unwrap();
+            fs::write(src_path.join("sub").join("local.txt"), "local").unwrap();
+            fs::create_dir(&other_fs_dir).unwrap();
+            fs::write(other_fs_dir.join("remote.txt"), "remote").unwrap();
+            symlink(&other_fs_dir, src_path.join("mount")).unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &options,
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join("sub").join("local.txt")).unwrap(),
+            "local"
+        );
+        assert!(!extract_output_root.join("mount").exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&other_fs_dir).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_relativize_symlink_target() {
+        let root = Path::new("/data/photos");
+
+        assert_eq!(
+            relativize_symlink_target(
+                root,
+                Path::new("photos/2024/latest"),
+                Path::new("/data/photos/2024/june/1.jpg")
+            ),
+            Some(PathBuf::from("../2024/june/1.jpg"))
+        );
+        assert_eq!(
+            relativize_symlink_target(root, Path::new("photos/top"), Path::new("/data/photos/a")),
+            Some(PathBuf::from("a"))
+        );
+        assert_eq!(
+            relativize_symlink_target(root, Path::new("photos/top"), Path::new("/data/photos")),
+            Some(PathBuf::from("."))
+        );
+        assert_eq!(
+            relativize_symlink_target(root, Path::new("photos/top"), Path::new("/data/music")),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_symlink_target() {
+        use std::os::unix::fs::symlink;
+
+        let src_path = non_existent_temp_file();
+        let outside_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::create_dir(src_path.join("sub")).unwrap();
+            fs::write(src_path.join("inner.txt"), "inner").unwrap();
+            fs::write(&outside_path, "outside").unwrap();
+            let canonical_src_path = fs::canonicalize(&src_path).unwrap();
+            symlink(
+                canonical_src_path.join("inner.txt"),
+                src_path.join("sub").join("absolute"),
+            )
+            .unwrap();
+            symlink("inner.txt", src_path.join("relative")).unwrap();
+            symlink(&outside_path, src_path.join("outside")).unwrap();
+        }
+
+        let backup_and_extract = |symlink_target| {
+            let backup_output_path = non_existent_temp_file();
+            let extract_output_path = non_existent_temp_file();
+
+            backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    symlink_target,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            fs::remove_file(&backup_output_path).unwrap();
+
+            extract_output_path
+        };
+
+        // Links are followed by default
+        let extract_output_path = backup_and_extract(SymlinkTarget::Follow);
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        assert!(!extract_output_root.join("relative").is_symlink());
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join("relative")).unwrap(),
+            "inner"
+        );
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        // Targets are stored as they are
+        let extract_output_path = backup_and_extract(SymlinkTarget::Verbatim);
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        assert_eq!(
+            fs::read_link(extract_output_root.join("sub").join("absolute")).unwrap(),
+            fs::canonicalize(&src_path).unwrap().join("inner.txt")
+        );
+        assert_eq!(
+            fs::read_link(extract_output_root.join("relative")).unwrap(),
+            Path::new("inner.txt")
+        );
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        // Absolute targets within the include path are made relative
+        let extract_output_path = backup_and_extract(SymlinkTarget::RelativizeWithinRoot);
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        assert_eq!(
+            fs::read_link(extract_output_root.join("sub").join("absolute")).unwrap(),
+            Path::new("../inner.txt")
+        );
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join("sub").join("absolute")).unwrap(),
+            "inner"
+        );
+        assert_eq!(
+            fs::read_link(extract_output_root.join("relative")).unwrap(),
+            Path::new("inner.txt")
+        );
+        assert_eq!(
+            fs::read_link(extract_output_root.join("outside")).unwrap(),
+            outside_path
+        );
+        fs::remove_dir_all(&extract_output_path).unwrap();
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&outside_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("original.txt"), "linked contents").unwrap();
+            fs::hard_link(src_path.join("original.txt"), src_path.join("link.txt")).unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+        let original_metadata = fs::metadata(extract_output_root.join("original.txt")).unwrap();
+        let link_metadata = fs::metadata(extract_output_root.join("link.txt")).unwrap();
+        assert_eq!(original_metadata.ino(), link_metadata.ino());
+        assert_eq!(original_metadata.nlink(), 2);
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_xattrs() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join("sub")).unwrap();
+            fs::write(src_path.join("sub").join("file.txt"), "Hello, xattrs!").unwrap();
+        }
+
+        // Not every filesystem supports extended attributes
+        if xattr::set(
+            src_path.join("sub").join("file.txt"),
+            "user.comment",
+            b"file",
+        )
+        .is_err()
+        {
+            fs::remove_dir_all(&src_path).unwrap();
+            return;
+        }
+        xattr::set(src_path.join("sub"), "user.comment", b"directory").unwrap();
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                preserve_xattrs: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Attributes are only restored when requested
+        for preserve_xattrs in [false, true] {
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions {
+                    preserve_xattrs,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+            let file_comment = xattr::get(
+                extract_output_root.join("sub").join("file.txt"),
+                "user.comment",
+            )
+            .unwrap();
+            let dir_comment = xattr::get(extract_output_root.join("sub"), "user.comment").unwrap();
+
+            if preserve_xattrs {
+                assert_eq!(file_comment.as_deref(), Some(b"file".as_slice()));
+                assert_eq!(dir_comment.as_deref(), Some(b"directory".as_slice()));
+            } else {
+                assert_eq!(file_comment, None);
+                assert_eq!(dir_comment, None);
+            }
+
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_btime() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join("sub")).unwrap();
+            fs::write(src_path.join("sub").join("file.txt"), "Hello, btime!").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                preserve_btime: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Restoring creation times never fails, even where it is unsupported
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions {
+                preserve_btime: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        verify_identical_trees(&src_path, &extract_output_root, false, &[], &[]).unwrap();
+
+        #[cfg(any(windows, target_os = "macos"))]
+        {
+            let src_file = src_path.join("sub").join("file.txt");
+            let extracted_file = extract_output_root.join("sub").join("file.txt");
+            assert_eq!(
+                fs::metadata(extracted_file).unwrap().created().unwrap(),
+                fs::metadata(src_file).unwrap().created().unwrap()
+            );
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_deeply_nested() {
+        // Absolute paths are limited by `PATH_MAX`, so rather than building
+        // an extremely deep tree, the backup is run on a thread with a small
+        // stack, which a recursive walk of this depth would overflow
+        const DEPTH: usize = 500;
+        const STACK_SIZE: usize = 256 * 1024;
+
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let nested_path = (0..DEPTH).fold(PathBuf::new(), |path, _| path.join("d"));
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join(&nested_path)).unwrap();
+            fs::write(src_path.join(&nested_path).join("leaf.txt"), "deep").unwrap();
+        }
+
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn({
+                let src_path = src_path.clone();
+                let backup_output_path = backup_output_path.clone();
+                move || {
+                    backup(
+                        &[&src_path],
+                        &[],
+                        &backup_output_path,
+                        password,
+                        chunk_size,
+                        pool_size,
+                        &BackupOptions::default(),
+                    )
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+            .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join(&nested_path).join("leaf.txt")).unwrap(),
+            "deep"
+        );
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_backup_root_directory_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let mtime = FileTime::from_unix_time(1_000_000_000, 0);
         let password = "password123";
         let chunk_size = 1024;
         let pool_size = 16;
 
+        {
+            fs::create_dir_all(src_path.join("subdir")).unwrap();
+            fs::write(src_path.join("subdir").join("file.txt"), "contents").unwrap();
+            filetime::set_file_mtime(src_path.join("subdir"), mtime).unwrap();
+            fs::set_permissions(&src_path, fs::Permissions::from_mode(0o700)).unwrap();
+            filetime::set_file_mtime(&src_path, mtime).unwrap();
+        }
+
         backup(
-            &include_paths,
-            &exclude_globs,
+            &[&src_path],
+            &[],
             &backup_output_path,
             password,
             chunk_size,
             pool_size,
+            &BackupOptions::default(),
         )
         .unwrap();
         extract(
@@ -401,44 +7603,446 @@ mod tests {
             &extract_output_path,
             password,
             pool_size,
+            &ExtractOptions::default(),
         )
         .unwrap();
 
+        // The include directory itself is restored with its own mode and
+        // time, as are the directories within it, even though their contents
+        // were written after them
+        let root_metadata = fs::metadata(&extract_output_root).unwrap();
+        assert_eq!(root_metadata.permissions().mode() & 0o777, 0o700);
+        assert_eq!(FileTime::from_last_modification_time(&root_metadata), mtime);
+        let subdir_metadata = fs::metadata(extract_output_root.join("subdir")).unwrap();
+        assert_eq!(
+            FileTime::from_last_modification_time(&subdir_metadata),
+            mtime
+        );
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_backup_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_path = non_existent_temp_file();
+        let src_path = temp_path.join(OsStr::from_bytes(b"src\xff"));
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let file_name = OsStr::from_bytes(b"file\xfe\xff.txt");
+        let dir_name = OsStr::from_bytes(b"dir\x80");
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir_all(src_path.join(dir_name)).unwrap();
+            fs::write(src_path.join(file_name), "not utf-8").unwrap();
+            fs::write(src_path.join(dir_name).join("inner.txt"), "inner").unwrap();
+        }
+
+        // Names that are not valid UTF-8 are archived and extracted as they
+        // are, rather than crashing the backup
+        backup(
+            &[&src_path],
+            &[],
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                index: IndexMode::Plain,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
         verify_identical_trees(
-            &root,
-            &extract_output_root,
-            false,
-            &ignore_dir_names,
-            &ignore_file_names,
+            &src_path,
+            extract_output_path.join(src_path.file_name().unwrap()),
+            true,
+            &[],
+            &[],
+        )
+        .unwrap();
+
+        // The index stores the names exactly
+        let entries = list(&backup_output_path, None).unwrap();
+        let root = Path::new(src_path.file_name().unwrap());
+        assert!(entries
+            .iter()
+            .any(|entry| entry.path == root.join(file_name)));
+        assert!(entries
+            .iter()
+            .any(|entry| entry.path == root.join(dir_name).join("inner.txt")));
+
+        // Globs still match such names, through a lossy conversion
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+        backup(
+            &[&src_path],
+            &[Pattern::new("**/dir*").unwrap()],
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        extract(
+            &backup_output_path,
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
         )
         .unwrap();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        assert!(extract_output_root.join(file_name).is_file());
+        assert!(!extract_output_root.join(dir_name).exists());
 
+        fs::remove_dir_all(&temp_path).unwrap();
         fs::remove_file(&backup_output_path).unwrap();
         fs::remove_dir_all(&extract_output_path).unwrap();
     }
 
     #[test]
-    fn test_backup_empty() {
+    fn test_backup_owner_override() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let backup_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::create_dir(src_path.join("dir")).unwrap();
+            fs::write(src_path.join("dir").join("file.txt"), "Hello, owner!").unwrap();
+        }
+
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                owner_override: Some((1234, 5678)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Every entry is recorded with the overridden owner
+        let mut backup = open_backup(&backup_output_path, Secret::Password(password)).unwrap();
+        let mut owners = Vec::new();
+        decrypt_reader(
+            &mut backup.payload,
+            backup.key,
+            pool_size,
+            backup.end_marker,
+            |reader| {
+                for entry in tar::Archive::new(reader).entries()? {
+                    let entry = entry?;
+
+                    if !entry.header().entry_type().is_pax_global_extensions() {
+                        owners.push((entry.header().uid()?, entry.header().gid()?));
+                    }
+                }
+
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(owners, [(1234, 5678); 3]);
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_list() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let name = PathBuf::from(src_path.file_name().unwrap());
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, index!").unwrap();
+            fs::create_dir(src_path.join("dir")).unwrap();
+        }
+
+        // Backups without an index cannot be listed
+        let backup_output_path = non_existent_temp_file();
+        backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            list(&backup_output_path, Some(password)).unwrap_err(),
+            BackupError::MissingIndex
+        ));
+        assert_eq!(
+            backup_info(&backup_output_path).unwrap().index,
+            IndexMode::None
+        );
+        fs::remove_file(&backup_output_path).unwrap();
+
+        for index in [IndexMode::Plain, IndexMode::Encrypted] {
+            let backup_output_path = non_existent_temp_file();
+            let extract_output_path = non_existent_temp_file();
+            backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    index,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(backup_info(&backup_output_path).unwrap().index, index);
+
+            let mut entries = list(&backup_output_path, Some(password)).unwrap();
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            assert_eq!(
+                entries,
+                [
+                    IndexEntry {
+                        path: name.clone(),
+                        kind: EntryKind::Directory,
+                        size: 0,
+                    },
+                    IndexEntry {
+                        path: name.join("dir"),
+                        kind: EntryKind::Directory,
+                        size: 0,
+                    },
+                    IndexEntry {
+                        path: name.join("file.txt"),
+                        kind: EntryKind::File,
+                        size: 13,
+                    },
+                ]
+            );
+
+            // Only a plain index can be listed without the password
+            match index {
+                IndexMode::Plain => assert_eq!(list(&backup_output_path, None).unwrap().len(), 3),
+                _ => assert!(matches!(
+                    list(&backup_output_path, None).unwrap_err(),
+                    BackupError::PasswordRequired
+                )),
+            }
+            assert!(matches!(
+                list(&backup_output_path, Some("password124")).unwrap_err(),
+                BackupError::IncorrectPassword
+            ));
+
+            // The index is covered by the checksum, and does not get in the
+            // way of extraction
+            verify_checksum(&backup_output_path).unwrap();
+            extract(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                fs::read_to_string(extract_output_path.join(&name).join("file.txt")).unwrap(),
+                "Hello, index!"
+            );
+
+            fs::remove_file(&backup_output_path).unwrap();
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        }
+
+        fs::remove_dir_all(&src_path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_matching() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let exclude_globs = [];
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let name = PathBuf::from(src_path.file_name().unwrap());
+        let mut rng = StdRng::seed_from_u64(1159);
+        let mut large = vec![0; chunk_size * 3 + 17];
+        rng.fill_bytes(&mut large);
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("large.bin"), &large).unwrap();
+            fs::write(src_path.join("small.txt"), "Hello, matching!").unwrap();
+            fs::create_dir(src_path.join("dir")).unwrap();
+            fs::write(src_path.join("dir").join("nested.txt"), "Nested").unwrap();
+        }
+
+        let patterns = [
+            Pattern::new("*/large.bin").unwrap(),
+            Pattern::new("*/dir").unwrap(),
+            Pattern::new("*/dir/*").unwrap(),
+        ];
+
+        for indexed in [true, false] {
+            let backup_output_path = non_existent_temp_file();
+            let extract_output_path = non_existent_temp_file();
+            backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    indexed,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            // An encrypted index is stored to hold the entry offsets
+            let expected_index = if indexed {
+                IndexMode::Encrypted
+            } else {
+                IndexMode::None
+            };
+            assert_eq!(
+                backup_info(&backup_output_path).unwrap().index,
+                expected_index
+            );
+
+            let extracted = extract_matching(
+                &backup_output_path,
+                &extract_output_path,
+                password,
+                &patterns,
+                pool_size,
+                &ExtractOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(extracted, 3);
+            assert_eq!(
+                fs::read(extract_output_path.join(&name).join("large.bin")).unwrap(),
+                large
+            );
+            assert_eq!(
+                fs::read_to_string(extract_output_path.join(&name).join("dir/nested.txt")).unwrap(),
+                "Nested"
+            );
+            assert!(!extract_output_path.join(&name).join("small.txt").exists());
+
+            assert!(matches!(
+                extract_matching(
+                    &backup_output_path,
+                    &extract_output_path,
+                    "password124",
+                    &patterns,
+                    pool_size,
+                    &ExtractOptions::default(),
+                )
+                .unwrap_err(),
+                BackupError::IncorrectPassword
+            ));
+
+            fs::remove_file(&backup_output_path).unwrap();
+            fs::remove_dir_all(&extract_output_path).unwrap();
+        }
+
+        // Indexed backups cannot be compressed
+        let backup_output_path = non_existent_temp_file();
+        assert!(matches!(
+            backup(
+                &include_paths,
+                &exclude_globs,
+                &backup_output_path,
+                password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    indexed: true,
+                    compression_level: Some(3),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err(),
+            BackupError::IncompatibleOptions(_)
+        ));
+        assert!(!backup_output_path.exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_cancel() {
         let src_path = non_existent_temp_file();
-        let empty_file = src_path.join("empty_file.txt");
-        let empty_dir = src_path.join("empty_dir");
         let include_paths = [&src_path];
         let exclude_globs = [];
-        let ignore_dir_names = [];
-        let ignore_file_names = [];
         let backup_output_path = non_existent_temp_file();
         let extract_output_path = non_existent_temp_file();
-        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
         let password = "password123";
         let chunk_size = 1024;
         let pool_size = 16;
 
         {
             fs::create_dir(&src_path).unwrap();
-            File::create(&empty_file).unwrap();
-            fs::create_dir(&empty_dir).unwrap();
+            fs::write(src_path.join("file.txt"), "Hello, cancellation!").unwrap();
         }
 
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        // A cancelled backup leaves no output file behind
+        let err = backup(
+            &include_paths,
+            &exclude_globs,
+            &backup_output_path,
+            password,
+            chunk_size,
+            pool_size,
+            &BackupOptions {
+                cancel: Some(cancel.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, BackupError::Cancelled));
+        assert!(!backup_output_path.exists());
+
+        // A cancelled extraction leaves neither the decrypted archive nor the
+        // output directory behind
         backup(
             &include_paths,
             &exclude_globs,
@@ -446,22 +8050,94 @@ mod tests {
             password,
             chunk_size,
             pool_size,
+            &BackupOptions::default(),
         )
         .unwrap();
-        extract(
+        let err = extract(
             &backup_output_path,
             &extract_output_path,
             password,
             pool_size,
+            &ExtractOptions {
+                cancel: Some(cancel),
+                ..Default::default()
+            },
         )
-        .unwrap();
+        .unwrap_err();
+        assert!(matches!(err, BackupError::Cancelled));
+        assert!(!extract_output_path.exists());
+        assert!(!tmp_file_for(&extract_output_path).exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_pause() {
+        let src_path = non_existent_temp_file();
+        let include_paths = [&src_path];
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 2;
+        let pause = PauseToken::new();
+        let paused_for = std::time::Duration::from_millis(200);
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.bin"), vec![7; chunk_size * 64]).unwrap();
+        }
+
+        // Operations started while paused wait, with the pool running, until
+        // they are resumed
+        pause.pause();
+        std::thread::scope(|s| {
+            let handle = s.spawn(|| {
+                backup(
+                    &include_paths,
+                    &[],
+                    &backup_output_path,
+                    password,
+                    chunk_size,
+                    pool_size,
+                    &BackupOptions {
+                        pause: Some(pause.clone()),
+                        ..Default::default()
+                    },
+                )
+            });
+            std::thread::sleep(paused_for);
+            assert!(!handle.is_finished());
+            pause.resume();
+            handle.join().unwrap().unwrap();
+        });
 
+        pause.pause();
+        std::thread::scope(|s| {
+            let handle = s.spawn(|| {
+                extract(
+                    &backup_output_path,
+                    &extract_output_path,
+                    password,
+                    pool_size,
+                    &ExtractOptions {
+                        pause: Some(pause.clone()),
+                        ..Default::default()
+                    },
+                )
+            });
+            std::thread::sleep(paused_for);
+            assert!(!handle.is_finished());
+            pause.resume();
+            handle.join().unwrap().unwrap();
+        });
         verify_identical_trees(
             &src_path,
-            &extract_output_root,
-            false,
-            &ignore_dir_names,
-            &ignore_file_names,
+            extract_output_path.join(src_path.file_name().unwrap()),
+            true,
+            &[],
+            &[],
         )
         .unwrap();
 
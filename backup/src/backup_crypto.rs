@@ -1,12 +1,16 @@
 //! Utilities for applying cryptography to a backup.
 
 use crate::crypto::*;
+use crate::header::*;
+use crate::memory::{MemoryBudget, MemoryReservation};
 use crate::pool::*;
 use crate::types::*;
-use crate::util::*;
 use std::fs::File;
-use std::io::{self, Read, Seek, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
 use std::thread::scope;
 
 /// The length of the size portion of each chunk of data.
@@ -30,9 +34,117 @@ pub fn decode_section_size(encoded_size: &[u8; LEN_SIZE]) -> usize {
         .fold(0, |size, val| (size << 8) + usize::from(*val))
 }
 
+/// The key the chunks of a payload are encrypted with.
+///
+/// AES-GCM can only safely encrypt a limited number of messages with one
+/// key, which a backup of millions of chunks approaches. When chunk keys are
+/// enabled, each chunk is instead encrypted with a key derived from the data
+/// key and the chunk's index, so that no key encrypts more than one chunk.
+/// Otherwise, every chunk is encrypted with the data key itself.
+#[derive(Clone, Copy)]
+pub struct PayloadKey {
+    /// The data key.
+    key: [u8; AES_KEY_SIZE],
+    /// Whether each chunk is encrypted with a key of its own.
+    chunk_keys: bool,
+}
+
+impl PayloadKey {
+    /// Creates a payload key from the data key, deriving a key for each chunk
+    /// if `chunk_keys` is set.
+    pub const fn new(key: [u8; AES_KEY_SIZE], chunk_keys: bool) -> Self {
+        Self { key, chunk_keys }
+    }
+
+    /// Returns the key the chunk at the given index is encrypted with.
+    pub fn chunk_key(&self, chunk_index: u64) -> [u8; AES_KEY_SIZE] {
+        if self.chunk_keys {
+            derived_chunk_key(self.key, chunk_index)
+        } else {
+            self.key
+        }
+    }
+}
+
+/// A backup whose data key has been unwrapped, ready for its payload to be
+/// decrypted.
+pub struct OpenedBackup<R> {
+    /// A reader positioned at the start of the encrypted payload.
+    pub payload: R,
+    /// The key the payload is encrypted with.
+    pub key: PayloadKey,
+    /// Whether the archive was compressed before it was encrypted.
+    pub compressed: bool,
+    /// Whether the payload ends with an empty section marking its end, so
+    /// that a payload missing it can be recognized as truncated.
+    pub end_marker: bool,
+}
+
+impl<R> OpenedBackup<R> {
+    /// Unwraps the data key from a backup's header with the secret.
+    fn new(payload: R, header: Option<BackupHeader>, secret: Secret) -> BackupResult<Self> {
+        let (key, compressed, end_marker) = match header {
+            Some(header) => (
+                PayloadKey::new(header.unwrap_key(secret)?, header.chunk_keys),
+                header.compressed,
+                header.has_checksum(),
+            ),
+            // Backups without a header are encrypted directly with the
+            // password derived key
+            None => match secret {
+                Secret::Password(password) => (
+                    PayloadKey::new(password_to_key(password), false),
+                    false,
+                    false,
+                ),
+                Secret::Key(key) => (PayloadKey::new(*key, false), false, false),
+                // Backups without a header have no recovery key slot
+                Secret::RecoveryKey(_) => return Err(BackupError::IncorrectPassword),
+            },
+        };
+
+        Ok(Self {
+            payload,
+            key,
+            compressed,
+            end_marker,
+        })
+    }
+}
+
+/// Opens a backup file and unwraps its data key with the secret. The
+/// returned file is positioned at the start of the encrypted payload.
+pub fn open_backup(path: impl AsRef<Path>, secret: Secret) -> BackupResult<OpenedBackup<File>> {
+    let mut file = File::open(path)?;
+    let header = BackupHeader::read(&mut file)?;
+
+    OpenedBackup::new(file, header, secret)
+}
+
+/// A reader over the encrypted payload of a backup stream, starting with any
+/// bytes consumed while looking for a header.
+pub type PayloadReader<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Opens a backup stream and unwraps its data key with the secret, without
+/// seeking. The returned reader yields the encrypted payload.
+pub fn open_backup_stream<R: Read>(
+    mut src: R,
+    secret: Secret,
+) -> BackupResult<OpenedBackup<PayloadReader<R>>> {
+    let (header, payload_start) = BackupHeader::read_unseekable(&mut src)?;
+
+    OpenedBackup::new(io::Cursor::new(payload_start).chain(src), header, secret)
+}
+
 /// Gets the chunk size of a given backup file.
-pub fn get_chunk_size(path: impl AsRef<Path>) -> io::Result<usize> {
+pub fn get_chunk_size(path: impl AsRef<Path>) -> BackupResult<usize> {
     let mut file = File::open(path)?;
+
+    if let Some(header) = BackupHeader::read(&mut file)? {
+        return usize::try_from(header.chunk_size)
+            .map_err(|_| BackupError::InvalidHeader("chunk size is too large".to_owned()));
+    }
+
     let mut size_buffer = [0u8; LEN_SIZE];
 
     let n = file.read(&mut size_buffer)?;
@@ -41,39 +153,55 @@ pub fn get_chunk_size(path: impl AsRef<Path>) -> io::Result<usize> {
         return Err(io::Error::new(
             io::ErrorKind::UnexpectedEof,
             "not enough bytes to read from backup file",
-        ));
+        )
+        .into());
     }
 
     Ok(decode_section_size(&size_buffer))
 }
 
-/// Reads a section of data from a file.
-fn read_section(file: &mut File) -> io::Result<Option<Vec<u8>>> {
+/// Reads a section of data from a file. The file may return fewer bytes than
+/// requested from each read, as network streams do.
+pub fn read_section(file: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
     let mut size_buffer = [0u8; LEN_SIZE];
 
-    let n = file.read(&mut size_buffer)?;
+    let n = loop {
+        match file.read(&mut size_buffer) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            result => break result?,
+        }
+    };
 
     if n == 0 {
         return Ok(None);
     }
 
-    let decoded_size = decode_section_size(&size_buffer);
+    let decoded_size = read_exact_or_truncated(file, &mut size_buffer[n..])
+        .map(|()| decode_section_size(&size_buffer))?;
     let mut buffer = vec![0u8; decoded_size];
 
-    let n = file.read(&mut buffer)?;
-
-    if n != decoded_size {
-        return Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "read fewer bytes from file than expected",
-        ));
-    }
+    read_exact_or_truncated(file, &mut buffer)?;
 
     Ok(Some(buffer))
 }
 
+/// Fills a buffer from a file, reporting a truncated section if the file ends
+/// first.
+fn read_exact_or_truncated(file: &mut impl Read, buf: &mut [u8]) -> io::Result<()> {
+    file.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read fewer bytes from file than expected",
+            )
+        } else {
+            e
+        }
+    })
+}
+
 /// Writes a section of data to a file.
-fn write_section(file: &mut File, data: &[u8]) -> io::Result<()> {
+pub fn write_section(file: &mut impl Write, data: &[u8]) -> io::Result<()> {
     let encoded_size = encode_section_size(data.len());
 
     file.write_all(&encoded_size)?;
@@ -82,29 +210,291 @@ fn write_section(file: &mut File, data: &[u8]) -> io::Result<()> {
     Ok(())
 }
 
+/// A writer that computes a checksum of the data written through it.
+pub struct ChecksumWriter<W> {
+    /// The underlying writer.
+    inner: W,
+    /// The running hash of the data written.
+    hasher: ChecksumHasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    /// Wraps a writer to compute a checksum of the data written to it with
+    /// the given algorithm.
+    pub fn new(inner: W, algorithm: ChecksumAlgorithm) -> BackupResult<Self> {
+        Ok(Self {
+            inner,
+            hasher: algorithm.hasher()?,
+        })
+    }
+
+    /// Returns the underlying writer and the checksum of the data written.
+    pub fn finish(self) -> (W, [u8; CHECKSUM_SIZE]) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Ends a payload written through a checksum writer, by writing an empty
+/// section to mark the end of the payload, followed by the checksum of
+/// everything written through it. If an index is given, it is written between
+/// the two as a section of its own, followed by the length of that section.
+pub fn write_checksum_trailer<W: Write>(
+    mut dest: ChecksumWriter<W>,
+    index: Option<&[u8]>,
+) -> io::Result<W> {
+    write_section(&mut dest, &[])?;
+
+    if let Some(index) = index {
+        write_section(&mut dest, index)?;
+        dest.write_all(&((index.len() + LEN_SIZE) as u64).to_be_bytes())?;
+    }
+
+    let (mut dest, checksum) = dest.finish();
+    dest.write_all(&checksum)?;
+    dest.flush()?;
+
+    Ok(dest)
+}
+
+/// The result of a chunk processed by a task pool, along with the memory
+/// reserved for it, if the pipeline is limited by a [`MemoryBudget`]. The
+/// reservation is held until the chunk has been consumed.
+type ChunkResult = (BackupResult<Vec<u8>>, Option<MemoryReservation>);
+
+/// A writer that splits the data written to it into chunks and sends each
+/// chunk to be encrypted by a task pool.
+pub struct ChunkEncryptor {
+    /// The chunk currently being filled.
+    buffer: Vec<u8>,
+    /// The size of each chunk.
+    chunk_size: usize,
+    /// The encryption key.
+    key: PayloadKey,
+    /// How the nonce for each chunk is chosen.
+    nonce_mode: NonceMode,
+    /// The index of the next chunk to be sent.
+    chunk_index: u64,
+    /// The sending side of the task pool performing the encryption.
+    task_request: TaskRequestSender<ChunkResult>,
+    /// The budget each chunk reserves its memory from before it is sent, if
+    /// memory use is limited.
+    budget: Option<Arc<MemoryBudget>>,
+    /// The total number of bytes written.
+    bytes_written: u64,
+}
+
+impl ChunkEncryptor {
+    /// Creates a new chunk encryptor that sends chunks through the given task
+    /// pool.
+    fn new(
+        key: PayloadKey,
+        chunk_size: usize,
+        nonce_mode: NonceMode,
+        task_request: TaskRequestSender<ChunkResult>,
+        budget: Option<Arc<MemoryBudget>>,
+    ) -> Self {
+        Self {
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size,
+            key,
+            nonce_mode,
+            chunk_index: 0,
+            task_request,
+            budget,
+            bytes_written: 0,
+        }
+    }
+
+    /// Sends the current chunk to the task pool to be encrypted.
+    fn send_chunk(&mut self) -> io::Result<()> {
+        let chunk = mem::replace(&mut self.buffer, Vec::with_capacity(self.chunk_size));
+        let key = self.key;
+        let nonce_mode = self.nonce_mode;
+        let chunk_index = self.chunk_index;
+        self.chunk_index += 1;
+        let reservation = self
+            .budget
+            .as_ref()
+            .map(|budget| budget.reserve(chunk.len()));
+
+        self.task_request
+            .send(move || {
+                let key = key.chunk_key(chunk_index);
+                let encrypted_data = match nonce_mode {
+                    NonceMode::Random => aes_encrypt(key, &chunk),
+                    NonceMode::Derived => {
+                        let nonce = derived_nonce(key, &chunk_index.to_be_bytes(), &chunk);
+                        aes_encrypt_with_nonce(key, nonce, &chunk, &[])
+                    }
+                };
+
+                (encrypted_data, reservation)
+            })
+            .map_err(|_| {
+                // The receiver has closed prematurely, meaning it most likely
+                // encountered an error.
+                io::Error::new(io::ErrorKind::BrokenPipe, "encryption pool closed")
+            })
+    }
+
+    /// Sends the final partial chunk, if any, and closes the task pool.
+    /// Returns the total number of bytes written.
+    fn finish(mut self) -> io::Result<u64> {
+        if !self.buffer.is_empty() {
+            self.send_chunk()?;
+        }
+
+        self.task_request.close();
+
+        Ok(self.bytes_written)
+    }
+}
+
+impl Write for ChunkEncryptor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        self.bytes_written += n as u64;
+
+        if self.buffer.len() == self.chunk_size {
+            self.send_chunk()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Partial chunks are only sent once the stream is finished, so that
+        // every chunk but the last is exactly `chunk_size` bytes.
+        Ok(())
+    }
+}
+
+/// Encrypts a stream of data in chunks as it is produced. `produce` is called
+/// with a writer, and everything it writes is encrypted by a pool of workers
+/// and written to `dest` as it becomes available. If `memory_limit` is set,
+/// fewer chunks are kept in flight when needed to stay under it. Returns the
+/// number of unencrypted bytes written by `produce`.
+pub fn encrypt_stream<W, F>(
+    dest: &mut W,
+    key: PayloadKey,
+    chunk_size: usize,
+    pool_size: u8,
+    memory_limit: Option<usize>,
+    nonce_mode: NonceMode,
+    produce: F,
+) -> BackupResult<u64>
+where
+    W: Write + Send,
+    F: FnOnce(&mut ChunkEncryptor) -> BackupResult<()>,
+{
+    let (task_request, task_response) = task_channel::<ChunkResult>(pool_size.into());
+
+    scope(|s| {
+        let write_handle = s.spawn(move || {
+            while let Some((encrypted_data, _reservation)) = task_response.recv() {
+                write_section(dest, &encrypted_data?)?;
+            }
+
+            dest.flush()?;
+
+            BackupResult::Ok(())
+        });
+
+        let budget = memory_limit.map(MemoryBudget::new);
+        let mut encryptor = ChunkEncryptor::new(key, chunk_size, nonce_mode, task_request, budget);
+        let produce_result =
+            produce(&mut encryptor).and_then(|()| encryptor.finish().map_err(BackupError::from));
+
+        // If writing failed, the pool will have closed and the producer will
+        // have failed as a consequence, so the write error takes precedence.
+        write_handle.join().unwrap()?;
+        produce_result
+    })
+}
+
 /// Encrypts a file in chunks.
+#[cfg(test)]
 fn encrypt_file(
     src: &mut File,
     dest: &mut File,
-    key: [u8; AES_KEY_SIZE],
+    key: PayloadKey,
     chunk_size: usize,
     pool_size: u8,
+    memory_limit: Option<usize>,
 ) -> BackupResult<()> {
-    let (task_request, task_response) = task_channel(pool_size.into());
+    encrypt_stream(
+        dest,
+        key,
+        chunk_size,
+        pool_size,
+        memory_limit,
+        NonceMode::Random,
+        |encryptor| {
+            io::copy(src, encryptor)?;
+            Ok(())
+        },
+    )?;
+
+    dest.rewind()?;
+
+    Ok(())
+}
+
+/// Decrypts a stream of sections from `src` in chunks, passing each
+/// decrypted chunk to `consume` in order. An empty section marks the end of
+/// the payload, and anything following it is left unread. If `end_marker` is
+/// set, the stream must contain that section, and ending without it is
+/// reported as truncation. If `memory_limit` is set, fewer chunks are kept in
+/// flight when needed to stay under it. Returns the number of decrypted
+/// bytes.
+pub fn decrypt_stream<F>(
+    src: &mut (impl Read + Send),
+    key: PayloadKey,
+    pool_size: u8,
+    memory_limit: Option<usize>,
+    end_marker: bool,
+    mut consume: F,
+) -> BackupResult<u64>
+where
+    F: FnMut(Vec<u8>) -> BackupResult<()> + Send,
+{
+    let (task_request, task_response) = task_channel::<ChunkResult>(pool_size.into());
+    let budget = memory_limit.map(MemoryBudget::new);
 
     scope(|s| {
         let read_handle = s.spawn(move || {
-            loop {
-                let mut buffer = vec![0u8; chunk_size];
+            for chunk_index in 0.. {
+                let Some(data) = read_section(src)? else {
+                    // A stream cut off between sections would otherwise look
+                    // complete
+                    if end_marker {
+                        return Err(BackupError::TruncatedBackup);
+                    }
 
-                let n = src.read(&mut buffer)?;
+                    break;
+                };
 
-                if n == 0 {
+                if data.is_empty() {
                     break;
                 }
 
+                let reservation = budget.as_ref().map(|budget| budget.reserve(data.len()));
+
                 if task_request
-                    .send(move || aes_encrypt(key, &buffer[..n]))
+                    .send(move || (aes_decrypt(key.chunk_key(chunk_index), &data), reservation))
                     .is_err()
                 {
                     // The receiver has closed prematurely, meaning it most
@@ -116,108 +506,231 @@ fn encrypt_file(
             BackupResult::Ok(())
         });
 
-        let write_handle = s.spawn(|| {
-            // `task_response` must be explicitly moved into this closure, but
-            // the closure cannot be a move closure, as that will move `dest`
-            // into it, which will cause problems below.
-            let task_response = task_response;
+        let consume_handle = s.spawn(move || {
+            let mut bytes_decrypted = 0;
 
-            while let Some(encrypted_data) = task_response.recv() {
-                write_section(dest, &encrypted_data?)?;
+            while let Some((decrypted_data, _reservation)) = task_response.recv() {
+                let decrypted_data = decrypted_data?;
+                bytes_decrypted += decrypted_data.len() as u64;
+                consume(decrypted_data)?;
             }
 
-            BackupResult::Ok(())
+            BackupResult::Ok(bytes_decrypted)
         });
 
         read_handle.join().unwrap()?;
-        write_handle.join().unwrap()?;
-        BackupResult::Ok(())
-    })?;
+        consume_handle.join().unwrap()
+    })
+}
 
-    dest.rewind()?;
-    dest.flush()?;
+/// A reader over the chunks produced by `decrypt_reader`.
+pub struct ChunkReader {
+    /// The receiving side of the decrypted chunks.
+    chunks: Receiver<Vec<u8>>,
+    /// The chunk currently being read.
+    chunk: Vec<u8>,
+    /// The position within the current chunk.
+    pos: usize,
+}
 
-    Ok(())
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // The sending side has closed, so the stream is finished.
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
 }
 
-/// Decrypts a file in chunks.
-fn decrypt_file(
-    src: &mut File,
-    dest: &mut File,
-    key: [u8; AES_KEY_SIZE],
+/// Decrypts a stream of sections from `src` in chunks, providing the
+/// decrypted data to `consume` as a reader. Anything `consume` leaves unread
+/// is still decrypted and discarded, so that the whole stream is always
+/// authenticated. Returns the number of decrypted bytes.
+pub fn decrypt_reader<F>(
+    src: &mut (impl Read + Send),
+    key: PayloadKey,
     pool_size: u8,
-) -> BackupResult<()> {
-    let (task_request, task_response) = task_channel(pool_size.into());
+    end_marker: bool,
+    consume: F,
+) -> BackupResult<u64>
+where
+    F: FnOnce(&mut ChunkReader) -> BackupResult<()>,
+{
+    let (chunk_sender, chunks) = sync_channel::<Vec<u8>>(pool_size.into());
 
     scope(|s| {
-        let read_handle = s.spawn(move || {
-            loop {
-                let Some(data) = read_section(src)? else {
-                    break;
-                };
-
-                if task_request.send(move || aes_decrypt(key, &data)).is_err() {
-                    // The receiver has closed prematurely, meaning it most
+        let decrypt_handle = s.spawn(move || {
+            decrypt_stream(src, key, pool_size, None, end_marker, |chunk| {
+                chunk_sender.send(chunk).map_err(|_| {
+                    // The reader has been dropped, meaning the consumer most
                     // likely encountered an error.
-                    break;
-                }
+                    io::Error::new(io::ErrorKind::BrokenPipe, "decryption reader closed").into()
+                })
+            })
+        });
+
+        let mut reader = ChunkReader {
+            chunks,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+        let consume_result = consume(&mut reader)
+            .and_then(|()| Ok(io::copy(&mut reader, &mut io::sink()).map(|_| ())?));
+        drop(reader);
+
+        // A decryption error will also have caused the consumer to see a
+        // truncated stream, so it takes precedence, unless decryption only
+        // failed because the consumer stopped reading.
+        match decrypt_handle.join().unwrap() {
+            Err(BackupError::IoError(e))
+                if e.kind() == io::ErrorKind::BrokenPipe && consume_result.is_err() =>
+            {
+                consume_result.map(|()| 0)
             }
+            decrypt_result => decrypt_result.and_then(|n| consume_result.map(|()| n)),
+        }
+    })
+}
 
-            BackupResult::Ok(())
-        });
+/// A reader over the decrypted payload of a backup from a given offset into
+/// the archive onwards, which decrypts only the chunks it reads. Every chunk
+/// but the last holds exactly the chunk size, so the chunk containing the
+/// offset can be found without reading the ones before it. The payload must
+/// not be compressed, since offsets are into the uncompressed archive.
+pub struct OffsetReader<'a, R> {
+    /// The backup, positioned at the next section to decrypt.
+    src: &'a mut R,
+    /// The key the payload is encrypted with.
+    key: PayloadKey,
+    /// The index of the next chunk to decrypt.
+    chunk_index: u64,
+    /// The chunk currently being read.
+    chunk: Vec<u8>,
+    /// The position within the current chunk.
+    pos: usize,
+}
 
-        let write_handle = s.spawn(|| {
-            // `task_response` must be explicitly moved into this closure, but
-            // the closure cannot be a move closure, as that will move `dest`
-            // into it, which will cause problems below.
-            let task_response = task_response;
+impl<'a, R: Read + Seek> OffsetReader<'a, R> {
+    /// Creates a reader starting at `offset` into the archive, in a payload
+    /// that begins at `payload_offset` into the backup.
+    pub fn new(
+        src: &'a mut R,
+        payload_offset: u64,
+        chunk_size: u64,
+        key: PayloadKey,
+        offset: u64,
+    ) -> BackupResult<Self> {
+        let section_size = (LEN_SIZE + AES_NONCE_SIZE + AES_TAG_SIZE) as u64 + chunk_size;
+        let chunk_index = offset / chunk_size;
+        let section_offset = chunk_index
+            .checked_mul(section_size)
+            .and_then(|offset| offset.checked_add(payload_offset))
+            .ok_or(BackupError::TruncatedBackup)?;
+        src.seek(SeekFrom::Start(section_offset))?;
+
+        let mut reader = Self {
+            src,
+            key,
+            chunk_index,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+
+        // Skip to the offset within the first chunk
+        if reader.next_chunk()? {
+            reader.pos = usize::try_from(offset % chunk_size)
+                .unwrap()
+                .min(reader.chunk.len());
+        }
+
+        Ok(reader)
+    }
 
-            while let Some(decrypted_data) = task_response.recv() {
-                dest.write_all(&decrypted_data?)?;
+    /// Decrypts the next chunk, returning whether there was one before the
+    /// end of the payload.
+    fn next_chunk(&mut self) -> BackupResult<bool> {
+        match read_section(self.src)? {
+            Some(data) if !data.is_empty() => {
+                self.chunk = aes_decrypt(self.key.chunk_key(self.chunk_index), &data)?;
+                self.chunk_index += 1;
+                self.pos = 0;
+                Ok(true)
             }
+            _ => Ok(false),
+        }
+    }
+}
 
-            BackupResult::Ok(())
-        });
-
-        read_handle.join().unwrap()?;
-        write_handle.join().unwrap()?;
-        BackupResult::Ok(())
-    })?;
+impl<R: Read + Seek> Read for OffsetReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if !self.next_chunk().map_err(io::Error::other)? {
+                return Ok(0);
+            }
+        }
 
-    dest.rewind()?;
-    dest.flush()?;
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
 
-    Ok(())
+        Ok(n)
+    }
 }
 
-/// Encrypts a backup file in chunks.
-pub fn encrypt_backup(
-    src_path: impl AsRef<Path>,
-    dest_path: impl AsRef<Path>,
-    key: [u8; AES_KEY_SIZE],
-    chunk_size: usize,
+/// Decrypts a file in chunks.
+fn decrypt_file(
+    src: &mut (impl Read + Send),
+    dest: &mut File,
+    key: PayloadKey,
     pool_size: u8,
+    memory_limit: Option<usize>,
+    end_marker: bool,
 ) -> BackupResult<()> {
-    let mut src = File::open(src_path)?;
-    let mut dest = File::create(dest_path)?;
+    decrypt_stream(
+        src,
+        key,
+        pool_size,
+        memory_limit,
+        end_marker,
+        |decrypted_data| {
+            dest.write_all(&decrypted_data)?;
+            Ok(())
+        },
+    )?;
+
+    dest.rewind()?;
+    dest.flush()?;
 
-    encrypt_file(&mut src, &mut dest, key, chunk_size, pool_size)
+    Ok(())
 }
 
-/// Decrypts a backup file in chunks.
+/// Decrypts the payload of an opened backup file in chunks to the given
+/// destination path.
 pub fn decrypt_backup(
-    src_path: impl AsRef<Path>,
-    key: [u8; AES_KEY_SIZE],
+    src: &mut (impl Read + Send),
+    dest_path: impl AsRef<Path>,
+    key: PayloadKey,
     pool_size: u8,
-) -> BackupResult<(PathBuf, File)> {
-    let mut src = File::open(&src_path)?;
-
-    let dest_path = tmp_file_for(&src_path);
+    memory_limit: Option<usize>,
+    end_marker: bool,
+) -> BackupResult<File> {
     let mut dest = File::create_new(&dest_path)?;
 
-    decrypt_file(&mut src, &mut dest, key, pool_size)?;
+    decrypt_file(src, &mut dest, key, pool_size, memory_limit, end_marker)?;
 
-    Ok((dest_path, dest))
+    Ok(dest)
 }
 
 /// Backup crypto tests.
@@ -235,8 +748,9 @@ mod tests {
         password: &str,
         chunk_size: usize,
         pool_size: u8,
+        memory_limit: Option<usize>,
     ) -> (Vec<u8>, Vec<u8>) {
-        let key = password_to_key(password);
+        let key = PayloadKey::new(password_to_key(password), false);
 
         let mut plaintext_file = tempfile::tempfile().unwrap();
         plaintext_file.write_all(data).unwrap();
@@ -249,6 +763,7 @@ mod tests {
             key,
             chunk_size,
             pool_size,
+            memory_limit,
         )
         .unwrap();
 
@@ -262,14 +777,23 @@ mod tests {
         ciphertext_file.rewind().unwrap();
 
         let mut decrypted_file = tempfile::tempfile().unwrap();
-        decrypt_file(&mut ciphertext_file, &mut decrypted_file, key, pool_size).unwrap();
+        decrypt_file(
+            &mut ciphertext_file,
+            &mut decrypted_file,
+            key,
+            pool_size,
+            memory_limit,
+            false,
+        )
+        .unwrap();
 
         decrypted_file.rewind().unwrap();
         let mut decrypted_value = Vec::new();
         decrypted_file.read_to_end(&mut decrypted_value).unwrap();
         decrypted_file.rewind().unwrap();
+        assert_eq!(decrypted_value, plaintext_value);
 
-        (ciphertext_value, plaintext_value)
+        (ciphertext_value, decrypted_value)
     }
 
     #[test]
@@ -313,8 +837,13 @@ mod tests {
         let chunk_size = 1 << 10;
         let pool_size = 16;
 
-        let (ciphertext, plaintext) =
-            encrypt_decrypt_file(file_message.as_bytes(), password, chunk_size, pool_size);
+        let (ciphertext, plaintext) = encrypt_decrypt_file(
+            file_message.as_bytes(),
+            password,
+            chunk_size,
+            pool_size,
+            None,
+        );
         assert_ne!(&ciphertext, file_message.as_bytes());
         assert_eq!(&plaintext, file_message.as_bytes());
         assert_ne!(plaintext, ciphertext);
@@ -324,9 +853,120 @@ mod tests {
         large_data.try_fill(&mut rng).unwrap();
 
         let (ciphertext, plaintext) =
-            encrypt_decrypt_file(&large_data, password, chunk_size, pool_size);
+            encrypt_decrypt_file(&large_data, password, chunk_size, pool_size, None);
         assert_ne!(ciphertext, large_data);
         assert_eq!(plaintext, large_data);
         assert_ne!(plaintext, ciphertext);
     }
+
+    #[test]
+    fn test_file_encryption_exact_chunks() {
+        let mut rng = thread_rng();
+
+        let password = "password123";
+        let chunk_size = 1 << 10;
+        let pool_size = 4;
+
+        let mut data = vec![0u8; 2 * chunk_size];
+        data.try_fill(&mut rng).unwrap();
+
+        let (ciphertext, plaintext) =
+            encrypt_decrypt_file(&data, password, chunk_size, pool_size, None);
+        assert_eq!(plaintext, data);
+
+        // When the data fills the last chunk exactly, no empty section
+        // follows it
+        let mut sections = Vec::new();
+        let mut reader = ciphertext.as_slice();
+        while let Some(section) = read_section(&mut reader).unwrap() {
+            sections.push(section.len());
+        }
+        assert_eq!(sections, [AES_NONCE_SIZE + chunk_size + AES_TAG_SIZE; 2]);
+    }
+
+    #[test]
+    fn test_file_encryption_memory_limit() {
+        let mut rng = thread_rng();
+
+        let password = "password123";
+        let chunk_size = 1 << 10;
+        let pool_size = 16;
+
+        let mut data = vec![0u8; rand_range(1 << 16, 1 << 17)];
+        data.try_fill(&mut rng).unwrap();
+
+        // A limit below a few chunks only reduces how many are in flight
+        for memory_limit in [2 * chunk_size, chunk_size / 2] {
+            let (ciphertext, plaintext) =
+                encrypt_decrypt_file(&data, password, chunk_size, pool_size, Some(memory_limit));
+            assert_ne!(ciphertext, data);
+            assert_eq!(plaintext, data);
+        }
+    }
+
+    #[test]
+    fn test_file_encryption_chunk_keys() {
+        let mut rng = thread_rng();
+
+        let data_key = random_key();
+        let key = PayloadKey::new(data_key, true);
+        let chunk_size = 1 << 10;
+        let pool_size = 4;
+
+        let mut data = vec![0u8; 3 * chunk_size + 1];
+        data.try_fill(&mut rng).unwrap();
+
+        let mut plaintext_file = tempfile::tempfile().unwrap();
+        plaintext_file.write_all(&data).unwrap();
+        plaintext_file.rewind().unwrap();
+        let mut ciphertext_file = tempfile::tempfile().unwrap();
+        encrypt_file(
+            &mut plaintext_file,
+            &mut ciphertext_file,
+            key,
+            chunk_size,
+            pool_size,
+            None,
+        )
+        .unwrap();
+
+        // Each chunk is encrypted with its own key rather than the data key
+        let mut ciphertext = Vec::new();
+        ciphertext_file.read_to_end(&mut ciphertext).unwrap();
+        let mut reader = ciphertext.as_slice();
+        let mut chunk_index = 0;
+        while let Some(section) = read_section(&mut reader).unwrap() {
+            assert!(aes_decrypt(data_key, &section).is_err());
+            assert!(aes_decrypt(derived_chunk_key(data_key, chunk_index + 1), &section).is_err());
+            aes_decrypt(derived_chunk_key(data_key, chunk_index), &section).unwrap();
+            chunk_index += 1;
+        }
+        assert_eq!(chunk_index, 4);
+
+        let mut decrypted_file = tempfile::tempfile().unwrap();
+        decrypt_file(
+            &mut ciphertext.as_slice(),
+            &mut decrypted_file,
+            key,
+            pool_size,
+            None,
+            false,
+        )
+        .unwrap();
+        let mut decrypted = Vec::new();
+        decrypted_file.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+
+        // Decrypting with the data key alone fails
+        let mut decrypted_file = tempfile::tempfile().unwrap();
+        assert!(decrypt_file(
+            &mut ciphertext.as_slice(),
+            &mut decrypted_file,
+            PayloadKey::new(data_key, false),
+            pool_size,
+            None,
+            false,
+        )
+        .is_err());
+    }
 }
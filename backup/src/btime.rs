@@ -0,0 +1,134 @@
+//! Creation time capture and restoration.
+//!
+//! Creation times are stored in a PAX header preceding the archive entry they
+//! belong to, under the same key that libarchive uses, so that other tools
+//! can restore them too. They can be read wherever the platform reports them,
+//! including Windows, macOS and Linux with `statx`, but can only be set on
+//! Windows and macOS. Anywhere they are not supported, they are skipped and
+//! a debug message is logged.
+
+use log::debug;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The PAX header key under which the creation time is stored.
+const PAX_BTIME_KEY: &str = "LIBARCHIVE.creationtime";
+
+/// Reads the creation time of a path, as a PAX header key and value. The
+/// value is the number of seconds since the Unix epoch, with a fractional
+/// part in nanoseconds.
+pub fn read_btime(path: &Path) -> Option<(String, Vec<u8>)> {
+    let since_epoch = fs::metadata(path)
+        .and_then(|metadata| metadata.created())
+        .and_then(|created| created.duration_since(UNIX_EPOCH).map_err(io::Error::other))
+        .map_err(|e| debug!("Skipping creation time of '{}': {e}", path.display()))
+        .ok()?;
+
+    let value = format!(
+        "{}.{:09}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    );
+
+    Some((PAX_BTIME_KEY.to_owned(), value.into_bytes()))
+}
+
+/// Reads the creation time stored in the PAX header of an archive entry, if
+/// any.
+pub fn entry_btime<R: Read>(entry: &mut tar::Entry<R>) -> Option<SystemTime> {
+    let extensions = entry.pax_extensions().ok()??;
+
+    extensions
+        .filter_map(Result::ok)
+        .find(|extension| extension.key_bytes() == PAX_BTIME_KEY.as_bytes())
+        .and_then(|extension| parse_btime(extension.value().ok()?))
+}
+
+/// Parses a creation time stored as seconds since the Unix epoch, with an
+/// optional fractional part.
+fn parse_btime(value: &str) -> Option<SystemTime> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let secs = secs.parse().ok()?;
+
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    // Only the first nine digits of the fraction fit in nanoseconds
+    let fraction = &fraction[..fraction.len().min(9)];
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u32>().ok()? * 10u32.pow(9 - u32::try_from(fraction.len()).ok()?)
+    };
+
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+}
+
+/// Sets the creation time of a path. A time that cannot be set is skipped.
+#[cfg(any(windows, target_os = "macos"))]
+pub fn write_btime(path: &Path, btime: SystemTime) {
+    use std::fs::{File, FileTimes};
+
+    #[cfg(windows)]
+    use std::os::windows::fs::{FileTimesExt, OpenOptionsExt};
+
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+
+    /// The access right needed to change the times of a file.
+    #[cfg(windows)]
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x0100;
+
+    /// The flag needed to open a directory.
+    #[cfg(windows)]
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+
+    #[cfg(windows)]
+    let file = File::options()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path);
+
+    #[cfg(target_os = "macos")]
+    let file = File::open(path);
+
+    if let Err(e) = file.and_then(|file| file.set_times(FileTimes::new().set_created(btime))) {
+        debug!("Skipping creation time of '{}': {e}", path.display());
+    }
+}
+
+/// Sets the creation time of a path. A time that cannot be set is skipped.
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn write_btime(path: &Path, _btime: SystemTime) {
+    debug!(
+        "Skipping creation time of '{}': not supported on this platform",
+        path.display()
+    );
+}
+
+/// Creation time tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_btime() {
+        assert_eq!(parse_btime("0"), Some(UNIX_EPOCH));
+        assert_eq!(
+            parse_btime("1700000000.5"),
+            Some(UNIX_EPOCH + Duration::new(1_700_000_000, 500_000_000))
+        );
+        assert_eq!(
+            parse_btime("1700000000.123456789123"),
+            Some(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789))
+        );
+        assert_eq!(parse_btime(""), None);
+        assert_eq!(parse_btime("-5"), None);
+        assert_eq!(parse_btime("12.x"), None);
+        assert_eq!(parse_btime("12.+5"), None);
+        assert_eq!(parse_btime("12.ééééééééé"), None);
+    }
+}
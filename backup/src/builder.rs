@@ -0,0 +1,326 @@
+//! Builders that configure a backup or extraction one setting at a time.
+
+use crate::backup::{backup, extract};
+use crate::cancel::{CancellationToken, PauseToken};
+use crate::options::{BackupOptions, ConflictPolicy, ExtractOptions, OverwriteMode};
+use crate::progress::{Progress, ProgressHandler};
+use crate::types::{BackupResult, BackupStats};
+use glob::Pattern;
+use std::path::PathBuf;
+
+/// The chunk size a [`BackupBuilder`] uses unless another is set, 64 KiB.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 16;
+
+/// The pool size a [`BackupBuilder`] uses unless another is set.
+pub const DEFAULT_BACKUP_POOL_SIZE: u8 = 4;
+
+/// The pool size an [`ExtractBuilder`] uses unless another is set.
+pub const DEFAULT_EXTRACT_POOL_SIZE: u8 = 16;
+
+/// Configures and runs a backup.
+///
+/// This is a more convenient way to call [`backup`], whose parameters grow
+/// with every feature. Settings without a setter of their own can be given
+/// all at once with [`Self::options`].
+///
+/// ```no_run
+/// use backup::BackupBuilder;
+///
+/// let stats = BackupBuilder::new("documents.ebk", "password123")
+///     .include("documents")
+///     .compression(3)
+///     .on_progress(|progress| println!("{} bytes", progress.bytes_processed))
+///     .run()?;
+/// # Ok::<(), backup::BackupError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct BackupBuilder {
+    /// The paths to back up.
+    include_paths: Vec<PathBuf>,
+    /// The globs of paths to leave out.
+    exclude_globs: Vec<Pattern>,
+    /// The path to write the backup to.
+    output_path: PathBuf,
+    /// The password to encrypt the backup with.
+    password: String,
+    /// The size of each chunk the archive is encrypted in.
+    chunk_size: usize,
+    /// The number of workers encrypting chunks in parallel.
+    pool_size: u8,
+    /// Every other setting.
+    options: BackupOptions,
+}
+
+impl BackupBuilder {
+    /// Starts configuring a backup to the given path, encrypted with the
+    /// given password. At least one path must be included before it is run.
+    pub fn new(output_path: impl Into<PathBuf>, password: impl Into<String>) -> Self {
+        Self {
+            include_paths: Vec::new(),
+            exclude_globs: Vec::new(),
+            output_path: output_path.into(),
+            password: password.into(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            pool_size: DEFAULT_BACKUP_POOL_SIZE,
+            options: BackupOptions::default(),
+        }
+    }
+
+    /// Includes a file or directory in the backup.
+    #[must_use]
+    pub fn include(mut self, path: impl Into<PathBuf>) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Leaves out the paths that match a glob.
+    #[must_use]
+    pub fn exclude(mut self, pattern: Pattern) -> Self {
+        self.exclude_globs.push(pattern);
+        self
+    }
+
+    /// Sets the size of each chunk the archive is encrypted in.
+    #[must_use]
+    pub const fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Sets the number of workers encrypting chunks in parallel.
+    #[must_use]
+    pub const fn pool_size(mut self, pool_size: u8) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Compresses the archive at the given zstd level before it is
+    /// encrypted. See [`BackupOptions::compression_level`].
+    #[must_use]
+    pub const fn compression(mut self, level: i32) -> Self {
+        self.options.compression_level = Some(level);
+        self
+    }
+
+    /// Reports progress to the callback as the backup is written.
+    #[must_use]
+    pub fn on_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.options.progress = Some(ProgressHandler::new(callback));
+        self
+    }
+
+    /// Lets the backup be cancelled with the token.
+    #[must_use]
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = Some(token);
+        self
+    }
+
+    /// Lets the backup be paused with the token.
+    #[must_use]
+    pub fn pause(mut self, token: PauseToken) -> Self {
+        self.options.pause = Some(token);
+        self
+    }
+
+    /// Replaces every other setting. Progress, cancellation, pausing and
+    /// compression set before this are replaced too, so it is best called
+    /// first.
+    #[must_use]
+    pub fn options(mut self, options: BackupOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Runs the backup.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if validation fails, or if any operation
+    /// involved in the backup fails.
+    pub fn run(&self) -> BackupResult<BackupStats> {
+        backup(
+            &self.include_paths,
+            &self.exclude_globs,
+            &self.output_path,
+            &self.password,
+            self.chunk_size,
+            self.pool_size,
+            &self.options,
+        )
+    }
+}
+
+/// Configures and runs an extraction.
+///
+/// This is a more convenient way to call [`extract`]. Settings without a
+/// setter of their own can be given all at once with [`Self::options`].
+#[derive(Debug, Clone)]
+pub struct ExtractBuilder {
+    /// The path to the backup to extract.
+    backup_path: PathBuf,
+    /// The path to extract the backup to.
+    output_path: PathBuf,
+    /// The password the backup was encrypted with.
+    password: String,
+    /// The number of workers decrypting chunks in parallel.
+    pool_size: u8,
+    /// Every other setting.
+    options: ExtractOptions,
+}
+
+impl ExtractBuilder {
+    /// Starts configuring an extraction of the backup at the given path to
+    /// the output path, opening it with the given password.
+    pub fn new(
+        backup_path: impl Into<PathBuf>,
+        output_path: impl Into<PathBuf>,
+        password: impl Into<String>,
+    ) -> Self {
+        Self {
+            backup_path: backup_path.into(),
+            output_path: output_path.into(),
+            password: password.into(),
+            pool_size: DEFAULT_EXTRACT_POOL_SIZE,
+            options: ExtractOptions::default(),
+        }
+    }
+
+    /// Sets the number of workers decrypting chunks in parallel.
+    #[must_use]
+    pub const fn pool_size(mut self, pool_size: u8) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Removes the given number of leading components from each path in the
+    /// backup. See [`ExtractOptions::strip_components`].
+    #[must_use]
+    pub const fn strip_components(mut self, strip_components: usize) -> Self {
+        self.options.strip_components = strip_components;
+        self
+    }
+
+    /// Sets whether the output directory may already exist, and which of the
+    /// files already in it are replaced.
+    #[must_use]
+    pub const fn overwrite(mut self, overwrite: OverwriteMode) -> Self {
+        self.options.overwrite = overwrite;
+        self
+    }
+
+    /// Sets what to do with entries whose paths already hold different files.
+    #[must_use]
+    pub const fn on_conflict(mut self, conflict: ConflictPolicy) -> Self {
+        self.options.conflict = conflict;
+        self
+    }
+
+    /// Reports progress to the callback as the backup is decrypted and
+    /// unpacked.
+    #[must_use]
+    pub fn on_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.options.progress = Some(ProgressHandler::new(callback));
+        self
+    }
+
+    /// Lets the extraction be cancelled with the token.
+    #[must_use]
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = Some(token);
+        self
+    }
+
+    /// Lets the extraction be paused with the token.
+    #[must_use]
+    pub fn pause(mut self, token: PauseToken) -> Self {
+        self.options.pause = Some(token);
+        self
+    }
+
+    /// Replaces every other setting. Anything set before this is replaced
+    /// too, so it is best called first.
+    #[must_use]
+    pub fn options(mut self, options: ExtractOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Runs the extraction, returning the path extracted to.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if validation fails, or if any operation
+    /// involved in the extraction fails.
+    pub fn run(&self) -> BackupResult<PathBuf> {
+        extract(
+            &self.backup_path,
+            &self.output_path,
+            &self.password,
+            self.pool_size,
+            &self.options,
+        )
+    }
+}
+
+/// Builder tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    fn non_existent_temp_file() -> PathBuf {
+        let temp_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let path = temp_path.to_path_buf();
+        temp_path.close().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_builders() {
+        let src_path = non_existent_temp_file();
+        let backup_output_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let password = "password123";
+        let reported = Arc::new(AtomicU64::new(0));
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("kept.txt"), "Hello, builder!").unwrap();
+            fs::write(src_path.join("excluded.log"), "Left out").unwrap();
+        }
+
+        let reported_by_backup = Arc::clone(&reported);
+        let stats = BackupBuilder::new(&backup_output_path, password)
+            .include(&src_path)
+            .exclude(Pattern::new("*.log").unwrap())
+            .chunk_size(1024)
+            .pool_size(2)
+            .compression(3)
+            .on_progress(move |progress| {
+                reported_by_backup.store(progress.bytes_processed, Ordering::SeqCst);
+            })
+            .run()
+            .unwrap();
+        assert_eq!(stats.path, backup_output_path);
+        assert!(reported.load(Ordering::SeqCst) > 0);
+
+        // Stripping the include path's name extracts its contents directly
+        ExtractBuilder::new(&backup_output_path, &extract_output_path, password)
+            .pool_size(2)
+            .strip_components(1)
+            .run()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(extract_output_path.join("kept.txt")).unwrap(),
+            "Hello, builder!"
+        );
+        assert!(!extract_output_path.join("excluded.log").exists());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_output_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+}
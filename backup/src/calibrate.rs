@@ -0,0 +1,185 @@
+//! Benchmarks for choosing the chunk size and pool size of backups.
+//!
+//! The throughput of a backup depends on how its chunk size and pool size
+//! interact with the CPU it runs on, which is hard to predict. Calibration
+//! encrypts the same amount of random data with each of a few combinations
+//! of the two, as a backup would, and recommends the fastest.
+
+use crate::crypto::*;
+use crate::pool::task_channel;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// The chunk size orders of magnitude that are benchmarked.
+pub const CALIBRATION_CHUNK_SIZE_MAGNITUDES: &[u8] = &[12, 14, 16, 18, 20];
+
+/// The pool sizes that are benchmarked.
+pub const CALIBRATION_POOL_SIZES: &[u8] = &[1, 2, 4, 8, 16, 32];
+
+/// How much slower than the fastest combination another one can be, as a
+/// fraction of its throughput, and still be recommended for using less
+/// memory. Timings vary from run to run by about this much anyway.
+const CALIBRATION_TOLERANCE: f64 = 0.05;
+
+/// The throughput measured for one combination of chunk size and pool size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationResult {
+    /// The order of magnitude of the chunk size.
+    pub chunk_size_magnitude: u8,
+    /// The number of workers in the pool.
+    pub pool_size: u8,
+    /// The number of bytes encrypted per second.
+    pub throughput: f64,
+}
+
+impl CalibrationResult {
+    /// Returns roughly how much memory a backup with this combination keeps
+    /// in chunks at once, as estimated by
+    /// [`check_memory`](crate::check_memory).
+    #[must_use]
+    pub fn memory_usage(&self) -> usize {
+        (1usize << self.chunk_size_magnitude) * (usize::from(self.pool_size) * 2 + 5)
+    }
+}
+
+/// The results of calibrating the chunk size and pool size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// The throughput of every combination benchmarked, in the order they
+    /// were benchmarked.
+    pub results: Vec<CalibrationResult>,
+    /// The recommended combination. This is the one using the least memory
+    /// among those close to the highest throughput.
+    pub recommended: CalibrationResult,
+}
+
+/// Recommends the chunk size and pool size to use on this machine.
+///
+/// Random data is encrypted with each combination of the chunk sizes in
+/// [`CALIBRATION_CHUNK_SIZE_MAGNITUDES`] and the pool sizes in
+/// [`CALIBRATION_POOL_SIZES`]. Each combination encrypts `data_size` bytes,
+/// or a single chunk if that is less. Larger sizes take longer, but give
+/// more reliable results.
+///
+/// # Panics
+///
+/// This will panic if a pool thread cannot be spawned.
+#[must_use]
+pub fn calibrate(data_size: usize) -> Calibration {
+    let key = random_key();
+    let results = CALIBRATION_CHUNK_SIZE_MAGNITUDES
+        .iter()
+        .flat_map(|&chunk_size_magnitude| {
+            let mut chunk = vec![0u8; 1 << chunk_size_magnitude];
+            OsRng.fill_bytes(&mut chunk);
+            let chunk = Arc::new(chunk);
+
+            CALIBRATION_POOL_SIZES
+                .iter()
+                .map(move |&pool_size| CalibrationResult {
+                    chunk_size_magnitude,
+                    pool_size,
+                    throughput: benchmark(key, &chunk, pool_size, data_size),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    Calibration {
+        recommended: recommend(&results),
+        results,
+    }
+}
+
+/// Measures the throughput of encrypting a chunk repeatedly in a pool of the
+/// given size, in bytes per second.
+#[allow(clippy::cast_precision_loss)]
+fn benchmark(
+    key: [u8; AES_KEY_SIZE],
+    chunk: &Arc<Vec<u8>>,
+    pool_size: u8,
+    data_size: usize,
+) -> f64 {
+    let num_chunks = (data_size / chunk.len()).max(1);
+    let (request_sender, response_receiver) = task_channel(usize::from(pool_size));
+    let start = Instant::now();
+
+    thread::spawn({
+        let chunk = Arc::clone(chunk);
+
+        move || {
+            for _ in 0..num_chunks {
+                let chunk = Arc::clone(&chunk);
+
+                if request_sender
+                    .send(move || aes_encrypt(key, &chunk))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    });
+
+    while response_receiver.recv().is_some() {}
+
+    (num_chunks * chunk.len()) as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON)
+}
+
+/// Picks the combination using the least memory among those whose
+/// throughput is within [`CALIBRATION_TOLERANCE`] of the highest.
+///
+/// # Panics
+///
+/// This will panic if there are no results.
+fn recommend(results: &[CalibrationResult]) -> CalibrationResult {
+    let fastest = results
+        .iter()
+        .map(|result| result.throughput)
+        .fold(0.0, f64::max);
+
+    *results
+        .iter()
+        .filter(|result| result.throughput >= fastest * (1.0 - CALIBRATION_TOLERANCE))
+        .min_by_key(|result| (result.memory_usage(), result.pool_size))
+        .expect("calibration should benchmark at least one combination")
+}
+
+/// Calibration tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrate() {
+        let calibration = calibrate(1 << 20);
+        assert_eq!(
+            calibration.results.len(),
+            CALIBRATION_CHUNK_SIZE_MAGNITUDES.len() * CALIBRATION_POOL_SIZES.len()
+        );
+        assert!(calibration.results.contains(&calibration.recommended));
+        assert!(calibration
+            .results
+            .iter()
+            .all(|result| result.throughput > 0.0));
+
+        // Among combinations that are about as fast, the one using the least
+        // memory is recommended
+        let result = |chunk_size_magnitude, pool_size, throughput| CalibrationResult {
+            chunk_size_magnitude,
+            pool_size,
+            throughput,
+        };
+        let results = [
+            result(12, 1, 100.0),
+            result(16, 16, 1000.0),
+            result(14, 8, 980.0),
+            result(14, 16, 990.0),
+            result(12, 4, 900.0),
+        ];
+        assert_eq!(recommend(&results), result(14, 8, 980.0));
+    }
+}
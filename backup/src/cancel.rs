@@ -0,0 +1,117 @@
+//! Cancellation and pausing of long-running operations.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+/// How often a paused operation wakes to check whether it has been
+/// cancelled.
+const PAUSED_CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A token that can be used to cancel an operation from another thread, such
+/// as a signal handler. Clones share the same state, so cancelling any one of
+/// them cancels them all.
+///
+/// The operation checks the token as it reads and writes data, and stops at
+/// the next check once it has been cancelled. Anything the operation left
+/// partially written is removed before it returns
+/// [`BackupError::Cancelled`](crate::BackupError::Cancelled).
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that has not been cancelled.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels every operation using the token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether the token has been cancelled.
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns an error if the token has been cancelled.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::other("operation cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returns whether an optional token has been cancelled.
+pub fn is_cancelled(cancel: Option<&CancellationToken>) -> bool {
+    cancel.is_some_and(CancellationToken::is_cancelled)
+}
+
+/// The state shared by clones of a pause token.
+#[derive(Debug, Default)]
+struct PauseState {
+    /// Whether the operation is paused.
+    paused: Mutex<bool>,
+    /// Signalled when the operation is resumed.
+    resumed: Condvar,
+}
+
+/// A token that can be used to pause an operation from another thread, and
+/// resume it later. Clones share the same state, so pausing any one of them
+/// pauses them all.
+///
+/// The operation checks the token as it reads and writes data, and blocks at
+/// the next check while it is paused. The worker pool is left running, so
+/// chunks already handed to it are still processed and written, after which
+/// the workers wait for more. Nothing is held open beyond what the operation
+/// already had open. A paused operation can still be cancelled, and stops
+/// shortly after its cancellation token is cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct PauseToken(Arc<PauseState>);
+
+impl PauseToken {
+    /// Creates a token that is not paused.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pauses every operation using the token.
+    pub fn pause(&self) {
+        *self.0.paused.lock().unwrap_or_else(PoisonError::into_inner) = true;
+    }
+
+    /// Resumes every operation using the token.
+    pub fn resume(&self) {
+        *self.0.paused.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        self.0.resumed.notify_all();
+    }
+
+    /// Returns whether the token is paused.
+    #[must_use]
+    pub fn is_paused(&self) -> bool {
+        *self.0.paused.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Blocks while the token is paused, unless the operation is cancelled.
+    pub(crate) fn wait(&self, cancel: Option<&CancellationToken>) {
+        let mut paused = self.0.paused.lock().unwrap_or_else(PoisonError::into_inner);
+
+        while *paused && !is_cancelled(cancel) {
+            paused = self
+                .0
+                .resumed
+                .wait_timeout(paused, PAUSED_CANCEL_CHECK_INTERVAL)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+
+        drop(paused);
+    }
+}
@@ -1,9 +1,38 @@
 //! Cryptographic utilities.
+//!
+//! Everything is encrypted with AES-256-GCM, using a key of
+//! [`AES_KEY_SIZE`] bytes. A fresh random nonce of [`AES_NONCE_SIZE`] bytes
+//! is chosen for each message and stored with it, so that nothing other
+//! than the key needs to be kept to decrypt it. The ciphertext produced by
+//! [`aes_encrypt`] and expected by [`aes_decrypt`] is laid out as:
+//!
+//! ```text
+//! +-------------------+--------------------------+-------------------+
+//! | nonce (12 bytes)  | encrypted data (n bytes) | tag (16 bytes)    |
+//! +-------------------+--------------------------+-------------------+
+//! ```
+//!
+//! The ciphertext of an `n` byte message is therefore always
+//! `AES_NONCE_SIZE + n + AES_TAG_SIZE` bytes long. The tag authenticates
+//! both the nonce and the data, so any change to the ciphertext causes
+//! decryption to fail rather than produce different data.
+//!
+//! ```
+//! use backup::crypto::{aes_decrypt, aes_encrypt, password_to_key, AES_NONCE_SIZE, AES_TAG_SIZE};
+//!
+//! let key = password_to_key("password123");
+//! let ciphertext = aes_encrypt(key, b"Hello, world!").unwrap();
+//! assert_eq!(ciphertext.len(), AES_NONCE_SIZE + 13 + AES_TAG_SIZE);
+//! assert_eq!(aes_decrypt(key, &ciphertext).unwrap(), b"Hello, world!");
+//! ```
 
-use crate::BackupResult;
-use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use crate::{BackupError, BackupResult};
+use aes_gcm::aead::rand_core::{CryptoRng, RngCore};
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
 use aes_gcm::{AeadCore, Aes256Gcm, Nonce};
+use hkdf::Hkdf;
 use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
 
 /// The number of bytes to use for an AES key.
 pub const AES_KEY_SIZE: usize = 32;
@@ -11,11 +40,73 @@ pub const AES_KEY_SIZE: usize = 32;
 /// The number of bytes to use for an AES nonce.
 pub const AES_NONCE_SIZE: usize = 12;
 
-/// Encrypts data with AES.
+/// The number of bytes in an AES-GCM authentication tag.
+pub const AES_TAG_SIZE: usize = 16;
+
+/// The number of bytes to use for a password salt.
+pub(crate) const SALT_SIZE: usize = 16;
+
+/// Generates a random AES key.
+pub fn random_key() -> [u8; AES_KEY_SIZE] {
+    Aes256Gcm::generate_key(&mut OsRng).into()
+}
+
+/// Generates a random password salt.
+pub(crate) fn random_salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Encrypts data with AES, returning the nonce followed by the encrypted
+/// data and its tag, as described in the [module documentation](self).
+///
+/// # Errors
+///
+/// This will return an error if the data is too large to encrypt.
 pub fn aes_encrypt(key: [u8; AES_KEY_SIZE], plaintext: &[u8]) -> BackupResult<Vec<u8>> {
+    aes_encrypt_with_aad(key, plaintext, &[])
+}
+
+/// Encrypts data with AES, authenticating additional data that is stored
+/// elsewhere in the clear.
+pub(crate) fn aes_encrypt_with_aad(
+    key: [u8; AES_KEY_SIZE],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> BackupResult<Vec<u8>> {
+    aes_encrypt_with_rng(key, plaintext, aad, &mut OsRng)
+}
+
+/// Encrypts data with AES, generating the nonce with the given random number
+/// generator. Outside of tests, this should always be the OS generator.
+pub(crate) fn aes_encrypt_with_rng<R: CryptoRng + RngCore>(
+    key: [u8; AES_KEY_SIZE],
+    plaintext: &[u8],
+    aad: &[u8],
+    rng: &mut R,
+) -> BackupResult<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(rng).into();
+    aes_encrypt_with_nonce(key, nonce, plaintext, aad)
+}
+
+/// Encrypts data with AES using the given nonce. The nonce must never be used
+/// with the same key to encrypt different data.
+pub(crate) fn aes_encrypt_with_nonce(
+    key: [u8; AES_KEY_SIZE],
+    nonce: [u8; AES_NONCE_SIZE],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> BackupResult<Vec<u8>> {
     let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let ciphertext = cipher.encrypt(&nonce, plaintext)?;
+    let nonce = Nonce::from(nonce);
+    let ciphertext = cipher.encrypt(
+        &nonce,
+        Payload {
+            msg: plaintext,
+            aad,
+        },
+    )?;
 
     let mut ciphertext_with_nonce = nonce.to_vec();
     ciphertext_with_nonce.extend(ciphertext);
@@ -23,19 +114,116 @@ pub fn aes_encrypt(key: [u8; AES_KEY_SIZE], plaintext: &[u8]) -> BackupResult<Ve
     Ok(ciphertext_with_nonce)
 }
 
-/// Decrypts data with AES.
+/// Decrypts data with AES that was encrypted with [`aes_encrypt`], checking
+/// that it has not been modified.
+///
+/// # Errors
+///
+/// This will return an error if the key is wrong, or if the ciphertext is
+/// truncated or has been modified in any way.
 pub fn aes_decrypt(key: [u8; AES_KEY_SIZE], ciphertext_with_nonce: &[u8]) -> BackupResult<Vec<u8>> {
+    aes_decrypt_with_aad(key, ciphertext_with_nonce, &[])
+}
+
+/// Decrypts data with AES, checking that the additional data is the same as
+/// when it was encrypted.
+pub(crate) fn aes_decrypt_with_aad(
+    key: [u8; AES_KEY_SIZE],
+    ciphertext_with_nonce: &[u8],
+    aad: &[u8],
+) -> BackupResult<Vec<u8>> {
     let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
+    if ciphertext_with_nonce.len() < AES_NONCE_SIZE {
+        return Err(aes_gcm::Error.into());
+    }
+
     let (nonce_slice, ciphertext) = ciphertext_with_nonce.split_at(AES_NONCE_SIZE);
     let nonce_slice_sized: [u8; AES_NONCE_SIZE] =
         nonce_slice.try_into().map_err(|_| aes_gcm::Error)?;
     let nonce = Nonce::from(nonce_slice_sized);
-    let plaintext = cipher.decrypt(&nonce, ciphertext.as_ref())?;
+    let plaintext = cipher.decrypt(
+        &nonce,
+        Payload {
+            msg: ciphertext,
+            aad,
+        },
+    )?;
 
     Ok(plaintext)
 }
 
+/// How the nonces used to encrypt each chunk of a payload are chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum NonceMode {
+    /// Nonces are chosen at random.
+    #[default]
+    Random,
+    /// Nonces are derived from the key, the index of the chunk and its
+    /// contents, so that the same payload always encrypts the same way.
+    Derived,
+}
+
+/// Derives a nonce from the key, a context and the data being encrypted with
+/// it, rather than choosing one at random.
+///
+/// Encrypting the same data in the same context always produces the same
+/// nonce, so the ciphertext is reproducible. Changing either one produces an
+/// unrelated nonce, so a nonce is never reused for different data. The only
+/// thing revealed is whether two ciphertexts hold identical data in an
+/// identical context.
+pub(crate) fn derived_nonce(
+    key: [u8; AES_KEY_SIZE],
+    context: &[u8],
+    plaintext: &[u8],
+) -> [u8; AES_NONCE_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"encrypted-backup nonce");
+    hasher.update(key);
+    hasher.update((context.len() as u64).to_be_bytes());
+    hasher.update(context);
+    hasher.update(plaintext);
+    hasher.finalize()[..AES_NONCE_SIZE].try_into().unwrap()
+}
+
+/// Derives a salt from a secret, for deterministic backups. Unlike a random
+/// salt, this lets the same password be recognized across backups, and lets
+/// an attacker precompute guesses against every backup it protects.
+pub(crate) fn derived_salt(secret: &[u8]) -> [u8; SALT_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"encrypted-backup salt");
+    hasher.update(secret);
+    hasher.finalize()[..SALT_SIZE].try_into().unwrap()
+}
+
+/// Derives a data key from a key derived from a password, for deterministic
+/// backups.
+pub(crate) fn derived_data_key(key: [u8; AES_KEY_SIZE]) -> [u8; AES_KEY_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"encrypted-backup data key");
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+/// Derives the key for a single chunk of a payload from the data key and the
+/// index of the chunk with HKDF-SHA256. Every chunk gets an unrelated key,
+/// and the key of one chunk reveals nothing about the data key or the keys
+/// of the others.
+pub(crate) fn derived_chunk_key(key: [u8; AES_KEY_SIZE], chunk_index: u64) -> [u8; AES_KEY_SIZE] {
+    let mut chunk_key = [0u8; AES_KEY_SIZE];
+    Hkdf::<Sha256>::new(None, &key)
+        .expand_multi_info(
+            &[b"encrypted-backup chunk key", &chunk_index.to_be_bytes()],
+            &mut chunk_key,
+        )
+        .unwrap();
+    chunk_key
+}
+
 /// Converts a password of arbitrary length to an AES key by performing a SHA-256 hash.
+///
+/// This is fast, so it offers little protection against guessing weak
+/// passwords. Backups derive their keys with Argon2 instead.
+#[must_use]
 pub fn password_to_key(password: &str) -> [u8; AES_KEY_SIZE] {
     let mut hasher = Sha256::new();
     hasher.update(password);
@@ -43,6 +231,272 @@ pub fn password_to_key(password: &str) -> [u8; AES_KEY_SIZE] {
     result.into()
 }
 
+/// Converts a password of arbitrary length and a salt to an AES key by
+/// performing a SHA-256 hash of the two.
+pub(crate) fn salted_password_to_key(password: &str, salt: &[u8; SALT_SIZE]) -> [u8; AES_KEY_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password);
+    let result = hasher.finalize();
+    result.into()
+}
+
+/// The number of bytes in an X25519 recovery key, whether private or public.
+pub const RECOVERY_KEY_SIZE: usize = 32;
+
+/// Generates an X25519 key pair for recovering backups.
+///
+/// Returns the private key followed by the public key. A backup made with the
+/// public key as its recovery key can always be opened with the private key,
+/// whatever its passwords.
+#[must_use]
+pub fn generate_recovery_key() -> ([u8; RECOVERY_KEY_SIZE], [u8; RECOVERY_KEY_SIZE]) {
+    let private_key = random_key();
+    (private_key, recovery_public_key(&private_key))
+}
+
+/// Returns the public key of an X25519 recovery private key.
+#[must_use]
+pub fn recovery_public_key(private_key: &[u8; RECOVERY_KEY_SIZE]) -> [u8; RECOVERY_KEY_SIZE] {
+    PublicKey::from(&StaticSecret::from(*private_key)).to_bytes()
+}
+
+/// Agrees on a key with the holder of a recovery private key, using a fresh
+/// ephemeral key pair. Returns the agreed key along with the ephemeral public
+/// key, which must be stored with whatever the key encrypts.
+pub(crate) fn recovery_key_agreement(
+    public_key: &[u8; RECOVERY_KEY_SIZE],
+) -> BackupResult<([u8; AES_KEY_SIZE], [u8; RECOVERY_KEY_SIZE])> {
+    let ephemeral_secret = StaticSecret::from(random_key());
+    let ephemeral_public_key = PublicKey::from(&ephemeral_secret).to_bytes();
+    let shared_secret = ephemeral_secret.diffie_hellman(&PublicKey::from(*public_key));
+    let key = recovery_shared_key(&shared_secret, &ephemeral_public_key, public_key)?;
+
+    Ok((key, ephemeral_public_key))
+}
+
+/// Derives the key agreed by [`recovery_key_agreement`] from the recovery
+/// private key and the stored ephemeral public key.
+pub(crate) fn recovery_agreed_key(
+    private_key: &[u8; RECOVERY_KEY_SIZE],
+    ephemeral_public_key: &[u8; RECOVERY_KEY_SIZE],
+) -> BackupResult<[u8; AES_KEY_SIZE]> {
+    let private_key = StaticSecret::from(*private_key);
+    let public_key = PublicKey::from(&private_key).to_bytes();
+    let shared_secret = private_key.diffie_hellman(&PublicKey::from(*ephemeral_public_key));
+
+    recovery_shared_key(&shared_secret, ephemeral_public_key, &public_key)
+}
+
+/// Hashes an X25519 shared secret together with both public keys into an AES
+/// key, binding the key to the key pairs that agreed on it.
+fn recovery_shared_key(
+    shared_secret: &SharedSecret,
+    ephemeral_public_key: &[u8; RECOVERY_KEY_SIZE],
+    public_key: &[u8; RECOVERY_KEY_SIZE],
+) -> BackupResult<[u8; AES_KEY_SIZE]> {
+    // A public key of low order produces a shared secret that anyone can
+    // compute
+    if !shared_secret.was_contributory() {
+        return Err(BackupError::InvalidRecoveryKey);
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"encrypted-backup recovery key");
+    hasher.update(shared_secret.as_bytes());
+    hasher.update(ephemeral_public_key);
+    hasher.update(public_key);
+    Ok(hasher.finalize().into())
+}
+
+/// The cost parameters of the Argon2id key derivation function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// The amount of memory to use, in KiB.
+    pub memory_kib: u32,
+    /// The number of passes over the memory.
+    pub iterations: u32,
+    /// The number of lanes to compute in parallel.
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    /// The minimum memory cost, 8 MiB.
+    pub const MIN_MEMORY_KIB: u32 = 8 * 1024;
+    /// The maximum memory cost, 4 GiB.
+    pub const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+    /// The maximum number of iterations.
+    pub const MAX_ITERATIONS: u32 = 64;
+    /// The maximum degree of parallelism.
+    pub const MAX_PARALLELISM: u32 = 64;
+
+    /// Checks that the parameters are within safe bounds. Parameters that are
+    /// too low make passwords easy to brute force, and parameters that are
+    /// too high, such as ones read from a malicious backup header, could
+    /// exhaust the memory or time of the machine deriving the key.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if any parameter is out of bounds.
+    pub fn validate(&self) -> BackupResult<()> {
+        let invalid = |message: String| Err(BackupError::InvalidKdfParams(message));
+
+        if !(Self::MIN_MEMORY_KIB..=Self::MAX_MEMORY_KIB).contains(&self.memory_kib) {
+            return invalid(format!(
+                "memory must be between {} and {} KiB, got {}",
+                Self::MIN_MEMORY_KIB,
+                Self::MAX_MEMORY_KIB,
+                self.memory_kib
+            ));
+        }
+
+        if !(1..=Self::MAX_ITERATIONS).contains(&self.iterations) {
+            return invalid(format!(
+                "iterations must be between 1 and {}, got {}",
+                Self::MAX_ITERATIONS,
+                self.iterations
+            ));
+        }
+
+        if !(1..=Self::MAX_PARALLELISM).contains(&self.parallelism) {
+            return invalid(format!(
+                "parallelism must be between 1 and {}, got {}",
+                Self::MAX_PARALLELISM,
+                self.parallelism
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Argon2Params {
+    /// The parameters recommended by OWASP for Argon2id: 19 MiB of memory, 2
+    /// iterations, and no parallelism.
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Converts a password and salt to an AES key using Argon2id.
+pub(crate) fn argon2_password_to_key(
+    password: &str,
+    salt: &[u8; SALT_SIZE],
+    params: Argon2Params,
+) -> BackupResult<[u8; AES_KEY_SIZE]> {
+    params.validate()?;
+
+    let argon2_params = argon2::Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(AES_KEY_SIZE),
+    )
+    .map_err(|e| BackupError::InvalidKdfParams(e.to_string()))?;
+    let argon2 = argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        argon2_params,
+    );
+
+    let mut key = [0u8; AES_KEY_SIZE];
+    argon2
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| BackupError::InvalidKdfParams(e.to_string()))?;
+
+    Ok(key)
+}
+
+/// The size of a checksum, regardless of the algorithm used to compute it.
+pub(crate) const CHECKSUM_SIZE: usize = 32;
+
+/// A hash algorithm used to checksum the contents of a backup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// SHA-256.
+    #[default]
+    Sha256,
+    /// BLAKE3, which is considerably faster than SHA-256. Computing BLAKE3
+    /// checksums requires the `blake3` feature.
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// Returns whether checksums can be computed with this algorithm in this
+    /// build.
+    #[must_use]
+    pub const fn is_supported(self) -> bool {
+        match self {
+            Self::Sha256 => true,
+            Self::Blake3 => cfg!(feature = "blake3"),
+        }
+    }
+
+    /// Returns the identifier of the algorithm in a backup header.
+    pub(crate) const fn id(self) -> u8 {
+        match self {
+            Self::Sha256 => 0,
+            Self::Blake3 => 1,
+        }
+    }
+
+    /// Returns the algorithm with the given identifier in a backup header.
+    pub(crate) const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Sha256),
+            1 => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Creates a hasher that computes checksums with this algorithm.
+    #[cfg_attr(feature = "blake3", allow(clippy::unnecessary_wraps))]
+    pub(crate) fn hasher(self) -> BackupResult<ChecksumHasher> {
+        match self {
+            Self::Sha256 => Ok(ChecksumHasher::Sha256(Sha256::new())),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => Ok(ChecksumHasher::Blake3(Box::default())),
+            #[cfg(not(feature = "blake3"))]
+            Self::Blake3 => Err(BackupError::UnsupportedChecksumAlgorithm(self)),
+        }
+    }
+}
+
+/// An in-progress checksum computation.
+pub(crate) enum ChecksumHasher {
+    /// A SHA-256 hasher.
+    Sha256(Sha256),
+    /// A BLAKE3 hasher.
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChecksumHasher {
+    /// Adds data to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Returns the checksum of all data added.
+    pub fn finalize(self) -> [u8; CHECKSUM_SIZE] {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().into(),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => hasher.finalize().into(),
+        }
+    }
+}
+
 /// Crypto tests.
 #[cfg(test)]
 mod tests {
@@ -58,6 +512,87 @@ mod tests {
         let aes_decrypted_message = std::str::from_utf8(&aes_decrypted).unwrap();
         assert_eq!(aes_decrypted_message, aes_message);
         assert_ne!(aes_encrypted, aes_message.as_bytes());
+
+        // The nonce is prepended and the tag appended to the encrypted data
+        assert_eq!(
+            aes_encrypted.len(),
+            AES_NONCE_SIZE + aes_message.len() + AES_TAG_SIZE
+        );
+
+        // Truncated ciphertext is rejected rather than panicking
+        for len in [
+            0,
+            AES_NONCE_SIZE - 1,
+            AES_NONCE_SIZE,
+            AES_NONCE_SIZE + AES_TAG_SIZE - 1,
+        ] {
+            assert!(aes_decrypt(key, &aes_encrypted[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_derived_nonce() {
+        let aes_message = "Hello, AES!";
+        let key = password_to_key("password123");
+        let nonce1 = derived_nonce(key, b"context", aes_message.as_bytes());
+        let nonce2 = derived_nonce(key, b"context", aes_message.as_bytes());
+        let nonce3 = derived_nonce(key, b"other context", aes_message.as_bytes());
+        let nonce4 = derived_nonce(key, b"context", b"Hello, AES?");
+        let nonce5 = derived_nonce(
+            password_to_key("password124"),
+            b"context",
+            aes_message.as_bytes(),
+        );
+        assert_eq!(nonce1, nonce2);
+        assert_ne!(nonce1, nonce3);
+        assert_ne!(nonce1, nonce4);
+        assert_ne!(nonce1, nonce5);
+
+        let aes_encrypted1 =
+            aes_encrypt_with_nonce(key, nonce1, aes_message.as_bytes(), &[]).unwrap();
+        let aes_encrypted2 =
+            aes_encrypt_with_nonce(key, nonce2, aes_message.as_bytes(), &[]).unwrap();
+        assert_eq!(aes_encrypted1, aes_encrypted2);
+        assert_eq!(
+            aes_decrypt(key, &aes_encrypted1).unwrap(),
+            aes_message.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_aes_encrypt_with_rng() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let key = password_to_key("password123");
+        let message1 = b"Hello, AES!";
+        let message2 = b"Goodbye, AES";
+
+        // The same seed produces the same ciphertext
+        let aes_encrypted1 =
+            aes_encrypt_with_rng(key, message1, &[], &mut StdRng::seed_from_u64(1)).unwrap();
+        let aes_encrypted2 =
+            aes_encrypt_with_rng(key, message1, &[], &mut StdRng::seed_from_u64(1)).unwrap();
+        let aes_encrypted3 =
+            aes_encrypt_with_rng(key, message1, &[], &mut StdRng::seed_from_u64(2)).unwrap();
+        assert_eq!(aes_encrypted1, aes_encrypted2);
+        assert_ne!(aes_encrypted1, aes_encrypted3);
+        assert_eq!(aes_decrypt(key, &aes_encrypted1).unwrap(), message1);
+
+        // A repeated nonce leaks the XOR of the plaintexts, which is why
+        // nonces must never repeat under the same key
+        let aes_encrypted4 =
+            aes_encrypt_with_rng(key, message2, &[], &mut StdRng::seed_from_u64(1)).unwrap();
+        assert_eq!(
+            aes_encrypted1[..AES_NONCE_SIZE],
+            aes_encrypted4[..AES_NONCE_SIZE]
+        );
+        let leaked = aes_encrypted1[AES_NONCE_SIZE..]
+            .iter()
+            .zip(&aes_encrypted4[AES_NONCE_SIZE..])
+            .zip(message1.iter().zip(message2))
+            .all(|((c1, c2), (m1, m2))| c1 ^ c2 == m1 ^ m2);
+        assert!(leaked);
     }
 
     #[test]
@@ -69,6 +604,128 @@ mod tests {
         assert_ne!(key1, key3);
     }
 
+    #[test]
+    fn test_salted_password_to_key() {
+        let salt1 = random_salt();
+        let salt2 = random_salt();
+        assert_ne!(salt1, salt2);
+
+        let key1 = salted_password_to_key("password123", &salt1);
+        let key2 = salted_password_to_key("password123", &salt1);
+        let key3 = salted_password_to_key("password123", &salt2);
+        let key4 = salted_password_to_key("password124", &salt1);
+        assert_eq!(key1, key2);
+        assert_ne!(key1, key3);
+        assert_ne!(key1, key4);
+        assert_ne!(key1, password_to_key("password123"));
+    }
+
+    #[test]
+    fn test_recovery_key_agreement() {
+        let (private_key, public_key) = generate_recovery_key();
+        assert_eq!(recovery_public_key(&private_key), public_key);
+
+        // Only the recovery private key agrees on the same key
+        let (key, ephemeral_public_key) = recovery_key_agreement(&public_key).unwrap();
+        assert_eq!(
+            recovery_agreed_key(&private_key, &ephemeral_public_key).unwrap(),
+            key
+        );
+        let (other_private_key, _) = generate_recovery_key();
+        assert_ne!(
+            recovery_agreed_key(&other_private_key, &ephemeral_public_key).unwrap(),
+            key
+        );
+
+        // Each agreement uses a new ephemeral key
+        let (other_key, other_ephemeral_public_key) = recovery_key_agreement(&public_key).unwrap();
+        assert_ne!(other_key, key);
+        assert_ne!(other_ephemeral_public_key, ephemeral_public_key);
+
+        // Public keys of low order are rejected
+        assert!(matches!(
+            recovery_key_agreement(&[0; RECOVERY_KEY_SIZE]).unwrap_err(),
+            BackupError::InvalidRecoveryKey
+        ));
+    }
+
+    #[test]
+    fn test_argon2_password_to_key() {
+        let params = Argon2Params {
+            memory_kib: Argon2Params::MIN_MEMORY_KIB,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let salt = random_salt();
+
+        let key1 = argon2_password_to_key("password123", &salt, params).unwrap();
+        let key2 = argon2_password_to_key("password123", &salt, params).unwrap();
+        let key3 = argon2_password_to_key("password124", &salt, params).unwrap();
+        let key4 = argon2_password_to_key(
+            "password123",
+            &salt,
+            Argon2Params {
+                iterations: 2,
+                ..params
+            },
+        )
+        .unwrap();
+        assert_eq!(key1, key2);
+        assert_ne!(key1, key3);
+        assert_ne!(key1, key4);
+        assert_ne!(key1, salted_password_to_key("password123", &salt));
+
+        for invalid_params in [
+            Argon2Params {
+                memory_kib: Argon2Params::MIN_MEMORY_KIB - 1,
+                ..params
+            },
+            Argon2Params {
+                memory_kib: Argon2Params::MAX_MEMORY_KIB + 1,
+                ..params
+            },
+            Argon2Params {
+                iterations: 0,
+                ..params
+            },
+            Argon2Params {
+                parallelism: Argon2Params::MAX_PARALLELISM + 1,
+                ..params
+            },
+        ] {
+            assert!(matches!(
+                argon2_password_to_key("password123", &salt, invalid_params),
+                Err(BackupError::InvalidKdfParams(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_derived_chunk_key() {
+        let key = random_key();
+        let chunk_keys = (0..64)
+            .map(|chunk_index| derived_chunk_key(key, chunk_index))
+            .collect::<Vec<_>>();
+
+        // The same chunk always gets the same key, and no two chunks share one
+        assert_eq!(chunk_keys[7], derived_chunk_key(key, 7));
+        assert!(chunk_keys.iter().all(|&chunk_key| chunk_key != key));
+        for (i, chunk_key) in chunk_keys.iter().enumerate() {
+            assert!(!chunk_keys[i + 1..].contains(chunk_key));
+        }
+
+        // A different data key gives every chunk a different key
+        assert_ne!(derived_chunk_key(random_key(), 0), chunk_keys[0]);
+
+        let ciphertext = aes_encrypt(chunk_keys[1], b"Hello, chunk!").unwrap();
+        assert!(aes_decrypt(key, &ciphertext).is_err());
+        assert!(aes_decrypt(chunk_keys[0], &ciphertext).is_err());
+        assert_eq!(
+            aes_decrypt(chunk_keys[1], &ciphertext).unwrap(),
+            b"Hello, chunk!"
+        );
+    }
+
     const DATA_SIZE: usize = 1 << 16;
 
     #[test]
@@ -0,0 +1,103 @@
+//! Deduplication of files with identical contents within a backup.
+//!
+//! When deduplication is enabled, each file whose contents are identical to
+//! a file already in the archive is stored as a hard link entry pointing at
+//! the first copy, preceded by a PAX header marking it as a duplicate.
+//! Extraction writes an independent copy of the first file in its place,
+//! rather than linking the two. Other tools ignore the marker and restore
+//! the duplicate as a hard link, which still has the right contents.
+//!
+//! Only files the same size as one already archived are hashed before they
+//! are appended, so a backup without duplicates reads most files only once.
+
+use crate::manifest::{hash_file, FILE_HASH_SIZE};
+use crate::util::OpenFileLimiter;
+use filetime::FileTime;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// The PAX header key marking a hard link entry as a duplicate file.
+const PAX_DUPLICATE_KEY: &str = "EBAK.duplicate";
+
+/// The files archived so far, keyed by the hash of their contents.
+#[derive(Debug, Default)]
+pub struct DedupTable {
+    /// The sizes of the files archived so far.
+    sizes: HashSet<u64>,
+    /// The archive path of the first file archived with each hash.
+    files: HashMap<[u8; FILE_HASH_SIZE], PathBuf>,
+}
+
+impl DedupTable {
+    /// Records a file archived at the given path, unless a file with the same
+    /// contents was archived before it.
+    pub fn insert(&mut self, name: &Path, size: u64, hash: [u8; FILE_HASH_SIZE]) {
+        self.sizes.insert(size);
+        self.files.entry(hash).or_insert_with(|| name.to_path_buf());
+    }
+
+    /// Finds an archived file with the same contents as the file at the given
+    /// path, returning its archive path along with the hash of the contents.
+    /// Empty files and files that cannot be read are never duplicates. The
+    /// file is only opened once the limiter allows it.
+    pub fn find(
+        &self,
+        path: &Path,
+        size: u64,
+        open_files: &OpenFileLimiter,
+    ) -> Option<(PathBuf, [u8; FILE_HASH_SIZE])> {
+        if size == 0 || !self.sizes.contains(&size) {
+            return None;
+        }
+
+        let hash = {
+            let _permit = open_files.acquire();
+            hash_file(path).ok()?
+        };
+        self.files.get(&hash).map(|name| (name.clone(), hash))
+    }
+}
+
+/// Returns the PAX header key and value marking a duplicate file.
+pub fn duplicate_extension() -> (String, Vec<u8>) {
+    (PAX_DUPLICATE_KEY.to_owned(), b"1".to_vec())
+}
+
+/// Checks whether an archive entry is a duplicate of an earlier file.
+pub fn is_duplicate<R: Read>(entry: &mut tar::Entry<R>) -> bool {
+    entry.header().entry_type() == tar::EntryType::Link
+        && entry
+            .pax_extensions()
+            .ok()
+            .flatten()
+            .is_some_and(|mut extensions| {
+                extensions.any(|extension| {
+                    extension.is_ok_and(|extension| {
+                        extension.key_bytes() == PAX_DUPLICATE_KEY.as_bytes()
+                    })
+                })
+            })
+}
+
+/// Writes a duplicate file by copying the first copy of its contents, giving
+/// it the permissions and modification time in its own header. Anything
+/// already at the destination is replaced rather than written through.
+pub fn copy_duplicate(src: &Path, dst: &Path, header: &tar::Header) -> io::Result<()> {
+    if fs::symlink_metadata(dst).is_ok_and(|metadata| !metadata.is_dir()) {
+        fs::remove_file(dst)?;
+    }
+
+    fs::copy(src, dst)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(dst, fs::Permissions::from_mode(header.mode()? & 0o777))?;
+    }
+
+    let mtime = i64::try_from(header.mtime()?).unwrap_or(i64::MAX);
+    filetime::set_file_mtime(dst, FileTime::from_unix_time(mtime, 0))
+}
@@ -0,0 +1,68 @@
+//! A curated set of exclusions for directories that are rarely worth backing
+//! up, such as caches and build output.
+
+use glob::Pattern;
+
+/// The names of directories excluded by [`common_exclude_globs`]. Each is
+/// excluded wherever it appears, at any depth.
+pub const COMMON_EXCLUDES: &[&str] = &[
+    ".cache",
+    ".git",
+    ".gradle",
+    ".mypy_cache",
+    ".next",
+    ".pytest_cache",
+    ".tox",
+    ".venv",
+    "__pycache__",
+    "node_modules",
+    "target",
+];
+
+/// Returns globs excluding the common directories in [`COMMON_EXCLUDES`],
+/// except for any listed in `keep`, to be merged into the exclude globs of a
+/// backup.
+///
+/// # Panics
+///
+/// This will panic if one of the common exclusions is not a valid glob,
+/// which would be a bug.
+#[must_use]
+pub fn common_exclude_globs(keep: &[impl AsRef<str>]) -> Vec<Pattern> {
+    COMMON_EXCLUDES
+        .iter()
+        .filter(|name| !keep.iter().any(|kept| kept.as_ref() == **name))
+        .map(|name| {
+            Pattern::new(&format!("**/{name}")).expect("common exclusions should be valid globs")
+        })
+        .collect()
+}
+
+/// Exclusion tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_common_exclude_globs() {
+        let excluded = |globs: &[Pattern], path: &str| {
+            globs.iter().any(|glob| glob.matches_path(Path::new(path)))
+        };
+
+        let globs = common_exclude_globs(&[] as &[&str]);
+        assert_eq!(globs.len(), COMMON_EXCLUDES.len());
+        assert!(excluded(&globs, "project/node_modules"));
+        assert!(excluded(&globs, "home/user/.cache"));
+        assert!(excluded(&globs, "home/user/code/app/target"));
+        assert!(!excluded(&globs, "project/src"));
+        assert!(!excluded(&globs, "project/targets"));
+
+        // Kept entries are left out
+        let globs = common_exclude_globs(&["target", ".git"]);
+        assert_eq!(globs.len(), COMMON_EXCLUDES.len() - 2);
+        assert!(!excluded(&globs, "project/target"));
+        assert!(!excluded(&globs, "project/.git"));
+        assert!(excluded(&globs, "project/node_modules"));
+    }
+}
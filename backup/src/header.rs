@@ -0,0 +1,1114 @@
+//! The header stored at the start of a backup file.
+//!
+//! The payload of a backup is encrypted with a random data key. The header
+//! stores a copy of that key for each password that can open the backup,
+//! wrapped with a key derived from the password, so that any one of the
+//! passwords can be used to extract it. A slot can also wrap the data key with
+//! a key provided directly by the caller, skipping key derivation, or with a
+//! key agreed with an X25519 recovery public key, so that whoever holds the
+//! matching private key can always recover the backup.
+//!
+//! Since version 2 of the format, the payload is followed by an empty section
+//! marking its end and a checksum of the payload, so that corruption can be
+//! detected without the password. Since version 3, the header records the
+//! algorithm used to compute the checksum. Since version 4, it records flags
+//! describing how the payload was produced, such as whether the archive was
+//! compressed before it was encrypted. Since version 5, it records when the
+//! backup was created and the version of the tool that created it, along with
+//! a tag that authenticates this metadata with the data key.
+//!
+//! Payload flags also record whether an index of the backup's contents
+//! follows the end of the payload, whether it is encrypted, and whether it
+//! records where each entry begins in the archive. Backups without an index
+//! are unchanged, so they can still be read by versions of the tool from
+//! before indexes were introduced. A flag likewise records whether each chunk
+//! of the payload is encrypted with a key derived for it alone, which older
+//! versions refuse to read rather than fail to decrypt.
+
+use crate::backup_crypto::*;
+use crate::crypto::*;
+use crate::options::IndexMode;
+use crate::types::*;
+use chrono::{DateTime, Utc};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+
+/// The bytes that identify a backup file with a header.
+pub const MAGIC: &[u8; 4] = b"EBAK";
+
+/// The current version of the backup file format.
+pub const FORMAT_VERSION: u8 = 5;
+
+/// The oldest version of the backup file format that can still be read.
+pub const MIN_FORMAT_VERSION: u8 = 1;
+
+/// The last version of the backup file format without a checksum trailer,
+/// which legacy backups are migrated to unless their format is upgraded.
+pub const CHECKSUMLESS_FORMAT_VERSION: u8 = 1;
+
+/// The first version of the backup file format with a checksum trailer.
+pub const CHECKSUM_FORMAT_VERSION: u8 = 2;
+
+/// The first version of the backup file format that records the checksum
+/// algorithm. Earlier versions always use SHA-256.
+pub const CHECKSUM_ALGORITHM_FORMAT_VERSION: u8 = 3;
+
+/// The first version of the backup file format with payload flags.
+pub const FLAGS_FORMAT_VERSION: u8 = 4;
+
+/// The first version of the backup file format with a creation time, tool
+/// version and metadata authentication tag.
+pub const METADATA_FORMAT_VERSION: u8 = 5;
+
+/// Returns the range of backup file format versions that can be read. Backups
+/// are always written in [`FORMAT_VERSION`], the last in the range.
+#[must_use]
+pub const fn supported_format_versions() -> RangeInclusive<u8> {
+    MIN_FORMAT_VERSION..=FORMAT_VERSION
+}
+
+/// The version of this crate, recorded in the headers it writes.
+pub const TOOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The payload flag set when the archive was compressed with zstd.
+const FLAG_COMPRESSED: u8 = 1 << 0;
+
+/// The payload flag set when an index of the backup's contents follows the
+/// payload.
+const FLAG_INDEX: u8 = 1 << 1;
+
+/// The payload flag set, along with [`FLAG_INDEX`], when the index is
+/// encrypted.
+const FLAG_INDEX_ENCRYPTED: u8 = 1 << 2;
+
+/// The payload flag set, along with [`FLAG_INDEX`], when the index records
+/// where each entry begins in the archive.
+const FLAG_INDEX_OFFSETS: u8 = 1 << 3;
+
+/// The payload flag set when each chunk of the payload is encrypted with a
+/// key derived from the data key and the chunk's index.
+const FLAG_CHUNK_KEYS: u8 = 1 << 4;
+
+/// All payload flags that can be read.
+const KNOWN_FLAGS: u8 =
+    FLAG_COMPRESSED | FLAG_INDEX | FLAG_INDEX_ENCRYPTED | FLAG_INDEX_OFFSETS | FLAG_CHUNK_KEYS;
+
+/// The size of a data key once it has been wrapped.
+pub const WRAPPED_KEY_SIZE: usize = AES_NONCE_SIZE + AES_KEY_SIZE + AES_TAG_SIZE;
+
+/// The size of the tag authenticating the header metadata.
+pub const METADATA_TAG_SIZE: usize = AES_NONCE_SIZE + AES_TAG_SIZE;
+
+/// A secret that opens a key slot.
+#[derive(Clone, Copy)]
+pub enum Secret<'a> {
+    /// A password, from which a key is derived.
+    Password(&'a str),
+    /// A key that is used as is.
+    Key(&'a [u8; AES_KEY_SIZE]),
+    /// An X25519 recovery private key, which opens slots wrapped with a key
+    /// agreed with its public key.
+    RecoveryKey(&'a [u8; RECOVERY_KEY_SIZE]),
+}
+
+impl Secret<'_> {
+    /// Returns the key derivation function to use for this secret, given the
+    /// one configured for passwords.
+    const fn kdf(self, password_kdf: Kdf) -> Kdf {
+        match self {
+            Self::Password(_) => password_kdf,
+            Self::Key(_) | Self::RecoveryKey(_) => Kdf::Raw,
+        }
+    }
+
+    /// Returns the salt to use for this secret in a deterministic backup.
+    fn derived_salt(self) -> [u8; SALT_SIZE] {
+        match self {
+            Self::Password(password) => derived_salt(password.as_bytes()),
+            Self::Key(key) | Self::RecoveryKey(key) => derived_salt(key),
+        }
+    }
+}
+
+/// A key derivation function used to turn a password into a key that wraps
+/// the data key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    /// A SHA-256 hash of the salt and password.
+    Sha256,
+    /// Argon2id with the given cost parameters.
+    Argon2(Argon2Params),
+    /// No key derivation. The slot is opened with a key rather than a
+    /// password.
+    Raw,
+    /// X25519 key agreement between the given ephemeral public key and a
+    /// recovery public key. The slot is opened with the recovery private key.
+    X25519 {
+        /// The public half of the ephemeral key pair the slot was wrapped
+        /// with.
+        ephemeral_public_key: [u8; RECOVERY_KEY_SIZE],
+    },
+}
+
+impl Kdf {
+    /// Encodes the key derivation function and its parameters.
+    fn encode(self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::Sha256 => bytes.push(0),
+            Self::Argon2(params) => {
+                bytes.push(1);
+                bytes.extend(params.memory_kib.to_be_bytes());
+                bytes.extend(params.iterations.to_be_bytes());
+                bytes.extend(params.parallelism.to_be_bytes());
+            }
+            Self::Raw => bytes.push(2),
+            Self::X25519 {
+                ephemeral_public_key,
+            } => {
+                bytes.push(3);
+                bytes.extend(ephemeral_public_key);
+            }
+        }
+    }
+
+    /// Decodes a key derivation function and its parameters.
+    fn decode(reader: &mut HeaderReader) -> BackupResult<Self> {
+        let [id] = reader.take()?;
+
+        match id {
+            0 => Ok(Self::Sha256),
+            1 => {
+                let params = Argon2Params {
+                    memory_kib: u32::from_be_bytes(reader.take()?),
+                    iterations: u32::from_be_bytes(reader.take()?),
+                    parallelism: u32::from_be_bytes(reader.take()?),
+                };
+                params.validate()?;
+
+                Ok(Self::Argon2(params))
+            }
+            2 => Ok(Self::Raw),
+            3 => Ok(Self::X25519 {
+                ephemeral_public_key: reader.take()?,
+            }),
+            _ => Err(BackupError::InvalidHeader(format!(
+                "unknown key derivation function {id}"
+            ))),
+        }
+    }
+
+    /// Derives a key from a secret and salt. Fails if the secret is of the
+    /// wrong kind for the key derivation function.
+    fn derive_key(
+        self,
+        secret: Secret,
+        salt: &[u8; SALT_SIZE],
+    ) -> BackupResult<[u8; AES_KEY_SIZE]> {
+        match (self, secret) {
+            (Self::Sha256, Secret::Password(password)) => {
+                Ok(salted_password_to_key(password, salt))
+            }
+            (Self::Argon2(params), Secret::Password(password)) => {
+                argon2_password_to_key(password, salt, params)
+            }
+            (Self::Raw, Secret::Key(key)) => Ok(*key),
+            (
+                Self::X25519 {
+                    ephemeral_public_key,
+                },
+                Secret::RecoveryKey(private_key),
+            ) => recovery_agreed_key(private_key, &ephemeral_public_key)
+                .map_err(|_| BackupError::IncorrectPassword),
+            _ => Err(BackupError::IncorrectPassword),
+        }
+    }
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Self::Argon2(Argon2Params::default())
+    }
+}
+
+/// A copy of the data key, wrapped with a key derived from one password.
+#[derive(Debug, Clone)]
+pub struct KeySlot {
+    /// The key derivation function used for the password.
+    pub kdf: Kdf,
+    /// The salt used for the password.
+    pub salt: [u8; SALT_SIZE],
+    /// The encrypted data key.
+    pub wrapped_key: [u8; WRAPPED_KEY_SIZE],
+}
+
+impl KeySlot {
+    /// Wraps the data key with the given secret.
+    pub fn new(secret: Secret, data_key: [u8; AES_KEY_SIZE], kdf: Kdf) -> BackupResult<Self> {
+        let salt = random_salt();
+        let wrapped_key = aes_encrypt(kdf.derive_key(secret, &salt)?, &data_key)?
+            .try_into()
+            .unwrap();
+
+        Ok(Self {
+            kdf,
+            salt,
+            wrapped_key,
+        })
+    }
+
+    /// Wraps the data key with a key agreed with the recovery public key,
+    /// using a fresh ephemeral key pair.
+    pub fn new_recovery(
+        public_key: &[u8; RECOVERY_KEY_SIZE],
+        data_key: [u8; AES_KEY_SIZE],
+    ) -> BackupResult<Self> {
+        let (wrapping_key, ephemeral_public_key) = recovery_key_agreement(public_key)?;
+        let wrapped_key = aes_encrypt(wrapping_key, &data_key)?.try_into().unwrap();
+
+        Ok(Self {
+            kdf: Kdf::X25519 {
+                ephemeral_public_key,
+            },
+            // The ephemeral key already makes each wrapping key unique, but
+            // the salt is kept so that every slot has the same layout
+            salt: random_salt(),
+            wrapped_key,
+        })
+    }
+
+    /// Wraps the data key with the given secret, reproducibly. The salt is
+    /// derived from the secret, and the nonce from the wrapping key and data
+    /// key. Returns the slot along with the wrapping key.
+    fn new_deterministic(
+        secret: Secret,
+        data_key: Option<[u8; AES_KEY_SIZE]>,
+        kdf: Kdf,
+    ) -> BackupResult<(Self, [u8; AES_KEY_SIZE])> {
+        let salt = secret.derived_salt();
+        let wrapping_key = kdf.derive_key(secret, &salt)?;
+        // The data key of a deterministic backup is derived from the first
+        // secret's wrapping key
+        let data_key = data_key.unwrap_or_else(|| derived_data_key(wrapping_key));
+        let nonce = derived_nonce(wrapping_key, b"key slot", &data_key);
+        let wrapped_key = aes_encrypt_with_nonce(wrapping_key, nonce, &data_key, &[])?
+            .try_into()
+            .unwrap();
+
+        Ok((
+            Self {
+                kdf,
+                salt,
+                wrapped_key,
+            },
+            data_key,
+        ))
+    }
+
+    /// Unwraps the data key with the given secret.
+    pub fn unwrap_key(&self, secret: Secret) -> BackupResult<[u8; AES_KEY_SIZE]> {
+        let data_key = aes_decrypt(self.kdf.derive_key(secret, &self.salt)?, &self.wrapped_key)?;
+
+        data_key
+            .try_into()
+            .map_err(|_| BackupError::InvalidHeader("wrapped key has an invalid size".to_owned()))
+    }
+}
+
+/// The header of a backup file.
+#[derive(Debug, Clone)]
+pub struct BackupHeader {
+    /// The version of the file format the backup is stored in.
+    pub version: u8,
+    /// The size of each unencrypted chunk of the payload.
+    pub chunk_size: u64,
+    /// The wrapped copies of the data key, one per password.
+    pub slots: Vec<KeySlot>,
+    /// The algorithm used to compute the checksum of the payload.
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Whether the archive was compressed with zstd before it was encrypted.
+    pub compressed: bool,
+    /// Whether and how an index of the backup's contents follows the
+    /// payload.
+    pub index: IndexMode,
+    /// Whether the index records where each entry begins in the archive.
+    pub index_offsets: bool,
+    /// Whether each chunk of the payload is encrypted with a key derived
+    /// from the data key and the chunk's index, rather than the data key
+    /// itself.
+    pub chunk_keys: bool,
+    /// When the backup was created, to the second. Headers from before
+    /// version 5 record the Unix epoch.
+    pub created: DateTime<Utc>,
+    /// The version of the tool that created the backup. Headers from before
+    /// version 5 record an empty string.
+    pub tool_version: String,
+    /// A tag over the metadata above, produced with the data key.
+    pub metadata_tag: [u8; METADATA_TAG_SIZE],
+}
+
+impl BackupHeader {
+    /// Creates a header for a backup that can be opened by any of the given
+    /// secrets. Passwords are turned into keys with the given key derivation
+    /// function. The header is sealed, so [`Self::seal`] only needs to be
+    /// called again if its metadata is changed.
+    pub fn new(
+        data_key: [u8; AES_KEY_SIZE],
+        secrets: &[Secret],
+        chunk_size: u64,
+        password_kdf: Kdf,
+    ) -> BackupResult<Self> {
+        Self::validate_secret_count(secrets)?;
+
+        let slots = secrets
+            .iter()
+            .map(|&secret| KeySlot::new(secret, data_key, secret.kdf(password_kdf)))
+            .collect::<BackupResult<Vec<_>>>()?;
+
+        Self::with_slots(data_key, slots, chunk_size, Utc::now())
+    }
+
+    /// Creates a header for a deterministic backup, which is identical every
+    /// time it is created from the same secrets and parameters. The data key
+    /// is derived from the first secret rather than generated, and is
+    /// returned along with the header. The creation time is recorded as the
+    /// Unix epoch.
+    pub fn new_deterministic(
+        secrets: &[Secret],
+        chunk_size: u64,
+        password_kdf: Kdf,
+    ) -> BackupResult<(Self, [u8; AES_KEY_SIZE])> {
+        Self::validate_secret_count(secrets)?;
+
+        let mut data_key = None;
+        let slots = secrets
+            .iter()
+            .map(|&secret| {
+                let (slot, key) =
+                    KeySlot::new_deterministic(secret, data_key, secret.kdf(password_kdf))?;
+                data_key = Some(key);
+                Ok(slot)
+            })
+            .collect::<BackupResult<Vec<_>>>()?;
+        let data_key = data_key.ok_or_else(|| {
+            BackupError::InvalidHeader("at least one password is required".to_owned())
+        })?;
+
+        let header = Self::with_slots(data_key, slots, chunk_size, DateTime::UNIX_EPOCH)?;
+
+        Ok((header, data_key))
+    }
+
+    /// Checks that the number of secrets fits in the header.
+    fn validate_secret_count(secrets: &[Secret]) -> BackupResult<()> {
+        if secrets.len() > usize::from(u8::MAX) {
+            return Err(BackupError::InvalidHeader(format!(
+                "at most {} passwords are supported",
+                u8::MAX
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Adds a key slot that the private key matching the recovery public key
+    /// opens. The slots are not covered by the metadata tag, so the header
+    /// does not need to be sealed again.
+    pub fn add_recovery_slot(
+        &mut self,
+        public_key: &[u8; RECOVERY_KEY_SIZE],
+        data_key: [u8; AES_KEY_SIZE],
+    ) -> BackupResult<()> {
+        if self.slots.len() >= usize::from(u8::MAX) {
+            return Err(BackupError::InvalidHeader(format!(
+                "at most {} passwords are supported, including the recovery key",
+                u8::MAX
+            )));
+        }
+
+        self.slots
+            .push(KeySlot::new_recovery(public_key, data_key)?);
+
+        Ok(())
+    }
+
+    /// Creates a sealed header from its key slots.
+    fn with_slots(
+        data_key: [u8; AES_KEY_SIZE],
+        slots: Vec<KeySlot>,
+        chunk_size: u64,
+        created: DateTime<Utc>,
+    ) -> BackupResult<Self> {
+        let mut header = Self {
+            version: FORMAT_VERSION,
+            chunk_size,
+            slots,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            compressed: false,
+            index: IndexMode::None,
+            index_offsets: false,
+            chunk_keys: false,
+            created: DateTime::from_timestamp(created.timestamp(), 0).unwrap(),
+            tool_version: TOOL_VERSION.to_owned(),
+            metadata_tag: [0; METADATA_TAG_SIZE],
+        };
+        header.seal(data_key)?;
+
+        Ok(header)
+    }
+
+    /// Returns whether the header records when and by what version of the
+    /// tool the backup was created.
+    pub const fn has_metadata(&self) -> bool {
+        self.version >= METADATA_FORMAT_VERSION
+    }
+
+    /// Encodes the metadata that the metadata tag covers. The key slots are
+    /// left out, since each one is already authenticated on its own.
+    fn metadata_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.version];
+        bytes.extend(self.chunk_size.to_be_bytes());
+        bytes.push(self.checksum_algorithm.id());
+        bytes.push(self.flags());
+        bytes.extend(self.created.timestamp().to_be_bytes());
+        bytes.extend(self.tool_version.as_bytes());
+        bytes
+    }
+
+    /// Computes the metadata tag with the data key. This must be called after
+    /// any of the metadata is changed, or the header will fail to
+    /// authenticate when the backup is opened.
+    ///
+    /// The nonce is derived from the metadata, so the tag is reproducible.
+    /// Nothing is encrypted under it, and different metadata never shares a
+    /// nonce.
+    pub fn seal(&mut self, data_key: [u8; AES_KEY_SIZE]) -> BackupResult<()> {
+        let metadata = self.metadata_bytes();
+        let nonce = derived_nonce(data_key, b"metadata", &metadata);
+        self.metadata_tag = aes_encrypt_with_nonce(data_key, nonce, &[], &metadata)?
+            .try_into()
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Checks the metadata tag with the data key, so that metadata that was
+    /// tampered with is not trusted. Headers from before version 5 have no
+    /// tag to check.
+    pub fn authenticate(&self, data_key: [u8; AES_KEY_SIZE]) -> BackupResult<()> {
+        if !self.has_metadata() {
+            return Ok(());
+        }
+
+        aes_decrypt_with_aad(data_key, &self.metadata_tag, &self.metadata_bytes())
+            .map(|_| ())
+            .map_err(|_| BackupError::InvalidHeader("metadata failed authentication".to_owned()))
+    }
+
+    /// Returns the payload flags.
+    const fn flags(&self) -> u8 {
+        let compressed = if self.compressed { FLAG_COMPRESSED } else { 0 };
+        let index = match self.index {
+            IndexMode::None => 0,
+            IndexMode::Plain => FLAG_INDEX,
+            IndexMode::Encrypted => FLAG_INDEX | FLAG_INDEX_ENCRYPTED,
+        };
+        let index_offsets = if self.index_offsets {
+            FLAG_INDEX_OFFSETS
+        } else {
+            0
+        };
+        let chunk_keys = if self.chunk_keys { FLAG_CHUNK_KEYS } else { 0 };
+
+        compressed | index | index_offsets | chunk_keys
+    }
+
+    /// Returns whether the payload is followed by a checksum trailer.
+    pub const fn has_checksum(&self) -> bool {
+        self.version >= CHECKSUM_FORMAT_VERSION
+    }
+
+    /// Unwraps the data key by trying the secret against each key slot, and
+    /// authenticates the metadata with it. A wrong secret fails here, before
+    /// any of the payload is decrypted, so it can be told apart from
+    /// corrupted data.
+    pub fn unwrap_key(&self, secret: Secret) -> BackupResult<[u8; AES_KEY_SIZE]> {
+        let data_key = self
+            .slots
+            .iter()
+            .find_map(|slot| slot.unwrap_key(secret).ok())
+            .ok_or(BackupError::IncorrectPassword)?;
+        self.authenticate(data_key)?;
+
+        Ok(data_key)
+    }
+
+    /// Replaces the key slot that the old password opens with one for the new
+    /// password, leaving the data key unchanged. The new slot uses the given
+    /// key derivation function, or the old slot's if none is given. Returns
+    /// the data key.
+    ///
+    /// The passwords of a deterministic backup cannot be replaced, since its
+    /// data key is derived from its first password rather than only wrapped
+    /// by it. Such a backup is recognised by the salt of the slot, which is
+    /// derived from the password instead of being random.
+    pub fn replace_password(
+        &mut self,
+        old_password: &str,
+        new_password: &str,
+        kdf: Option<Kdf>,
+    ) -> BackupResult<[u8; AES_KEY_SIZE]> {
+        let (index, data_key) = self
+            .slots
+            .iter()
+            .enumerate()
+            .find_map(|(index, slot)| {
+                Some((index, slot.unwrap_key(Secret::Password(old_password)).ok()?))
+            })
+            .ok_or(BackupError::IncorrectPassword)?;
+
+        if self.slots[index].salt == Secret::Password(old_password).derived_salt() {
+            return Err(BackupError::DeterministicPasswordChange);
+        }
+
+        self.slots[index] = KeySlot::new(
+            Secret::Password(new_password),
+            data_key,
+            kdf.unwrap_or(self.slots[index].kdf),
+        )?;
+
+        Ok(data_key)
+    }
+
+    /// Upgrades the header to the current format version, resealing it with
+    /// the data key. The metadata is authenticated first, so that tampered
+    /// metadata is never sealed as if it were genuine. Metadata that older
+    /// versions did not record keeps the value they are read with.
+    ///
+    /// A header from before [`CHECKSUM_FORMAT_VERSION`] describes a payload
+    /// without a checksum trailer, which must be added for the upgraded
+    /// header to describe it correctly.
+    pub fn upgrade(&mut self, data_key: [u8; AES_KEY_SIZE]) -> BackupResult<()> {
+        self.authenticate(data_key)?;
+        self.version = FORMAT_VERSION;
+        self.seal(data_key)
+    }
+
+    /// Encodes the header.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.chunk_size.to_be_bytes());
+        bytes.push(u8::try_from(self.slots.len()).unwrap());
+
+        for slot in &self.slots {
+            slot.kdf.encode(&mut bytes);
+            bytes.extend(slot.salt);
+            bytes.extend(slot.wrapped_key);
+        }
+
+        if self.version >= CHECKSUM_ALGORITHM_FORMAT_VERSION {
+            bytes.push(self.checksum_algorithm.id());
+        }
+
+        if self.version >= FLAGS_FORMAT_VERSION {
+            bytes.push(self.flags());
+        }
+
+        if self.has_metadata() {
+            bytes.extend(self.created.timestamp().to_be_bytes());
+            bytes.push(u8::try_from(self.tool_version.len()).unwrap());
+            bytes.extend(self.tool_version.as_bytes());
+            bytes.extend(self.metadata_tag);
+        }
+
+        bytes
+    }
+
+    /// Decodes a header stored in the given format version.
+    fn from_bytes(version: u8, bytes: &[u8]) -> BackupResult<Self> {
+        let mut reader = HeaderReader(bytes);
+        let chunk_size = u64::from_be_bytes(reader.take()?);
+        let [slot_count] = reader.take()?;
+        let slots = (0..slot_count)
+            .map(|_| {
+                Ok(KeySlot {
+                    kdf: Kdf::decode(&mut reader)?,
+                    salt: reader.take()?,
+                    wrapped_key: reader.take()?,
+                })
+            })
+            .collect::<BackupResult<Vec<_>>>()?;
+        let checksum_algorithm = if version >= CHECKSUM_ALGORITHM_FORMAT_VERSION {
+            let [id] = reader.take()?;
+            ChecksumAlgorithm::from_id(id).ok_or_else(|| {
+                BackupError::InvalidHeader(format!("unknown checksum algorithm {id}"))
+            })?
+        } else {
+            ChecksumAlgorithm::Sha256
+        };
+        let flags = if version >= FLAGS_FORMAT_VERSION {
+            let [flags] = reader.take()?;
+            flags
+        } else {
+            0
+        };
+
+        if flags & !KNOWN_FLAGS != 0
+            || flags & (FLAG_INDEX | FLAG_INDEX_ENCRYPTED) == FLAG_INDEX_ENCRYPTED
+            || flags & (FLAG_INDEX | FLAG_INDEX_OFFSETS) == FLAG_INDEX_OFFSETS
+        {
+            return Err(BackupError::InvalidHeader(format!(
+                "unknown payload flags {flags:#04x}"
+            )));
+        }
+
+        let (created, tool_version, metadata_tag) = if version >= METADATA_FORMAT_VERSION {
+            let timestamp = i64::from_be_bytes(reader.take()?);
+            let created = DateTime::from_timestamp(timestamp, 0).ok_or_else(|| {
+                BackupError::InvalidHeader(format!("invalid creation time {timestamp}"))
+            })?;
+            let [tool_version_len] = reader.take()?;
+            let tool_version = String::from_utf8(
+                reader.take_slice(tool_version_len.into())?.to_vec(),
+            )
+            .map_err(|_| BackupError::InvalidHeader("tool version is not UTF-8".to_owned()))?;
+
+            (created, tool_version, reader.take()?)
+        } else {
+            (DateTime::UNIX_EPOCH, String::new(), [0; METADATA_TAG_SIZE])
+        };
+
+        if !reader.0.is_empty() {
+            return Err(BackupError::InvalidHeader(
+                "unexpected data at the end of the header".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            version,
+            chunk_size,
+            slots,
+            checksum_algorithm,
+            compressed: flags & FLAG_COMPRESSED != 0,
+            index: if flags & FLAG_INDEX_ENCRYPTED != 0 {
+                IndexMode::Encrypted
+            } else if flags & FLAG_INDEX != 0 {
+                IndexMode::Plain
+            } else {
+                IndexMode::None
+            },
+            index_offsets: flags & FLAG_INDEX_OFFSETS != 0,
+            chunk_keys: flags & FLAG_CHUNK_KEYS != 0,
+            created,
+            tool_version,
+            metadata_tag,
+        })
+    }
+
+    /// Writes the header to the start of a backup file.
+    pub fn write(&self, dest: &mut impl Write) -> io::Result<()> {
+        dest.write_all(MAGIC)?;
+        dest.write_all(&[self.version])?;
+        write_section(dest, &self.to_bytes())
+    }
+
+    /// Reads the header from the start of a backup file, leaving the file
+    /// positioned at the start of the encrypted payload. Backups created
+    /// before headers were introduced have no header, in which case `None` is
+    /// returned and the file is left positioned at its start.
+    pub fn read(src: &mut (impl Read + Seek)) -> BackupResult<Option<Self>> {
+        let (header, _) = Self::read_unseekable(src)?;
+
+        if header.is_none() {
+            src.seek(SeekFrom::Start(0))?;
+        }
+
+        Ok(header)
+    }
+
+    /// Reads the header from the start of a backup stream that cannot be
+    /// rewound. Backups created before headers were introduced have no header,
+    /// in which case `None` is returned along with the bytes that were read
+    /// while looking for it, which belong to the encrypted payload.
+    pub fn read_unseekable(src: &mut impl Read) -> BackupResult<(Option<Self>, Vec<u8>)> {
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        src.take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+
+        if magic != MAGIC {
+            return Ok((None, magic));
+        }
+
+        let mut version = [0u8; 1];
+        src.read_exact(&mut version)?;
+
+        if !supported_format_versions().contains(&version[0]) {
+            return Err(BackupError::UnsupportedFormatVersion(version[0]));
+        }
+
+        let bytes = read_section(src)?
+            .ok_or_else(|| BackupError::InvalidHeader("header is missing".to_owned()))?;
+
+        Ok((Some(Self::from_bytes(version[0], &bytes)?), Vec::new()))
+    }
+}
+
+/// A cursor over the bytes of an encoded header.
+struct HeaderReader<'a>(&'a [u8]);
+
+impl HeaderReader<'_> {
+    /// Takes a fixed number of bytes from the front of the header.
+    fn take<const N: usize>(&mut self) -> BackupResult<[u8; N]> {
+        Ok(self.take_slice(N)?.try_into().unwrap())
+    }
+
+    /// Takes a variable number of bytes from the front of the header.
+    fn take_slice(&mut self, len: usize) -> BackupResult<&[u8]> {
+        if self.0.len() < len {
+            return Err(BackupError::InvalidHeader("header is truncated".to_owned()));
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(taken)
+    }
+}
+
+/// Header tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Cheap parameters, so that tests run quickly.
+    const TEST_KDF: Kdf = Kdf::Argon2(Argon2Params {
+        memory_kib: Argon2Params::MIN_MEMORY_KIB,
+        iterations: 1,
+        parallelism: 1,
+    });
+
+    #[test]
+    fn test_header_roundtrip() {
+        let data_key = random_key();
+        let mut header = BackupHeader::new(
+            data_key,
+            &[
+                Secret::Password("password123"),
+                Secret::Password("hunter22"),
+            ],
+            1 << 16,
+            TEST_KDF,
+        )
+        .unwrap();
+        header.checksum_algorithm = ChecksumAlgorithm::Blake3;
+        header.compressed = true;
+        header.index = IndexMode::Encrypted;
+        header.index_offsets = true;
+        header.chunk_keys = true;
+        header.seal(data_key).unwrap();
+
+        let mut bytes = Cursor::new(Vec::new());
+        header.write(&mut bytes).unwrap();
+        bytes.write_all(b"payload").unwrap();
+        bytes.set_position(0);
+
+        let read_header = BackupHeader::read(&mut bytes).unwrap().unwrap();
+        assert_eq!(read_header.version, FORMAT_VERSION);
+        assert!(read_header.has_checksum());
+        assert_eq!(read_header.checksum_algorithm, ChecksumAlgorithm::Blake3);
+        assert!(read_header.compressed);
+        assert_eq!(read_header.index, IndexMode::Encrypted);
+        assert!(read_header.index_offsets);
+        assert!(read_header.chunk_keys);
+        assert!(read_header.has_metadata());
+        assert_eq!(read_header.created, header.created);
+        assert_eq!(read_header.tool_version, TOOL_VERSION);
+        assert_eq!(read_header.chunk_size, 1 << 16);
+        assert_eq!(read_header.slots.len(), 2);
+        assert_eq!(
+            read_header
+                .unwrap_key(Secret::Password("password123"))
+                .unwrap(),
+            data_key
+        );
+        assert_eq!(
+            read_header
+                .unwrap_key(Secret::Password("hunter22"))
+                .unwrap(),
+            data_key
+        );
+        assert!(matches!(
+            read_header.unwrap_key(Secret::Password("password124")),
+            Err(BackupError::IncorrectPassword)
+        ));
+
+        let mut payload = Vec::new();
+        bytes.read_to_end(&mut payload).unwrap();
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn test_raw_key_slot() {
+        let data_key = random_key();
+        let raw_key = random_key();
+        let header = BackupHeader::new(
+            data_key,
+            &[Secret::Key(&raw_key), Secret::Password("password123")],
+            1024,
+            TEST_KDF,
+        )
+        .unwrap();
+        assert_eq!(header.slots[0].kdf, Kdf::Raw);
+        assert_eq!(header.slots[1].kdf, TEST_KDF);
+
+        let mut bytes = Cursor::new(Vec::new());
+        header.write(&mut bytes).unwrap();
+        bytes.set_position(0);
+        let header = BackupHeader::read(&mut bytes).unwrap().unwrap();
+
+        assert_eq!(header.unwrap_key(Secret::Key(&raw_key)).unwrap(), data_key);
+        assert_eq!(
+            header.unwrap_key(Secret::Password("password123")).unwrap(),
+            data_key
+        );
+        assert!(matches!(
+            header.unwrap_key(Secret::Key(&random_key())),
+            Err(BackupError::IncorrectPassword)
+        ));
+    }
+
+    #[test]
+    fn test_recovery_key_slot() {
+        let data_key = random_key();
+        let (private_key, public_key) = generate_recovery_key();
+        let mut header =
+            BackupHeader::new(data_key, &[Secret::Password("password123")], 1024, TEST_KDF)
+                .unwrap();
+        header.add_recovery_slot(&public_key, data_key).unwrap();
+        assert!(matches!(header.slots[1].kdf, Kdf::X25519 { .. }));
+
+        let mut bytes = Cursor::new(Vec::new());
+        header.write(&mut bytes).unwrap();
+        bytes.set_position(0);
+        let header = BackupHeader::read(&mut bytes).unwrap().unwrap();
+
+        // Either the password or the recovery key opens the backup
+        assert_eq!(
+            header
+                .unwrap_key(Secret::RecoveryKey(&private_key))
+                .unwrap(),
+            data_key
+        );
+        assert_eq!(
+            header.unwrap_key(Secret::Password("password123")).unwrap(),
+            data_key
+        );
+        let (other_private_key, _) = generate_recovery_key();
+        assert!(matches!(
+            header.unwrap_key(Secret::RecoveryKey(&other_private_key)),
+            Err(BackupError::IncorrectPassword)
+        ));
+        assert!(matches!(
+            header.unwrap_key(Secret::Key(&private_key)),
+            Err(BackupError::IncorrectPassword)
+        ));
+    }
+
+    #[test]
+    fn test_metadata_authentication() {
+        let data_key = random_key();
+        let mut header =
+            BackupHeader::new(data_key, &[Secret::Password("password123")], 1024, TEST_KDF)
+                .unwrap();
+
+        // Metadata changed without the data key is rejected once the backup
+        // is opened
+        header.tool_version = "0.0.0".to_owned();
+        let mut bytes = Cursor::new(Vec::new());
+        header.write(&mut bytes).unwrap();
+        bytes.set_position(0);
+        let read_header = BackupHeader::read(&mut bytes).unwrap().unwrap();
+        assert_eq!(read_header.tool_version, "0.0.0");
+        assert!(matches!(
+            read_header.unwrap_key(Secret::Password("password123")),
+            Err(BackupError::InvalidHeader(_))
+        ));
+
+        // Resealing with the data key makes it valid again
+        header.seal(data_key).unwrap();
+        let mut bytes = Cursor::new(Vec::new());
+        header.write(&mut bytes).unwrap();
+        bytes.set_position(0);
+        let read_header = BackupHeader::read(&mut bytes).unwrap().unwrap();
+        assert_eq!(
+            read_header
+                .unwrap_key(Secret::Password("password123"))
+                .unwrap(),
+            data_key
+        );
+    }
+
+    #[test]
+    fn test_replace_password() {
+        let data_key = random_key();
+        let mut header = BackupHeader::new(
+            data_key,
+            &[
+                Secret::Password("password123"),
+                Secret::Password("hunter22"),
+            ],
+            1024,
+            TEST_KDF,
+        )
+        .unwrap();
+        let encoded_len = header.to_bytes().len();
+
+        assert_eq!(
+            header
+                .replace_password("hunter22", "hunter23", None)
+                .unwrap(),
+            data_key
+        );
+        assert_eq!(header.to_bytes().len(), encoded_len);
+        assert_eq!(
+            header.unwrap_key(Secret::Password("password123")).unwrap(),
+            data_key
+        );
+        assert_eq!(
+            header.unwrap_key(Secret::Password("hunter23")).unwrap(),
+            data_key
+        );
+        assert!(header.unwrap_key(Secret::Password("hunter22")).is_err());
+        assert!(matches!(
+            header.replace_password("hunter22", "hunter24", None),
+            Err(BackupError::IncorrectPassword)
+        ));
+
+        // The key derivation function of the replaced slot can be changed
+        assert_eq!(header.slots[1].kdf, TEST_KDF);
+        header
+            .replace_password("hunter23", "hunter24", Some(Kdf::Sha256))
+            .unwrap();
+        assert_eq!(header.slots[0].kdf, TEST_KDF);
+        assert_eq!(header.slots[1].kdf, Kdf::Sha256);
+        assert_eq!(
+            header.unwrap_key(Secret::Password("hunter24")).unwrap(),
+            data_key
+        );
+
+        // No password of a deterministic backup can be replaced
+        let (mut header, _) = BackupHeader::new_deterministic(
+            &[
+                Secret::Password("password123"),
+                Secret::Password("hunter22"),
+            ],
+            1024,
+            TEST_KDF,
+        )
+        .unwrap();
+        let original = header.to_bytes();
+
+        for password in ["password123", "hunter22"] {
+            assert!(matches!(
+                header.replace_password(password, "hunter23", None),
+                Err(BackupError::DeterministicPasswordChange)
+            ));
+        }
+        assert_eq!(header.to_bytes(), original);
+    }
+
+    #[test]
+    fn test_header_upgrade() {
+        let data_key = random_key();
+        let mut header =
+            BackupHeader::new(data_key, &[Secret::Password("password123")], 1024, TEST_KDF)
+                .unwrap();
+        header.version = CHECKSUM_FORMAT_VERSION;
+
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        let mut old_header = BackupHeader::read(&mut Cursor::new(bytes))
+            .unwrap()
+            .unwrap();
+        assert!(!old_header.has_metadata());
+
+        old_header.upgrade(data_key).unwrap();
+        assert_eq!(old_header.version, FORMAT_VERSION);
+        assert_eq!(old_header.created, DateTime::UNIX_EPOCH);
+
+        let mut bytes = Vec::new();
+        old_header.write(&mut bytes).unwrap();
+        let upgraded_header = BackupHeader::read(&mut Cursor::new(bytes))
+            .unwrap()
+            .unwrap();
+        assert!(upgraded_header.has_metadata());
+        assert_eq!(
+            upgraded_header
+                .unwrap_key(Secret::Password("password123"))
+                .unwrap(),
+            data_key
+        );
+
+        // Tampered metadata is not resealed
+        let mut tampered_header = upgraded_header;
+        tampered_header.chunk_size = 2048;
+        assert!(matches!(
+            tampered_header.upgrade(data_key),
+            Err(BackupError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_header_missing() {
+        let mut bytes = Cursor::new(b"not a header".to_vec());
+        assert!(BackupHeader::read(&mut bytes).unwrap().is_none());
+        assert_eq!(bytes.position(), 0);
+
+        let mut bytes = Cursor::new(b"EB".to_vec());
+        assert!(BackupHeader::read(&mut bytes).unwrap().is_none());
+        assert_eq!(bytes.position(), 0);
+    }
+
+    #[test]
+    fn test_header_invalid() {
+        let header = BackupHeader::new(
+            random_key(),
+            &[Secret::Password("password123")],
+            1024,
+            TEST_KDF,
+        )
+        .unwrap();
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+
+        let mut truncated = Cursor::new(bytes[..bytes.len() - 1].to_vec());
+        assert!(BackupHeader::read(&mut truncated).is_err());
+
+        let mut future_version = bytes.clone();
+        future_version[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            BackupHeader::read(&mut Cursor::new(future_version)),
+            Err(BackupError::UnsupportedFormatVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+
+        let mut zero_version = bytes.clone();
+        zero_version[MAGIC.len()] = 0;
+        assert!(matches!(
+            BackupHeader::read(&mut Cursor::new(zero_version)),
+            Err(BackupError::UnsupportedFormatVersion(0))
+        ));
+        assert!(!supported_format_versions().contains(&0));
+        assert_eq!(*supported_format_versions().end(), FORMAT_VERSION);
+
+        // Older versions are still readable, but have no checksum
+        let mut first_version = header;
+        first_version.version = MIN_FORMAT_VERSION;
+        let mut bytes = Vec::new();
+        first_version.write(&mut bytes).unwrap();
+        assert_eq!(bytes[MAGIC.len()], MIN_FORMAT_VERSION);
+        let read_header = BackupHeader::read(&mut Cursor::new(bytes))
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_header.version, MIN_FORMAT_VERSION);
+        assert!(!read_header.has_checksum());
+    }
+}
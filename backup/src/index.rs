@@ -0,0 +1,369 @@
+//! Indexes of the contents of backups.
+//!
+//! An index lists the path, type and size of every entry in the archive. It
+//! is written after the section marking the end of the encrypted payload,
+//! followed by its own length, so that it can be found from the end of the
+//! backup without reading the payload. Both are covered by the checksum, and
+//! older versions of the tool never read past the end of the payload.
+//!
+//! A plain index is followed by a tag that authenticates it with the data
+//! key, while an encrypted index is authenticated by its encryption. Either
+//! way, an index that was tampered with is only detected with the password.
+//!
+//! An index can also record where each entry begins in the archive. Every
+//! chunk of the payload but the last holds exactly the chunk size, so the
+//! chunk containing any offset into an uncompressed archive can be found
+//! without reading the ones before it, and single entries can be extracted
+//! without decrypting the whole payload.
+
+use crate::backup_crypto::*;
+use crate::crypto::*;
+use crate::manifest::{bytes_to_path, path_to_bytes};
+use crate::options::IndexMode;
+use crate::types::*;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The number of bytes used to store the length of the index, which follows
+/// it.
+pub const INDEX_LEN_SIZE: usize = 8;
+
+/// The size of the tag authenticating a plain index.
+const INDEX_TAG_SIZE: usize = AES_NONCE_SIZE + AES_TAG_SIZE;
+
+/// The context from which the nonce authenticating a plain index is derived.
+const INDEX_TAG_CONTEXT: &[u8] = b"index tag";
+
+/// The context from which the nonce encrypting a deterministic backup's
+/// index is derived.
+const INDEX_CONTEXT: &[u8] = b"index";
+
+/// The size of the fixed-length part of each encoded entry: its type, size
+/// and the length of its path.
+const ENTRY_FIXED_SIZE: usize = 1 + 8 + 4;
+
+/// The size of the offset stored with each entry, when offsets are recorded.
+const ENTRY_OFFSET_SIZE: usize = 8;
+
+/// The entries of a backup's archive, in the order they were archived.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveIndex {
+    /// The entries recorded so far.
+    entries: Vec<IndexEntry>,
+    /// The offset into the archive at which each entry begins, if offsets are
+    /// being recorded.
+    offsets: Option<Vec<u64>>,
+}
+
+impl ArchiveIndex {
+    /// Creates an empty index that records where each entry begins in the
+    /// archive.
+    pub const fn with_offsets() -> Self {
+        Self {
+            entries: Vec::new(),
+            offsets: Some(Vec::new()),
+        }
+    }
+
+    /// Records an entry at the given archive path, beginning at the given
+    /// offset into the archive. The offset is ignored unless offsets are
+    /// being recorded.
+    pub fn push(&mut self, path: &Path, kind: EntryKind, size: u64, offset: u64) {
+        self.entries.push(IndexEntry {
+            path: path.to_path_buf(),
+            kind,
+            size,
+        });
+
+        if let Some(offsets) = &mut self.offsets {
+            offsets.push(offset);
+        }
+    }
+
+    /// Returns the recorded entries.
+    pub fn into_entries(self) -> Vec<IndexEntry> {
+        self.entries
+    }
+
+    /// Returns the recorded entries along with the offset into the archive at
+    /// which each one begins, or `None` if offsets were not recorded.
+    pub fn into_entries_with_offsets(self) -> Option<Vec<(IndexEntry, u64)>> {
+        let entries = self.entries;
+        self.offsets
+            .map(|offsets| entries.into_iter().zip(offsets).collect())
+    }
+
+    /// Encodes the entries. Each is stored as its type, its size and the
+    /// length of its path, followed by its offset if offsets are recorded,
+    /// and then the path itself.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let path = path_to_bytes(&entry.path);
+            bytes.push(kind_id(entry.kind));
+            bytes.extend(entry.size.to_be_bytes());
+            bytes.extend(u32::try_from(path.len()).unwrap_or(u32::MAX).to_be_bytes());
+
+            if let Some(offsets) = &self.offsets {
+                bytes.extend(offsets[i].to_be_bytes());
+            }
+
+            bytes.extend(path);
+        }
+
+        bytes
+    }
+
+    /// Decodes entries encoded with [`Self::encode`], with offsets if they
+    /// were recorded.
+    fn decode(mut bytes: &[u8], with_offsets: bool) -> BackupResult<Self> {
+        let malformed = || BackupError::InvalidIndex("malformed entry".to_owned());
+        let mut index = if with_offsets {
+            Self::with_offsets()
+        } else {
+            Self::default()
+        };
+        let offset_size = if with_offsets { ENTRY_OFFSET_SIZE } else { 0 };
+
+        while !bytes.is_empty() {
+            let (fixed, rest) = bytes
+                .split_at_checked(ENTRY_FIXED_SIZE + offset_size)
+                .ok_or_else(malformed)?;
+            let kind = kind_from_id(fixed[0]).ok_or_else(malformed)?;
+            let size = u64::from_be_bytes(fixed[1..9].try_into().unwrap());
+            let path_len = u32::from_be_bytes(fixed[9..13].try_into().unwrap());
+            let offset = fixed
+                .get(ENTRY_FIXED_SIZE..)
+                .and_then(|offset| offset.try_into().ok())
+                .map_or(0, u64::from_be_bytes);
+            let (path, rest) = rest
+                .split_at_checked(path_len as usize)
+                .ok_or_else(malformed)?;
+
+            index.push(&bytes_to_path(path), kind, size, offset);
+            bytes = rest;
+        }
+
+        Ok(index)
+    }
+
+    /// Encodes the index to be stored in a backup with the data key. A plain
+    /// index is followed by a tag authenticating it, while an encrypted one
+    /// is encrypted like a chunk of the payload.
+    pub fn seal(
+        &self,
+        mode: IndexMode,
+        key: [u8; AES_KEY_SIZE],
+        nonce_mode: NonceMode,
+    ) -> BackupResult<Vec<u8>> {
+        let mut encoded = self.encode();
+
+        match mode {
+            IndexMode::None => Ok(Vec::new()),
+            IndexMode::Plain => {
+                let nonce = derived_nonce(key, INDEX_TAG_CONTEXT, &encoded);
+                let tag = aes_encrypt_with_nonce(key, nonce, &[], &encoded)?;
+                encoded.extend(tag);
+                Ok(encoded)
+            }
+            IndexMode::Encrypted => match nonce_mode {
+                NonceMode::Random => aes_encrypt(key, &encoded),
+                NonceMode::Derived => {
+                    let nonce = derived_nonce(key, INDEX_CONTEXT, &encoded);
+                    aes_encrypt_with_nonce(key, nonce, &encoded, &[])
+                }
+            },
+        }
+    }
+
+    /// Decodes an index stored in a backup, which records offsets if
+    /// `with_offsets` is set. An encrypted index can only be decoded with the
+    /// data key. A plain index is authenticated if the key is given, and
+    /// trusted as it is otherwise.
+    pub fn open(
+        data: &[u8],
+        mode: IndexMode,
+        key: Option<[u8; AES_KEY_SIZE]>,
+        with_offsets: bool,
+    ) -> BackupResult<Self> {
+        let failed = |_| BackupError::InvalidIndex("index failed authentication".to_owned());
+
+        match mode {
+            IndexMode::None => Err(BackupError::MissingIndex),
+            IndexMode::Plain => {
+                let split = data
+                    .len()
+                    .checked_sub(INDEX_TAG_SIZE)
+                    .ok_or_else(|| BackupError::InvalidIndex("index is too short".to_owned()))?;
+                let (encoded, tag) = data.split_at(split);
+
+                if let Some(key) = key {
+                    aes_decrypt_with_aad(key, tag, encoded).map_err(failed)?;
+                }
+
+                Self::decode(encoded, with_offsets)
+            }
+            IndexMode::Encrypted => {
+                let key = key.ok_or(BackupError::PasswordRequired)?;
+
+                if data.len() < AES_NONCE_SIZE {
+                    return Err(BackupError::InvalidIndex("index is too short".to_owned()));
+                }
+
+                Self::decode(&aes_decrypt(key, data).map_err(failed)?, with_offsets)
+            }
+        }
+    }
+}
+
+/// Returns the identifier an entry type is encoded as.
+const fn kind_id(kind: EntryKind) -> u8 {
+    match kind {
+        EntryKind::File => 0,
+        EntryKind::Directory => 1,
+        EntryKind::Symlink => 2,
+        EntryKind::HardLink => 3,
+        EntryKind::Special => 4,
+    }
+}
+
+/// Returns the entry type with the given identifier, if there is one.
+const fn kind_from_id(id: u8) -> Option<EntryKind> {
+    match id {
+        0 => Some(EntryKind::File),
+        1 => Some(EntryKind::Directory),
+        2 => Some(EntryKind::Symlink),
+        3 => Some(EntryKind::HardLink),
+        4 => Some(EntryKind::Special),
+        _ => None,
+    }
+}
+
+/// Reads the stored index from the end of a backup, without reading the
+/// payload. The payload begins at the given offset, and the index must lie
+/// after it.
+pub fn read_index_section(
+    file: &mut (impl Read + Seek),
+    payload_offset: u64,
+) -> BackupResult<Vec<u8>> {
+    let too_short = || BackupError::InvalidIndex("backup is too short".to_owned());
+
+    let len_offset = file
+        .seek(SeekFrom::End(0))?
+        .checked_sub((CHECKSUM_SIZE + INDEX_LEN_SIZE) as u64)
+        .filter(|&offset| offset >= payload_offset)
+        .ok_or_else(too_short)?;
+
+    let mut len_buffer = [0u8; INDEX_LEN_SIZE];
+    file.seek(SeekFrom::Start(len_offset))?;
+    file.read_exact(&mut len_buffer)?;
+    let index_len = u64::from_be_bytes(len_buffer);
+
+    let index_offset = len_offset
+        .checked_sub(index_len)
+        .filter(|&offset| offset >= payload_offset)
+        .ok_or_else(too_short)?;
+    file.seek(SeekFrom::Start(index_offset))?;
+
+    match read_section(&mut file.take(index_len))? {
+        Some(data) if (data.len() + LEN_SIZE) as u64 == index_len => Ok(data),
+        _ => Err(BackupError::InvalidIndex(
+            "index length does not match".to_owned(),
+        )),
+    }
+}
+
+/// Index tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        let mut index = ArchiveIndex::default();
+        index.push(Path::new("dir"), EntryKind::Directory, 0, 0);
+        index.push(Path::new("dir/file.txt"), EntryKind::File, 1234, 512);
+        index.push(Path::new("dir/link"), EntryKind::Symlink, 0, 2048);
+        index.push(Path::new("dir/hard"), EntryKind::HardLink, 0, 2560);
+        index.push(Path::new("dir/fifo"), EntryKind::Special, 0, 3072);
+        let key = random_key();
+        let other_key = random_key();
+
+        // A plain index can be read with or without the key, but not with the
+        // wrong one
+        let data = index
+            .seal(IndexMode::Plain, key, NonceMode::Random)
+            .unwrap();
+        let opened = ArchiveIndex::open(&data, IndexMode::Plain, None, false).unwrap();
+        assert_eq!(opened, index);
+        let opened = ArchiveIndex::open(&data, IndexMode::Plain, Some(key), false).unwrap();
+        assert_eq!(opened, index);
+        assert!(matches!(
+            ArchiveIndex::open(&data, IndexMode::Plain, Some(other_key), false),
+            Err(BackupError::InvalidIndex(_))
+        ));
+
+        // Tampering with a plain index is detected with the key
+        let mut tampered = data;
+        tampered[ENTRY_FIXED_SIZE + 3 + 8] ^= 1;
+        assert!(matches!(
+            ArchiveIndex::open(&tampered, IndexMode::Plain, Some(key), false),
+            Err(BackupError::InvalidIndex(_))
+        ));
+
+        // An encrypted index needs the key
+        for nonce_mode in [NonceMode::Random, NonceMode::Derived] {
+            let data = index.seal(IndexMode::Encrypted, key, nonce_mode).unwrap();
+            let opened = ArchiveIndex::open(&data, IndexMode::Encrypted, Some(key), false).unwrap();
+            assert_eq!(opened, index);
+            assert!(matches!(
+                ArchiveIndex::open(&data, IndexMode::Encrypted, None, false),
+                Err(BackupError::PasswordRequired)
+            ));
+            assert!(matches!(
+                ArchiveIndex::open(&data, IndexMode::Encrypted, Some(other_key), false),
+                Err(BackupError::InvalidIndex(_))
+            ));
+        }
+
+        // Deterministic encryption is reproducible
+        assert_eq!(
+            index
+                .seal(IndexMode::Encrypted, key, NonceMode::Derived)
+                .unwrap(),
+            index
+                .seal(IndexMode::Encrypted, key, NonceMode::Derived)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_index_offsets() {
+        let mut index = ArchiveIndex::with_offsets();
+        index.push(Path::new("dir"), EntryKind::Directory, 0, 0);
+        index.push(Path::new("dir/file.txt"), EntryKind::File, 1234, 512);
+        index.push(Path::new("dir/other.txt"), EntryKind::File, 5, 2560);
+        let key = random_key();
+
+        for mode in [IndexMode::Plain, IndexMode::Encrypted] {
+            let data = index.seal(mode, key, NonceMode::Random).unwrap();
+            let opened = ArchiveIndex::open(&data, mode, Some(key), true).unwrap();
+            assert_eq!(opened, index);
+            assert_eq!(
+                opened
+                    .into_entries_with_offsets()
+                    .unwrap()
+                    .iter()
+                    .map(|(entry, offset)| (entry.path.to_str().unwrap(), *offset))
+                    .collect::<Vec<_>>(),
+                [("dir", 0), ("dir/file.txt", 512), ("dir/other.txt", 2560)]
+            );
+        }
+
+        // An index without offsets has none to return
+        let mut index = ArchiveIndex::default();
+        index.push(Path::new("file.txt"), EntryKind::File, 5, 0);
+        assert_eq!(index.into_entries_with_offsets(), None);
+    }
+}
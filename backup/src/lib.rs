@@ -17,14 +17,55 @@
 
 mod backup;
 mod backup_crypto;
-mod crypto;
+mod btime;
+mod builder;
+mod calibrate;
+mod cancel;
+pub mod crypto;
+mod dedup;
+mod excludes;
+mod header;
+mod index;
 mod logger;
+mod manifest;
 mod memory;
+mod options;
 mod pool;
+mod progress;
+mod special;
+mod stream;
 mod types;
 mod util;
+mod xattrs;
 
-pub use crate::backup::{backup, backup_chunk_size, extract};
-pub use crate::logger::init_logger;
-pub use crate::memory::check_memory;
-pub use crate::types::{BackupError, BackupResult};
+pub use crate::backup::{
+    backup, backup_chunk_size, backup_info, backup_with_key, change_password, check_password,
+    decrypt_backup_from, encrypt_backup_to, extract, extract_matching, extract_with_key,
+    extract_with_recovery_key, list, merge, verify, verify_checksum,
+};
+pub use crate::builder::{
+    BackupBuilder, ExtractBuilder, DEFAULT_BACKUP_POOL_SIZE, DEFAULT_CHUNK_SIZE,
+    DEFAULT_EXTRACT_POOL_SIZE,
+};
+pub use crate::calibrate::{
+    calibrate, Calibration, CalibrationResult, CALIBRATION_CHUNK_SIZE_MAGNITUDES,
+    CALIBRATION_POOL_SIZES,
+};
+pub use crate::cancel::{CancellationToken, PauseToken};
+pub use crate::crypto::{
+    generate_recovery_key, recovery_public_key, Argon2Params, ChecksumAlgorithm, AES_KEY_SIZE,
+    RECOVERY_KEY_SIZE,
+};
+pub use crate::excludes::{common_exclude_globs, COMMON_EXCLUDES};
+pub use crate::header::{supported_format_versions, FORMAT_VERSION};
+pub use crate::logger::{init_logger, LogFormat, LogTarget};
+pub use crate::memory::{check_memory, format_bytes, parse_bytes, MEMORY_LIMIT};
+pub use crate::options::*;
+pub use crate::pool::{
+    optimal_pool_size, task_channel, TaskRequestSender, TaskResponseReceiver, FALLBACK_POOL_SIZE,
+};
+pub use crate::progress::{Progress, ProgressHandler, ProgressStage};
+pub use crate::stream::EncryptReader;
+pub use crate::types::{
+    BackupError, BackupInfo, BackupResult, BackupStats, EntryKind, IndexEntry, SkippedPath,
+};
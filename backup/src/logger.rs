@@ -1,9 +1,39 @@
 //! Application-level logging configuration.
 
 use log::{LevelFilter, SetLoggerError};
+use serde_json::json;
+use std::io::{self, Write};
+
+/// The format of log records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines of the form `[timestamp] message`.
+    #[default]
+    Human,
+    /// One JSON object per line, of the form
+    /// `{"ts":"...","level":"info","msg":"..."}`, for ingestion into log
+    /// pipelines. Timestamps are in RFC 3339 format, in UTC.
+    Json,
+}
+
+/// Where log records are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Standard output.
+    #[default]
+    Stdout,
+    /// Standard error, which keeps standard output free for results that
+    /// are meant to be parsed, such as those printed as JSON.
+    Stderr,
+}
 
 /// The application-level logger.
-struct BackupLogger;
+struct BackupLogger {
+    /// The format in which records are written.
+    format: LogFormat,
+    /// Where records are written.
+    target: LogTarget,
+}
 
 impl log::Log for BackupLogger {
     fn enabled(&self, _metadata: &log::Metadata) -> bool {
@@ -12,31 +42,49 @@ impl log::Log for BackupLogger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            println!(
-                "[{}] {}",
-                chrono::Local::now().format("%a %Y-%m-%d %H:%M:%S%.3f"),
-                record.args()
-            );
+            let line = match self.format {
+                LogFormat::Human => format!(
+                    "[{}] {}",
+                    chrono::Local::now().format("%a %Y-%m-%d %H:%M:%S%.3f"),
+                    record.args()
+                ),
+                LogFormat::Json => json!({
+                    "ts": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                    "level": record.level().as_str().to_ascii_lowercase(),
+                    "msg": record.args().to_string(),
+                })
+                .to_string(),
+            };
+
+            // A record that cannot be written has nowhere else to go
+            let _ = match self.target {
+                LogTarget::Stdout => writeln!(io::stdout(), "{line}"),
+                LogTarget::Stderr => writeln!(io::stderr(), "{line}"),
+            };
         }
     }
 
     fn flush(&self) {}
 }
 
-/// The global logging instance.
-static LOGGER: BackupLogger = BackupLogger;
-
-/// Initializes logging.
+/// Initializes logging, writing records in the given format to the given
+/// target.
 ///
 /// # Errors
 ///
 /// This will return an error if the logger has already been initialized.
-pub fn init_logger(debug: bool) -> Result<(), SetLoggerError> {
+pub fn init_logger(
+    debug: bool,
+    format: LogFormat,
+    target: LogTarget,
+) -> Result<(), SetLoggerError> {
     let max_level = if debug {
         LevelFilter::Debug
     } else {
         LevelFilter::Warn
     };
+    // The logger lives for the rest of the program, and is only set once
+    let logger = Box::leak(Box::new(BackupLogger { format, target }));
 
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(max_level))
+    log::set_logger(logger).map(|()| log::set_max_level(max_level))
 }
@@ -0,0 +1,324 @@
+//! Per-file hash manifests.
+//!
+//! The SHA-256 hash of each regular file is computed as it is archived, and
+//! the hashes are appended to the end of the archive in a PAX global header,
+//! one record per file. Other tools ignore the records, and extracting the
+//! header writes nothing to disk. Extraction can use the hashes to check that
+//! each file was written out exactly as it was backed up.
+//!
+//! A delta backup also records the hashes of the files it left out because
+//! they were unchanged from a reference directory, under a separate key, so
+//! that a restore applying the delta over the reference can check that the
+//! files it relies on are still intact.
+
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// The PAX header key under which the hash of each file is stored.
+const PAX_FILE_HASH_KEY: &str = "EBAK.sha256";
+
+/// The PAX header key under which the hash of each file left out of a delta
+/// backup is stored.
+const PAX_UNCHANGED_FILE_HASH_KEY: &str = "EBAK.unchanged.sha256";
+
+/// The size of a file hash, in bytes.
+pub const FILE_HASH_SIZE: usize = 32;
+
+/// A reader that hashes everything read through it.
+pub struct HashingReader<R> {
+    /// The underlying reader.
+    inner: R,
+    /// The hash of the data read so far.
+    hasher: Sha256,
+}
+
+impl<R> HashingReader<R> {
+    /// Wraps a reader to hash the data read from it.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the hash of all data read.
+    pub fn finish(self) -> [u8; FILE_HASH_SIZE] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Computes the hash of a file on disk.
+pub fn hash_file(path: &Path) -> io::Result<[u8; FILE_HASH_SIZE]> {
+    let mut reader = HashingReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+/// The hashes of the files in an archive, keyed by their archive paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The archive path and hash of each file, in the order they were
+    /// archived.
+    files: Vec<(PathBuf, [u8; FILE_HASH_SIZE])>,
+    /// The archive path and hash of each file left out of a delta backup
+    /// because it was unchanged from the reference directory.
+    unchanged_files: Vec<(PathBuf, [u8; FILE_HASH_SIZE])>,
+}
+
+impl Manifest {
+    /// Records the hash of a file at the given archive path.
+    pub fn push(&mut self, path: &Path, hash: [u8; FILE_HASH_SIZE]) {
+        self.files.push((path.to_path_buf(), hash));
+    }
+
+    /// Records the hash of a file left out of a delta backup, at the archive
+    /// path it would have had.
+    pub fn push_unchanged(&mut self, path: &Path, hash: [u8; FILE_HASH_SIZE]) {
+        self.unchanged_files.push((path.to_path_buf(), hash));
+    }
+
+    /// Returns an iterator over the archive path and hash of each file.
+    pub fn files(&self) -> impl Iterator<Item = &(PathBuf, [u8; FILE_HASH_SIZE])> {
+        self.files.iter()
+    }
+
+    /// Returns an iterator over the archive path and hash of each file left
+    /// out of a delta backup because it was unchanged.
+    pub fn unchanged_files(&self) -> impl Iterator<Item = &(PathBuf, [u8; FILE_HASH_SIZE])> {
+        self.unchanged_files.iter()
+    }
+
+    /// Appends the manifest to an archive as a PAX global header. Nothing is
+    /// appended if no files were recorded.
+    pub fn append_to<T: Write>(&self, archive: &mut tar::Builder<T>) -> io::Result<()> {
+        if self.files.is_empty() && self.unchanged_files.is_empty() {
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        let records = self
+            .files
+            .iter()
+            .map(|file| (PAX_FILE_HASH_KEY, file))
+            .chain(
+                self.unchanged_files
+                    .iter()
+                    .map(|file| (PAX_UNCHANGED_FILE_HASH_KEY, file)),
+            );
+
+        for (key, (path, hash)) in records {
+            let mut value = hex_encode(hash).into_bytes();
+            value.push(b' ');
+            value.extend(escape(&path_to_bytes(path)));
+            write_pax_record(&mut data, key, &value);
+        }
+
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_entry_type(tar::EntryType::XGlobalHeader);
+        header.set_cksum();
+        archive.append(&header, data.as_slice())
+    }
+
+    /// Reads the manifest from an archive entry, if it is the global header
+    /// the manifest was appended as. Records that cannot be parsed are
+    /// skipped.
+    pub fn from_entry<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<Self>> {
+        if !entry.header().entry_type().is_pax_global_extensions() {
+            return Ok(None);
+        }
+
+        let Some(extensions) = entry.pax_extensions()? else {
+            return Ok(None);
+        };
+
+        let mut manifest = Self::default();
+
+        for extension in extensions.filter_map(Result::ok) {
+            let files = match extension.key_bytes() {
+                key if key == PAX_FILE_HASH_KEY.as_bytes() => &mut manifest.files,
+                key if key == PAX_UNCHANGED_FILE_HASH_KEY.as_bytes() => {
+                    &mut manifest.unchanged_files
+                }
+                _ => continue,
+            };
+            let value = extension.value_bytes();
+            let Some((hash, path)) = value.split_at_checked(FILE_HASH_SIZE * 2) else {
+                continue;
+            };
+            let (Some(path), Some(hash)) = (path.strip_prefix(b" "), hex_decode(hash)) else {
+                continue;
+            };
+            files.push((bytes_to_path(&unescape(path)), hash));
+        }
+
+        Ok((manifest != Self::default()).then_some(manifest))
+    }
+}
+
+/// Writes a single PAX record, which is prefixed by its own length.
+fn write_pax_record(data: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // The length includes the digits of the length itself, as well as the
+    // space, equals sign and trailing newline
+    let rest_len = key.len() + value.len() + 3;
+    let mut len = rest_len + 1;
+
+    while len.to_string().len() + rest_len != len {
+        len = len.to_string().len() + rest_len;
+    }
+
+    data.extend(format!("{len} {key}=").into_bytes());
+    data.extend_from_slice(value);
+    data.push(b'\n');
+}
+
+/// Encodes a hash as lowercase hexadecimal.
+fn hex_encode(hash: &[u8]) -> String {
+    hash.iter().fold(String::new(), |mut hex, byte| {
+        // Writing to a string cannot fail
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Decodes a hash from hexadecimal.
+fn hex_decode(hex: &[u8]) -> Option<[u8; FILE_HASH_SIZE]> {
+    let mut hash = [0u8; FILE_HASH_SIZE];
+
+    for (byte, pair) in hash.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(hash)
+}
+
+/// Escapes the bytes that cannot appear in a PAX record value. Newlines end
+/// a record, so they are percent-encoded, along with `%` itself.
+fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+
+    for &byte in bytes {
+        match byte {
+            b'%' => escaped.extend_from_slice(b"%25"),
+            b'\n' => escaped.extend_from_slice(b"%0A"),
+            _ => escaped.push(byte),
+        }
+    }
+
+    escaped
+}
+
+/// Reverses [`escape`].
+fn unescape(bytes: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if let Some(tail) = tail.strip_prefix(b"25").filter(|_| byte == b'%') {
+            unescaped.push(b'%');
+            rest = tail;
+        } else if let Some(tail) = tail.strip_prefix(b"0A").filter(|_| byte == b'%') {
+            unescaped.push(b'\n');
+            rest = tail;
+        } else {
+            unescaped.push(byte);
+            rest = tail;
+        }
+    }
+
+    unescaped
+}
+
+/// Converts an archive path to bytes, always separating components with `/`
+/// as tar does.
+pub fn path_to_bytes(path: &Path) -> Vec<u8> {
+    let components = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(os_str_bytes(part)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    components.join(&b'/')
+}
+
+/// Returns the bytes of a path component.
+#[cfg(unix)]
+fn os_str_bytes(part: &std::ffi::OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    part.as_bytes().to_vec()
+}
+
+/// Returns the bytes of a path component.
+#[cfg(not(unix))]
+fn os_str_bytes(part: &std::ffi::OsStr) -> Vec<u8> {
+    part.to_string_lossy().into_owned().into_bytes()
+}
+
+/// Converts bytes from an archive back to a path.
+#[cfg(unix)]
+pub fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+/// Converts bytes from an archive back to a path.
+#[cfg(not(unix))]
+pub fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Manifest tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let mut manifest = Manifest::default();
+        manifest.push(Path::new("dir/file.txt"), [1; FILE_HASH_SIZE]);
+        manifest.push(Path::new("dir/100%\nodd=name"), [0xab; FILE_HASH_SIZE]);
+        manifest.push_unchanged(Path::new("dir/unchanged.txt"), [2; FILE_HASH_SIZE]);
+
+        let mut archive = tar::Builder::new(Vec::new());
+        manifest.append_to(&mut archive).unwrap();
+        let bytes = archive.into_inner().unwrap();
+
+        // The manifest is read back from the archive exactly
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(Manifest::from_entry(&mut entry).unwrap(), Some(manifest));
+        assert!(entries.next().is_none());
+
+        // Nothing is appended for an empty manifest
+        let mut archive = tar::Builder::new(Vec::new());
+        Manifest::default().append_to(&mut archive).unwrap();
+        let bytes = archive.into_inner().unwrap();
+        let mut archive = tar::Archive::new(bytes.as_slice());
+        assert!(archive.entries().unwrap().next().is_none());
+
+        // Record lengths account for their own digits
+        for value_len in [0, 1, 80, 85, 86, 990, 995, 996] {
+            let mut data = Vec::new();
+            write_pax_record(&mut data, "key", &vec![b'x'; value_len]);
+            let (len, _) = std::str::from_utf8(&data).unwrap().split_once(' ').unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), data.len());
+        }
+    }
+}
@@ -1,5 +1,8 @@
 //! Utilities for predicting memory usage and reporting potential problems
-//! early.
+//! early, and for keeping it within a limit while a backup runs.
+
+use log::warn;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
 
 /// The suggested memory limit, 1 GiB.
 pub const MEMORY_LIMIT: usize = 1 << 30;
@@ -12,8 +15,9 @@ fn floor_to(n: f64, decimals: u8) -> f64 {
 
 /// Stringifies a number representing a number of bytes in human-readable
 /// form.
+#[must_use]
 #[allow(clippy::cast_precision_loss)]
-fn format_bytes(size: usize) -> String {
+pub fn format_bytes(size: u64) -> String {
     if size == 1 {
         "1 byte".to_owned()
     } else if size < (1 << 10) {
@@ -27,14 +31,51 @@ fn format_bytes(size: usize) -> String {
     }
 }
 
+/// Parses a human-readable number of bytes, such as `48 KiB`.
+///
+/// The number must be a whole number, and may be followed by a unit of `B`,
+/// `K`, `M`, or `G`, optionally suffixed with `B` or `iB`, in any case. Units
+/// are powers of 1024, as in [`format_bytes`], whether or not they include
+/// the `i`.
+///
+/// # Errors
+///
+/// This will return an error if the number or unit is invalid, or if the
+/// number of bytes does not fit in a `u64`.
+pub fn parse_bytes(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_start);
+
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid number of bytes: {size}"))?;
+    let shift = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        _ => return Err(format!("Invalid unit of bytes: {}", unit.trim())),
+    };
+
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("Number of bytes is too large: {size}"))
+}
+
 /// Checks roughly how much memory will be allocated during the backup or
 /// extraction. This will prompt for confirmation if the threshold is exceeded
 /// and confirmation is not overridden.
 ///
+/// The memory is then reserved and released again, to confirm that it can be
+/// allocated at all.
+///
 /// # Errors
 ///
 /// This will return an error if the suggested memory limit is exceeded and is
-/// not overridden.
+/// not overridden, or if the memory cannot be allocated.
 pub fn check_memory(chunk_size: usize, pool_size: u8, override_limit: bool) -> Result<(), String> {
     // `total_pool_size` is a necessary transformation of `pool_size` since
     // the internals of the task pool can cause up to `2n+3` chunks to be in
@@ -43,17 +84,96 @@ pub fn check_memory(chunk_size: usize, pool_size: u8, override_limit: bool) -> R
     // either end, one for the next request and one for the most recent
     // response.
     let total_pool_size = usize::from(pool_size) * 2 + 5;
-    let required_bytes = chunk_size * total_pool_size;
+    let required_bytes = chunk_size.saturating_mul(total_pool_size);
 
     if required_bytes > MEMORY_LIMIT {
         if !override_limit {
-            Err(format!("The suggested memory limit of 1 GiB has been exceeded.\nThe expected memory usage with the current configuration is {}.\nChange the chunk size magnitude or pool size to lower the expected memory usage, or override the memory limit to proceed with the existing configuration.", format_bytes(required_bytes)))
-        } else {
-            println!("The suggested memory limit of 1 GiB has been exceeded and the expected memory usage will be {}, but the limit has been overridden", format_bytes(required_bytes));
-            Ok(())
+            return Err(format!("The suggested memory limit of 1 GiB has been exceeded.\nThe expected memory usage with the current configuration is {}.\nChange the chunk size magnitude or pool size to lower the expected memory usage, or override the memory limit to proceed with the existing configuration.", format_bytes(required_bytes as u64)));
+        }
+
+        warn!("The suggested memory limit of 1 GiB has been exceeded and the expected memory usage will be {}, but the limit has been overridden", format_bytes(required_bytes as u64));
+    }
+
+    // Fail now rather than running out of memory part way through. The
+    // reservation is never written to, so it is released without being used.
+    Vec::<u8>::new()
+        .try_reserve_exact(required_bytes)
+        .map_err(|_| format!("Unable to allocate the {} of memory needed with the current configuration.\nChange the chunk size magnitude or pool size to lower the expected memory usage.", format_bytes(required_bytes as u64)))
+}
+
+/// A limit on the number of bytes held by chunks in flight through an
+/// encryption or decryption pipeline. Each chunk reserves its size before it
+/// is handed to the task pool and releases it once it has been consumed, so
+/// when chunks are large, fewer of them are in flight at once. This trades
+/// some throughput for staying under the limit regardless of the chunk size
+/// and pool size.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// The number of bytes that can be reserved at once.
+    limit: usize,
+    /// The number of bytes currently reserved.
+    reserved: Mutex<usize>,
+    /// Notified whenever a reservation is released.
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    /// Creates a budget allowing up to `limit` bytes to be reserved at once.
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            reserved: Mutex::new(0),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Reserves a number of bytes, waiting until enough have been released if
+    /// the limit would be exceeded. A reservation larger than the limit is
+    /// granted once nothing else is reserved, so that the pipeline can always
+    /// make progress with a single chunk.
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> MemoryReservation {
+        // Nothing that can panic runs while the lock is held, so a poisoned
+        // lock is safe to recover
+        let mut reserved = self
+            .released
+            .wait_while(
+                self.reserved.lock().unwrap_or_else(PoisonError::into_inner),
+                |reserved| *reserved > 0 && reserved.saturating_add(bytes) > self.limit,
+            )
+            .unwrap_or_else(PoisonError::into_inner);
+        *reserved += bytes;
+        drop(reserved);
+
+        MemoryReservation {
+            budget: Arc::clone(self),
+            bytes,
         }
-    } else {
-        Ok(())
+    }
+
+    /// Returns the number of bytes currently reserved.
+    #[cfg(test)]
+    pub fn reserved(&self) -> usize {
+        *self.reserved.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`], released when dropped.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    /// The budget the bytes were reserved from.
+    budget: Arc<MemoryBudget>,
+    /// The number of bytes reserved.
+    bytes: usize,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        *self
+            .budget
+            .reserved
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) -= self.bytes;
+        self.budget.released.notify_all();
     }
 }
 
@@ -82,6 +202,63 @@ mod tests {
         assert_floats_eq!(floor_to(6.789, 4), 6.789);
     }
 
+    #[test]
+    fn test_check_memory() {
+        assert!(check_memory(1 << 16, 4, false).is_ok());
+        assert!(check_memory(1 << 30, 4, false).is_err());
+
+        // Overriding the limit still fails if the memory cannot be allocated
+        assert!(check_memory(usize::MAX / 2, 64, true).is_err());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(100);
+
+        let first = budget.reserve(60);
+        let second = budget.reserve(40);
+        assert_eq!(budget.reserved(), 100);
+
+        // A reservation over the limit waits until enough has been released
+        let waiter = {
+            let budget = Arc::clone(&budget);
+            std::thread::spawn(move || budget.reserve(50))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        assert_eq!(budget.reserved(), 100);
+
+        drop(first);
+        let third = waiter.join().unwrap();
+        assert_eq!(budget.reserved(), 90);
+        drop((second, third));
+        assert_eq!(budget.reserved(), 0);
+
+        // A single reservation larger than the limit is still granted
+        let oversized = budget.reserve(1000);
+        assert_eq!(budget.reserved(), 1000);
+        drop(oversized);
+        assert_eq!(budget.reserved(), 0);
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("0"), Ok(0));
+        assert_eq!(parse_bytes("49152"), Ok(49_152));
+        assert_eq!(parse_bytes("512B"), Ok(512));
+        assert_eq!(parse_bytes("48K"), Ok(49_152));
+        assert_eq!(parse_bytes("48 KiB"), Ok(49_152));
+        assert_eq!(parse_bytes("48kb"), Ok(49_152));
+        assert_eq!(parse_bytes(" 3 MiB "), Ok(3 << 20));
+        assert_eq!(parse_bytes("1G"), Ok(1 << 30));
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("KiB").is_err());
+        assert!(parse_bytes("1.5 KiB").is_err());
+        assert!(parse_bytes("-1").is_err());
+        assert!(parse_bytes("48 TiB").is_err());
+        assert!(parse_bytes("18446744073709551615 GiB").is_err());
+    }
+
     #[test]
     fn test_format_bytes() {
         assert_eq!(format_bytes(0), "0 bytes");
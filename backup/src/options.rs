@@ -0,0 +1,525 @@
+//! Backup and extraction options.
+
+use crate::cancel::{CancellationToken, PauseToken};
+use crate::crypto::{Argon2Params, ChecksumAlgorithm, RECOVERY_KEY_SIZE};
+use crate::progress::ProgressHandler;
+use regex::Regex;
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+/// The name of the per-directory ignore file.
+pub const BACKUP_IGNORE_FILE_NAME: &str = ".backupignore";
+
+/// The supported zstd compression levels. Higher levels compress better, but
+/// more slowly.
+pub const COMPRESSION_LEVELS: RangeInclusive<i32> = 1..=19;
+
+/// How symbolic links found while walking the include paths are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkTarget {
+    /// Follow symbolic links, storing whatever they point to in their place.
+    #[default]
+    Follow,
+    /// Store symbolic links as links, with their targets exactly as they are.
+    Verbatim,
+    /// Store symbolic links as links, rewriting absolute targets within the
+    /// include path being backed up to be relative, so that the links still
+    /// resolve wherever the backup is extracted. Targets outside the include
+    /// path are stored as they are, and a warning is logged.
+    RelativizeWithinRoot,
+}
+
+/// What to do with special files, such as FIFOs, sockets and device nodes,
+/// found while walking the include paths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialFileMode {
+    /// Leave special files out of the backup, logging a warning for each.
+    #[default]
+    Skip,
+    /// Store special files as tar entries of their own types, so that they
+    /// are recreated on extraction. FIFOs are stored on Unix platforms, and
+    /// device nodes, along with their device numbers, on Linux. Sockets
+    /// cannot be stored, so they are still skipped with a warning.
+    Store,
+}
+
+/// How the paths of included files are stored in a backup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathMode {
+    /// Store each include path under its own name, so that extracting the
+    /// backup creates a directory named after each include path inside the
+    /// output directory.
+    #[default]
+    Basename,
+    /// Store each include path under its full absolute path, with the
+    /// leading separator removed, so that extracting the backup into the
+    /// filesystem root restores everything to its original location. On
+    /// Windows, the drive is not stored, so everything is restored to the
+    /// current drive.
+    Absolute,
+}
+
+/// Whether and how a backup stores an index of its contents, which lists
+/// the path, type and size of every entry in the archive so that they can be
+/// listed without decrypting the whole backup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Store no index. Listing the backup's contents requires decrypting it
+    /// in full.
+    #[default]
+    None,
+    /// Store the index in plain text, so that the contents can be listed
+    /// without the password. The index is authenticated with the data key,
+    /// so tampering is detected when it is listed with the password, but
+    /// anyone holding the backup can read the names and sizes of the files
+    /// within it.
+    Plain,
+    /// Store the index encrypted with the data key, so that listing the
+    /// contents requires the password but is still fast.
+    Encrypted,
+}
+
+/// Whether extraction may write into an output directory that already
+/// exists, and which of the files already in it are replaced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwriteMode {
+    /// Require that the output directory does not exist yet.
+    #[default]
+    Never,
+    /// Extract into an existing output directory, replacing any files that
+    /// are already at the paths of entries in the backup.
+    Always,
+    /// Extract into an existing output directory, like `rsync --update`,
+    /// only replacing files whose modification time is older than that of
+    /// the entry in the backup. Files that are as new or newer are left as
+    /// they are, which makes re-extracting a backup over an earlier
+    /// extraction a way to bring it up to date. Directories are always
+    /// extracted.
+    OnlyNewer,
+}
+
+/// What to do with an entry when extracting into an existing output
+/// directory that already has a different file at the entry's path.
+///
+/// A file counts as the same if its size and modification time match the
+/// entry's, and is then left as it is unless overwriting. Directories are
+/// always merged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file with the entry.
+    #[default]
+    Overwrite,
+    /// Leave the existing file as it is, and do not extract the entry.
+    Skip,
+    /// Extract the entry next to the existing file, with a numeric suffix
+    /// added to its name before any extension, such as `notes.1.txt`. The
+    /// first suffix that is not already taken is used.
+    Rename,
+    /// Fail the extraction, leaving what has been extracted so far.
+    Fail,
+}
+
+/// Additional options for a backup.
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct BackupOptions {
+    /// Whether to apply the patterns in `.backupignore` files found in
+    /// directories being backed up. Each line in the file is a glob, matched
+    /// against paths relative to the directory containing the file, and
+    /// applies only to that directory's subtree. Blank lines and lines
+    /// beginning with `#` are ignored.
+    ///
+    /// Exclusion is additive: a path is excluded if it matches any of the
+    /// global exclude globs or any applicable `.backupignore` pattern. A
+    /// `.backupignore` file cannot re-include a path excluded globally.
+    pub follow_backupignore: bool,
+    /// Whether to allow backing up the root of a filesystem or a special
+    /// pseudo-filesystem path such as `/proc`. These are rejected by default
+    /// since they are almost always included by accident.
+    pub allow_root: bool,
+    /// Whether to store hard linked files as independent copies. By default,
+    /// files that are hard linked together are stored once, and later
+    /// occurrences are stored as hard links to the first, so that the links
+    /// are restored on extraction. Hard link detection is only supported on
+    /// Unix platforms; elsewhere, copies are always stored.
+    pub dereference_hardlinks: bool,
+    /// Whether to stay on the filesystem of each include path, skipping
+    /// anything inside it that is on a different one, such as mounted
+    /// network drives. This mirrors `tar --one-file-system`. On Unix
+    /// platforms, filesystems are told apart by device number. On Windows,
+    /// reparse points, which include mounted volumes and directory
+    /// junctions, are skipped instead.
+    pub one_file_system: bool,
+    /// How to store symbolic links found inside the include paths. Include
+    /// paths that are symbolic links themselves are always followed.
+    pub symlink_target: SymlinkTarget,
+    /// What to do with special files found inside the include paths, or
+    /// given as include paths themselves. Recreating a device node on
+    /// extraction usually requires root privileges.
+    pub special_files: SpecialFileMode,
+    /// How to store the paths of included files. Exclude globs and regexes
+    /// are matched against the stored paths, so with [`PathMode::Absolute`]
+    /// they must match the full path, without the leading separator.
+    pub path_mode: PathMode,
+    /// The user and group IDs to record as the owner of every entry, in
+    /// place of their actual owners, or `None` to record the actual owners.
+    /// This mirrors `tar --owner` and `--group`, and is useful when the
+    /// backup will be restored by a different user, or on a system where the
+    /// original IDs mean something else. Ownership is not restored on
+    /// extraction either way, but other tools that unpack the archive may
+    /// restore it.
+    pub owner_override: Option<(u32, u32)>,
+    /// Whether to store an index of the backup's contents after the payload.
+    /// Backups with an index cannot be read by versions of the tool from
+    /// before indexes were introduced.
+    pub index: IndexMode,
+    /// Whether to record where each entry begins in the archive in the
+    /// index, so that single entries can be extracted with
+    /// [`extract_matching`](crate::extract_matching) by decrypting only the
+    /// chunks that hold them, rather than the whole backup. An encrypted
+    /// index is stored if no index is requested. Cannot be combined with
+    /// compression, since a compressed archive can only be read from the
+    /// start.
+    pub indexed: bool,
+    /// The maximum number of files and directories to hold open at once
+    /// while archiving, or `None` for no limit beyond the operating system's.
+    /// Lower this if backups fail because too many files are open, such as
+    /// when running alongside other processes under a low per-process limit.
+    pub max_open_files: Option<NonZeroUsize>,
+    /// Whether to skip hidden files and directories: those whose names begin
+    /// with `.`, and on Windows, those with the hidden attribute. Include
+    /// paths that are hidden themselves are still backed up, along with
+    /// everything that is not hidden inside them.
+    pub exclude_hidden: bool,
+    /// Regular expressions to exclude from the backup, in addition to the
+    /// exclude globs. Each is matched against the archive-relative path of
+    /// every entry, which begins with the name of its include path and always
+    /// uses `/` as the separator, regardless of platform. A path is excluded
+    /// if any regex matches anywhere within it, so anchor the regex with `^`
+    /// and `$` to match the whole path.
+    pub exclude_regex: Vec<Regex>,
+    /// Whether to log every path that is excluded from the backup, along
+    /// with the glob, regex or `.backupignore` pattern that excluded it, or
+    /// whether it was excluded for being hidden. Each is logged at the info
+    /// level. This helps track down which pattern is responsible when a
+    /// backup leaves out files unexpectedly. Only the excluded path itself is
+    /// logged, not the contents of excluded directories.
+    pub list_excluded: bool,
+    /// Passwords that can open the backup in addition to the main password.
+    /// Each password gets its own copy of the key used to encrypt the
+    /// backup, so any one of them is enough to extract it.
+    pub additional_passwords: Vec<String>,
+    /// The Argon2id cost parameters used to derive a key from each password.
+    /// They are stored in the backup header, so extraction always uses the
+    /// parameters the backup was created with.
+    pub kdf_params: Argon2Params,
+    /// The hash algorithm used to checksum the encrypted payload. It is
+    /// recorded in the backup header, so verification always uses the
+    /// algorithm the backup was created with.
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// The zstd level to compress the archive with before it is encrypted,
+    /// or `None` to leave it uncompressed. Must be within
+    /// [`COMPRESSION_LEVELS`]. Whether the backup is compressed is recorded
+    /// in its header, so extraction decompresses it automatically.
+    pub compression_level: Option<i32>,
+    /// Whether to read back and fully verify the backup once it has been
+    /// written, so that a bad write is caught while the source files are
+    /// still available. This roughly doubles the time a backup takes. Only
+    /// applies to backups written to a file.
+    pub verify_after_write: bool,
+    /// Whether to remove the backup if verification after writing fails.
+    /// When replacing an existing backup, the new one is always removed and
+    /// the existing one is left in place.
+    pub remove_unverified_output: bool,
+    /// Whether to replace the output file if it already exists. The new
+    /// backup is written to a temporary file in the same directory and
+    /// renamed over the existing one only once it is complete, so a failed
+    /// or interrupted backup never leaves a partial file in its place. By
+    /// default, an existing output file is an error.
+    pub overwrite: bool,
+    /// Whether to flush the backup file, and the directory it is written to,
+    /// to the storage device before the backup is reported as complete. This
+    /// is on by default, so that a crash or power loss just after a backup
+    /// completes cannot leave it missing or truncated. Flushing waits for
+    /// everything still in the operating system's write cache to reach the
+    /// device, which can take several seconds for a large backup on slow
+    /// storage, and longer on network filesystems. Only turn it off when the
+    /// backup will be copied elsewhere immediately, or can be made again.
+    pub durable: bool,
+    /// Whether to encrypt each chunk with a key of its own, derived from the
+    /// data key and the chunk's index with HKDF, rather than encrypting every
+    /// chunk with the data key. AES-GCM can only safely encrypt a limited
+    /// number of chunks under one key, so this widens the security margin of
+    /// very large backups. The choice is recorded in the backup header, so
+    /// extraction needs nothing extra, but versions of the tool from before
+    /// this option cannot read such backups.
+    pub chunk_keys: bool,
+    /// Whether to make the backup reproducible, so that backing up the same
+    /// files with the same password and options always produces the same
+    /// bytes. Directory entries are archived in sorted order, the creation
+    /// time is recorded as the Unix epoch, and nothing is chosen at random:
+    /// the salt for each password is derived from the password, the data key
+    /// from the first password, and the nonce for each chunk from the key,
+    /// the chunk's index and its contents.
+    ///
+    /// This weakens the backup's security. Anyone holding two deterministic
+    /// backups can tell whether they share a password, and whether any chunk
+    /// at the same position in both is identical. A password can also be
+    /// attacked once for every deterministic backup it protects, since they
+    /// all share a salt. Nonces are never reused for different data, since
+    /// each is bound to the chunk it encrypts. Only use this when comparing
+    /// backups byte for byte matters more than these risks.
+    ///
+    /// The passwords of a deterministic backup cannot be changed with
+    /// [`change_password`](crate::change_password). The data key is derived
+    /// from the first password, so that password would still decrypt the
+    /// backup after being replaced. Create a new backup with the new password
+    /// instead.
+    pub deterministic: bool,
+    /// An X25519 public key to store an extra key slot for, alongside the
+    /// password slots. The data key is wrapped with a key agreed with it, so
+    /// the backup can always be extracted with the matching private key, such
+    /// as one held by an administrator, even if every password is lost. See
+    /// [`generate_recovery_key`](crate::generate_recovery_key). Cannot be
+    /// combined with [`deterministic`](Self::deterministic), since the slot
+    /// uses a fresh ephemeral key each time.
+    pub recovery_public_key: Option<[u8; RECOVERY_KEY_SIZE]>,
+    /// A directory to make a delta backup against, such as an earlier
+    /// extraction of a backup of the same paths. Each file whose copy at the
+    /// same archive path in this directory has identical contents, compared
+    /// by hash, is left out of the backup, even if its modification time
+    /// differs. The hashes of the files left out are recorded in the
+    /// backup's manifest, so extracting the delta over a copy of the
+    /// directory restores the full tree, and verifying the extracted files
+    /// checks those it relies on too. Directories and links are always
+    /// stored, and files removed since the reference are not recorded.
+    pub reference_dir: Option<PathBuf>,
+    /// Whether to store each file whose contents are identical to a file
+    /// already in the backup as a reference to that file, rather than
+    /// storing its contents again. Extraction writes a separate copy of the
+    /// contents for each reference. This can greatly shrink backups with
+    /// repeated content, but a file is read twice when another file of the
+    /// same size has already been archived, and the hash and archive path of
+    /// every file archived are kept in memory until the backup is written,
+    /// which is about a hundred bytes per file plus the length of its path.
+    pub dedup: bool,
+    /// Whether to store the extended attributes of files and directories,
+    /// such as `user.*` attributes and security labels. They are stored in
+    /// PAX headers, and are only supported on Unix platforms. Attributes that
+    /// cannot be read are skipped.
+    pub preserve_xattrs: bool,
+    /// Whether to store the creation times of files and directories, where
+    /// the platform reports them, as on Windows, macOS and Linux with
+    /// `statx`. They are stored in PAX headers. Creation times that cannot be
+    /// read are skipped.
+    pub preserve_btime: bool,
+    /// Whether to keep going when a file or directory cannot be read,
+    /// instead of failing the whole backup. Such paths are left out of the
+    /// backup and listed in the returned statistics. A file that fails part
+    /// way through being read is kept, with the unread remainder filled with
+    /// zeros. Paths that cannot be read due to insufficient permissions are
+    /// always skipped, regardless of this option.
+    pub continue_on_error: bool,
+    /// Whether to skip paths that cannot be read due to insufficient
+    /// permissions without logging a warning for each. By default, every
+    /// such path is logged, so that files missing from the backup do not go
+    /// unnoticed. Useful when backing up directories known to contain many
+    /// unreadable system files.
+    pub quiet_permission_denied: bool,
+    /// Whether to keep the memory held by chunks being encrypted under
+    /// [`MEMORY_LIMIT`](crate::MEMORY_LIMIT) by reducing how many are in
+    /// flight at once, rather than relying on the chunk size and pool size
+    /// alone. Each chunk waits for earlier ones to be written when the limit
+    /// would be exceeded, which costs some throughput but allows large chunk
+    /// sizes on machines with little memory. At least one chunk is always in
+    /// flight, however large.
+    pub adaptive_memory: bool,
+    /// A callback to report progress to as the backup is written. The total
+    /// size of the archive is not known ahead of time, so only the number of
+    /// bytes archived so far is reported.
+    pub progress: Option<ProgressHandler>,
+    /// A token to cancel the backup with from another thread. Once it is
+    /// cancelled, the backup stops and the partially written output file is
+    /// removed. An existing file that was being replaced is left untouched.
+    pub cancel: Option<CancellationToken>,
+    /// A token to pause the backup with from another thread. While it is
+    /// paused, the backup stops reading files and encrypting chunks, but
+    /// keeps the output file open, and carries on where it left off once
+    /// resumed.
+    pub pause: Option<PauseToken>,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            follow_backupignore: false,
+            allow_root: false,
+            dereference_hardlinks: false,
+            one_file_system: false,
+            symlink_target: SymlinkTarget::default(),
+            special_files: SpecialFileMode::default(),
+            path_mode: PathMode::default(),
+            owner_override: None,
+            index: IndexMode::default(),
+            indexed: false,
+            max_open_files: None,
+            exclude_hidden: false,
+            exclude_regex: Vec::new(),
+            list_excluded: false,
+            additional_passwords: Vec::new(),
+            kdf_params: Argon2Params::default(),
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            compression_level: None,
+            verify_after_write: false,
+            remove_unverified_output: false,
+            overwrite: false,
+            durable: true,
+            chunk_keys: false,
+            deterministic: false,
+            recovery_public_key: None,
+            reference_dir: None,
+            dedup: false,
+            preserve_xattrs: false,
+            preserve_btime: false,
+            continue_on_error: false,
+            quiet_permission_denied: false,
+            adaptive_memory: false,
+            progress: None,
+            cancel: None,
+            pause: None,
+        }
+    }
+}
+
+impl BackupOptions {
+    /// Returns how the index is stored, accounting for indexed backups
+    /// always storing one.
+    pub(crate) const fn index_mode(&self) -> IndexMode {
+        match self.index {
+            IndexMode::None if self.indexed => IndexMode::Encrypted,
+            index => index,
+        }
+    }
+}
+
+/// Additional options for an extraction.
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ExtractOptions {
+    /// Whether to record extraction progress so that an interrupted
+    /// extraction can be resumed. Progress is recorded in a `.progress` file
+    /// alongside the output directory, and an interrupted extraction is
+    /// resumed by extracting again with the same arguments. The backup must
+    /// still be decrypted in full, but entries that were already written are
+    /// skipped if their size and modification time match.
+    pub resume: bool,
+    /// The directory in which to write the decrypted archive while it is
+    /// being extracted. Defaults to the directory containing the backup.
+    ///
+    /// Note that the full unencrypted contents of the backup transit this
+    /// directory, so it should be on a volume trusted with the plaintext.
+    pub temp_dir: Option<PathBuf>,
+    /// Whether to overwrite the decrypted archive with zeros before removing
+    /// it. This makes the plaintext harder to recover from the temporary
+    /// directory, but is not a guarantee: SSDs and copy-on-write or
+    /// journaling filesystems may retain the original blocks.
+    pub secure_delete: bool,
+    /// Whether to restore the extended attributes stored in the backup. Only
+    /// supported on Unix platforms. Attributes that cannot be set, such as
+    /// those the filesystem does not support or that require privileges, are
+    /// skipped.
+    pub preserve_xattrs: bool,
+    /// Whether to restore the creation times stored in the backup. Only
+    /// supported on Windows and macOS, since other platforms do not allow
+    /// creation times to be set. Creation times that cannot be set are
+    /// skipped.
+    pub preserve_btime: bool,
+    /// The number of leading components to remove from the path of each
+    /// entry, as with `tar --strip-components`. Stripping one component
+    /// places the contents of each include path directly in the output
+    /// directory, without the directory named after it. Entries with no
+    /// components left, such as the include paths themselves, are skipped.
+    /// If two entries would be extracted to the same path, as can happen
+    /// when a backup has several include paths, extraction fails.
+    pub strip_components: usize,
+    /// Whether to extract into the filesystem root instead of the output
+    /// path, which is ignored. This restores a backup made with
+    /// [`PathMode::Absolute`] to the original locations of its files,
+    /// overwriting anything already there, including system files if the
+    /// backup contains them. The decrypted archive is written to the system
+    /// temporary directory unless another one is configured.
+    pub restore_to_root: bool,
+    /// Whether the output directory may already exist, and which of the
+    /// files already in it are replaced. Overwriting cannot be combined with
+    /// an atomic extraction, which needs the output directory not to exist.
+    /// Files left as they are because they are newer are not verified, even
+    /// when verifying extracted files.
+    pub overwrite: OverwriteMode,
+    /// What to do with each entry whose path already holds a different file
+    /// in the output directory. Only applies to files that the overwrite
+    /// mode would replace, so it needs an overwrite mode other than
+    /// [`OverwriteMode::Never`] unless it is left as the default. Files that
+    /// are skipped are not verified, while renamed files are verified at
+    /// their new paths.
+    pub conflict: ConflictPolicy,
+    /// Whether to read back each extracted file once extraction completes
+    /// and compare its hash to the one recorded when it was backed up. This
+    /// catches files that were archived incorrectly or corrupted while being
+    /// written to disk, which the authentication of the encrypted backup as a
+    /// whole cannot. Backups made before hashes were recorded are extracted
+    /// without verification, and a warning is logged.
+    pub verify_on_extract: bool,
+    /// Whether to extract into a `.partial` directory alongside the output
+    /// directory, and only rename it to the output path once extraction has
+    /// fully succeeded. The output directory then never exists in a
+    /// partially extracted state, and the staging directory is removed if
+    /// extraction fails, unless progress is being recorded to resume from.
+    /// Ignored when restoring to the filesystem root.
+    pub atomic: bool,
+    /// Whether to keep the memory held by chunks being decrypted under
+    /// [`MEMORY_LIMIT`](crate::MEMORY_LIMIT) by reducing how many are in
+    /// flight at once. See [`BackupOptions::adaptive_memory`].
+    pub adaptive_memory: bool,
+    /// A callback to report progress to as the backup is decrypted and then
+    /// unpacked.
+    pub progress: Option<ProgressHandler>,
+    /// A token to cancel the extraction with from another thread. Once it is
+    /// cancelled, the extraction stops and the decrypted archive staged in
+    /// the temporary directory is removed. The partially extracted output
+    /// directory is removed too, unless progress is being recorded to resume
+    /// from, the backup is being restored to the filesystem root, or an
+    /// existing directory is being overwritten.
+    pub cancel: Option<CancellationToken>,
+    /// A token to pause the extraction with from another thread. While it is
+    /// paused, the extraction stops decrypting the backup or unpacking the
+    /// decrypted archive, and carries on where it left off once resumed.
+    pub pause: Option<PauseToken>,
+}
+
+/// Additional options for verifying a backup.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Whether to only decrypt and authenticate the backup, without reading
+    /// the archive within it. This measures raw decryption throughput, but
+    /// will not detect an archive that was corrupted before it was encrypted.
+    pub stats_only: bool,
+}
+
+/// Additional options for changing the password of a backup.
+#[derive(Debug, Clone, Default)]
+pub struct ChangePasswordOptions {
+    /// Whether to upgrade the backup to the current format while it is being
+    /// rewritten. The new password's copy of the data key is wrapped using
+    /// the default key derivation function and parameters, rather than those
+    /// of the old password, and the header is upgraded to the current format
+    /// version. Backups from before checksums were introduced also have a
+    /// checksum trailer added, which requires copying the payload once. The
+    /// payload itself is never re-encrypted, as it is still encrypted with
+    /// the current cipher.
+    ///
+    /// Other passwords that can open the backup keep their key derivation
+    /// function, since their copies of the data key cannot be rewrapped
+    /// without them.
+    pub upgrade_format: bool,
+}
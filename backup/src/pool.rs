@@ -1,14 +1,25 @@
 //! A synchronous task pool implementation.
 
-use std::sync::mpsc::{sync_channel, Receiver, SendError, SyncSender};
-use std::thread::spawn;
+use log::debug;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::mpsc::{sync_channel, Receiver, SendError, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// The pool size used when the number of CPUs cannot be determined.
+pub const FALLBACK_POOL_SIZE: u8 = 4;
+
+/// The largest pool size chosen from the number of CPUs.
+const MAX_OPTIMAL_POOL_SIZE: u8 = 64;
 
 /// Type alias for a heap-allocated thread-safe synchronous task.
 type Task<T> = Box<dyn FnOnce() -> T + Send>;
 
-/// The sending side of a task channel.
+/// The sending side of a task channel. Clones of the sender share the same
+/// underlying channel, so closing one closes all of them.
 #[derive(Debug, Clone)]
-pub struct TaskRequestSender<T>(SyncSender<Task<T>>)
+pub struct TaskRequestSender<T>(Arc<Mutex<Option<SyncSender<Task<T>>>>>)
 where
     T: Send;
 
@@ -16,13 +27,44 @@ impl<T> TaskRequestSender<T>
 where
     T: Send,
 {
+    /// Locks the inner sender. The lock is never held across an operation
+    /// that can panic, so a poisoned lock is safe to recover.
+    fn inner(&self) -> MutexGuard<'_, Option<SyncSender<Task<T>>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     /// Sends a task through the channel where it will be executed on a worker
     /// thread.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the pool has shut down or the sender has
+    /// been closed.
     pub fn send<F>(&self, task: F) -> Result<(), SendError<Task<T>>>
     where
         F: FnOnce() -> T + Send + 'static,
     {
-        self.0.send(Box::new(task))
+        // Clone the inner sender so that the lock is not held while blocking
+        // on a full channel.
+        let sender = self.inner().clone();
+
+        match sender {
+            Some(sender) => sender.send(Box::new(task)),
+            None => Err(SendError(Box::new(task))),
+        }
+    }
+
+    /// Closes the request side of the channel. No further tasks can be sent
+    /// through this sender or any of its clones. Tasks that have already been
+    /// sent will still be executed, after which the workers will shut down.
+    pub fn close(&self) {
+        self.inner().take();
+    }
+
+    /// Checks whether the request side of the channel has been closed.
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.inner().is_none()
     }
 }
 
@@ -42,21 +84,99 @@ where
     /// will block until a new response is available or all senders have
     /// disconnected. This will return `None` after all senders have dropped
     /// and all responses have been received.
+    #[must_use]
     pub fn recv(&self) -> Option<T> {
         self.0.recv().ok()
     }
+
+    /// Attempts to receive the next task response without blocking. This
+    /// returns `Ok(None)` if the next response is not yet available.
+    ///
+    /// # Errors
+    ///
+    /// This will return `TryRecvError::Disconnected` after all senders have
+    /// dropped or closed and all responses have been received.
+    pub fn try_recv(&self) -> Result<Option<T>, TryRecvError> {
+        match self.0.try_recv() {
+            Ok(response) => Ok(Some(response)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(TryRecvError::Disconnected),
+        }
+    }
 }
 
-/// Creates a task pool of the given size and returns a
-/// request sender/response receiver pair. The sender can be used to send
-/// synchronous tasks to workers in the pool. The receiver can get the return
-/// values of each task. The return values will be received in the same order
-/// in which the task requests were sent.
+/// Spawns a named pool thread. Threads are named so that they can be told
+/// apart in profilers and backtraces.
+///
+/// # Panics
+///
+/// This will panic if the thread cannot be spawned, as [`thread::spawn`]
+/// does.
+fn spawn_named<F>(name: String, f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::Builder::new()
+        .name(name)
+        .spawn(f)
+        .expect("failed to spawn pool thread");
+}
+
+/// Pins the calling worker thread to one of the CPU cores, chosen by the
+/// worker's index, so that each worker keeps its caches warm. Failing to pin
+/// the thread is harmless, so it is ignored.
+#[cfg(feature = "affinity")]
+fn pin_worker(worker_index: usize) {
+    if let Some(core_ids) = core_affinity::get_core_ids().filter(|ids| !ids.is_empty()) {
+        core_affinity::set_for_current(core_ids[worker_index % core_ids.len()]);
+    }
+}
+
+/// Returns a pool size suited to this machine, with one worker for each CPU
+/// available to the process.
+///
+/// The number of CPUs cannot always be determined, such as in some
+/// containers, in which case [`FALLBACK_POOL_SIZE`] is returned instead.
+#[must_use]
+pub fn optimal_pool_size() -> u8 {
+    pool_size_for(thread::available_parallelism())
+}
+
+/// Chooses a pool size from the detected number of CPUs, falling back to
+/// [`FALLBACK_POOL_SIZE`] if it could not be detected.
+fn pool_size_for(parallelism: io::Result<NonZeroUsize>) -> u8 {
+    match parallelism {
+        Ok(cpus) => u8::try_from(cpus.get())
+            .unwrap_or(MAX_OPTIMAL_POOL_SIZE)
+            .min(MAX_OPTIMAL_POOL_SIZE),
+        Err(e) => {
+            debug!(
+                "Failed to detect the number of CPUs, using a pool size of {FALLBACK_POOL_SIZE}: {e}"
+            );
+            FALLBACK_POOL_SIZE
+        }
+    }
+}
+
+/// Creates a task pool of the given size.
+///
+/// Returns a request sender/response receiver pair. The sender can be used to
+/// send synchronous tasks to workers in the pool. The receiver can get the
+/// return values of each task. The return values will be received in the same
+/// order in which the task requests were sent.
 ///
 /// Note that the pool will continue to exist until one or both halves of the
-/// task channel have disconnected.
+/// task channel have disconnected, or until the request sender is closed.
+///
+/// The worker threads are named `backup-worker-{id}`, and the threads that
+/// hand tasks out to them and collect their responses are named
+/// `backup-dispatch` and `backup-collect`. With the `affinity` feature, each
+/// worker is pinned to a CPU core.
 ///
-/// This will panic if `size` is 0.
+/// # Panics
+///
+/// This will panic if `size` is 0, or if a thread cannot be spawned.
+#[must_use]
 pub fn task_channel<T>(size: usize) -> (TaskRequestSender<T>, TaskResponseReceiver<T>)
 where
     T: Send + 'static,
@@ -74,7 +194,7 @@ where
         .into_iter()
         .zip(worker_response_senders);
 
-    spawn(move || {
+    spawn_named("backup-dispatch".to_owned(), move || {
         let mut request_index = 0;
 
         while let Ok(request) = request_receiver.recv() {
@@ -86,8 +206,13 @@ where
         }
     });
 
-    for (worker_request_receiver, worker_response_sender) in worker_channels {
-        spawn(move || {
+    for (worker_index, (worker_request_receiver, worker_response_sender)) in
+        worker_channels.enumerate()
+    {
+        spawn_named(format!("backup-worker-{worker_index}"), move || {
+            #[cfg(feature = "affinity")]
+            pin_worker(worker_index);
+
             while let Ok(request) = worker_request_receiver.recv() {
                 let response = request();
 
@@ -98,7 +223,7 @@ where
         });
     }
 
-    spawn(move || {
+    spawn_named("backup-collect".to_owned(), move || {
         let mut response_index = 0;
 
         while let Ok(response) = worker_response_receivers[response_index].recv() {
@@ -111,7 +236,7 @@ where
     });
 
     (
-        TaskRequestSender(request_sender),
+        TaskRequestSender(Arc::new(Mutex::new(Some(request_sender)))),
         TaskResponseReceiver(response_receiver),
     )
 }
@@ -120,9 +245,27 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
+    use std::thread::{sleep, spawn};
     use std::time::Duration;
 
+    #[test]
+    fn test_optimal_pool_size() {
+        let size = optimal_pool_size();
+        assert!((1..=MAX_OPTIMAL_POOL_SIZE).contains(&size));
+
+        // One worker is used per CPU, up to the limit
+        let cpus = |n| Ok(NonZeroUsize::new(n).unwrap());
+        assert_eq!(pool_size_for(cpus(1)), 1);
+        assert_eq!(pool_size_for(cpus(12)), 12);
+        assert_eq!(pool_size_for(cpus(1000)), MAX_OPTIMAL_POOL_SIZE);
+
+        // Failing to detect the CPUs falls back to a fixed size
+        assert_eq!(
+            pool_size_for(Err(io::Error::other("no cgroup quota"))),
+            FALLBACK_POOL_SIZE
+        );
+    }
+
     /// Tests the task pool.
     #[test]
     fn test_task_pool() {
@@ -166,4 +309,68 @@ mod tests {
         assert_eq!(response_receiver.recv(), Some(7));
         assert_eq!(response_receiver.recv(), None);
     }
+
+    /// Tests non-blocking response polling.
+    #[test]
+    fn test_try_recv() {
+        let (request_sender, response_receiver) = task_channel(2);
+
+        assert_eq!(response_receiver.try_recv(), Ok(None));
+
+        request_sender.send(|| 1).unwrap();
+        request_sender.send(|| 2).unwrap();
+        drop(request_sender);
+
+        let mut responses = Vec::new();
+
+        loop {
+            match response_receiver.try_recv() {
+                Ok(Some(response)) => responses.push(response),
+                Ok(None) => sleep(Duration::from_millis(10)),
+                Err(err) => {
+                    assert_eq!(err, TryRecvError::Disconnected);
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(responses, vec![1, 2]);
+    }
+
+    /// Tests that tasks run on named worker threads.
+    #[test]
+    fn test_worker_names() {
+        let (request_sender, response_receiver) = task_channel(2);
+        let thread_name = || thread::current().name().map(ToOwned::to_owned);
+
+        request_sender.send(thread_name).unwrap();
+        request_sender.send(thread_name).unwrap();
+        drop(request_sender);
+
+        assert_eq!(
+            response_receiver.recv(),
+            Some(Some("backup-worker-0".to_owned()))
+        );
+        assert_eq!(
+            response_receiver.recv(),
+            Some(Some("backup-worker-1".to_owned()))
+        );
+    }
+
+    /// Tests closing the request side of a task channel.
+    #[test]
+    fn test_close() {
+        let (request_sender, response_receiver) = task_channel(2);
+        let request_sender_clone = request_sender.clone();
+
+        request_sender.send(|| 1).unwrap();
+        assert!(!request_sender.is_closed());
+
+        request_sender_clone.close();
+        assert!(request_sender.is_closed());
+        assert!(request_sender.send(|| 2).is_err());
+
+        assert_eq!(response_receiver.recv(), Some(1));
+        assert_eq!(response_receiver.recv(), None);
+    }
 }
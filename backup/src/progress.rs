@@ -0,0 +1,331 @@
+//! Progress reporting for long-running operations.
+
+use crate::cancel::{CancellationToken, PauseToken};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+/// The minimum number of bytes between two progress reports.
+const REPORT_INTERVAL: u64 = 1 << 20;
+
+/// The stage of an operation that progress is being reported for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressStage {
+    /// Files are being archived and encrypted.
+    Archiving,
+    /// The backup is being decrypted.
+    Decrypting,
+    /// The decrypted archive is being unpacked.
+    Unpacking,
+}
+
+/// A snapshot of the progress of an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The stage the operation is in.
+    pub stage: ProgressStage,
+    /// The number of bytes processed so far in this stage.
+    pub bytes_processed: u64,
+    /// The total number of bytes to process in this stage, if known ahead of
+    /// time.
+    pub total_bytes: Option<u64>,
+}
+
+impl Progress {
+    /// Returns the fraction of this stage that is complete, between 0 and 1,
+    /// if the total is known.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn fraction(&self) -> Option<f64> {
+        self.total_bytes.map(|total_bytes| {
+            if total_bytes == 0 {
+                1.
+            } else {
+                (self.bytes_processed as f64 / total_bytes as f64).min(1.)
+            }
+        })
+    }
+}
+
+/// A callback that receives progress reports during an operation. Reports are
+/// made from the thread performing the operation, at most once per MiB
+/// processed and once at the end of each stage.
+#[derive(Clone)]
+pub struct ProgressHandler(Arc<dyn Fn(Progress) + Send + Sync>);
+
+impl ProgressHandler {
+    /// Creates a progress handler from a callback.
+    pub fn new(callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    /// Reports progress to the callback.
+    pub fn report(&self, progress: Progress) {
+        (self.0)(progress);
+    }
+}
+
+impl fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHandler")
+    }
+}
+
+/// Tracks the bytes processed in one stage and reports them at intervals.
+struct ProgressTracker {
+    /// The handler to report to, if any.
+    handler: Option<ProgressHandler>,
+    /// The stage being tracked.
+    stage: ProgressStage,
+    /// The number of bytes processed so far.
+    bytes_processed: u64,
+    /// The total number of bytes to process, if known.
+    total_bytes: Option<u64>,
+    /// The number of bytes processed at the time of the last report.
+    last_report: u64,
+    /// A token that stops the stage once cancelled, if any.
+    cancel: Option<CancellationToken>,
+    /// A token that holds the stage while paused, if any.
+    pause: Option<PauseToken>,
+}
+
+impl ProgressTracker {
+    /// Creates a new tracker for a stage.
+    const fn new(
+        handler: Option<ProgressHandler>,
+        stage: ProgressStage,
+        total_bytes: Option<u64>,
+        cancel: Option<CancellationToken>,
+        pause: Option<PauseToken>,
+    ) -> Self {
+        Self {
+            handler,
+            stage,
+            bytes_processed: 0,
+            total_bytes,
+            last_report: 0,
+            cancel,
+            pause,
+        }
+    }
+
+    /// Blocks while the stage is paused, then returns an error if it has
+    /// been cancelled.
+    fn checkpoint(&self) -> io::Result<()> {
+        if let Some(pause) = &self.pause {
+            pause.wait(self.cancel.as_ref());
+        }
+
+        self.cancel
+            .as_ref()
+            .map_or(Ok(()), CancellationToken::check)
+    }
+
+    /// Records that more bytes have been processed, reporting if enough have
+    /// accumulated since the last report.
+    fn advance(&mut self, n: usize) {
+        self.bytes_processed += n as u64;
+
+        if self.bytes_processed - self.last_report >= REPORT_INTERVAL {
+            self.report();
+        }
+    }
+
+    /// Reports the current progress.
+    fn report(&mut self) {
+        if let Some(handler) = &self.handler {
+            handler.report(Progress {
+                stage: self.stage,
+                bytes_processed: self.bytes_processed,
+                total_bytes: self.total_bytes,
+            });
+        }
+
+        self.last_report = self.bytes_processed;
+    }
+}
+
+/// A writer that reports the number of bytes written through it.
+pub struct ProgressWriter<W> {
+    /// The underlying writer.
+    inner: W,
+    /// The progress tracker.
+    tracker: ProgressTracker,
+}
+
+impl<W: Write> ProgressWriter<W> {
+    /// Wraps a writer to report the bytes written for a stage. Writes block
+    /// while the pause token, if any, is paused, and fail once the
+    /// cancellation token, if any, has been cancelled.
+    pub const fn new(
+        inner: W,
+        handler: Option<ProgressHandler>,
+        stage: ProgressStage,
+        total_bytes: Option<u64>,
+        cancel: Option<CancellationToken>,
+        pause: Option<PauseToken>,
+    ) -> Self {
+        Self {
+            inner,
+            tracker: ProgressTracker::new(handler, stage, total_bytes, cancel, pause),
+        }
+    }
+
+    /// Makes a final report and returns the underlying writer.
+    pub fn finish(mut self) -> W {
+        self.tracker.report();
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tracker.checkpoint()?;
+        let n = self.inner.write(buf)?;
+        self.tracker.advance(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that reports the number of bytes read through it.
+pub struct ProgressReader<R> {
+    /// The underlying reader.
+    inner: R,
+    /// The progress tracker.
+    tracker: ProgressTracker,
+}
+
+impl<R: Read> ProgressReader<R> {
+    /// Wraps a reader to report the bytes read for a stage. Reads block while
+    /// the pause token, if any, is paused, and fail once the cancellation
+    /// token, if any, has been cancelled.
+    pub const fn new(
+        inner: R,
+        handler: Option<ProgressHandler>,
+        stage: ProgressStage,
+        total_bytes: Option<u64>,
+        cancel: Option<CancellationToken>,
+        pause: Option<PauseToken>,
+    ) -> Self {
+        Self {
+            inner,
+            tracker: ProgressTracker::new(handler, stage, total_bytes, cancel, pause),
+        }
+    }
+
+    /// Makes a final report and returns the underlying reader.
+    pub fn finish(mut self) -> R {
+        self.tracker.report();
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tracker.checkpoint()?;
+        let n = self.inner.read(buf)?;
+        self.tracker.advance(n);
+        Ok(n)
+    }
+}
+
+/// Progress tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_progress_writer() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let reports = Arc::clone(&reports);
+            ProgressHandler::new(move |progress| reports.lock().unwrap().push(progress))
+        };
+
+        let total = REPORT_INTERVAL * 2 + 5;
+        let mut writer = ProgressWriter::new(
+            io::sink(),
+            Some(handler),
+            ProgressStage::Archiving,
+            Some(total),
+            None,
+            None,
+        );
+        io::copy(&mut io::repeat(0).take(total), &mut writer).unwrap();
+        writer.finish();
+
+        let reports = reports.lock().unwrap().clone();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].bytes_processed, REPORT_INTERVAL);
+        assert_eq!(reports[1].bytes_processed, REPORT_INTERVAL * 2);
+        assert_eq!(reports[2].bytes_processed, total);
+        assert_eq!(reports[2].fraction(), Some(1.));
+        assert!(reports
+            .iter()
+            .all(|progress| progress.stage == ProgressStage::Archiving));
+    }
+
+    #[test]
+    fn test_progress_cancel() {
+        let cancel = CancellationToken::new();
+        let mut reader = ProgressReader::new(
+            io::repeat(0),
+            None,
+            ProgressStage::Decrypting,
+            None,
+            Some(cancel.clone()),
+            None,
+        );
+        let mut buf = [0; 16];
+        reader.read_exact(&mut buf).unwrap();
+
+        // Reads fail once cancelled
+        cancel.cancel();
+        assert!(cancel.is_cancelled());
+        assert!(reader.read_exact(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_progress_pause() {
+        let cancel = CancellationToken::new();
+        let pause = PauseToken::new();
+        let mut writer = ProgressWriter::new(
+            Vec::new(),
+            None,
+            ProgressStage::Archiving,
+            None,
+            Some(cancel.clone()),
+            Some(pause.clone()),
+        );
+        writer.write_all(b"before").unwrap();
+
+        // Writes block while paused, and continue once resumed
+        pause.pause();
+        assert!(pause.is_paused());
+        let resumer = {
+            let pause = pause.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                pause.resume();
+            })
+        };
+        writer.write_all(b" after").unwrap();
+        resumer.join().unwrap();
+        assert!(!pause.is_paused());
+
+        // Paused writes still fail once cancelled
+        pause.pause();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            cancel.cancel();
+        });
+        assert!(writer.write_all(b" never").is_err());
+        canceller.join().unwrap();
+        assert_eq!(writer.finish(), b"before after");
+    }
+}
@@ -0,0 +1,129 @@
+//! Storage and restoration of special files, such as FIFOs and device nodes.
+//!
+//! Special files have no contents to back up, so each is stored as an empty
+//! tar entry of its own type, with the major and minor numbers of a device
+//! node in its header, as GNU tar stores them. FIFOs can be stored on any
+//! Unix platform, but device nodes only on Linux, where the encoding of their
+//! numbers is known. Sockets cannot be represented in a tar archive, so they
+//! are never stored. Creating a device node usually requires root privileges.
+
+use filetime::FileTime;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Returns the archive entry type a special file is stored as, or `None` if
+/// it is not a special file that can be stored on this platform.
+#[cfg(unix)]
+pub fn special_entry_type(metadata: &fs::Metadata) -> Option<tar::EntryType> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+
+    if file_type.is_fifo() {
+        Some(tar::EntryType::Fifo)
+    } else if cfg!(target_os = "linux") && file_type.is_char_device() {
+        Some(tar::EntryType::Char)
+    } else if cfg!(target_os = "linux") && file_type.is_block_device() {
+        Some(tar::EntryType::Block)
+    } else {
+        None
+    }
+}
+
+/// Returns the archive entry type a special file is stored as, or `None` if
+/// it is not a special file that can be stored on this platform.
+#[cfg(not(unix))]
+pub const fn special_entry_type(_metadata: &fs::Metadata) -> Option<tar::EntryType> {
+    None
+}
+
+/// Checks whether an archive entry type is that of a special file.
+pub const fn is_special(entry_type: tar::EntryType) -> bool {
+    matches!(
+        entry_type,
+        tar::EntryType::Fifo | tar::EntryType::Char | tar::EntryType::Block
+    )
+}
+
+/// Records the major and minor numbers of a device node in its entry header.
+#[cfg(target_os = "linux")]
+pub fn set_device_numbers(header: &mut tar::Header, metadata: &fs::Metadata) -> io::Result<()> {
+    use nix::sys::stat::{major, minor};
+    use std::os::unix::fs::MetadataExt;
+
+    let device_id = metadata.rdev();
+    header.set_device_major(u32::try_from(major(device_id)).map_err(io::Error::other)?)?;
+    header.set_device_minor(u32::try_from(minor(device_id)).map_err(io::Error::other)?)
+}
+
+/// Records the major and minor numbers of a device node in its entry header.
+/// Device nodes are only stored on Linux, so there is nothing to record.
+#[cfg(not(target_os = "linux"))]
+pub const fn set_device_numbers(
+    _header: &mut tar::Header,
+    _metadata: &fs::Metadata,
+) -> io::Result<()> {
+    Ok(())
+}
+
+/// Creates a special file described by an archive entry header, giving it the
+/// permissions and modification time in the header. Anything already at the
+/// destination is replaced.
+#[cfg(unix)]
+pub fn create_special(dst: &Path, header: &tar::Header) -> io::Result<()> {
+    use nix::sys::stat::Mode;
+    use std::os::unix::fs::PermissionsExt;
+
+    if fs::symlink_metadata(dst).is_ok_and(|metadata| !metadata.is_dir()) {
+        fs::remove_file(dst)?;
+    }
+
+    let mode = header.mode()? & 0o777;
+    // The width of a mode differs between platforms
+    let creation_mode = Mode::from_bits_truncate(mode as _);
+
+    match header.entry_type() {
+        tar::EntryType::Fifo => nix::unistd::mkfifo(dst, creation_mode)?,
+        #[cfg(target_os = "linux")]
+        entry_type @ (tar::EntryType::Char | tar::EntryType::Block) => {
+            use nix::sys::stat::{makedev, mknod, SFlag};
+
+            let kind = if entry_type == tar::EntryType::Char {
+                SFlag::S_IFCHR
+            } else {
+                SFlag::S_IFBLK
+            };
+            let device_id = makedev(
+                header.device_major()?.unwrap_or(0).into(),
+                header.device_minor()?.unwrap_or(0).into(),
+            );
+            mknod(dst, kind, creation_mode, device_id)?;
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this kind of special file cannot be created on this platform",
+            ))
+        }
+    }
+
+    // The permissions given on creation are limited by the umask
+    fs::set_permissions(dst, fs::Permissions::from_mode(mode))?;
+
+    // Setting the time of a path opens it, unless it is set as the time of a
+    // link, and opening a FIFO blocks until something writes to it
+    let mtime = i64::try_from(header.mtime()?).unwrap_or(i64::MAX);
+    let mtime = FileTime::from_unix_time(mtime, 0);
+    filetime::set_symlink_file_times(dst, mtime, mtime)
+}
+
+/// Creates a special file described by an archive entry header. Special files
+/// can only be created on Unix platforms.
+#[cfg(not(unix))]
+pub fn create_special(_dst: &Path, _header: &tar::Header) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "special files can only be created on Unix platforms",
+    ))
+}
@@ -0,0 +1,256 @@
+//! Pull-based access to the encrypted output of a backup.
+
+use crate::backup::{encrypt_backup_to, validate_backup};
+use crate::options::BackupOptions;
+use crate::types::{BackupResult, BackupStats};
+use glob::Pattern;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// The number of buffers of encrypted output that can be waiting to be read
+/// before the backup pauses.
+const BUFFER_COUNT: usize = 4;
+
+/// A writer that sends everything written to it over a channel.
+struct ChannelWriter(SyncSender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| {
+            // The reader has been dropped, so nothing wants the output
+            io::Error::new(io::ErrorKind::BrokenPipe, "encrypt reader closed")
+        })?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader that produces an encrypted backup on demand.
+///
+/// The backup runs on a background thread as the reader is read from, and
+/// pauses whenever a few buffers of output are waiting to be read, so memory
+/// use stays bounded no matter how slowly the output is consumed. This allows
+/// a backup to be piped anywhere that accepts a reader, such as with
+/// [`io::copy`], without staging it in a file.
+pub struct EncryptReader {
+    /// The receiving side of the channel the backup writes its output to.
+    receiver: Option<Receiver<Vec<u8>>>,
+    /// The buffer currently being read from.
+    buffer: io::Cursor<Vec<u8>>,
+    /// The background thread performing the backup.
+    handle: Option<JoinHandle<BackupResult<BackupStats>>>,
+    /// The result of the backup, once the thread has finished.
+    result: Option<BackupResult<BackupStats>>,
+}
+
+impl EncryptReader {
+    /// Starts a backup of a set of paths, to be read from the returned reader.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if validation fails. Errors during the backup
+    /// itself are returned from reads, and in full from [`Self::finish`].
+    pub fn new(
+        include_paths: Vec<PathBuf>,
+        exclude_globs: Vec<Pattern>,
+        password: String,
+        chunk_size: usize,
+        pool_size: u8,
+        options: BackupOptions,
+    ) -> BackupResult<Self> {
+        // Validate up front, so that invalid arguments are reported here
+        // rather than on the first read
+        validate_backup(&include_paths, &options)?;
+
+        let (sender, receiver) = sync_channel(BUFFER_COUNT);
+        let handle = thread::spawn(move || {
+            let mut dest = BufWriter::with_capacity(chunk_size, ChannelWriter(sender));
+            let stats = encrypt_backup_to(
+                &include_paths,
+                &exclude_globs,
+                &mut dest,
+                &password,
+                chunk_size,
+                pool_size,
+                &options,
+            )?;
+            dest.flush()?;
+
+            Ok(stats)
+        });
+
+        Ok(Self {
+            receiver: Some(receiver),
+            buffer: io::Cursor::new(Vec::new()),
+            handle: Some(handle),
+            result: None,
+        })
+    }
+
+    /// Waits for the backup to finish, returning its statistics. If the
+    /// reader has not been read to the end, the backup is abandoned and an
+    /// error is returned.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if any operation involved in the backup
+    /// failed.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the background thread panicked.
+    pub fn finish(mut self) -> BackupResult<BackupStats> {
+        self.join();
+        self.result
+            .take()
+            .expect("the backup result should be recorded once joined")
+    }
+
+    /// Stops receiving output and waits for the background thread, recording
+    /// its result.
+    fn join(&mut self) {
+        self.receiver = None;
+
+        if let Some(handle) = self.handle.take() {
+            self.result = Some(handle.join().unwrap());
+        }
+    }
+}
+
+impl Read for EncryptReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.buffer.read(buf)?;
+
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+
+            let next = self
+                .receiver
+                .as_ref()
+                .and_then(|receiver| receiver.recv().ok());
+
+            let Some(data) = next else {
+                // The backup has finished, one way or another
+                self.join();
+
+                return match &self.result {
+                    Some(Err(e)) => Err(io::Error::other(e.to_string())),
+                    _ => Ok(0),
+                };
+            };
+
+            self.buffer = io::Cursor::new(data);
+        }
+    }
+}
+
+impl Drop for EncryptReader {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Stream tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::decrypt_backup_from;
+    use crate::options::ExtractOptions;
+    use crate::types::BackupError;
+    use std::fs;
+
+    fn non_existent_temp_file() -> PathBuf {
+        let temp_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let path = temp_path.to_path_buf();
+        temp_path.close().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_encrypt_reader() {
+        let src_path = non_existent_temp_file();
+        let extract_output_path = non_existent_temp_file();
+        let extract_output_root = extract_output_path.join(src_path.file_name().unwrap());
+        let password = "password123";
+        let chunk_size = 1024;
+        let pool_size = 16;
+        let contents = "Hello, reader! ".repeat(10_000);
+
+        {
+            fs::create_dir(&src_path).unwrap();
+            fs::write(src_path.join("file.txt"), &contents).unwrap();
+        }
+
+        // Invalid arguments are rejected before anything is read
+        assert!(matches!(
+            EncryptReader::new(
+                vec![non_existent_temp_file()],
+                Vec::new(),
+                password.to_owned(),
+                chunk_size,
+                pool_size,
+                BackupOptions::default(),
+            ),
+            Err(BackupError::InvalidIncludePath(_))
+        ));
+
+        // Read in small pieces, to exercise reads that span buffers
+        let mut reader = EncryptReader::new(
+            vec![src_path.clone()],
+            Vec::new(),
+            password.to_owned(),
+            chunk_size,
+            pool_size,
+            BackupOptions::default(),
+        )
+        .unwrap();
+        let mut data = Vec::new();
+        let mut piece = [0u8; 100];
+        loop {
+            let n = reader.read(&mut piece).unwrap();
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&piece[..n]);
+        }
+        let stats = reader.finish().unwrap();
+        assert_eq!(stats.output_size, data.len() as u64);
+
+        decrypt_backup_from(
+            data.as_slice(),
+            &extract_output_path,
+            password,
+            pool_size,
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(extract_output_root.join("file.txt")).unwrap(),
+            contents
+        );
+
+        // Dropping a reader part way through abandons the backup
+        let mut reader = EncryptReader::new(
+            vec![src_path.clone()],
+            Vec::new(),
+            password.to_owned(),
+            chunk_size,
+            pool_size,
+            BackupOptions::default(),
+        )
+        .unwrap();
+        reader.read_exact(&mut piece).unwrap();
+        assert!(reader.finish().is_err());
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_dir_all(&extract_output_path).unwrap();
+    }
+}
@@ -1,8 +1,12 @@
 //! Application-level type definitions.
 
+use crate::crypto::ChecksumAlgorithm;
+use crate::options::IndexMode;
+use chrono::{DateTime, Utc};
 use std::fmt;
 use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
 /// An error during a backup or extraction.
@@ -17,12 +21,177 @@ pub enum BackupError {
     /// An include path is invalid.
     #[error("invalid include path: {0}")]
     InvalidIncludePath(PathBuf),
-    /// A duplicate include path name was encountered.
-    #[error("duplicate include path name: {0}")]
-    DuplicateIncludeName(String),
+    /// Two include paths have the same name, so they would collide in the
+    /// backup.
+    #[error(
+        "duplicate include path name: \"{name}\" from both {} and {}",
+        first.display(),
+        second.display()
+    )]
+    DuplicateIncludeName {
+        /// The name shared by both include paths.
+        name: String,
+        /// The first include path with the name.
+        first: PathBuf,
+        /// The include path that collides with the first.
+        second: PathBuf,
+    },
+    /// The operating system refused to open another file, because too many
+    /// are open already.
+    #[error(
+        "too many open files while reading {0}; lower the maximum number of open files or raise the operating system limit"
+    )]
+    TooManyOpenFiles(PathBuf),
+    /// Nothing was archived, because every include path was excluded or
+    /// skipped.
+    #[error("nothing to back up: every include path was excluded or skipped")]
+    EmptyBackup,
+    /// An extracted file does not match the hash recorded for it when it was
+    /// backed up.
+    #[error("extracted file does not match its hash in the backup: {}", path.display())]
+    FileHashMismatch {
+        /// The path of the extracted file.
+        path: PathBuf,
+    },
+    /// Two archive entries would be extracted to the same path once leading
+    /// components are stripped from their paths.
+    #[error(
+        "stripping leading path components would extract both {} and {} to {}",
+        first.display(),
+        second.display(),
+        path.display()
+    )]
+    StrippedPathCollision {
+        /// The path both entries would be extracted to.
+        path: PathBuf,
+        /// The archive path of the first entry.
+        first: PathBuf,
+        /// The archive path of the entry that collides with the first.
+        second: PathBuf,
+    },
+    /// An entry would be extracted over a different file that is already in
+    /// the output directory, and the conflict policy is to fail.
+    #[error("a different file already exists at the path of an entry: {0}")]
+    ExtractConflict(PathBuf),
     /// The specified path already exists.
     #[error("path already exists: {0}")]
     PathAlreadyExists(PathBuf),
+    /// An include path is the root of a filesystem or a pseudo-filesystem
+    /// path.
+    #[error("refusing to back up filesystem root or special path: {0}")]
+    DangerousIncludePath(PathBuf),
+    /// An ignore file contains an invalid pattern.
+    #[error("invalid pattern in ignore file {0}: {1}")]
+    InvalidIgnorePattern(PathBuf, String),
+    /// The reference directory of a delta backup is not a directory.
+    #[error("invalid reference directory: {0}")]
+    InvalidReferenceDir(PathBuf),
+    /// The temporary directory does not exist or is not writable.
+    #[error("invalid temporary directory: {0}")]
+    InvalidTempDir(PathBuf),
+    /// The header of a backup file is malformed or unsupported.
+    #[error("invalid backup header: {0}")]
+    InvalidHeader(String),
+    /// The backup file was written in a format version that this build
+    /// cannot read, either because it is too old or because it was written
+    /// by a newer version of the tool.
+    #[error("unsupported backup format version {0}")]
+    UnsupportedFormatVersion(u8),
+    /// The password does not open any of the key slots in the backup header.
+    #[error("incorrect password")]
+    IncorrectPassword,
+    /// The key derivation function parameters are out of bounds.
+    #[error("invalid key derivation parameters: {0}")]
+    InvalidKdfParams(String),
+    /// The backup was created before checksums were introduced.
+    #[error("backup has no checksum")]
+    MissingChecksum,
+    /// The backup was created without an index of its contents.
+    #[error("backup has no index")]
+    MissingIndex,
+    /// The index of the backup's contents is encrypted, but no password was
+    /// given to decrypt it.
+    #[error("backup index is encrypted, so a password is required to list it")]
+    PasswordRequired,
+    /// The index of the backup's contents is malformed or failed
+    /// authentication.
+    #[error("invalid backup index: {0}")]
+    InvalidIndex(String),
+    /// The encrypted payload ends without the marker that follows its last
+    /// section, so the backup has been truncated.
+    #[error("backup is truncated")]
+    TruncatedBackup,
+    /// The checksum of the encrypted payload does not match the one stored
+    /// in the backup, so the backup is corrupted or truncated.
+    #[error("backup checksum does not match")]
+    ChecksumMismatch,
+    /// Checksums with the given algorithm cannot be computed, because the
+    /// feature providing it is not enabled.
+    #[error("unsupported checksum algorithm: {0:?}")]
+    UnsupportedChecksumAlgorithm(ChecksumAlgorithm),
+    /// The compression level is not supported.
+    #[error("invalid compression level: {0}")]
+    InvalidCompressionLevel(i32),
+    /// Options were given that cannot be used together.
+    #[error("incompatible options: {0}")]
+    IncompatibleOptions(String),
+    /// A recovery public key cannot be used, since anyone could compute the
+    /// keys agreed with it.
+    #[error("invalid recovery key: the public key is of low order")]
+    InvalidRecoveryKey,
+    /// The password of a deterministic backup cannot be changed, since its
+    /// data key is derived from its first password, which would go on
+    /// opening it.
+    #[error("cannot change the password of a deterministic backup")]
+    DeterministicPasswordChange,
+    /// The operation was cancelled through its cancellation token.
+    #[error("operation cancelled")]
+    Cancelled,
+    /// A backup that was just written failed to verify.
+    #[error("backup failed verification: {0}")]
+    VerificationFailed(Box<Self>),
+}
+
+impl BackupError {
+    /// Returns a stable, machine-readable identifier for the kind of error.
+    /// Unlike the `Display` representation, these identifiers will not change
+    /// between versions.
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::IoError(_) => "io",
+            Self::CryptoError(_) => "crypto",
+            Self::InvalidIncludePath(_) => "invalid-include-path",
+            Self::DuplicateIncludeName { .. } => "duplicate-include-name",
+            Self::TooManyOpenFiles(_) => "too-many-open-files",
+            Self::EmptyBackup => "empty-backup",
+            Self::FileHashMismatch { .. } => "file-hash-mismatch",
+            Self::StrippedPathCollision { .. } => "stripped-path-collision",
+            Self::ExtractConflict(_) => "extract-conflict",
+            Self::PathAlreadyExists(_) => "path-exists",
+            Self::DangerousIncludePath(_) => "dangerous-include-path",
+            Self::InvalidIgnorePattern(_, _) => "invalid-ignore-pattern",
+            Self::InvalidReferenceDir(_) => "invalid-reference-dir",
+            Self::InvalidTempDir(_) => "invalid-temp-dir",
+            Self::InvalidHeader(_) => "invalid-header",
+            Self::UnsupportedFormatVersion(_) => "unsupported-format-version",
+            Self::IncorrectPassword => "incorrect-password",
+            Self::InvalidKdfParams(_) => "invalid-kdf-params",
+            Self::MissingChecksum => "missing-checksum",
+            Self::MissingIndex => "missing-index",
+            Self::PasswordRequired => "password-required",
+            Self::InvalidIndex(_) => "invalid-index",
+            Self::TruncatedBackup => "truncated-backup",
+            Self::ChecksumMismatch => "checksum-mismatch",
+            Self::UnsupportedChecksumAlgorithm(_) => "unsupported-checksum-algorithm",
+            Self::InvalidCompressionLevel(_) => "invalid-compression-level",
+            Self::IncompatibleOptions(_) => "incompatible-options",
+            Self::InvalidRecoveryKey => "invalid-recovery-key",
+            Self::DeterministicPasswordChange => "deterministic-password-change",
+            Self::Cancelled => "cancelled",
+            Self::VerificationFailed(_) => "verification-failed",
+        }
+    }
 }
 
 impl From<aes_gcm::Error> for BackupError {
@@ -34,6 +203,133 @@ impl From<aes_gcm::Error> for BackupError {
 /// An application-level backup-related `Result`.
 pub type BackupResult<T> = Result<T, BackupError>;
 
+/// Statistics about a completed backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupStats {
+    /// The path to the encrypted backup file, or an empty path if the backup
+    /// was written to or read from a stream.
+    pub path: PathBuf,
+    /// The size of the unencrypted archive, in bytes. If the archive was
+    /// compressed, this is its compressed size.
+    pub archive_size: u64,
+    /// The size of the encrypted backup file, in bytes.
+    pub output_size: u64,
+    /// How long the operation took.
+    pub elapsed: Duration,
+    /// Paths that were skipped, or only partially captured, because they
+    /// could not be read. This is only ever populated by backups that
+    /// continue past errors.
+    pub skipped: Vec<SkippedPath>,
+}
+
+/// A path that could not be read during a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedPath {
+    /// The path that could not be read.
+    pub path: PathBuf,
+    /// A description of the error encountered.
+    pub error: String,
+}
+
+impl BackupStats {
+    /// Returns the ratio of the encrypted backup size to the unencrypted
+    /// archive size.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn expansion_ratio(&self) -> f64 {
+        if self.archive_size == 0 {
+            1.
+        } else {
+            self.output_size as f64 / self.archive_size as f64
+        }
+    }
+
+    /// Returns the number of unencrypted archive bytes processed per second.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+
+        if secs == 0. {
+            0.
+        } else {
+            self.archive_size as f64 / secs
+        }
+    }
+}
+
+/// Information about an encrypted backup, read from its header without the
+/// password.
+///
+/// The metadata is authenticated with the data key, so tampering with it is
+/// only detected once the backup is opened with a password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    /// The path to the encrypted backup file.
+    pub path: PathBuf,
+    /// The version of the file format the backup is stored in, or `None` if
+    /// the backup was created before headers were introduced.
+    pub format_version: Option<u8>,
+    /// The size of each unencrypted chunk of the payload.
+    pub chunk_size: u64,
+    /// The number of passwords or keys that can open the backup.
+    pub key_slots: usize,
+    /// The algorithm used to compute the checksum of the payload, or `None`
+    /// if the backup has no checksum.
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Whether the archive was compressed before it was encrypted.
+    pub compressed: bool,
+    /// When the backup was created, or `None` if the format version predates
+    /// recording it.
+    pub created: Option<DateTime<Utc>>,
+    /// The version of the tool that created the backup, or `None` if the
+    /// format version predates recording it.
+    pub tool_version: Option<String>,
+    /// Whether and how the backup stores an index of its contents.
+    pub index: IndexMode,
+    /// The size of the encrypted backup file, in bytes.
+    pub size: u64,
+}
+
+/// The type of an entry in a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+    /// A hard link to a file stored earlier in the backup.
+    HardLink,
+    /// A special file, such as a FIFO or device node.
+    Special,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::File => "file",
+            Self::Directory => "directory",
+            Self::Symlink => "symlink",
+            Self::HardLink => "hard link",
+            Self::Special => "special file",
+        })
+    }
+}
+
+/// An entry in the index of a backup's contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The path of the entry within the backup.
+    pub path: PathBuf,
+    /// The type of the entry.
+    pub kind: EntryKind,
+    /// The size of the entry's contents, in bytes. This is zero for
+    /// everything but regular files.
+    pub size: u64,
+}
+
 /// A type of path.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
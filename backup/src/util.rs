@@ -1,6 +1,15 @@
 //! Application-level utility functions.
 
+use std::cell::Cell;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+/// The size of the blocks of zeros written when securely removing a file.
+const WIPE_BLOCK_SIZE: usize = 64 * 1024;
 
 /// Returns the provided path with `.tmp` added to it.
 pub fn tmp_file_for(path: impl AsRef<Path>) -> PathBuf {
@@ -8,3 +17,459 @@ pub fn tmp_file_for(path: impl AsRef<Path>) -> PathBuf {
     tmp_path.as_mut_os_string().push(".tmp");
     tmp_path
 }
+
+/// Returns the path of a temporary file for the provided path, with `.tmp`
+/// added to it. If a directory is provided, the temporary file is placed in
+/// that directory instead of alongside the provided path.
+pub fn tmp_file_in(dir: Option<&Path>, path: impl AsRef<Path>) -> PathBuf {
+    match (dir, path.as_ref().file_name()) {
+        (Some(dir), Some(file_name)) => tmp_file_for(dir.join(file_name)),
+        _ => tmp_file_for(path),
+    }
+}
+
+/// Returns the provided path with `.partial` added to it, for a directory
+/// that is extracted to before being renamed to the provided path.
+pub fn partial_dir_for(path: impl AsRef<Path>) -> PathBuf {
+    let mut partial_path = path.as_ref().to_path_buf();
+    partial_path.as_mut_os_string().push(".partial");
+    partial_path
+}
+
+/// Returns the provided path with `.progress` added to it.
+pub fn progress_file_for(path: impl AsRef<Path>) -> PathBuf {
+    let mut progress_path = path.as_ref().to_path_buf();
+    progress_path.as_mut_os_string().push(".progress");
+    progress_path
+}
+
+/// A writer that counts the number of bytes written through it.
+pub struct CountingWriter<W> {
+    /// The underlying writer.
+    inner: W,
+    /// The number of bytes written so far.
+    bytes_written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    /// Wraps a writer to count the bytes written to it.
+    pub const fn new(inner: W) -> Self {
+        Self {
+            inner,
+            bytes_written: 0,
+        }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub const fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer that keeps a shared count of the bytes written through it, so
+/// that its position can be read while it is owned by something else, such
+/// as a tar builder.
+pub struct PositionWriter<W> {
+    /// The underlying writer.
+    inner: W,
+    /// The number of bytes written so far.
+    position: Rc<Cell<u64>>,
+}
+
+impl<W: Write> PositionWriter<W> {
+    /// Wraps a writer to count the bytes written to it in the given counter.
+    pub const fn new(inner: W, position: Rc<Cell<u64>>) -> Self {
+        Self { inner, position }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for PositionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.position.set(self.position.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that produces exactly a fixed number of bytes from an underlying
+/// reader, truncating anything beyond it and padding with zeros if the
+/// underlying reader ends early or fails.
+pub struct SizedReader<R> {
+    /// The underlying reader.
+    inner: R,
+    /// The number of bytes left to produce.
+    remaining: u64,
+    /// The number of zero bytes produced after the underlying reader ended.
+    padding: u64,
+    /// The error the underlying reader failed with, if any.
+    error: Option<io::Error>,
+}
+
+impl<R: Read> SizedReader<R> {
+    /// Wraps a reader to produce exactly `size` bytes from it.
+    pub const fn new(inner: R, size: u64) -> Self {
+        Self {
+            inner,
+            remaining: size,
+            padding: 0,
+            error: None,
+        }
+    }
+
+    /// Takes the error the underlying reader failed with, if any. Everything
+    /// after the failure was padded with zeros.
+    pub const fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Returns the number of zero bytes produced because the underlying reader
+    /// ended early.
+    pub const fn padding(&self) -> u64 {
+        self.padding
+    }
+
+    /// Checks whether the underlying reader has data beyond the bytes that
+    /// were produced, which would have been truncated.
+    pub fn truncated(&mut self) -> io::Result<bool> {
+        Ok(self.padding == 0 && self.inner.read(&mut [0])? > 0)
+    }
+}
+
+impl<R: Read> Read for SizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[allow(clippy::cast_possible_truncation)]
+        let len = self.remaining.min(buf.len() as u64) as usize;
+
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let n = if self.padding > 0 {
+            0
+        } else {
+            match self.inner.read(&mut buf[..len]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => {
+                    // Keep the error for the caller, so that what has been
+                    // produced so far is still padded to the full size
+                    self.error = Some(e);
+                    0
+                }
+            }
+        };
+
+        let n = if n == 0 {
+            // The underlying reader has ended, so pad out the rest
+            buf[..len].fill(0);
+            self.padding += len as u64;
+            len
+        } else {
+            n
+        };
+
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// The number of permits left in an open file limiter, and a condition
+/// variable notified whenever one is returned.
+type PermitState = Arc<(Mutex<usize>, Condvar)>;
+
+/// A counting semaphore that limits how many files are open at once.
+#[derive(Debug, Clone)]
+pub struct OpenFileLimiter {
+    /// The shared permit state, or `None` if there is no limit.
+    state: Option<PermitState>,
+}
+
+impl OpenFileLimiter {
+    /// Creates a limiter allowing up to `limit` files to be open at once, or
+    /// any number if no limit is given.
+    pub fn new(limit: Option<NonZeroUsize>) -> Self {
+        Self {
+            state: limit.map(|limit| Arc::new((Mutex::new(limit.get()), Condvar::new()))),
+        }
+    }
+
+    /// Waits until a file may be opened, returning a permit that allows it
+    /// to stay open until the permit is dropped.
+    pub fn acquire(&self) -> OpenFilePermit {
+        if let Some(state) = &self.state {
+            let (available, returned) = &**state;
+            let mut available = returned
+                .wait_while(
+                    available.lock().unwrap_or_else(PoisonError::into_inner),
+                    |available| *available == 0,
+                )
+                .unwrap_or_else(PoisonError::into_inner);
+            *available -= 1;
+        }
+
+        OpenFilePermit {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// Permission to keep a file open, returned to its limiter when dropped.
+#[derive(Debug)]
+pub struct OpenFilePermit {
+    /// The shared permit state of the limiter, or `None` if it has no limit.
+    state: Option<PermitState>,
+}
+
+impl Drop for OpenFilePermit {
+    fn drop(&mut self) {
+        if let Some(state) = &self.state {
+            let (available, returned) = &**state;
+            *available.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+            returned.notify_one();
+        }
+    }
+}
+
+/// Checks whether an I/O error was caused by the process or system running
+/// out of file descriptors.
+pub fn is_too_many_open_files(error: &io::Error) -> bool {
+    /// `EMFILE` and `ENFILE`.
+    #[cfg(unix)]
+    const TOO_MANY_OPEN_FILES_CODES: &[i32] = &[24, 23];
+
+    /// `ERROR_TOO_MANY_OPEN_FILES`.
+    #[cfg(windows)]
+    const TOO_MANY_OPEN_FILES_CODES: &[i32] = &[4];
+
+    #[cfg(not(any(unix, windows)))]
+    const TOO_MANY_OPEN_FILES_CODES: &[i32] = &[];
+
+    error
+        .raw_os_error()
+        .is_some_and(|code| TOO_MANY_OPEN_FILES_CODES.contains(&code))
+}
+
+/// Removes a file when dropped, unless it has been marked as complete.
+///
+/// This keeps an output file that was only partly written, because an
+/// operation failed part way through, from being mistaken for a good one.
+#[derive(Debug)]
+pub struct PartialFileGuard {
+    /// The path of the file, or `None` once it has been marked as complete.
+    path: Option<PathBuf>,
+}
+
+impl PartialFileGuard {
+    /// Guards a file that has just been created.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: Some(path.as_ref().to_path_buf()),
+        }
+    }
+
+    /// Marks the file as complete, so that it is kept.
+    pub fn complete(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PartialFileGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Overwrites a file with zeros and flushes it to disk before removing it.
+///
+/// This is a best effort. Copy-on-write filesystems, journaling, and SSD wear
+/// leveling may all keep the original contents elsewhere on the device, so
+/// this reduces casual recovery but does not guarantee the data is gone.
+pub fn remove_file_securely(path: impl AsRef<Path>) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(&path)?;
+    let mut remaining = file.metadata()?.len();
+    let zeros = vec![0; WIPE_BLOCK_SIZE];
+
+    while remaining > 0 {
+        #[allow(clippy::cast_possible_truncation)]
+        let block_size = remaining.min(WIPE_BLOCK_SIZE as u64) as usize;
+        file.write_all(&zeros[..block_size])?;
+        remaining -= block_size as u64;
+    }
+
+    file.sync_all()?;
+    drop(file);
+
+    fs::remove_file(path)
+}
+
+/// Flushes the contents and metadata of a file to the storage device, so that
+/// they survive a crash or power loss.
+pub fn sync_file(path: impl AsRef<Path>) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
+/// Flushes the directory containing a path to the storage device, so that a
+/// file just created or renamed into it survives a crash or power loss. Only
+/// supported on Unix platforms, since directories cannot be opened to be
+/// flushed elsewhere.
+pub fn sync_parent_dir(path: impl AsRef<Path>) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.as_ref().parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::File::open(parent)?.sync_all()?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("read failed"))
+        }
+    }
+
+    #[test]
+    fn test_sized_reader() {
+        // Shorter than the size
+        let mut reader = SizedReader::new(&b"abc"[..], 5);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abc\0\0");
+        assert_eq!(reader.padding(), 2);
+        assert!(!reader.truncated().unwrap());
+
+        // Longer than the size
+        let mut reader = SizedReader::new(&b"abcdef"[..], 4);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abcd");
+        assert_eq!(reader.padding(), 0);
+        assert!(reader.truncated().unwrap());
+
+        // Failing part way through
+        let failing = (&b"ab"[..]).chain(FailingReader);
+        let mut reader = SizedReader::new(failing, 4);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"ab\0\0");
+        assert!(reader.take_error().is_some());
+
+        // Exactly the size
+        let mut reader = SizedReader::new(&b"abc"[..], 3);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abc");
+        assert_eq!(reader.padding(), 0);
+        assert!(!reader.truncated().unwrap());
+    }
+
+    #[test]
+    fn test_open_file_limiter() {
+        use std::sync::mpsc::channel;
+        use std::thread;
+        use std::time::Duration;
+
+        let limiter = OpenFileLimiter::new(NonZeroUsize::new(2));
+        let first = limiter.acquire();
+        let _second = limiter.acquire();
+
+        // A third permit is only granted once one is returned
+        let (sender, receiver) = channel();
+        let waiter = thread::spawn(move || {
+            let _third = limiter.acquire();
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(first);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        waiter.join().unwrap();
+
+        // Without a limit, permits are always granted
+        let limiter = OpenFileLimiter::new(None);
+        let _permits = (0..100).map(|_| limiter.acquire()).collect::<Vec<_>>();
+    }
+
+    #[test]
+    fn test_partial_file_guard() {
+        let dir = tempfile::tempdir().unwrap();
+        let partial_path = dir.path().join("partial");
+        let complete_path = dir.path().join("complete");
+        fs::write(&partial_path, "partial").unwrap();
+        fs::write(&complete_path, "complete").unwrap();
+
+        drop(PartialFileGuard::new(&partial_path));
+        PartialFileGuard::new(&complete_path).complete();
+
+        assert!(!partial_path.exists());
+        assert!(complete_path.exists());
+    }
+
+    #[test]
+    fn test_remove_file_securely() {
+        let path =
+            std::env::temp_dir().join(format!("encrypted-backup-wipe-test-{}", std::process::id()));
+        let contents = vec![0xAB; WIPE_BLOCK_SIZE * 2 + 17];
+        fs::write(&path, &contents).unwrap();
+
+        // A handle kept open across the removal still reads what the file
+        // held when it was removed
+        let mut handle = fs::File::open(&path).unwrap();
+        remove_file_securely(&path).unwrap();
+        assert!(!path.exists());
+        assert!(remove_file_securely(&path).is_err());
+
+        let mut wiped = Vec::new();
+        handle.read_to_end(&mut wiped).unwrap();
+        assert_eq!(wiped.len(), contents.len());
+        assert!(wiped.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_sync_file() {
+        let path =
+            std::env::temp_dir().join(format!("encrypted-backup-sync-test-{}", std::process::id()));
+        fs::write(&path, "synced").unwrap();
+
+        sync_file(&path).unwrap();
+        sync_parent_dir(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "synced");
+
+        fs::remove_file(&path).unwrap();
+        assert!(sync_file(&path).is_err());
+    }
+}
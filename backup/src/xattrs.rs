@@ -0,0 +1,112 @@
+//! Extended attribute capture and restoration.
+//!
+//! Extended attributes are stored in a PAX header preceding the archive entry
+//! they belong to, under the same keys that GNU tar and libarchive use, so
+//! that other tools can restore them too. They are only supported on Unix
+//! platforms. Anywhere they are not supported, including filesystems without
+//! extended attributes, they are skipped and a debug message is logged.
+
+use log::debug;
+use std::io::Read;
+use std::path::Path;
+
+/// The prefix of the PAX header keys under which extended attributes are
+/// stored.
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Reads the extended attributes of a path, as PAX header keys and values.
+#[cfg(unix)]
+pub fn read_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("Skipping extended attributes of '{}': {e}", path.display());
+            return Vec::new();
+        }
+    };
+
+    names
+        .filter_map(|name| {
+            // PAX header keys must be UTF-8
+            let Some(key_name) = name.to_str() else {
+                debug!(
+                    "Skipping extended attribute {} of '{}': name is not UTF-8",
+                    name.display(),
+                    path.display()
+                );
+                return None;
+            };
+
+            match xattr::get(path, &name) {
+                Ok(value) => value.map(|value| (format!("{PAX_XATTR_PREFIX}{key_name}"), value)),
+                Err(e) => {
+                    debug!(
+                        "Skipping extended attribute {key_name} of '{}': {e}",
+                        path.display()
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Reads the extended attributes of a path, as PAX header keys and values.
+#[cfg(not(unix))]
+pub fn read_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    debug!(
+        "Skipping extended attributes of '{}': not supported on this platform",
+        path.display()
+    );
+    Vec::new()
+}
+
+/// Reads the extended attributes stored in the PAX header of an archive
+/// entry, as attribute names and values.
+pub fn entry_xattrs<R: Read>(entry: &mut tar::Entry<R>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let Ok(Some(extensions)) = entry.pax_extensions() else {
+        return Vec::new();
+    };
+
+    extensions
+        .filter_map(Result::ok)
+        .filter_map(|extension| {
+            let name = extension
+                .key_bytes()
+                .strip_prefix(PAX_XATTR_PREFIX.as_bytes())?;
+            Some((name.to_vec(), extension.value_bytes().to_vec()))
+        })
+        .collect()
+}
+
+/// Sets extended attributes on a path. Attributes that cannot be set are
+/// skipped.
+#[cfg(unix)]
+pub fn write_xattrs(path: &Path, xattrs: &[(Vec<u8>, Vec<u8>)]) {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    for (name, value) in xattrs {
+        let name = OsStr::from_bytes(name);
+
+        if let Err(e) = xattr::set(path, name, value) {
+            debug!(
+                "Skipping extended attribute {} of '{}': {e}",
+                name.display(),
+                path.display()
+            );
+        }
+    }
+}
+
+/// Sets extended attributes on a path. Attributes that cannot be set are
+/// skipped.
+#[cfg(not(unix))]
+pub fn write_xattrs(path: &Path, xattrs: &[(Vec<u8>, Vec<u8>)]) {
+    if !xattrs.is_empty() {
+        debug!(
+            "Skipping extended attributes of '{}': not supported on this platform",
+            path.display()
+        );
+    }
+}
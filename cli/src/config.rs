@@ -0,0 +1,152 @@
+//! Project files holding the settings of repeatable backups.
+//!
+//! A project file is a TOML file written by the user, passed to the backup
+//! subcommand with `--config`. It can hold the same settings as the
+//! corresponding flags:
+//!
+//! ```toml
+//! include = ["documents", "photos"]
+//! exclude = ["**/*.tmp"]
+//! exclude-regex = ["/cache/"]
+//! output-template = "/backups/home-{date}.ebk"
+//! chunk-size-magnitude = 18
+//! pool-size = 8
+//! ```
+//!
+//! Every setting is optional. Relative paths are resolved from the directory
+//! containing the file, so that a project can be backed up from anywhere.
+//! Flags given on the command line override the file's settings, with lists
+//! replaced rather than extended.
+
+use crate::{
+    validate_chunk_size, validate_glob, validate_output_parent, validate_output_template,
+    validate_path, validate_pool_size, validate_regex, BackupArgs, Failure,
+};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The settings of a backup project file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct BackupConfig {
+    /// Paths to include in the backup.
+    include: Vec<PathBuf>,
+    /// Globs to exclude from the backup.
+    exclude: Vec<String>,
+    /// Regular expressions to exclude from the backup.
+    exclude_regex: Vec<String>,
+    /// Output path of the backup.
+    output: Option<PathBuf>,
+    /// A template for the output path of the backup.
+    output_template: Option<String>,
+    /// Size of each chunk of the backup, as an order of magnitude.
+    chunk_size_magnitude: Option<u8>,
+    /// Number of workers in the crypto pool.
+    pool_size: Option<u8>,
+}
+
+impl BackupConfig {
+    /// Reads and parses a project file.
+    fn load(path: &Path) -> Result<Self, Failure> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            Failure::new(
+                "invalid-config",
+                format!("Failed to read config file: {}, {e}", path.display()),
+            )
+        })?;
+
+        toml::from_str(&contents).map_err(|e| {
+            Failure::new(
+                "invalid-config",
+                format!("Invalid config file: {}, {e}", path.display()),
+            )
+        })
+    }
+}
+
+/// Fills in the backup arguments that were not given on the command line
+/// from the project file, if one was given. The file's settings are
+/// validated as the flags would be.
+pub fn apply_backup_config(args: &mut BackupArgs) -> Result<(), Failure> {
+    let Some(config_path) = args.config.clone() else {
+        return Ok(());
+    };
+
+    let config = BackupConfig::load(&config_path)?;
+    let base = config_path.parent().unwrap_or_else(|| Path::new(""));
+    let invalid = |e: String| {
+        Failure::new(
+            "invalid-config",
+            format!("{e} (in {})", config_path.display()),
+        )
+    };
+
+    if args.include_paths.is_empty() && args.include_from.is_empty() {
+        args.include_paths = config
+            .include
+            .iter()
+            .map(|path| validate_path(&base.join(path).to_string_lossy()))
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+    }
+
+    if args.exclude_globs.is_empty() {
+        args.exclude_globs = config
+            .exclude
+            .iter()
+            .map(|glob| validate_glob(glob))
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+    }
+
+    if args.exclude_regex.is_empty() {
+        args.exclude_regex = config
+            .exclude_regex
+            .iter()
+            .map(|regex| validate_regex(regex))
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+    }
+
+    if config.output.is_some() && config.output_template.is_some() {
+        return Err(invalid(
+            "An output path and output template cannot both be set".to_owned(),
+        ));
+    }
+
+    if args.output_path.is_none() && args.output_template.is_none() {
+        args.output_path = config
+            .output
+            .map(|path| validate_output_parent(&base.join(path).to_string_lossy()))
+            .transpose()
+            .map_err(invalid)?;
+        args.output_template = config
+            .output_template
+            .map(|template| validate_output_template(&base.join(template).to_string_lossy()))
+            .transpose()
+            .map_err(invalid)?;
+    }
+
+    if args.chunk_size_magnitude.is_none() && args.chunk_bytes.is_none() {
+        args.chunk_size_magnitude = config
+            .chunk_size_magnitude
+            .map(|magnitude| validate_chunk_size(&magnitude.to_string()))
+            .transpose()
+            .map_err(invalid)?;
+    }
+
+    if args.pool_size.is_none() {
+        args.pool_size = config
+            .pool_size
+            .map(|size| validate_pool_size(&size.to_string()))
+            .transpose()
+            .map_err(invalid)?;
+    }
+
+    if args.include_paths.is_empty() && args.include_from.is_empty() {
+        return Err(invalid("At least one include path is required".to_owned()));
+    }
+
+    Ok(())
+}
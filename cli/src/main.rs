@@ -15,11 +15,36 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::multiple_crate_versions)]
 
+mod config;
+mod progress;
+mod serve;
+
+use crate::progress::ProgressDisplay;
 use backup::*;
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Local};
+use clap::builder::PossibleValuesParser;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 use glob::Pattern;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::process::exit;
+use std::process::{self, exit};
+use std::thread;
+use std::time::Duration;
+
+/// The chunk size magnitude of backups that do not specify one.
+const DEFAULT_CHUNK_SIZE_MAGNITUDE: u8 = 16;
+
+/// How often a backup checks whether its cancel file exists.
+const CANCEL_FILE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The exit code of an operation that was cancelled and cleaned up after
+/// itself, so that it can be told apart from one that failed.
+const CANCELLED_EXIT_CODE: i32 = 3;
 
 /// A tool to securely back up files and directories.
 #[derive(Parser, Debug)]
@@ -28,72 +53,867 @@ struct Cli {
     /// Encrypted backup subcommands.
     #[command(subcommand)]
     command: Commands,
+    /// The format of the final result. The JSON format emits a single object
+    /// describing the success or failure of the command, and log records are
+    /// written to standard error so that they cannot be mixed up with it.
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
+    /// The format of log records. The JSON format emits one object per line,
+    /// with the timestamp, level, and message of the record, for ingestion
+    /// into log pipelines.
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Human)]
+    log_format: OutputFormat,
+}
+
+/// The format of the final result or the log records of a command.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human-readable prose.
+    Human,
+    /// Machine-readable JSON.
+    Json,
+}
+
+impl From<OutputFormat> for LogFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Human => Self::Human,
+            OutputFormat::Json => Self::Json,
+        }
+    }
+}
+
+/// How a command writes its log records.
+#[derive(Debug, Clone, Copy)]
+struct Logging {
+    /// The format of the records.
+    format: LogFormat,
+    /// Where the records are written.
+    target: LogTarget,
+}
+
+impl Logging {
+    /// Initializes logging, including debug records if requested.
+    fn init(self, debug: bool) {
+        init_logger(debug, self.format, self.target).unwrap();
+    }
+}
+
+/// The hash algorithm used to checksum a backup.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ChecksumArg {
+    /// SHA-256.
+    Sha256,
+    /// BLAKE3. Only available when built with the `blake3` feature.
+    Blake3,
+}
+
+impl From<ChecksumArg> for ChecksumAlgorithm {
+    fn from(checksum: ChecksumArg) -> Self {
+        match checksum {
+            ChecksumArg::Sha256 => Self::Sha256,
+            ChecksumArg::Blake3 => Self::Blake3,
+        }
+    }
+}
+
+/// How symbolic links are stored in a backup.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SymlinkArg {
+    /// Store whatever the link points to.
+    Follow,
+    /// Store the link with its target as it is.
+    Verbatim,
+    /// Store the link, making absolute targets inside the backed up
+    /// directory relative.
+    Relativize,
+}
+
+impl From<SymlinkArg> for SymlinkTarget {
+    fn from(symlinks: SymlinkArg) -> Self {
+        match symlinks {
+            SymlinkArg::Follow => Self::Follow,
+            SymlinkArg::Verbatim => Self::Verbatim,
+            SymlinkArg::Relativize => Self::RelativizeWithinRoot,
+        }
+    }
+}
+
+/// What to do with special files in a backup.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SpecialFileArg {
+    /// Leave special files out, with a warning for each.
+    Skip,
+    /// Store FIFOs and device nodes, so that they are recreated on
+    /// extraction.
+    Store,
+}
+
+impl From<SpecialFileArg> for SpecialFileMode {
+    fn from(special_files: SpecialFileArg) -> Self {
+        match special_files {
+            SpecialFileArg::Skip => Self::Skip,
+            SpecialFileArg::Store => Self::Store,
+        }
+    }
+}
+
+/// Whether a backup stores an index of its contents.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum IndexArg {
+    /// Store no index.
+    None,
+    /// Store an index that can be listed without the password.
+    Plain,
+    /// Store an index that needs the password to be listed.
+    Encrypted,
+}
+
+impl From<IndexArg> for IndexMode {
+    fn from(index: IndexArg) -> Self {
+        match index {
+            IndexArg::None => Self::None,
+            IndexArg::Plain => Self::Plain,
+            IndexArg::Encrypted => Self::Encrypted,
+        }
+    }
+}
+
+/// What to do with an entry whose path already holds a different file.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictArg {
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file.
+    Skip,
+    /// Extract next to the existing file, with a numeric suffix.
+    Rename,
+    /// Stop the extraction.
+    Fail,
+}
+
+impl From<ConflictArg> for ConflictPolicy {
+    fn from(conflict: ConflictArg) -> Self {
+        match conflict {
+            ConflictArg::Overwrite => Self::Overwrite,
+            ConflictArg::Skip => Self::Skip,
+            ConflictArg::Rename => Self::Rename,
+            ConflictArg::Fail => Self::Fail,
+        }
+    }
 }
 
 /// Encrypted backup subcommands.
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Backs up and encrypts files and directories.
-    Backup {
-        /// Paths to include in the backup.
-        #[arg(required = true, value_parser = validate_path)]
-        include_paths: Vec<PathBuf>,
-        /// Globs to exclude from the backup, separated by commas.
-        #[arg(short, long, value_delimiter = ',', value_parser = validate_glob)]
-        exclude_globs: Vec<Pattern>,
-        /// Output path of the backup.
-        #[arg(short, long, required = true, value_parser = validate_output_path)]
-        output_path: PathBuf,
-        /// Password for the backup file. The same password will be needed to
-        /// extract the backup later. Without it, the backup cannot be
-        /// extracted. If not provided, the password will be prompted from
-        /// standard input.
-        #[arg(short, long, value_parser = validate_password)]
-        password: Option<String>,
-        /// Size of each chunk of the backup, as an order of magnitude. For a
-        /// provided chunk size magnitude n, each chunk will be 2^n bytes. A
-        /// higher chunk size means a faster backup, but greater memory usage.
-        /// The default magnitude is 16, equivalent to a chunk size of 64 KiB.
-        /// Note that the same chunk size will be used to extract the backup.
-        #[arg(short, long, value_parser = validate_chunk_size, default_value_t = 16)]
-        chunk_size_magnitude: u8,
-        /// Number of workers to spawn in the pool that will perform crypto
-        /// operations in parallel. The default pool size is 4. The optimal size
-        /// is typically closer to 16, but higher numbers will be more taxing on
-        /// the CPU.
-        #[arg(long, value_parser = validate_pool_size, default_value_t = 4)]
-        pool_size: u8,
-        /// Overrides the 1GB memory limit.
-        #[arg(long, value_parser, default_value_t = false)]
-        override_memory_limit: bool,
-        /// Debug mode.
-        #[arg(short, long, value_parser, default_value_t = false)]
-        debug: bool,
-    },
+    Backup(Box<BackupArgs>),
     /// Decrypts and extracts an encrypted backup.
-    Extract {
-        /// Path to the encrypted backup.
-        #[arg(required = true, value_parser = validate_file)]
-        backup_path: PathBuf,
-        /// Path to extract the backup to.
-        #[arg(short, long, value_parser = validate_output_path)]
-        output_path: PathBuf,
-        /// Password for the backup file. If not provided, the password will
-        /// be prompted from standard input.
-        #[arg(short, long, value_parser)]
-        password: Option<String>,
-        /// Number of workers to spawn in the pool that will perform crypto
-        /// operations in parallel. The default pool size is 16. This is
-        /// usually an optimal size, and can speed things up substantially.
-        #[arg(short, long, value_parser = validate_pool_size, default_value_t = 16)]
-        pool_size: u8,
-        /// Overrides the 1GB memory limit.
-        #[arg(long, value_parser, default_value_t = false)]
-        override_memory_limit: bool,
-        /// Debug mode.
-        #[arg(short, long, value_parser, default_value_t = false)]
-        debug: bool,
-    },
+    Extract(ExtractArgs),
+    /// Checks that an encrypted backup decrypts in full, without extracting
+    /// it.
+    Verify(VerifyArgs),
+    /// Changes one of the passwords that can open an encrypted backup,
+    /// without re-encrypting it.
+    ChangePassword(ChangePasswordArgs),
+    /// Combines several encrypted backups into one new encrypted backup.
+    Merge(MergeArgs),
+    /// Generates a key pair for recovering backups. The private key is
+    /// written to a new file, and the public key is printed, to be given to
+    /// `backup --recovery-pubkey`.
+    RecoveryKeygen(RecoveryKeygenArgs),
+    /// Shows the format details recorded in an encrypted backup's header,
+    /// without the password.
+    Info(InfoArgs),
+    /// Lists the contents of an encrypted backup from its index, without
+    /// decrypting it.
+    List(ListArgs),
+    /// Checks encrypted backups against their stored checksums, without the
+    /// password, to detect corruption in storage.
+    Scrub(ScrubArgs),
+    /// Benchmarks encryption with a range of chunk sizes and pool sizes, and
+    /// recommends the fastest settings for this machine.
+    Calibrate(CalibrateArgs),
+    /// Runs as a daemon, performing backups and extractions requested as
+    /// lines of JSON over a local socket, one operation at a time.
+    Serve(ServeArgs),
+}
+
+/// Arguments to the backup subcommand.
+#[derive(Args, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct BackupArgs {
+    /// Paths to include in the backup.
+    #[arg(
+        required_unless_present_any = ["include_from", "config"],
+        value_parser = validate_include_path
+    )]
+    include_paths: Vec<PathBuf>,
+    /// Expands each include path as a glob, such as `photos/*.jpg`, and
+    /// includes every file and directory it matches. Useful when the shell
+    /// does not expand globs itself, or when they are quoted. Without this,
+    /// include paths are always taken literally.
+    #[arg(long, value_parser, default_value_t = false)]
+    glob: bool,
+    /// A TOML project file holding the settings of the backup, such as its
+    /// include paths, exclusions, output path, chunk size and pool size.
+    /// Relative paths in the file are resolved from its directory. Flags
+    /// override the settings in the file.
+    #[arg(long, value_parser = validate_file)]
+    config: Option<PathBuf>,
+    /// Files listing additional paths to include in the backup, one per
+    /// line. Blank lines and lines beginning with `#` are ignored. Relative
+    /// paths are resolved from the current directory, as they are on the
+    /// command line. May be given multiple times.
+    #[arg(long = "include-from", value_name = "FILE", value_parser = validate_include_file)]
+    include_from: Vec<IncludeList>,
+    /// Globs to exclude from the backup, separated by commas.
+    #[arg(short, long, value_delimiter = ',', value_parser = validate_glob)]
+    exclude_globs: Vec<Pattern>,
+    /// Excludes directories that are rarely worth backing up, such as
+    /// caches, version control data, and build output, wherever they
+    /// appear. Individual entries can be kept with --keep-common.
+    #[arg(long, value_parser, default_value_t = false)]
+    exclude_common: bool,
+    /// Common exclusions to back up anyway, separated by commas, when
+    /// excluding common directories.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        requires = "exclude_common",
+        value_parser = PossibleValuesParser::new(COMMON_EXCLUDES)
+    )]
+    keep_common: Vec<String>,
+    /// Regular expressions to exclude from the backup. May be given multiple
+    /// times. Each is matched against the path of every entry relative to
+    /// the parent of its include path, using `/` as the separator on all
+    /// platforms.
+    #[arg(long = "exclude-regex", value_parser = validate_regex)]
+    exclude_regex: Vec<Regex>,
+    /// Applies the globs listed in `.backupignore` files found in backed
+    /// up directories. Each file's globs are matched relative to its
+    /// directory and only apply within it. A path is excluded if it
+    /// matches either an exclude glob or a `.backupignore` glob.
+    #[arg(long, value_parser, default_value_t = false)]
+    follow_backupignore: bool,
+    /// Skips hidden files and directories, such as dotfiles. Include paths
+    /// that are hidden themselves are still backed up.
+    #[arg(long, value_parser, default_value_t = false)]
+    exclude_hidden: bool,
+    /// Logs every path that is excluded from the backup, along with the
+    /// glob, regex or `.backupignore` pattern that excluded it. Useful for
+    /// finding out why files are missing from a backup. Implies debug mode,
+    /// since the paths are logged.
+    #[arg(long, value_parser, default_value_t = false)]
+    list_excluded: bool,
+    /// Output path of the backup. Required unless an output template is
+    /// given or the output is set in the project file.
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["config", "output_template"],
+        value_parser = validate_output_parent
+    )]
+    output_path: Option<PathBuf>,
+    /// A template for the output path, in which `{date}`, `{time}` and
+    /// `{datetime}` are replaced with the current local date and time, such
+    /// as `/backups/home-{date}.ebk`. The format of each can be changed
+    /// with a chrono format string after a colon, as in `{date:%Y%m%d}`.
+    /// By default, dates are formatted as `2024-01-31`, times as
+    /// `13-45-00`, and both together as `2024-01-31_13-45-00`.
+    #[arg(long, conflicts_with = "output_path", value_parser = validate_output_template)]
+    output_template: Option<String>,
+    /// The extension appended to the output path if it does not have one.
+    #[arg(long, default_value = "ebk", value_parser = validate_extension)]
+    extension: String,
+    /// Uses the output path exactly as given, without appending an
+    /// extension if it does not have one.
+    #[arg(long, value_parser, default_value_t = false)]
+    no_auto_extension: bool,
+    /// Replaces the output file if it already exists. The new backup is
+    /// written alongside it and only renamed over it once complete, so the
+    /// existing backup is kept if anything goes wrong.
+    #[arg(long, value_parser, default_value_t = false)]
+    overwrite: bool,
+    /// Password for the backup file. The same password will be needed to
+    /// extract the backup later. Without it, the backup cannot be
+    /// extracted. If not provided, the password will be prompted from
+    /// standard input.
+    #[arg(short, long, value_parser = validate_password)]
+    password: Option<String>,
+    /// Reads the password from a single line of standard input instead of
+    /// prompting for it, for use in scripts. Only the trailing newline is
+    /// removed.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "password"
+    )]
+    password_stdin: bool,
+    /// Size of each chunk of the backup, as an order of magnitude. For a
+    /// provided chunk size magnitude n, each chunk will be 2^n bytes. A
+    /// higher chunk size means a faster backup, but greater memory usage.
+    /// The default magnitude is 16, equivalent to a chunk size of 64 KiB.
+    /// Note that the same chunk size will be used to extract the backup.
+    #[arg(short, long, value_parser = validate_chunk_size)]
+    chunk_size_magnitude: Option<u8>,
+    /// Size of each chunk of the backup in bytes, for chunk sizes that are
+    /// not a power of two, such as "48 KiB" to match a storage block size.
+    /// Accepts units of B, KiB, MiB, and GiB, and must be between 1 KiB and
+    /// 1 GiB. Cannot be combined with --chunk-size-magnitude.
+    #[arg(long, value_parser = validate_chunk_bytes, conflicts_with = "chunk_size_magnitude")]
+    chunk_bytes: Option<usize>,
+    /// Number of workers to spawn in the pool that will perform crypto
+    /// operations in parallel. The default pool size is 4. The optimal size
+    /// is typically closer to 16, but higher numbers will be more taxing on
+    /// the CPU.
+    #[arg(long, value_parser = validate_pool_size)]
+    pool_size: Option<u8>,
+    /// Stores hard linked files as independent copies instead of
+    /// preserving the links between them.
+    #[arg(long, value_parser, default_value_t = false)]
+    dereference_hardlinks: bool,
+    /// Whether to descend into directories on other filesystems, such as
+    /// mounted network drives. Pass `--follow-mounts=false` to stay on the
+    /// filesystem of each include path, as with `tar --one-file-system`. On
+    /// Windows, this skips reparse points such as mounted volumes and
+    /// directory junctions.
+    #[arg(long, action = ArgAction::Set, default_value_t = true)]
+    follow_mounts: bool,
+    /// How to store symbolic links inside the backed up directories. By
+    /// default they are followed, storing what they point to. Include paths
+    /// that are symbolic links themselves are always followed.
+    #[arg(long, value_enum, default_value_t = SymlinkArg::Follow)]
+    symlinks: SymlinkArg,
+    /// What to do with special files, such as FIFOs, sockets and device
+    /// nodes. By default they are skipped with a warning. When stored, FIFOs
+    /// are kept on Unix platforms and device nodes on Linux, while sockets
+    /// are always skipped. Restoring device nodes usually requires root.
+    #[arg(long, value_enum, default_value_t = SpecialFileArg::Skip)]
+    special_files: SpecialFileArg,
+    /// Stores each include path under its full absolute path instead of its
+    /// name, so that extracting with `--restore-to-root` puts everything
+    /// back in its original location. Exclude globs and regexes must then
+    /// match the full path, without the leading separator.
+    #[arg(long, value_parser, default_value_t = false)]
+    absolute_paths: bool,
+    /// Records every entry as owned by the given user and group IDs, given
+    /// as `UID:GID`, instead of their actual owners. Useful when the backup
+    /// will be restored by a different user.
+    #[arg(long, value_name = "UID:GID", value_parser = validate_owner)]
+    owner: Option<(u32, u32)>,
+    /// Stores an index of the backup's contents, so that they can be listed
+    /// quickly with the list subcommand. A plain index can be listed without
+    /// the password, but reveals the names and sizes of the files backed up.
+    /// Backups with an index cannot be read by older versions of the tool.
+    #[arg(long, value_enum, default_value_t = IndexArg::None)]
+    index: IndexArg,
+    /// Records where each entry begins in the index, so that extracting
+    /// entries with `extract --matching` only decrypts the parts of the
+    /// backup that hold them. Stores an encrypted index unless `--index` is
+    /// given. Cannot be combined with compression.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "compression_level"
+    )]
+    indexed: bool,
+    /// The maximum number of files to hold open at once while archiving.
+    /// Lower this if backups fail with too many open files. By default, only
+    /// the operating system limit applies.
+    #[arg(long, value_parser)]
+    max_open_files: Option<NonZeroUsize>,
+    /// Allows backing up the root of a filesystem or a special
+    /// pseudo-filesystem path such as `/proc`, which are otherwise
+    /// rejected.
+    #[arg(long, value_parser, default_value_t = false)]
+    allow_root: bool,
+    /// Reads back and fully verifies the backup once it has been written,
+    /// so that a bad write is caught while the source files are still
+    /// available. This roughly doubles the time the backup takes.
+    #[arg(long = "verify", value_parser, default_value_t = false)]
+    verify_after_write: bool,
+    /// Removes the backup if verifying it after writing fails.
+    #[arg(
+        long = "remove-unverified",
+        value_parser,
+        default_value_t = false,
+        requires = "verify_after_write"
+    )]
+    remove_unverified_output: bool,
+    /// An additional password that can also be used to extract the backup.
+    /// May be provided multiple times. Any one of the passwords is enough to
+    /// extract the backup.
+    #[arg(long = "add-password", value_parser = validate_password)]
+    additional_passwords: Vec<String>,
+    /// Memory cost of deriving a key from each password, in KiB. Higher
+    /// values make passwords harder to brute force, at the cost of slower
+    /// backups and extractions. The value is stored in the backup, so the
+    /// same cost is used to extract it. Defaults to 19456 (19 MiB).
+    #[arg(long, value_parser = validate_kdf_memory)]
+    kdf_memory: Option<u32>,
+    /// Number of iterations used to derive a key from each password. The
+    /// value is stored in the backup, so the same cost is used to extract it.
+    /// Defaults to 2.
+    #[arg(long, value_parser = validate_kdf_iterations)]
+    kdf_iterations: Option<u32>,
+    /// Hash algorithm used to checksum the backup. The algorithm is stored in
+    /// the backup, so verification always uses the right one.
+    #[arg(long, value_enum, default_value_t = ChecksumArg::Sha256)]
+    checksum: ChecksumArg,
+    /// Compresses the backup with zstd at the given level, from 1 to 19,
+    /// before encrypting it. Higher levels compress better, but more slowly.
+    /// Compressed backups are decompressed automatically on extraction.
+    #[arg(long = "compress", value_name = "LEVEL", value_parser = validate_compression_level)]
+    compression_level: Option<i32>,
+    /// Encrypts each chunk with a key of its own, derived from the backup's
+    /// key, so that no key encrypts more than one chunk. This widens the
+    /// security margin of very large backups. Extraction detects it
+    /// automatically, but older versions of this tool cannot read the backup.
+    #[arg(long, value_parser, default_value_t = false)]
+    chunk_keys: bool,
+    /// Makes the backup reproducible, so that backing up the same files with
+    /// the same password and options produces a byte-identical file.
+    /// WARNING: this weakens security. Deterministic backups reveal whether
+    /// they share a password and which chunks they have in common, and all
+    /// backups with the same password share a salt.
+    #[arg(long, value_parser, default_value_t = false)]
+    deterministic: bool,
+    /// Stores an extra copy of the backup's key wrapped for the given X25519
+    /// public key, as 64 hexadecimal digits, so that whoever holds the
+    /// matching private key can always extract the backup, even without the
+    /// password. Generate a key pair with the `recovery-keygen` subcommand.
+    #[arg(
+        long = "recovery-pubkey",
+        value_name = "HEX",
+        value_parser = validate_recovery_key,
+        conflicts_with = "deterministic"
+    )]
+    recovery_public_key: Option<[u8; RECOVERY_KEY_SIZE]>,
+    /// Makes a delta backup against the given directory, such as an earlier
+    /// extraction of a backup of the same paths, leaving out every file whose
+    /// contents are identical to its copy there. Extracting the delta over a
+    /// copy of the directory with `--on-conflict overwrite` restores the full
+    /// tree.
+    #[arg(long, value_name = "DIR", value_parser = validate_dir)]
+    delta_from: Option<PathBuf>,
+    /// Stores each file whose contents are identical to a file already in
+    /// the backup as a reference to that file, rather than storing the
+    /// contents again. Extraction restores a separate copy for each. This can
+    /// greatly shrink backups with repeated content, but the hash and path of
+    /// every file are kept in memory, about a hundred bytes per file plus the
+    /// length of its path.
+    #[arg(long, value_parser, default_value_t = false)]
+    dedup: bool,
+    /// Stores the extended attributes of files and directories, such as
+    /// security labels. Only supported on Unix platforms.
+    #[arg(long = "xattrs", value_parser, default_value_t = false)]
+    preserve_xattrs: bool,
+    /// Stores the creation times of files and directories, where the
+    /// platform reports them.
+    #[arg(long = "btime", value_parser, default_value_t = false)]
+    preserve_btime: bool,
+    /// Keeps going when a file or directory cannot be read, instead of
+    /// failing the backup. Skipped paths are listed once the backup
+    /// completes.
+    #[arg(long, value_parser, default_value_t = false)]
+    continue_on_error: bool,
+    /// Skips files and directories that cannot be read due to insufficient
+    /// permissions without warning about each of them.
+    #[arg(long, value_parser, default_value_t = false)]
+    quiet_permission_denied: bool,
+    /// Skips flushing the backup to the storage device before reporting
+    /// success. This makes large backups finish sooner, but a crash or power
+    /// loss shortly afterwards may leave the backup missing or truncated.
+    #[arg(long, value_parser, default_value_t = false)]
+    no_sync: bool,
+    /// Stops the backup cleanly once a file exists at the given path, as an
+    /// interrupt or SIGTERM does. The partial backup is removed and the
+    /// command exits with code 3. The file is checked for every half second,
+    /// including when the backup starts, and is left in place.
+    #[arg(long, value_name = "PATH")]
+    cancel_file: Option<PathBuf>,
+    /// How much memory the operation may use.
+    #[command(flatten)]
+    memory: MemoryArgs,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments controlling how much memory a backup or extraction may use.
+#[derive(Args, Debug)]
+struct MemoryArgs {
+    /// Overrides the 1GB memory limit.
+    #[arg(long, value_parser, default_value_t = false)]
+    override_memory_limit: bool,
+    /// Keeps memory use under the 1GB limit by reducing how many chunks are
+    /// in flight at once, instead of failing when the limit would be
+    /// exceeded. This can be slower.
+    #[arg(long, value_parser, default_value_t = false)]
+    adaptive_memory: bool,
+}
+
+impl MemoryArgs {
+    /// Checks that the expected memory usage is within the memory limit,
+    /// unless memory use is adapted to stay under it as the operation runs.
+    fn check(&self, chunk_size: usize, pool_size: u8) -> Result<(), Failure> {
+        if self.adaptive_memory {
+            return Ok(());
+        }
+
+        check_memory(chunk_size, pool_size, self.override_memory_limit)
+            .map_err(|e| Failure::new("memory-limit-exceeded", e))
+    }
+}
+
+/// Arguments to the extract subcommand.
+#[derive(Args, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct ExtractArgs {
+    /// Path to the encrypted backup.
+    #[arg(required = true, value_parser = validate_file)]
+    backup_path: PathBuf,
+    /// Path to extract the backup to. Required unless restoring to the
+    /// root or only checking the password.
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["restore_to_root", "check_password"]
+    )]
+    output_path: Option<PathBuf>,
+    /// Password for the backup file. If not provided, the password will
+    /// be prompted from standard input.
+    #[arg(short, long, value_parser)]
+    password: Option<String>,
+    /// Reads the password from a single line of standard input instead of
+    /// prompting for it, for use in scripts. Only the trailing newline is
+    /// removed.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "password"
+    )]
+    password_stdin: bool,
+    /// Number of workers to spawn in the pool that will perform crypto
+    /// operations in parallel. The default pool size is 16. This is
+    /// usually an optimal size, and can speed things up substantially.
+    #[arg(long, value_parser = validate_pool_size, default_value_t = 16)]
+    pool_size: u8,
+    /// Records extraction progress so that an interrupted extraction can
+    /// be resumed. If a previous extraction to the same output path was
+    /// interrupted, entries that were already extracted will be skipped.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    resume: bool,
+    /// Directory in which to write the decrypted archive during
+    /// extraction. Defaults to the directory containing the backup. Note
+    /// that the full unencrypted contents of the backup will be written
+    /// here temporarily.
+    #[arg(long, value_parser = validate_dir)]
+    temp_dir: Option<PathBuf>,
+    /// Overwrites the decrypted archive with zeros before deleting it.
+    /// This is a best effort, as SSDs and some filesystems may keep copies
+    /// of the original data.
+    #[arg(long, value_parser, default_value_t = false)]
+    secure_delete: bool,
+    /// Restores the extended attributes stored in the backup. Attributes that
+    /// cannot be set are skipped. Only supported on Unix platforms.
+    #[arg(long = "xattrs", value_parser, default_value_t = false)]
+    preserve_xattrs: bool,
+    /// Restores the creation times stored in the backup. Only supported on
+    /// Windows and macOS.
+    #[arg(long = "btime", value_parser, default_value_t = false)]
+    preserve_btime: bool,
+    /// Removes the given number of leading components from each path in the
+    /// backup, like `tar --strip-components`. A value of 1 extracts the
+    /// contents of each backed up directory directly into the output path.
+    /// Fails if two entries would be extracted to the same path.
+    #[arg(long, value_parser, default_value_t = 0)]
+    strip_components: usize,
+    /// Extracts into the filesystem root, restoring a backup made with
+    /// `--absolute-paths` to the original locations of its files. WARNING:
+    /// this overwrites anything already at those locations, including system
+    /// files if the backup contains them.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "output_path"
+    )]
+    restore_to_root: bool,
+    /// Extracts into an output directory that may already exist, only
+    /// replacing files that are older than their copies in the backup, like
+    /// `rsync --update`. Files that are as new or newer are left as they
+    /// are, so re-extracting brings an earlier extraction up to date.
+    #[arg(long, value_parser, default_value_t = false, conflicts_with = "atomic")]
+    overwrite_older: bool,
+    /// Extracts into an output directory that may already exist, handling
+    /// each file already at the path of an entry with different contents as
+    /// given. Combined with `--overwrite-older`, only applies to files that
+    /// are older than their copies in the backup.
+    #[arg(long, value_enum, value_name = "POLICY", conflicts_with = "atomic")]
+    on_conflict: Option<ConflictArg>,
+    /// Reads back each extracted file and checks it against the hash
+    /// recorded when it was backed up.
+    #[arg(long, value_parser, default_value_t = false)]
+    verify_files: bool,
+    /// Extracts into a `.partial` directory next to the output path, and
+    /// only renames it to the output path once extraction has succeeded, so
+    /// the output directory never appears partially extracted.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "restore_to_root"
+    )]
+    atomic: bool,
+    /// Only checks that the password opens the backup, then exits without
+    /// extracting it. This is much faster than finding out part way through
+    /// decrypting a large backup.
+    #[arg(long, alias = "verify-password", value_parser, default_value_t = false)]
+    check_password: bool,
+    /// Opens the backup with the recovery private key in the given file,
+    /// written by the `recovery-keygen` subcommand, instead of a password.
+    /// Only backups made with the matching `--recovery-pubkey` can be opened
+    /// this way.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = validate_recovery_key_file,
+        conflicts_with_all = ["password", "password_stdin", "check_password", "matching"]
+    )]
+    recovery_key_file: Option<[u8; RECOVERY_KEY_SIZE]>,
+    /// Only extracts the entries whose paths within the backup match the
+    /// glob, such as `photos/2020/*`. May be given more than once. Backups
+    /// made with `--indexed` are only decrypted where the matching entries
+    /// are stored. The output path may already exist.
+    #[arg(
+        long,
+        value_name = "GLOB",
+        value_parser = validate_glob,
+        conflicts_with_all = ["resume", "atomic", "restore_to_root"]
+    )]
+    matching: Vec<Pattern>,
+    /// How much memory the operation may use.
+    #[command(flatten)]
+    memory: MemoryArgs,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the verify subcommand.
+#[derive(Args, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct VerifyArgs {
+    /// Path to the encrypted backup.
+    #[arg(required = true, value_parser = validate_file)]
+    backup_path: PathBuf,
+    /// Password for the backup file. If not provided, the password will
+    /// be prompted from standard input.
+    #[arg(short, long, value_parser)]
+    password: Option<String>,
+    /// Reads the password from a single line of standard input instead of
+    /// prompting for it, for use in scripts. Only the trailing newline is
+    /// removed.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "password"
+    )]
+    password_stdin: bool,
+    /// Number of workers to spawn in the pool that will perform crypto
+    /// operations in parallel. The default pool size is 16.
+    #[arg(long, value_parser = validate_pool_size, default_value_t = 16)]
+    pool_size: u8,
+    /// Only decrypts the backup and reports decryption throughput, without
+    /// reading the archive within it. Useful for benchmarking hardware and
+    /// confirming that a backup reads end to end.
+    #[arg(long, value_parser, default_value_t = false)]
+    stats_only: bool,
+    /// Only checks the backup against its stored checksum, without the
+    /// password. This is much faster than decrypting it, but only detects
+    /// corruption.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "stats_only"
+    )]
+    checksum_only: bool,
+    /// Overrides the 1GB memory limit.
+    #[arg(long, value_parser, default_value_t = false)]
+    override_memory_limit: bool,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the change-password subcommand.
+#[derive(Args, Debug)]
+struct ChangePasswordArgs {
+    /// Path to the encrypted backup.
+    #[arg(required = true, value_parser = validate_file)]
+    backup_path: PathBuf,
+    /// The current password for the backup file. If not provided, the
+    /// password will be prompted from standard input.
+    #[arg(long, value_parser)]
+    old_password: Option<String>,
+    /// The new password for the backup file. If not provided, the password
+    /// will be prompted from standard input.
+    #[arg(long, value_parser = validate_password)]
+    new_password: Option<String>,
+    /// Upgrade the backup to the current format while changing the password.
+    /// The new password uses the current default key derivation settings, and
+    /// a checksum is added to backups that lack one. The old and new
+    /// passwords may be the same to only upgrade the backup.
+    #[arg(long, value_parser, default_value_t = false)]
+    upgrade_format: bool,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the merge subcommand.
+#[derive(Args, Debug)]
+struct MergeArgs {
+    /// Paths to the encrypted backups to merge. No top-level name, such as
+    /// the name of an include path, may appear in more than one of them.
+    #[arg(required = true, num_args = 2.., value_parser = validate_file)]
+    backup_paths: Vec<PathBuf>,
+    /// Path to write the merged backup to.
+    #[arg(short, long, value_parser = validate_output_path)]
+    output_path: PathBuf,
+    /// Password for every backup being merged, which also encrypts the
+    /// merged backup. If not provided, the password will be prompted from
+    /// standard input.
+    #[arg(short, long, value_parser)]
+    password: Option<String>,
+    /// Reads the password from a single line of standard input instead of
+    /// prompting for it, for use in scripts. Only the trailing newline is
+    /// removed.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "password"
+    )]
+    password_stdin: bool,
+    /// Number of workers to spawn in the pool that will perform crypto
+    /// operations in parallel. The default pool size is 16.
+    #[arg(long, value_parser = validate_pool_size, default_value_t = 16)]
+    pool_size: u8,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the recovery-keygen subcommand.
+#[derive(Args, Debug)]
+struct RecoveryKeygenArgs {
+    /// Path to write the private key to, which must not already exist. Keep
+    /// it secret, since it opens every backup made with its public key.
+    #[arg(short, long, value_parser = validate_output_path)]
+    output_path: PathBuf,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the info subcommand.
+#[derive(Args, Debug)]
+struct InfoArgs {
+    /// Path to the encrypted backup.
+    #[arg(required = true, value_parser = validate_file)]
+    backup_path: PathBuf,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the list subcommand.
+#[derive(Args, Debug)]
+struct ListArgs {
+    /// Path to the encrypted backup.
+    #[arg(required = true, value_parser = validate_file)]
+    backup_path: PathBuf,
+    /// Password for the backup file. If the index is encrypted and this is
+    /// not provided, the password will be prompted from standard input. A
+    /// plain index is authenticated if the password is provided.
+    #[arg(short, long, value_parser)]
+    password: Option<String>,
+    /// Reads the password from a single line of standard input instead of
+    /// prompting for it, for use in scripts. Only the trailing newline is
+    /// removed.
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "password"
+    )]
+    password_stdin: bool,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the scrub subcommand.
+#[derive(Args, Debug)]
+struct ScrubArgs {
+    /// Paths to the encrypted backups to check. Every backup is checked,
+    /// and the command fails if any of them do not match their checksums.
+    #[arg(required = true, value_parser = validate_file)]
+    backup_paths: Vec<PathBuf>,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the calibrate subcommand.
+#[derive(Args, Debug, Clone, Copy)]
+struct CalibrateArgs {
+    /// The amount of data to encrypt with each combination of chunk size
+    /// and pool size, such as `64M`. Larger amounts take longer, but give
+    /// more reliable results.
+    #[arg(long, default_value = "16M", value_parser = validate_sample_size)]
+    sample_size: usize,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+/// Arguments to the serve subcommand.
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// The TCP port to listen on. Only connections from the local machine
+    /// are accepted.
+    #[arg(long, required_unless_present = "socket", conflicts_with = "socket")]
+    port: Option<u16>,
+    /// The path of a Unix domain socket to listen on. Only supported on Unix
+    /// platforms.
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// A file holding the token that every request must carry in its
+    /// `token` field. Only a trailing newline is removed. The token is never
+    /// taken from the command line, where other users could see it.
+    #[arg(
+        long,
+        required_unless_present = "token_stdin",
+        conflicts_with = "token_stdin",
+        value_parser = validate_file
+    )]
+    token_file: Option<PathBuf>,
+    /// Reads the token from a single line of standard input instead of a
+    /// file. Only the trailing newline is removed.
+    #[arg(long, value_parser, default_value_t = false)]
+    token_stdin: bool,
+    /// Debug mode.
+    #[arg(short, long, value_parser, default_value_t = false)]
+    debug: bool,
 }
 
 /// Validates that a provided path exists and is a file.
@@ -109,6 +929,19 @@ fn validate_file(path_str: &str) -> Result<PathBuf, String> {
     }
 }
 
+/// Validates that a provided path exists and is a directory.
+fn validate_dir(path_str: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path_str);
+
+    if !path.exists() {
+        Err(format!("Path does not exist: {path_str}"))
+    } else if !path.is_dir() {
+        Err(format!("Path is not a directory: {path_str}"))
+    } else {
+        Ok(path.to_owned())
+    }
+}
+
 /// Validates that a provided path exists and is either a file or directory.
 fn validate_path(path_str: &str) -> Result<PathBuf, String> {
     let path = Path::new(path_str);
@@ -125,11 +958,104 @@ fn validate_path(path_str: &str) -> Result<PathBuf, String> {
     }
 }
 
+/// Validates an include path given on the command line. Paths that do not
+/// exist are let through if they look like globs, so that they can be
+/// expanded once it is known whether `--glob` was given.
+fn validate_include_path(path_str: &str) -> Result<PathBuf, String> {
+    if is_glob(path_str) && !Path::new(path_str).exists() {
+        Ok(PathBuf::from(path_str))
+    } else {
+        validate_path(path_str)
+    }
+}
+
+/// Checks whether a string contains any glob special characters.
+fn is_glob(path_str: &str) -> bool {
+    path_str.contains(['*', '?', '['])
+}
+
+/// Expands include paths given on the command line as globs if requested,
+/// returning every file and directory matched. Otherwise, paths are taken
+/// literally, and any that do not exist are rejected.
+fn expand_include_paths(include_paths: Vec<PathBuf>, glob: bool) -> Result<Vec<PathBuf>, Failure> {
+    if !glob {
+        return include_paths
+            .into_iter()
+            .map(|path| {
+                validate_path(&path.to_string_lossy()).map_err(|e| {
+                    Failure::new(
+                        "invalid-include-path",
+                        format!("{e} (use --glob to expand it as a glob)"),
+                    )
+                })
+            })
+            .collect();
+    }
+
+    let mut expanded = Vec::new();
+
+    for pattern in include_paths {
+        let pattern = pattern.to_string_lossy();
+        let matches = glob::glob(&pattern).map_err(|e| {
+            Failure::new(
+                "invalid-include-path",
+                format!("Invalid glob: {pattern}, {e}"),
+            )
+        })?;
+        let count = expanded.len();
+
+        for path in matches {
+            let path = path.map_err(|e| {
+                Failure::new(
+                    "invalid-include-path",
+                    format!("Failed to expand glob: {pattern}, {e}"),
+                )
+            })?;
+
+            if path.is_dir() || path.is_file() {
+                expanded.push(path);
+            }
+        }
+
+        if expanded.len() == count {
+            return Err(Failure::new(
+                "invalid-include-path",
+                format!("Glob matched no files or directories: {pattern}"),
+            ));
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// A list of include paths read from a file.
+#[derive(Clone, Debug)]
+struct IncludeList(Vec<PathBuf>);
+
+/// Validates that a file lists existing include paths, one per line.
+fn validate_include_file(path_str: &str) -> Result<IncludeList, String> {
+    let contents = fs::read_to_string(path_str)
+        .map_err(|e| format!("Failed to read include file: {path_str}, {e}"))?;
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| validate_path(line).map_err(|e| format!("{e} (listed in {path_str})")))
+        .collect::<Result<_, _>>()
+        .map(IncludeList)
+}
+
 /// Validates that a glob is legitimate.
 fn validate_glob(glob_str: &str) -> Result<Pattern, String> {
     Pattern::new(glob_str).map_err(|e| format!("Invalid glob: {glob_str}, {e}"))
 }
 
+/// Validates that a regular expression is valid.
+fn validate_regex(regex_str: &str) -> Result<Regex, String> {
+    Regex::new(regex_str).map_err(|e| format!("Invalid regex: {regex_str}, {e}"))
+}
+
 /// Validates that a password is of the correct length.
 fn validate_password(password: &str) -> Result<String, String> {
     if password.len() < 8 {
@@ -141,19 +1067,85 @@ fn validate_password(password: &str) -> Result<String, String> {
     }
 }
 
-/// Validates that a provided output path does not yet exist and has a valid
-/// parent directory.
+/// Validates that a provided output path does not yet exist and has a valid,
+/// writable parent directory.
 fn validate_output_path(path_str: &str) -> Result<PathBuf, String> {
+    let path = validate_output_parent(path_str)?;
+
+    if !path.exists() {
+        Ok(path)
+    } else {
+        Err(format!("Path already exists: {}", path.display()))
+    }
+}
+
+/// Validates that an output template can be rendered to a path with a valid,
+/// writable parent directory.
+fn validate_output_template(template: &str) -> Result<String, String> {
+    validate_output_parent(&render_output_template(template, &Local::now())?)?;
+    Ok(template.to_owned())
+}
+
+/// Renders an output template at the given time, replacing each `{date}`,
+/// `{time}` or `{datetime}` token, optionally with a format after a colon.
+fn render_output_template(template: &str, now: &DateTime<Local>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed token in output template: {template}"))?;
+        let token = &rest[start + 1..start + end];
+        let (name, format) = token.split_once(':').unwrap_or((token, ""));
+        let default_format = match name {
+            "date" => "%Y-%m-%d",
+            "time" => "%H-%M-%S",
+            "datetime" => "%Y-%m-%d_%H-%M-%S",
+            _ => return Err(format!("Unknown token in output template: {{{token}}}")),
+        };
+        let format = if format.is_empty() {
+            default_format
+        } else {
+            format
+        };
+
+        rendered.push_str(&rest[..start]);
+        write!(rendered, "{}", now.format(format))
+            .map_err(|_| format!("Invalid format in output template: {{{token}}}"))?;
+        rest = &rest[start + end + 1..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Validates that a provided file extension is non-empty and does not
+/// contain a path separator. A leading `.` is removed.
+fn validate_extension(extension: &str) -> Result<String, String> {
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+
+    if extension.is_empty() {
+        Err("Extension must not be empty".to_owned())
+    } else if extension.contains(std::path::is_separator) {
+        Err(format!(
+            "Extension must not contain a path separator: {extension}"
+        ))
+    } else {
+        Ok(extension.to_owned())
+    }
+}
+
+/// Validates that a provided output path has a valid, writable parent
+/// directory. The path itself may already exist.
+fn validate_output_parent(path_str: &str) -> Result<PathBuf, String> {
     let path = PathBuf::from(path_str);
 
     match path.parent() {
         Some(parent) => {
             if parent.exists() {
-                if !path.exists() {
-                    Ok(path)
-                } else {
-                    Err(format!("Path already exists: {}", path.display()))
-                }
+                check_writable(parent, &path)?;
+                Ok(path)
             } else {
                 Err(format!("Parent path does not exist: {}", path.display()))
             }
@@ -162,6 +1154,21 @@ fn validate_output_path(path_str: &str) -> Result<PathBuf, String> {
     }
 }
 
+/// Checks that files can be created in the directory that will contain the
+/// output path, by creating and removing an empty file there. This catches
+/// permission problems before any work is done, rather than once the output
+/// is finally written.
+fn check_writable(parent: &Path, path: &Path) -> Result<(), String> {
+    let mut probe_path = path.to_path_buf();
+    probe_path
+        .as_mut_os_string()
+        .push(format!(".{}.probe", process::id()));
+
+    File::create_new(&probe_path)
+        .and_then(|_| fs::remove_file(&probe_path))
+        .map_err(|e| format!("Directory is not writable: {}, {e}", parent.display()))
+}
+
 /// Validates that the provided chunk size is within the accepted range.
 fn validate_chunk_size(chunk_size: &str) -> Result<u8, String> {
     let size = chunk_size.parse::<u8>().map_err(|e| e.to_string())?;
@@ -175,6 +1182,34 @@ fn validate_chunk_size(chunk_size: &str) -> Result<u8, String> {
     }
 }
 
+/// Validates that the provided chunk size in bytes is within the accepted
+/// range.
+fn validate_chunk_bytes(chunk_bytes: &str) -> Result<usize, String> {
+    let size = parse_bytes(chunk_bytes)?;
+
+    if size < 1 << 10 {
+        Err("Chunk size must be at least 1 KiB".to_owned())
+    } else if size > 1 << 30 {
+        Err("Chunk size must be at most 1 GiB".to_owned())
+    } else {
+        usize::try_from(size).map_err(|e| e.to_string())
+    }
+}
+
+/// Validates that the provided calibration sample size is within the
+/// accepted range.
+fn validate_sample_size(sample_size: &str) -> Result<usize, String> {
+    let size = parse_bytes(sample_size)?;
+
+    if size < 1 << 20 {
+        Err("Sample size must be at least 1 MiB".to_owned())
+    } else if size > 1 << 30 {
+        Err("Sample size must be at most 1 GiB".to_owned())
+    } else {
+        usize::try_from(size).map_err(|e| e.to_string())
+    }
+}
+
 /// Validates that the provided pool size is within the accepted range.
 fn validate_pool_size(pool_size: &str) -> Result<u8, String> {
     let size = pool_size.parse::<u8>().map_err(|e| e.to_string())?;
@@ -188,15 +1223,124 @@ fn validate_pool_size(pool_size: &str) -> Result<u8, String> {
     }
 }
 
+/// Validates the memory cost of the key derivation function.
+fn validate_kdf_memory(memory: &str) -> Result<u32, String> {
+    let memory_kib = memory.parse::<u32>().map_err(|e| e.to_string())?;
+
+    if memory_kib < Argon2Params::MIN_MEMORY_KIB {
+        Err(format!(
+            "KDF memory must be at least {} KiB",
+            Argon2Params::MIN_MEMORY_KIB
+        ))
+    } else if memory_kib > Argon2Params::MAX_MEMORY_KIB {
+        Err(format!(
+            "KDF memory must be at most {} KiB",
+            Argon2Params::MAX_MEMORY_KIB
+        ))
+    } else {
+        Ok(memory_kib)
+    }
+}
+
+/// Validates the number of iterations of the key derivation function.
+fn validate_kdf_iterations(iterations: &str) -> Result<u32, String> {
+    let iterations = iterations.parse::<u32>().map_err(|e| e.to_string())?;
+
+    if iterations < 1 {
+        Err("KDF iterations must be at least 1".to_owned())
+    } else if iterations > Argon2Params::MAX_ITERATIONS {
+        Err(format!(
+            "KDF iterations must be at most {}",
+            Argon2Params::MAX_ITERATIONS
+        ))
+    } else {
+        Ok(iterations)
+    }
+}
+
+/// Validates an owner given as a user and group ID separated by a colon.
+fn validate_owner(owner: &str) -> Result<(u32, u32), String> {
+    let (uid, gid) = owner
+        .split_once(':')
+        .ok_or_else(|| "Owner must be given as UID:GID".to_owned())?;
+    let uid = uid
+        .parse::<u32>()
+        .map_err(|e| format!("Invalid user ID: {e}"))?;
+    let gid = gid
+        .parse::<u32>()
+        .map_err(|e| format!("Invalid group ID: {e}"))?;
+
+    Ok((uid, gid))
+}
+
+/// Validates that a recovery key is given as 64 hexadecimal digits.
+fn validate_recovery_key(hex: &str) -> Result<[u8; RECOVERY_KEY_SIZE], String> {
+    let hex = hex.trim().as_bytes();
+    let mut key = [0; RECOVERY_KEY_SIZE];
+
+    if hex.len() != RECOVERY_KEY_SIZE * 2 {
+        return Err(format!(
+            "Recovery key must be {} hexadecimal digits",
+            RECOVERY_KEY_SIZE * 2
+        ));
+    }
+
+    for (byte, pair) in key.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = str::from_utf8(pair)
+            .ok()
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or_else(|| "Recovery key must be hexadecimal".to_owned())?;
+    }
+
+    Ok(key)
+}
+
+/// Validates that a file contains a recovery private key.
+fn validate_recovery_key_file(path_str: &str) -> Result<[u8; RECOVERY_KEY_SIZE], String> {
+    let contents = fs::read_to_string(path_str)
+        .map_err(|e| format!("Failed to read recovery key file: {path_str}, {e}"))?;
+
+    validate_recovery_key(&contents).map_err(|e| format!("{e} (in {path_str})"))
+}
+
+/// Encodes a recovery key as hexadecimal digits.
+fn recovery_key_hex(key: &[u8; RECOVERY_KEY_SIZE]) -> String {
+    key.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Validates that a compression level is supported.
+fn validate_compression_level(level: &str) -> Result<i32, String> {
+    let level = level.parse::<i32>().map_err(|e| e.to_string())?;
+
+    if COMPRESSION_LEVELS.contains(&level) {
+        Ok(level)
+    } else {
+        Err(format!(
+            "Compression level must be between {} and {}",
+            COMPRESSION_LEVELS.start(),
+            COMPRESSION_LEVELS.end()
+        ))
+    }
+}
+
 /// Prompts for the password from standard input.
-fn get_password(password: Option<String>, confirm: bool, validate: bool) -> Result<String, String> {
+fn get_password(
+    password: Option<String>,
+    prompt: &str,
+    confirm: bool,
+    validate: bool,
+) -> Result<String, String> {
     if let Some(pw) = password {
         Ok(pw)
     } else {
-        let pw = rpassword::prompt_password("Backup password: ").unwrap();
+        let pw = rpassword::prompt_password(format!("{prompt}: ")).unwrap();
 
         if confirm {
-            let pw_confirm = rpassword::prompt_password("Confirm password: ").unwrap();
+            let pw_confirm =
+                rpassword::prompt_password(format!("Confirm {}: ", prompt.to_lowercase())).unwrap();
 
             if pw != pw_confirm {
                 return Err("Passwords do not match".to_owned());
@@ -211,76 +1355,1081 @@ fn get_password(password: Option<String>, confirm: bool, validate: bool) -> Resu
     }
 }
 
-/// Attempt to perform a backup or extraction.
-fn perform_backup(command: Commands) -> Result<String, String> {
-    match command {
-        Commands::Backup {
-            include_paths,
-            exclude_globs,
-            output_path,
-            password,
-            chunk_size_magnitude,
-            pool_size,
-            override_memory_limit,
-            debug,
-        } => {
-            init_logger(debug).unwrap();
-
-            let chunk_size = 1 << chunk_size_magnitude;
-            check_memory(chunk_size, pool_size, override_memory_limit)?;
-
-            match get_password(password, true, true) {
-                Ok(pw) => match backup::backup(
-                    &include_paths,
-                    &exclude_globs,
-                    output_path,
-                    &pw,
-                    chunk_size,
-                    pool_size,
-                ) {
-                    Ok(path) => Ok(format!("Successfully backed up to {}", path.display())),
-                    Err(e) => Err(format!("Failed to perform backup: {e}")),
-                },
-                Err(e) => Err(format!("Invalid password: {e}")),
+/// Returns the password given on the command line, or read from a single
+/// line of standard input if requested. Returns `None` if neither was given,
+/// so that the password can be prompted for instead.
+fn given_password(
+    password: Option<String>,
+    password_stdin: bool,
+    validate: bool,
+) -> Result<Option<String>, Failure> {
+    if !password_stdin {
+        return Ok(password);
+    }
+
+    let invalid = |e: String| Failure::new("invalid-password", format!("Invalid password: {e}"));
+    let mut line = String::new();
+
+    if io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| invalid(e.to_string()))?
+        == 0
+    {
+        return Err(invalid("nothing was read from standard input".to_owned()));
+    }
+
+    // Only the line ending is removed, since other whitespace may be part of
+    // the password
+    let pw = strip_line_ending(&line).to_owned();
+
+    if validate {
+        validate_password(&pw).map(Some).map_err(invalid)
+    } else {
+        Ok(Some(pw))
+    }
+}
+
+/// Removes a single trailing line ending from a line of input.
+fn strip_line_ending(line: &str) -> &str {
+    line.strip_suffix('\n')
+        .map_or(line, |line| line.strip_suffix('\r').unwrap_or(line))
+}
+
+/// Gets the password from the command line or standard input, prompting for
+/// it if it was given in neither. A new password is confirmed and validated.
+fn obtain_password(
+    password: Option<String>,
+    password_stdin: bool,
+    prompt: &str,
+    new: bool,
+) -> Result<String, Failure> {
+    let password = given_password(password, password_stdin, new)?;
+
+    get_password(password, prompt, new, new)
+        .map_err(|e| Failure::new("invalid-password", format!("Invalid password: {e}")))
+}
+
+/// The result of a successful command.
+struct Success {
+    /// A human-readable success message.
+    message: String,
+    /// The path to the command's output, if it has one.
+    output: Option<PathBuf>,
+    /// The size of the command's output in bytes, if applicable.
+    bytes: Option<u64>,
+    /// Additional structured results, if applicable.
+    details: Option<Value>,
+}
+
+/// The result of a failed command.
+struct Failure {
+    /// A machine-readable identifier for the kind of failure.
+    kind: &'static str,
+    /// A human-readable failure message.
+    message: String,
+}
+
+impl Failure {
+    /// Creates a new failure.
+    fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Creates a failure from a backup error, explaining the error where a
+    /// hint is useful.
+    fn from_error(context: &str, e: &BackupError) -> Self {
+        let hint = match e {
+            BackupError::IncorrectPassword => {
+                "\nThe password does not match any of the passwords for this backup."
             }
+            BackupError::CryptoError(_) => {
+                "\nThis means that the backup is corrupted or has been tampered with. For backups created by older versions, it can also mean that the password was incorrect."
+            }
+            _ => "",
+        };
+
+        Self::new(e.kind(), format!("{context}: {e}{hint}"))
+    }
+
+    /// Returns the code to exit the process with.
+    fn exit_code(&self) -> i32 {
+        if self.kind == BackupError::Cancelled.kind() {
+            CANCELLED_EXIT_CODE
+        } else {
+            1
+        }
+    }
+}
+
+/// Converts a path to a string for JSON output. JSON strings must be valid
+/// UTF-8, so paths that are not are converted lossily, with a warning.
+fn json_path(path: &Path) -> String {
+    let lossy = path.to_string_lossy();
+
+    if path.to_str().is_none() {
+        log::warn!("Path '{lossy}' is not valid UTF-8, so it is shown with replacement characters");
+    }
+
+    lossy.into_owned()
+}
+
+/// Builds the result of a successful backup, listing any paths that were
+/// skipped.
+fn backup_success(stats: BackupStats) -> Success {
+    let mut lines = vec![format!(
+        "Successfully backed up to {} ({}, {:.1}x expansion due to encryption overhead)",
+        stats.path.display(),
+        format_bytes(stats.output_size),
+        stats.expansion_ratio()
+    )];
+
+    if !stats.skipped.is_empty() {
+        lines.push(format!(
+            "{} paths could not be read and were skipped or only partially captured:",
+            stats.skipped.len()
+        ));
+        lines.extend(
+            stats
+                .skipped
+                .iter()
+                .map(|skipped| format!("  {}: {}", skipped.path.display(), skipped.error)),
+        );
+    }
+
+    let details = (!stats.skipped.is_empty()).then(|| {
+        json!({
+            "skipped": stats
+                .skipped
+                .iter()
+                .map(|skipped| json!({
+                    "path": json_path(&skipped.path),
+                    "error": skipped.error,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    });
+
+    Success {
+        message: lines.join("\n"),
+        output: Some(stats.path),
+        bytes: Some(stats.output_size),
+        details,
+    }
+}
+
+/// Determines the backup output path from the given path or template. An
+/// extension is appended to a path that does not have one, so that the
+/// backup can be recognized later, and the resulting path must not exist
+/// unless it is being overwritten. The path the backup will be written to is
+/// logged either way.
+fn backup_output_path(args: &BackupArgs) -> Result<PathBuf, Failure> {
+    let path = match (&args.output_path, &args.output_template) {
+        (Some(path), _) => path.clone(),
+        (None, Some(template)) => render_output_template(template, &Local::now())
+            .and_then(|rendered| validate_output_parent(&rendered))
+            .map_err(|e| Failure::new("invalid-output-template", e))?,
+        (None, None) => {
+            return Err(Failure::new(
+                "missing-output-path",
+                "An output path is required, either with --output-path or --output-template, or in the config file",
+            ))
+        }
+    };
+    let path = if !args.no_auto_extension && path.extension().is_none() {
+        path.with_extension(&args.extension)
+    } else {
+        path
+    };
+
+    if !args.overwrite && path.exists() {
+        return Err(Failure::new(
+            "path-exists",
+            format!(
+                "Path already exists: {}\nUse --overwrite to replace it.",
+                path.display()
+            ),
+        ));
+    }
+
+    log::info!("Writing the backup to '{}'", path.display());
+    Ok(path)
+}
+
+/// Attempt to perform a backup.
+fn perform_backup(mut args: BackupArgs, logging: Logging) -> Result<Success, Failure> {
+    logging.init(args.debug || args.list_excluded);
+    config::apply_backup_config(&mut args)?;
+    let output_path = backup_output_path(&args)?;
+    let (chunk_size, pool_size) = backup_sizes(&args)?;
+
+    let BackupArgs {
+        include_paths,
+        glob,
+        include_from,
+        exclude_globs,
+        exclude_common,
+        keep_common,
+        exclude_regex,
+        follow_backupignore,
+        exclude_hidden,
+        list_excluded,
+        overwrite,
+        password,
+        password_stdin,
+        dereference_hardlinks,
+        follow_mounts,
+        symlinks,
+        special_files,
+        index,
+        indexed,
+        absolute_paths,
+        owner,
+        max_open_files,
+        allow_root,
+        verify_after_write,
+        remove_unverified_output,
+        additional_passwords,
+        kdf_memory,
+        kdf_iterations,
+        checksum,
+        compression_level,
+        chunk_keys,
+        deterministic,
+        recovery_public_key,
+        delta_from,
+        dedup,
+        preserve_xattrs,
+        preserve_btime,
+        continue_on_error,
+        quiet_permission_denied,
+        no_sync,
+        cancel_file,
+        memory,
+        // The config, output path, sizes and debug mode have already been applied
+        ..
+    } = args;
+
+    let include_paths = backup_include_paths(include_paths, glob, include_from)?;
+    let exclude_globs = backup_exclude_globs(exclude_globs, exclude_common, &keep_common);
+
+    let pw = obtain_password(password, password_stdin, "Backup password", true)?;
+    let progress = ProgressDisplay::new();
+
+    backup::backup(
+        &include_paths,
+        &exclude_globs,
+        output_path,
+        &pw,
+        chunk_size,
+        pool_size,
+        &BackupOptions {
+            follow_backupignore,
+            allow_root,
+            dereference_hardlinks,
+            one_file_system: !follow_mounts,
+            symlink_target: symlinks.into(),
+            special_files: special_files.into(),
+            path_mode: path_mode(absolute_paths),
+            owner_override: owner,
+            index: index.into(),
+            indexed,
+            max_open_files,
+            exclude_hidden,
+            exclude_regex,
+            list_excluded,
+            additional_passwords,
+            kdf_params: kdf_params(kdf_memory, kdf_iterations),
+            checksum_algorithm: checksum.into(),
+            compression_level,
+            verify_after_write,
+            remove_unverified_output,
+            overwrite,
+            durable: !no_sync,
+            chunk_keys,
+            deterministic,
+            recovery_public_key,
+            reference_dir: delta_from,
+            dedup,
+            preserve_xattrs,
+            preserve_btime,
+            continue_on_error,
+            quiet_permission_denied,
+            adaptive_memory: memory.adaptive_memory,
+            progress: Some(progress.handler()),
+            cancel: Some(cancel_on_request(cancel_file)),
+            pause: None,
+        },
+    )
+    .map(backup_success)
+    .map_err(|e| Failure::from_error("Failed to perform backup", &e))
+}
+
+/// Returns the paths to include in a backup, from the command line and any
+/// include lists.
+fn backup_include_paths(
+    include_paths: Vec<PathBuf>,
+    glob: bool,
+    include_from: Vec<IncludeList>,
+) -> Result<Vec<PathBuf>, Failure> {
+    let mut include_paths = expand_include_paths(include_paths, glob)?;
+    include_paths.extend(include_from.into_iter().flat_map(|list| list.0));
+    Ok(include_paths)
+}
+
+/// Returns the globs to exclude from a backup, adding the common exclusions
+/// other than those to keep, if requested.
+fn backup_exclude_globs(
+    mut exclude_globs: Vec<Pattern>,
+    exclude_common: bool,
+    keep_common: &[String],
+) -> Vec<Pattern> {
+    if exclude_common {
+        exclude_globs.extend(common_exclude_globs(keep_common));
+    }
+
+    exclude_globs
+}
+
+/// Returns the chunk size and pool size of a backup, using the defaults for
+/// any that were not given, after checking that they fit in memory.
+fn backup_sizes(args: &BackupArgs) -> Result<(usize, u8), Failure> {
+    let chunk_size = args.chunk_bytes.unwrap_or_else(|| {
+        1 << args
+            .chunk_size_magnitude
+            .unwrap_or(DEFAULT_CHUNK_SIZE_MAGNITUDE)
+    });
+    let pool_size = args.pool_size.unwrap_or(DEFAULT_BACKUP_POOL_SIZE);
+
+    args.memory.check(chunk_size, pool_size)?;
+    Ok((chunk_size, pool_size))
+}
+
+/// Returns how paths are recorded in a backup.
+const fn path_mode(absolute_paths: bool) -> PathMode {
+    if absolute_paths {
+        PathMode::Absolute
+    } else {
+        PathMode::Basename
+    }
+}
+
+/// Returns the key derivation parameters to use for a backup, with any that
+/// were not given left at their defaults.
+fn kdf_params(memory_kib: Option<u32>, iterations: Option<u32>) -> Argon2Params {
+    let default_kdf_params = Argon2Params::default();
+
+    Argon2Params {
+        memory_kib: memory_kib.unwrap_or(default_kdf_params.memory_kib),
+        iterations: iterations.unwrap_or(default_kdf_params.iterations),
+        ..default_kdf_params
+    }
+}
+
+/// Returns a token that is cancelled when the process is interrupted or
+/// terminated, so that the operation using it can remove its partial output
+/// before exiting. A second signal exits immediately, in case cleaning up
+/// hangs.
+fn cancel_on_interrupt() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let handler_cancel = cancel.clone();
+
+    let result = ctrlc::set_handler(move || {
+        if handler_cancel.is_cancelled() {
+            exit(130);
         }
-        Commands::Extract {
+
+        eprintln!("Interrupted, cleaning up (interrupt again to exit immediately)");
+        handler_cancel.cancel();
+    });
+
+    if let Err(e) = result {
+        log::warn!(
+            "Failed to handle interrupts, so interrupting will leave partial output behind: {e}"
+        );
+    }
+
+    cancel
+}
+
+/// Returns a token that is cancelled when the process is interrupted or
+/// terminated, or once a file exists at the cancel file path, if one is
+/// given.
+fn cancel_on_request(cancel_file: Option<PathBuf>) -> CancellationToken {
+    let cancel = cancel_on_interrupt();
+
+    if let Some(cancel_file) = cancel_file {
+        let watcher_cancel = cancel.clone();
+
+        thread::spawn(move || {
+            while !watcher_cancel.is_cancelled() {
+                if cancel_file.exists() {
+                    eprintln!("Found cancel file '{}', cleaning up", cancel_file.display());
+                    watcher_cancel.cancel();
+                    break;
+                }
+
+                thread::sleep(CANCEL_FILE_POLL_INTERVAL);
+            }
+        });
+    }
+
+    cancel
+}
+
+/// Attempt to perform an extraction.
+fn perform_extract(args: ExtractArgs, logging: Logging) -> Result<Success, Failure> {
+    let ExtractArgs {
+        backup_path,
+        output_path,
+        password,
+        password_stdin,
+        pool_size,
+        resume,
+        temp_dir,
+        secure_delete,
+        preserve_xattrs,
+        preserve_btime,
+        strip_components,
+        restore_to_root,
+        overwrite_older,
+        on_conflict,
+        verify_files,
+        atomic,
+        check_password,
+        recovery_key_file,
+        matching,
+        memory,
+        debug,
+    } = args;
+
+    logging.init(debug);
+
+    if check_password {
+        return check_backup_password(backup_path, password, password_stdin);
+    }
+
+    // The output path is ignored when restoring to the root
+    let output_path = output_path.unwrap_or_default();
+
+    if !resume
+        && !restore_to_root
+        && !overwrite_older
+        && on_conflict.is_none()
+        && matching.is_empty()
+    {
+        validate_output_path(&output_path.to_string_lossy())
+            .map_err(|e| Failure::new("invalid-output-path", e))?;
+    }
+
+    let chunk_size = backup::backup_chunk_size(&backup_path)
+        .map_err(|e| Failure::new(e.kind(), format!("Failed to perform extraction: {e}")))?;
+    memory.check(chunk_size, pool_size)?;
+
+    let pw = match recovery_key_file {
+        Some(_) => String::new(),
+        None => obtain_password(password, password_stdin, "Backup password", false)?,
+    };
+    let progress = ProgressDisplay::new();
+    let options = ExtractOptions {
+        resume,
+        temp_dir,
+        secure_delete,
+        preserve_xattrs,
+        preserve_btime,
+        strip_components,
+        restore_to_root,
+        overwrite: overwrite_mode(overwrite_older, on_conflict),
+        conflict: on_conflict.map(Into::into).unwrap_or_default(),
+        verify_on_extract: verify_files,
+        atomic,
+        adaptive_memory: memory.adaptive_memory,
+        progress: Some(progress.handler()),
+        cancel: Some(cancel_on_interrupt()),
+        pause: None,
+    };
+
+    if !matching.is_empty() {
+        return backup::extract_matching(
+            backup_path,
+            &output_path,
+            &pw,
+            &matching,
+            pool_size,
+            &options,
+        )
+        .map(|count| Success {
+            message: format!(
+                "Successfully extracted {count} matching entries to {}",
+                output_path.display()
+            ),
+            output: Some(output_path),
+            bytes: None,
+            details: None,
+        })
+        .map_err(|e| Failure::from_error("Failed to perform extraction", &e));
+    }
+
+    match recovery_key_file {
+        Some(recovery_key) => backup::extract_with_recovery_key(
             backup_path,
             output_path,
-            password,
+            recovery_key,
             pool_size,
-            override_memory_limit,
-            debug,
-        } => {
-            init_logger(debug).unwrap();
-
-            let chunk_size = backup::backup_chunk_size(&backup_path)
-                .map_err(|e| format!("Failed to perform extraction: {e}"))?;
-            check_memory(chunk_size, pool_size, override_memory_limit)?;
-
-            match get_password(password, false, false) {
-                Ok(pw) => match backup::extract(backup_path, output_path, &pw, pool_size) {
-                    Ok(path) => Ok(format!("Successfully extracted to {}", path.display())),
-                    Err(e) => Err(if let BackupError::CryptoError(_) = e {
-                        format!("Failed to perform extraction: {e}.\nThis usually means that the provided password was incorrect, and cannot be used to extract the backup.")
-                    } else {
-                        format!("Failed to perform extraction: {e}")
-                    }),
-                },
-                Err(e) => Err(format!("Invalid password: {e}")),
-            }
-        }
+            &options,
+        ),
+        None => backup::extract(backup_path, output_path, &pw, pool_size, &options),
+    }
+    .map(|path| Success {
+        message: format!("Successfully extracted to {}", path.display()),
+        output: Some(path),
+        bytes: None,
+        details: None,
+    })
+    .map_err(|e| Failure::from_error("Failed to perform extraction", &e))
+}
+
+/// Returns which files already in the output directory an extraction may
+/// replace.
+const fn overwrite_mode(overwrite_older: bool, on_conflict: Option<ConflictArg>) -> OverwriteMode {
+    match (overwrite_older, on_conflict) {
+        (true, _) => OverwriteMode::OnlyNewer,
+        (false, Some(_)) => OverwriteMode::Always,
+        (false, None) => OverwriteMode::Never,
+    }
+}
+
+/// Checks that a password opens a backup, without extracting it.
+fn check_backup_password(
+    backup_path: PathBuf,
+    password: Option<String>,
+    password_stdin: bool,
+) -> Result<Success, Failure> {
+    let pw = obtain_password(password, password_stdin, "Backup password", false)?;
+
+    match backup::check_password(&backup_path, &pw) {
+        Ok(true) => Ok(Success {
+            message: "Password OK".to_owned(),
+            output: Some(backup_path),
+            bytes: None,
+            details: None,
+        }),
+        Ok(false) => Err(Failure::from_error(
+            "Failed to check password",
+            &BackupError::IncorrectPassword,
+        )),
+        Err(e) => Err(Failure::from_error("Failed to check password", &e)),
+    }
+}
+
+/// Attempt to verify a backup.
+fn perform_verify(args: VerifyArgs, logging: Logging) -> Result<Success, Failure> {
+    let VerifyArgs {
+        backup_path,
+        password,
+        password_stdin,
+        pool_size,
+        stats_only,
+        checksum_only,
+        override_memory_limit,
+        debug,
+    } = args;
+
+    logging.init(debug);
+
+    if checksum_only {
+        return backup::verify_checksum(&backup_path)
+            .map(|()| Success {
+                message: format!("Checksum of {} is valid", backup_path.display()),
+                output: Some(backup_path),
+                bytes: None,
+                details: None,
+            })
+            .map_err(|e| Failure::from_error("Failed to verify backup checksum", &e));
+    }
+
+    let chunk_size = backup::backup_chunk_size(&backup_path)
+        .map_err(|e| Failure::new(e.kind(), format!("Failed to perform verification: {e}")))?;
+    check_memory(chunk_size, pool_size, override_memory_limit)
+        .map_err(|e| Failure::new("memory-limit-exceeded", e))?;
+
+    let pw = obtain_password(password, password_stdin, "Backup password", false)?;
+
+    backup::verify(&backup_path, &pw, pool_size, &VerifyOptions { stats_only })
+        .map(|stats| Success {
+            message: if stats_only {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let throughput = stats.throughput() as u64;
+                format!(
+                    "Verified {} in {:.1}s ({}/s), 0 errors",
+                    format_bytes(stats.archive_size),
+                    stats.elapsed.as_secs_f64(),
+                    format_bytes(throughput)
+                )
+            } else {
+                format!(
+                    "Successfully verified {} ({})",
+                    stats.path.display(),
+                    format_bytes(stats.archive_size)
+                )
+            },
+            output: Some(stats.path),
+            bytes: Some(stats.archive_size),
+            details: None,
+        })
+        .map_err(|e| Failure::from_error("Failed to verify backup", &e))
+}
+
+/// Attempt to change a backup password.
+fn perform_change_password(args: ChangePasswordArgs, logging: Logging) -> Result<Success, Failure> {
+    let ChangePasswordArgs {
+        backup_path,
+        old_password,
+        new_password,
+        upgrade_format,
+        debug,
+    } = args;
+
+    logging.init(debug);
+
+    let old_pw = get_password(old_password, "Current backup password", false, false)
+        .map_err(|e| Failure::new("invalid-password", format!("Invalid password: {e}")))?;
+    let new_pw = get_password(new_password, "New backup password", true, true)
+        .map_err(|e| Failure::new("invalid-password", format!("Invalid password: {e}")))?;
+
+    let options = ChangePasswordOptions { upgrade_format };
+
+    backup::change_password(&backup_path, &old_pw, &new_pw, &options)
+        .map(|()| Success {
+            message: format!(
+                "Successfully changed password for {}",
+                backup_path.display()
+            ),
+            output: Some(backup_path),
+            bytes: None,
+            details: None,
+        })
+        .map_err(|e| Failure::from_error("Failed to change password", &e))
+}
+
+/// Attempt to merge backups.
+fn perform_merge(args: MergeArgs, logging: Logging) -> Result<Success, Failure> {
+    let MergeArgs {
+        backup_paths,
+        output_path,
+        password,
+        password_stdin,
+        pool_size,
+        debug,
+    } = args;
+
+    logging.init(debug);
+
+    let pw = obtain_password(password, password_stdin, "Backup password", false)?;
+
+    backup::merge(&backup_paths, output_path, &pw, pool_size)
+        .map(|stats| Success {
+            message: format!(
+                "Successfully merged {} backups into {}",
+                backup_paths.len(),
+                stats.path.display()
+            ),
+            bytes: Some(stats.output_size),
+            output: Some(stats.path),
+            details: None,
+        })
+        .map_err(|e| Failure::from_error("Failed to merge backups", &e))
+}
+
+/// Attempt to generate a recovery key pair.
+fn perform_recovery_keygen(args: RecoveryKeygenArgs, logging: Logging) -> Result<Success, Failure> {
+    let RecoveryKeygenArgs { output_path, debug } = args;
+
+    logging.init(debug);
+
+    let (private_key, public_key) = generate_recovery_key();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Only the owner may read the private key
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(&output_path)
+        .and_then(|mut file| {
+            io::Write::write_all(&mut file, recovery_key_hex(&private_key).as_bytes())
+        })
+        .map_err(|e| Failure::from_error("Failed to write recovery key", &BackupError::from(e)))?;
+
+    let public_key = recovery_key_hex(&public_key);
+
+    Ok(Success {
+        message: format!(
+            "Wrote recovery private key to {}\nPublic key: {public_key}",
+            output_path.display()
+        ),
+        output: Some(output_path),
+        bytes: None,
+        details: Some(json!({ "public_key": public_key })),
+    })
+}
+
+/// Attempt to show information about a backup.
+fn perform_info(args: InfoArgs, logging: Logging) -> Result<Success, Failure> {
+    let InfoArgs { backup_path, debug } = args;
+
+    logging.init(debug);
+
+    let info = backup::backup_info(&backup_path)
+        .map_err(|e| Failure::from_error("Failed to read backup info", &e))?;
+
+    let checksum = info.checksum_algorithm.map(|algorithm| match algorithm {
+        ChecksumAlgorithm::Sha256 => "sha256",
+        ChecksumAlgorithm::Blake3 => "blake3",
+    });
+    let created = info.created.map(|created| created.to_rfc3339());
+
+    let lines = [
+        ("Path", backup_path.display().to_string()),
+        (
+            "Format version",
+            info.format_version
+                .map_or_else(|| "none (legacy backup)".to_owned(), |v| v.to_string()),
+        ),
+        ("Cipher", "AES-256-GCM".to_owned()),
+        ("Chunk size", format_bytes(info.chunk_size)),
+        ("Passwords", info.key_slots.to_string()),
+        ("Checksum", checksum.unwrap_or("none").to_owned()),
+        (
+            "Compressed",
+            if info.compressed { "yes" } else { "no" }.to_owned(),
+        ),
+        (
+            "Created",
+            created.clone().unwrap_or_else(|| "unknown".to_owned()),
+        ),
+        (
+            "Tool version",
+            info.tool_version
+                .clone()
+                .unwrap_or_else(|| "unknown".to_owned()),
+        ),
+        ("Index", index_name(info.index).to_owned()),
+        ("Size", format_bytes(info.size)),
+    ];
+    let message = lines
+        .iter()
+        .map(|(label, value)| format!("{label}: {value}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(Success {
+        message,
+        bytes: Some(info.size),
+        details: Some(json!({
+            "format_version": info.format_version,
+            "cipher": "aes-256-gcm",
+            "chunk_size": info.chunk_size,
+            "key_slots": info.key_slots,
+            "checksum": checksum,
+            "compressed": info.compressed,
+            "created": created,
+            "tool_version": info.tool_version,
+            "index": index_name(info.index),
+        })),
+        output: Some(backup_path),
+    })
+}
+
+/// Returns the name of an index mode, as accepted by `--index`.
+const fn index_name(index: IndexMode) -> &'static str {
+    match index {
+        IndexMode::None => "none",
+        IndexMode::Plain => "plain",
+        IndexMode::Encrypted => "encrypted",
+    }
+}
+
+/// Attempt to list the contents of a backup.
+fn perform_list(args: ListArgs, logging: Logging) -> Result<Success, Failure> {
+    let ListArgs {
+        backup_path,
+        password,
+        password_stdin,
+        debug,
+    } = args;
+
+    logging.init(debug);
+
+    let info = backup::backup_info(&backup_path)
+        .map_err(|e| Failure::from_error("Failed to list backup", &e))?;
+
+    // A plain index only needs the password to be authenticated
+    let password = given_password(password, password_stdin, false)?;
+    let pw = if info.index == IndexMode::Encrypted {
+        Some(
+            get_password(password, "Backup password", false, false)
+                .map_err(|e| Failure::new("invalid-password", format!("Invalid password: {e}")))?,
+        )
+    } else {
+        password
+    };
+
+    let entries = backup::list(&backup_path, pw.as_deref())
+        .map_err(|e| Failure::from_error("Failed to list backup", &e))?;
+
+    let message = entries
+        .iter()
+        .map(|entry| {
+            let suffix = if entry.kind == EntryKind::Directory {
+                "/"
+            } else {
+                ""
+            };
+            format!(
+                "{:>10}  {}{suffix}",
+                format_bytes(entry.size),
+                entry.path.display()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let details = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "path": json_path(&entry.path),
+                "type": entry.kind.to_string(),
+                "size": entry.size,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Success {
+        message,
+        bytes: Some(entries.iter().map(|entry| entry.size).sum()),
+        details: Some(json!({ "entries": details })),
+        output: Some(backup_path),
+    })
+}
+
+/// Attempt to check backups against their stored checksums.
+fn perform_scrub(args: ScrubArgs, logging: Logging) -> Result<Success, Failure> {
+    let ScrubArgs {
+        backup_paths,
+        debug,
+    } = args;
+
+    logging.init(debug);
+
+    // Every backup is checked, even after one fails, so that a single run
+    // reports all of the corrupted backups
+    let results = backup_paths
+        .iter()
+        .map(|path| (path, backup::verify_checksum(path)))
+        .collect::<Vec<_>>();
+    let mut lines = results
+        .iter()
+        .map(|(path, result)| match result {
+            Ok(()) => format!("OK      {}", path.display()),
+            Err(e) => format!("FAILED  {}: {e}", path.display()),
+        })
+        .collect::<Vec<_>>();
+    let failures = results
+        .iter()
+        .filter_map(|(_, result)| result.as_ref().err())
+        .collect::<Vec<_>>();
+
+    if let Some(first) = failures.first() {
+        lines.push(format!(
+            "{} of {} backups failed their checksums",
+            failures.len(),
+            results.len()
+        ));
+        return Err(Failure::new(first.kind(), lines.join("\n")));
+    }
+
+    lines.push(format!(
+        "All {} backups match their checksums",
+        results.len()
+    ));
+
+    Ok(Success {
+        message: lines.join("\n"),
+        output: None,
+        bytes: Some(
+            backup_paths
+                .iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .map(|metadata| metadata.len())
+                .sum(),
+        ),
+        details: Some(json!({
+            "backups": backup_paths.iter().map(|path| json_path(path)).collect::<Vec<_>>(),
+        })),
+    })
+}
+
+/// Calibrate the chunk size and pool size.
+fn perform_calibrate(args: CalibrateArgs, logging: Logging) -> Success {
+    let CalibrateArgs { sample_size, debug } = args;
+
+    logging.init(debug);
+
+    let calibration = backup::calibrate(sample_size);
+    let recommended = calibration.recommended;
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let format_throughput = |throughput: f64| format!("{}/s", format_bytes(throughput as u64));
+    let mut lines = vec![format!(
+        "{:>10}  {:>9}  {:>14}",
+        "Chunk size", "Pool size", "Throughput"
+    )];
+    lines.extend(calibration.results.iter().map(|result| {
+        format!(
+            "{:>10}  {:>9}  {:>14}",
+            format_bytes(1 << result.chunk_size_magnitude),
+            result.pool_size,
+            format_throughput(result.throughput)
+        )
+    }));
+    lines.push(format!(
+        "Recommended for this machine: --chunk-size-magnitude {} --pool-size {} ({})",
+        recommended.chunk_size_magnitude,
+        recommended.pool_size,
+        format_throughput(recommended.throughput)
+    ));
+
+    let result_json = |result: &CalibrationResult| {
+        json!({
+            "chunk_size_magnitude": result.chunk_size_magnitude,
+            "pool_size": result.pool_size,
+            "throughput": result.throughput,
+        })
+    };
+
+    Success {
+        message: lines.join("\n"),
+        output: None,
+        bytes: None,
+        details: Some(json!({
+            "results": calibration.results.iter().map(result_json).collect::<Vec<_>>(),
+            "recommended": result_json(&recommended),
+        })),
+    }
+}
+
+/// Run the server until it is stopped.
+fn perform_serve(args: ServeArgs, logging: Logging) -> Result<Success, Failure> {
+    let ServeArgs {
+        port,
+        socket,
+        token_file,
+        token_stdin: _,
+        debug,
+    } = args;
+
+    logging.init(debug);
+
+    // Without a token file, clap requires the token on standard input
+    let token = read_token(token_file.as_deref())?;
+
+    let endpoint = match (port, socket) {
+        (_, Some(socket)) => serve::Endpoint::Socket(socket),
+        (Some(port), None) => serve::Endpoint::Port(port),
+        (None, None) => unreachable!("clap requires a port or socket"),
+    };
+
+    serve::serve(endpoint, token)
+}
+
+/// Reads the server token from a file, or from a single line of standard
+/// input if no file is given.
+fn read_token(token_file: Option<&Path>) -> Result<String, Failure> {
+    let invalid = |e: String| Failure::new("invalid-token", format!("Invalid token: {e}"));
+
+    let contents = if let Some(path) = token_file {
+        fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?
+    } else {
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .map_err(|e| invalid(e.to_string()))?;
+        line
+    };
+    let token = strip_line_ending(&contents);
+
+    if token.is_empty() {
+        return Err(invalid("the token must not be empty".to_owned()));
+    }
+
+    Ok(token.to_owned())
+}
+
+/// Attempt to perform the given command.
+fn perform_command(command: Commands, logging: Logging) -> Result<Success, Failure> {
+    match command {
+        Commands::Backup(args) => perform_backup(*args, logging),
+        Commands::Extract(args) => perform_extract(args, logging),
+        Commands::Verify(args) => perform_verify(args, logging),
+        Commands::ChangePassword(args) => perform_change_password(args, logging),
+        Commands::Merge(args) => perform_merge(args, logging),
+        Commands::RecoveryKeygen(args) => perform_recovery_keygen(args, logging),
+        Commands::Info(args) => perform_info(args, logging),
+        Commands::List(args) => perform_list(args, logging),
+        Commands::Scrub(args) => perform_scrub(args, logging),
+        Commands::Calibrate(args) => Ok(perform_calibrate(args, logging)),
+        Commands::Serve(args) => perform_serve(args, logging),
     }
 }
 
 fn main() {
     let cli = Cli::parse();
+    // Log records are kept out of the way of a result printed as JSON
+    let logging = Logging {
+        format: cli.log_format.into(),
+        target: match cli.format {
+            OutputFormat::Human => LogTarget::Stdout,
+            OutputFormat::Json => LogTarget::Stderr,
+        },
+    };
+    let result = perform_command(cli.command, logging);
 
-    match perform_backup(cli.command) {
-        Ok(msg) => println!("{msg}"),
-        Err(msg) => {
-            eprintln!("{msg}");
-            exit(1);
-        }
+    match cli.format {
+        OutputFormat::Human => match result {
+            Ok(success) => println!("{}", success.message),
+            Err(failure) => {
+                eprintln!("{}", failure.message);
+                exit(failure.exit_code());
+            }
+        },
+        OutputFormat::Json => match result {
+            Ok(success) => {
+                let mut result = json!({
+                    "status": "ok",
+                    "output": success.output.as_deref().map(json_path),
+                    "bytes": success.bytes,
+                });
+
+                if let Some(details) = success.details {
+                    result["details"] = details;
+                }
+
+                println!("{result}");
+            }
+            Err(failure) => {
+                println!(
+                    "{}",
+                    json!({
+                        "status": "error",
+                        "kind": failure.kind,
+                        "message": failure.message,
+                    })
+                );
+                exit(failure.exit_code());
+            }
+        },
     }
 }
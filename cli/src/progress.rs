@@ -0,0 +1,164 @@
+//! Progress display for long-running commands.
+//!
+//! When standard error is a terminal, progress is drawn there as a bar with
+//! the percentage complete, throughput and estimated time remaining, or as a
+//! spinner for stages whose total size is not known ahead of time. Otherwise,
+//! such as when the output is piped to a file, progress is logged at
+//! intervals instead, and is shown in debug mode.
+
+use backup::{format_bytes, Progress, ProgressHandler, ProgressStage};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use std::io::{self, IsTerminal};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How often progress is logged when it is not drawn.
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the spinner of a stage with an unknown total is redrawn, so that
+/// it keeps moving between reports.
+const SPINNER_TICK: Duration = Duration::from_millis(100);
+
+/// The template of the bar shown for stages with a known total.
+const BAR_TEMPLATE: &str = "{msg:<10} [{bar:30}] {percent:>3}% {bytes}/{total_bytes} \
+    ({binary_bytes_per_sec}, ETA {eta})";
+
+/// The template of the spinner shown for stages with an unknown total.
+const SPINNER_TEMPLATE: &str = "{spinner} {msg:<10} {bytes} ({binary_bytes_per_sec}, {elapsed})";
+
+/// The display of the stage currently in progress.
+struct StageDisplay {
+    /// The stage being displayed.
+    stage: ProgressStage,
+    /// The bar or spinner drawn for the stage, if progress is being drawn.
+    bar: Option<ProgressBar>,
+    /// When progress was last logged, if progress is being logged.
+    last_logged: Option<Instant>,
+}
+
+/// Displays the progress reported by an operation. The bar of the stage in
+/// progress is removed once the display is dropped, so it should be kept
+/// until the operation is over.
+pub struct ProgressDisplay {
+    /// Whether progress is drawn, rather than logged.
+    interactive: bool,
+    /// The stage currently in progress, if any has been reported.
+    current: Arc<Mutex<Option<StageDisplay>>>,
+}
+
+impl ProgressDisplay {
+    /// Creates a progress display, which draws progress if standard error is
+    /// a terminal and logs it otherwise.
+    pub fn new() -> Self {
+        Self {
+            interactive: io::stderr().is_terminal(),
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns a handler that shows the progress reported to it.
+    pub fn handler(&self) -> ProgressHandler {
+        let interactive = self.interactive;
+        let current = Arc::clone(&self.current);
+
+        ProgressHandler::new(move |progress| {
+            show(
+                &mut current.lock().unwrap_or_else(PoisonError::into_inner),
+                interactive,
+                progress,
+            );
+        })
+    }
+}
+
+impl Drop for ProgressDisplay {
+    fn drop(&mut self) {
+        let current = self
+            .current
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        if let Some(bar) = current.and_then(|display| display.bar) {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Shows a progress report, starting a new bar if a new stage has begun.
+fn show(current: &mut Option<StageDisplay>, interactive: bool, progress: Progress) {
+    if current
+        .as_ref()
+        .is_none_or(|display| display.stage != progress.stage)
+    {
+        if let Some(bar) = current.take().and_then(|display| display.bar) {
+            bar.finish_and_clear();
+        }
+
+        *current = Some(StageDisplay {
+            stage: progress.stage,
+            bar: interactive.then(|| stage_bar(&progress)),
+            last_logged: None,
+        });
+    }
+
+    let Some(display) = current.as_mut() else {
+        return;
+    };
+
+    if let Some(bar) = &display.bar {
+        bar.set_position(progress.bytes_processed);
+    } else if display
+        .last_logged
+        .is_none_or(|last_logged| last_logged.elapsed() >= LOG_INTERVAL)
+    {
+        log_progress(&progress);
+        display.last_logged = Some(Instant::now());
+    }
+}
+
+/// Creates the bar or spinner for a stage.
+fn stage_bar(progress: &Progress) -> ProgressBar {
+    let bar = progress.total_bytes.map_or_else(
+        || {
+            let spinner = ProgressBar::new_spinner()
+                .with_style(ProgressStyle::with_template(SPINNER_TEMPLATE).unwrap());
+            spinner.enable_steady_tick(SPINNER_TICK);
+            spinner
+        },
+        |total_bytes| {
+            ProgressBar::new(total_bytes).with_style(
+                ProgressStyle::with_template(BAR_TEMPLATE)
+                    .unwrap()
+                    .progress_chars("=> "),
+            )
+        },
+    );
+
+    bar.with_message(stage_name(progress.stage))
+}
+
+/// Logs a progress report.
+fn log_progress(progress: &Progress) {
+    let stage = stage_name(progress.stage);
+    let processed = format_bytes(progress.bytes_processed);
+
+    match (progress.fraction(), progress.total_bytes) {
+        (Some(fraction), Some(total_bytes)) => info!(
+            "{stage}: {:.0}% ({processed} of {})",
+            fraction * 100.,
+            format_bytes(total_bytes)
+        ),
+        _ => info!("{stage}: {processed}"),
+    }
+}
+
+/// Returns the name of a stage, as shown beside its progress.
+const fn stage_name(stage: ProgressStage) -> &'static str {
+    match stage {
+        ProgressStage::Archiving => "Archiving",
+        ProgressStage::Decrypting => "Decrypting",
+        ProgressStage::Unpacking => "Unpacking",
+    }
+}
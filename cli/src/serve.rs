@@ -0,0 +1,803 @@
+//! A daemon mode that performs backups and extractions requested over a local
+//! socket.
+//!
+//! Each connection sends a single request as a line of JSON, and receives
+//! one or more events as lines of JSON in return, after which the connection
+//! is closed. Every request must carry the token the server was started with.
+//! Only one backup or extraction runs at a time; the connection that started
+//! it receives its progress as it runs, and any connection can ask for the
+//! status of the current or most recent operation.
+//!
+//! Requests look like:
+//!
+//! ```json
+//! {"token": "...", "type": "backup", "include_paths": ["/data"], "output_path": "/backups/data.ebk", "password": "..."}
+//! {"token": "...", "type": "extract", "backup_path": "/backups/data.ebk", "output_path": "/restore", "password": "..."}
+//! {"token": "...", "type": "status"}
+//! ```
+//!
+//! Backups also accept `exclude_globs`, `chunk_size` in bytes, `pool_size`
+//! and `compression_level`, and extractions accept `pool_size`.
+
+use crate::{
+    json_path, validate_chunk_bytes, validate_password, validate_pool_size, Failure, Success,
+};
+use backup::*;
+use glob::Pattern;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// The chunk size of backups that do not specify one.
+const DEFAULT_CHUNK_SIZE: usize = 1 << 16;
+
+/// The pool size of operations that do not specify one.
+const DEFAULT_POOL_SIZE: u8 = 16;
+
+/// The longest request accepted, in bytes, including its newline. Anything
+/// longer is rejected without being read to the end.
+const MAX_REQUEST_LEN: u64 = 1 << 20;
+
+/// A connection to the server, which can be split into a reading and a
+/// writing half.
+pub trait Connection: io::Read + Write + Send + Sized + 'static {
+    /// Returns another handle to the same connection.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Self::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Self::try_clone(self)
+    }
+}
+
+/// The state of the operation being performed, or the last one performed.
+enum JobState {
+    /// No operation has been performed yet.
+    Idle,
+    /// An operation is in progress.
+    Running {
+        /// The identifier of the operation.
+        job: u64,
+        /// The kind of operation.
+        operation: &'static str,
+        /// The most recent progress report, if any.
+        progress: Option<Progress>,
+    },
+    /// The last operation has finished.
+    Finished {
+        /// The identifier of the operation.
+        job: u64,
+        /// The kind of operation.
+        operation: &'static str,
+        /// The event describing how the operation finished.
+        result: Value,
+    },
+}
+
+impl JobState {
+    /// Describes the state as a status event.
+    fn to_event(&self) -> Value {
+        match self {
+            Self::Idle => json!({ "event": "status", "state": "idle" }),
+            Self::Running {
+                job,
+                operation,
+                progress,
+            } => json!({
+                "event": "status",
+                "state": "running",
+                "job": job,
+                "operation": operation,
+                "progress": progress.as_ref().map(progress_value),
+            }),
+            Self::Finished {
+                job,
+                operation,
+                result,
+            } => json!({
+                "event": "status",
+                "state": "finished",
+                "job": job,
+                "operation": operation,
+                "result": result,
+            }),
+        }
+    }
+
+    /// Returns the identifier of the last operation started, if any.
+    const fn last_job(&self) -> Option<u64> {
+        match self {
+            Self::Idle => None,
+            Self::Running { job, .. } | Self::Finished { job, .. } => Some(*job),
+        }
+    }
+}
+
+/// State shared between connections.
+struct Server {
+    /// The token every request must carry.
+    token: String,
+    /// The state of the current or last operation.
+    job: Mutex<JobState>,
+}
+
+impl Server {
+    /// Locks the job state. A panic while it was held cannot leave it
+    /// inconsistent, so a poisoned lock is still used.
+    fn job(&self) -> std::sync::MutexGuard<'_, JobState> {
+        self.job.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Records how a job finished once it is done. If the job ends without
+/// finishing, such as when its operation panics, it is recorded as failed
+/// instead of being reported as running forever.
+struct JobGuard<'a> {
+    /// The server running the job.
+    server: &'a Server,
+    /// The identifier of the job.
+    job: u64,
+    /// The kind of operation.
+    operation: &'static str,
+    /// Whether the job has finished.
+    finished: bool,
+}
+
+impl<'a> JobGuard<'a> {
+    /// Guards a job that has just started running.
+    const fn new(server: &'a Server, job: u64, operation: &'static str) -> Self {
+        Self {
+            server,
+            job,
+            operation,
+            finished: false,
+        }
+    }
+
+    /// Records the event describing how the job finished.
+    fn finish(mut self, result: Value) {
+        self.record(result);
+        self.finished = true;
+    }
+
+    /// Replaces the running job with the finished one.
+    fn record(&self, result: Value) {
+        *self.server.job() = JobState::Finished {
+            job: self.job,
+            operation: self.operation,
+            result,
+        };
+    }
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            warn!("{} job {} stopped unexpectedly", self.operation, self.job);
+            self.record(json!({
+                "event": "finished",
+                "job": self.job,
+                "status": "error",
+                "kind": "internal-error",
+                "message": "The operation stopped unexpectedly",
+            }));
+        }
+    }
+}
+
+/// Where the server listens for connections.
+pub enum Endpoint {
+    /// A TCP port on the loopback interface.
+    Port(u16),
+    /// A Unix domain socket at the given path.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Socket(PathBuf),
+}
+
+/// Listens for requests until the process is stopped. Only returns if the
+/// server cannot be started or stops accepting connections.
+pub fn serve(endpoint: Endpoint, token: String) -> Result<Success, Failure> {
+    let server = Arc::new(Server {
+        token,
+        job: Mutex::new(JobState::Idle),
+    });
+
+    match endpoint {
+        Endpoint::Port(port) => {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+                .map_err(|e| Failure::new("serve-failed", format!("Failed to listen: {e}")))?;
+            info!("Listening on {}", listener.local_addr().unwrap());
+            accept(listener.incoming(), &server);
+        }
+        #[cfg(unix)]
+        Endpoint::Socket(path) => {
+            use std::os::unix::fs::FileTypeExt;
+            use std::os::unix::net::UnixListener;
+
+            // A socket left behind by a previous server is replaced, but
+            // nothing else is
+            if std::fs::symlink_metadata(&path)
+                .is_ok_and(|metadata| metadata.file_type().is_socket())
+            {
+                std::fs::remove_file(&path).map_err(|e| {
+                    Failure::new("serve-failed", format!("Failed to remove old socket: {e}"))
+                })?;
+            }
+
+            let listener = UnixListener::bind(&path)
+                .map_err(|e| Failure::new("serve-failed", format!("Failed to listen: {e}")))?;
+            info!("Listening on {}", path.display());
+            accept(listener.incoming(), &server);
+        }
+        #[cfg(not(unix))]
+        Endpoint::Socket(_) => {
+            return Err(Failure::new(
+                "serve-failed",
+                "Unix sockets are not supported on this platform",
+            ));
+        }
+    }
+
+    Err(Failure::new(
+        "serve-failed",
+        "Stopped accepting connections",
+    ))
+}
+
+/// Handles each incoming connection on its own thread.
+fn accept<C: Connection>(incoming: impl Iterator<Item = io::Result<C>>, server: &Arc<Server>) {
+    for connection in incoming {
+        match connection {
+            Ok(connection) => {
+                let server = Arc::clone(server);
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(&server, connection) {
+                        warn!("Connection failed: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept connection: {e}"),
+        }
+    }
+}
+
+/// Writes a single event to a connection as a line of JSON.
+fn send(writer: &Mutex<impl Write>, event: &Value) -> io::Result<()> {
+    let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+    writeln!(writer, "{event}")?;
+    writer.flush()
+}
+
+/// Builds an error event.
+fn error_event(kind: &str, message: impl Into<String>) -> Value {
+    json!({ "event": "error", "kind": kind, "message": message.into() })
+}
+
+/// Describes a progress report.
+fn progress_value(progress: &Progress) -> Value {
+    let stage = match progress.stage {
+        ProgressStage::Archiving => "archiving",
+        ProgressStage::Decrypting => "decrypting",
+        ProgressStage::Unpacking => "unpacking",
+    };
+
+    json!({
+        "stage": stage,
+        "bytes_processed": progress.bytes_processed,
+        "total_bytes": progress.total_bytes,
+    })
+}
+
+/// Compares the token of a request to the server's without revealing how
+/// much of it matched through timing.
+fn token_matches(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reads a request from a connection and responds to it.
+fn handle_connection<C: Connection>(server: &Arc<Server>, connection: C) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(connection.try_clone()?.take(MAX_REQUEST_LEN + 1)).read_line(&mut line)?;
+    let writer = Arc::new(Mutex::new(connection));
+
+    if line.len() as u64 > MAX_REQUEST_LEN {
+        return send(
+            &writer,
+            &error_event(
+                "request-too-large",
+                format!("Requests must not be longer than {MAX_REQUEST_LEN} bytes"),
+            ),
+        );
+    }
+
+    let request = match serde_json::from_str::<Value>(&line) {
+        Ok(request) if request.is_object() => request,
+        _ => {
+            return send(
+                &writer,
+                &error_event("invalid-request", "Requests must be JSON objects"),
+            )
+        }
+    };
+
+    let token = request.get("token").and_then(Value::as_str).unwrap_or("");
+
+    if !token_matches(&server.token, token) {
+        return send(&writer, &error_event("unauthorized", "Invalid token"));
+    }
+
+    match request.get("type").and_then(Value::as_str) {
+        Some("status") => send(&writer, &server.job().to_event()),
+        Some("backup") => run_operation(server, &writer, "backup", &request),
+        Some("extract") => run_operation(server, &writer, "extract", &request),
+        _ => send(
+            &writer,
+            &error_event(
+                "invalid-request",
+                "The request type must be backup, extract or status",
+            ),
+        ),
+    }
+}
+
+/// Starts an operation if none is running, streaming its progress to the
+/// connection that requested it.
+fn run_operation<W: Write + Send + 'static>(
+    server: &Arc<Server>,
+    writer: &Arc<Mutex<W>>,
+    operation: &'static str,
+    request: &Value,
+) -> io::Result<()> {
+    let job = {
+        let mut state = server.job();
+
+        if matches!(*state, JobState::Running { .. }) {
+            return send(
+                writer,
+                &error_event("busy", "Another operation is already running"),
+            );
+        }
+
+        let job = state.last_job().map_or(1, |job| job + 1);
+        *state = JobState::Running {
+            job,
+            operation,
+            progress: None,
+        };
+        job
+    };
+
+    let guard = JobGuard::new(server, job, operation);
+    info!("Starting {operation} job {job}");
+    // The client may disconnect at any time without stopping the operation
+    _ = send(writer, &json!({ "event": "started", "job": job }));
+
+    // Progress is streamed to the client and recorded for status requests
+    let handler = {
+        let server = Arc::clone(server);
+        let writer = Arc::clone(writer);
+        ProgressHandler::new(move |reported| {
+            if let JobState::Running { progress, .. } = &mut *server.job() {
+                *progress = Some(reported);
+            }
+
+            _ = send(
+                &writer,
+                &json!({ "event": "progress", "job": job, "progress": progress_value(&reported) }),
+            );
+        })
+    };
+
+    let result = match operation {
+        "backup" => perform_backup(request, handler),
+        _ => perform_extract(request, handler),
+    };
+
+    let event = match result {
+        Ok(success) => json!({
+            "event": "finished",
+            "job": job,
+            "status": "ok",
+            "message": success.message,
+            "output": success.output.as_deref().map(json_path),
+            "bytes": success.bytes,
+        }),
+        Err(failure) => json!({
+            "event": "finished",
+            "job": job,
+            "status": "error",
+            "kind": failure.kind,
+            "message": failure.message,
+        }),
+    };
+
+    info!("Finished {operation} job {job}");
+    guard.finish(event.clone());
+    send(writer, &event)
+}
+
+/// Reads a required string field of a request.
+fn string_field<'a>(request: &'a Value, field: &str) -> Result<&'a str, Failure> {
+    request
+        .get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| Failure::new("invalid-request", format!("Missing string field: {field}")))
+}
+
+/// Reads an optional unsigned integer field of a request.
+fn integer_field<T: TryFrom<u64>>(request: &Value, field: &str) -> Result<Option<T>, Failure> {
+    request
+        .get(field)
+        .filter(|value| !value.is_null())
+        .map(|value| {
+            value
+                .as_u64()
+                .and_then(|value| T::try_from(value).ok())
+                .ok_or_else(|| {
+                    Failure::new("invalid-request", format!("Invalid integer field: {field}"))
+                })
+        })
+        .transpose()
+}
+
+/// Reads an optional unsigned integer field of a request, checked with the
+/// validator of the matching command line argument.
+fn validated_integer_field<T>(
+    request: &Value,
+    field: &str,
+    validate: fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, Failure> {
+    integer_field::<u64>(request, field)?
+        .map(|value| {
+            validate(&value.to_string()).map_err(|e| {
+                Failure::new(
+                    "invalid-request",
+                    format!("Invalid integer field: {field}, {e}"),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// Reads an optional list of strings from a request.
+fn string_list_field<'a>(request: &'a Value, field: &str) -> Result<Vec<&'a str>, Failure> {
+    let Some(values) = request.get(field).filter(|value| !value.is_null()) else {
+        return Ok(Vec::new());
+    };
+
+    values
+        .as_array()
+        .and_then(|values| values.iter().map(Value::as_str).collect())
+        .ok_or_else(|| Failure::new("invalid-request", format!("Invalid string list: {field}")))
+}
+
+/// Performs a requested backup.
+fn perform_backup(request: &Value, progress: ProgressHandler) -> Result<Success, Failure> {
+    let include_paths = string_list_field(request, "include_paths")?;
+    let exclude_globs = string_list_field(request, "exclude_globs")?
+        .into_iter()
+        .map(|glob| {
+            Pattern::new(glob)
+                .map_err(|e| Failure::new("invalid-request", format!("Invalid glob: {glob}, {e}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let output_path = PathBuf::from(string_field(request, "output_path")?);
+    let password = validate_password(string_field(request, "password")?)
+        .map_err(|e| Failure::new("invalid-password", format!("Invalid password: {e}")))?;
+    let chunk_size = validated_integer_field(request, "chunk_size", validate_chunk_bytes)?
+        .unwrap_or(DEFAULT_CHUNK_SIZE);
+    let pool_size = validated_integer_field(request, "pool_size", validate_pool_size)?
+        .unwrap_or(DEFAULT_POOL_SIZE);
+    let compression_level = integer_field::<u8>(request, "compression_level")?.map(i32::from);
+
+    if include_paths.is_empty() {
+        return Err(Failure::new(
+            "invalid-request",
+            "At least one include path is required",
+        ));
+    }
+
+    check_memory(chunk_size, pool_size, false)
+        .map_err(|e| Failure::new("memory-limit-exceeded", e))?;
+
+    backup::backup(
+        &include_paths,
+        &exclude_globs,
+        output_path,
+        &password,
+        chunk_size,
+        pool_size,
+        &BackupOptions {
+            compression_level,
+            progress: Some(progress),
+            ..Default::default()
+        },
+    )
+    .map(|stats| Success {
+        message: format!("Successfully backed up to {}", stats.path.display()),
+        output: Some(stats.path),
+        bytes: Some(stats.output_size),
+        details: None,
+    })
+    .map_err(|e| Failure::from_error("Failed to perform backup", &e))
+}
+
+/// Performs a requested extraction.
+fn perform_extract(request: &Value, progress: ProgressHandler) -> Result<Success, Failure> {
+    let backup_path = PathBuf::from(string_field(request, "backup_path")?);
+    let output_path = PathBuf::from(string_field(request, "output_path")?);
+    let password = string_field(request, "password")?;
+    let pool_size = validated_integer_field(request, "pool_size", validate_pool_size)?
+        .unwrap_or(DEFAULT_POOL_SIZE);
+
+    let chunk_size = backup::backup_chunk_size(&backup_path)
+        .map_err(|e| Failure::from_error("Failed to perform extraction", &e))?;
+    check_memory(chunk_size, pool_size, false)
+        .map_err(|e| Failure::new("memory-limit-exceeded", e))?;
+
+    backup::extract(
+        backup_path,
+        output_path,
+        password,
+        pool_size,
+        &ExtractOptions {
+            progress: Some(progress),
+            ..Default::default()
+        },
+    )
+    .map(|path| Success {
+        message: format!("Successfully extracted to {}", path.display()),
+        output: Some(path),
+        bytes: None,
+        details: None,
+    })
+    .map_err(|e| Failure::from_error("Failed to perform extraction", &e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::BufRead;
+
+    fn non_existent_temp_file() -> PathBuf {
+        let temp_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let path = temp_path.to_path_buf();
+        temp_path.close().unwrap();
+        path
+    }
+
+    fn test_server() -> Arc<Server> {
+        Arc::new(Server {
+            token: "token123".to_owned(),
+            job: Mutex::new(JobState::Idle),
+        })
+    }
+
+    /// Sends a request over a connection to the server, returning every
+    /// event it responds with.
+    fn request(server: &Arc<Server>, request: &str) -> Vec<Value> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (connection, _) = listener.accept().unwrap();
+
+        writeln!(client, "{request}").unwrap();
+        handle_connection(server, connection).unwrap();
+
+        BufReader::new(client)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    fn assert_error(events: &[Value], kind: &str) {
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "error");
+        assert_eq!(events[0]["kind"], kind);
+    }
+
+    #[test]
+    fn test_unauthorized() {
+        let server = test_server();
+
+        assert_error(
+            &request(&server, r#"{"token": "token124", "type": "status"}"#),
+            "unauthorized",
+        );
+        assert_error(
+            &request(&server, r#"{"token": "token12", "type": "status"}"#),
+            "unauthorized",
+        );
+        assert_error(&request(&server, r#"{"type": "status"}"#), "unauthorized");
+
+        // A rejected request does not start an operation
+        let events = request(
+            &server,
+            r#"{"token": "", "type": "extract", "backup_path": "a", "output_path": "b", "password": "password123"}"#,
+        );
+        assert_error(&events, "unauthorized");
+        assert!(matches!(*server.job(), JobState::Idle));
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let server = test_server();
+
+        assert_error(&request(&server, "not json"), "invalid-request");
+        assert_error(&request(&server, r#"["token123"]"#), "invalid-request");
+        assert_error(&request(&server, ""), "invalid-request");
+        assert_error(
+            &request(&server, r#"{"token": "token123", "type": "delete"}"#),
+            "invalid-request",
+        );
+        assert_error(
+            &request(&server, r#"{"token": "token123"}"#),
+            "invalid-request",
+        );
+
+        // A malformed operation finishes with an error instead of running
+        let events = request(
+            &server,
+            r#"{"token": "token123", "type": "backup", "output_path": "a", "password": "password123"}"#,
+        );
+        let finished = events.last().unwrap();
+        assert_eq!(finished["event"], "finished");
+        assert_eq!(finished["status"], "error");
+        assert_eq!(finished["kind"], "invalid-request");
+
+        // Sizes outside the range accepted on the command line are rejected
+        for (field, value) in [
+            ("chunk_size", 0),
+            ("chunk_size", 1 << 31),
+            ("pool_size", 0),
+            ("pool_size", 65),
+        ] {
+            let mut backup_request = json!({
+                "token": "token123",
+                "type": "backup",
+                "include_paths": ["a"],
+                "output_path": "b",
+                "password": "password123",
+            });
+            backup_request[field] = json!(value);
+            let events = request(&server, &backup_request.to_string());
+            let finished = events.last().unwrap();
+            assert_eq!(finished["status"], "error");
+            assert_eq!(finished["kind"], "invalid-request", "{field}: {value}");
+        }
+
+        for pool_size in [0, 65] {
+            let extract_request = json!({
+                "token": "token123",
+                "type": "extract",
+                "backup_path": "a",
+                "output_path": "b",
+                "password": "password123",
+                "pool_size": pool_size,
+            });
+            let events = request(&server, &extract_request.to_string());
+            let finished = events.last().unwrap();
+            assert_eq!(finished["status"], "error");
+            assert_eq!(finished["kind"], "invalid-request", "{pool_size}");
+        }
+    }
+
+    #[test]
+    fn test_job_status() {
+        let server = test_server();
+        let src_path = non_existent_temp_file();
+        let backup_path = non_existent_temp_file();
+        let extract_path = non_existent_temp_file();
+
+        fs::create_dir(&src_path).unwrap();
+        fs::write(src_path.join("file.txt"), "Hello, world!").unwrap();
+
+        let status = request(&server, r#"{"token": "token123", "type": "status"}"#);
+        assert_eq!(status, [json!({ "event": "status", "state": "idle" })]);
+
+        let backup_request = json!({
+            "token": "token123",
+            "type": "backup",
+            "include_paths": [src_path],
+            "output_path": backup_path,
+            "password": "password123",
+            "chunk_size": 1024,
+            "pool_size": 2,
+        });
+        let events = request(&server, &backup_request.to_string());
+        assert_eq!(events[0], json!({ "event": "started", "job": 1 }));
+        assert!(events[1..events.len() - 1]
+            .iter()
+            .all(|event| event["event"] == "progress" && event["job"] == 1));
+        let finished = events.last().unwrap();
+        assert_eq!(finished["event"], "finished");
+        assert_eq!(finished["status"], "ok", "{finished}");
+        assert!(backup_path.is_file());
+
+        // The status reports how the last job finished
+        let status = request(&server, r#"{"token": "token123", "type": "status"}"#);
+        assert_eq!(
+            status,
+            [json!({
+                "event": "status",
+                "state": "finished",
+                "job": 1,
+                "operation": "backup",
+                "result": finished,
+            })]
+        );
+
+        // The next job gets the next identifier
+        let extract_request = json!({
+            "token": "token123",
+            "type": "extract",
+            "backup_path": backup_path,
+            "output_path": extract_path,
+            "password": "password123",
+            "pool_size": 2,
+        });
+        let events = request(&server, &extract_request.to_string());
+        assert_eq!(events[0], json!({ "event": "started", "job": 2 }));
+        assert_eq!(events.last().unwrap()["status"], "ok");
+        assert_eq!(
+            fs::read_to_string(
+                extract_path
+                    .join(src_path.file_name().unwrap())
+                    .join("file.txt")
+            )
+            .unwrap(),
+            "Hello, world!"
+        );
+
+        let status = request(&server, r#"{"token": "token123", "type": "status"}"#);
+        assert_eq!(status[0]["job"], 2);
+        assert_eq!(status[0]["operation"], "extract");
+
+        fs::remove_dir_all(&src_path).unwrap();
+        fs::remove_file(&backup_path).unwrap();
+        fs::remove_dir_all(&extract_path).unwrap();
+    }
+
+    #[test]
+    fn test_job_guard() {
+        let server = test_server();
+
+        // A job that ends without finishing is recorded as failed
+        *server.job() = JobState::Running {
+            job: 1,
+            operation: "backup",
+            progress: None,
+        };
+        drop(JobGuard::new(&server, 1, "backup"));
+
+        let status = server.job().to_event();
+        assert_eq!(status["state"], "finished");
+        assert_eq!(status["job"], 1);
+        assert_eq!(status["result"]["status"], "error");
+        assert_eq!(status["result"]["kind"], "internal-error");
+
+        // Finishing a job records its result
+        JobGuard::new(&server, 2, "extract").finish(json!({ "status": "ok" }));
+
+        let status = server.job().to_event();
+        assert_eq!(status["job"], 2);
+        assert_eq!(status["result"], json!({ "status": "ok" }));
+    }
+}
@@ -1,18 +1,110 @@
 //! Backup operation configuration.
 
-use super::{ExcludeGlobs, FileSelect, IncludePathsSelect, Slider};
+use super::{
+    Checkbox, ExcludeGlobs, FileSelect, IncludePathsSelect, PasswordPrompt, RunningOperation,
+    Slider,
+};
+use crate::constants::BACKUP_FILE_EXTENSION;
 use crate::format::*;
+use crate::services::{scan_size, BackupProfile, Operation, Profiles};
+use backup::COMPRESSION_LEVELS;
+use chrono::Local;
 use dioxus::prelude::*;
+use glob::{Pattern, PatternError};
+
+/// Returns the name of a new backup file, from the time it is created.
+fn backup_file_name() -> String {
+    format!(
+        "backup_{}.{BACKUP_FILE_EXTENSION}",
+        Local::now().format("%Y-%m-%d_%H-%M-%S")
+    )
+}
 
 /// The backup operation configuration component.
 #[component]
 pub fn BackupConfig() -> Element {
-    let include_paths = use_signal(Vec::new);
-    let output_path = use_signal(|| None);
+    let mut profiles = use_context::<Signal<Profiles>>();
+    let saved = use_hook(|| profiles.peek().active().backup.clone());
+
+    let include_paths = use_signal(|| saved.include_paths.clone());
+    let output_path = use_signal(|| saved.output_path.clone());
     let output_path_error = use_signal(|| None);
-    let exclude_globs = use_signal(Vec::new);
-    let chunk_size_magnitude = use_signal(|| 16u8);
-    let pool_size = use_signal(|| 4u8);
+    let exclude_globs = use_signal(|| {
+        saved
+            .exclude_globs
+            .iter()
+            .map(|pattern| Pattern::new(pattern).map_err(|err| (pattern.clone(), err)))
+            .collect::<Vec<Result<Pattern, (String, PatternError)>>>()
+    });
+    let chunk_size_magnitude = use_signal(|| saved.chunk_size_magnitude);
+    let pool_size = use_signal(|| saved.pool_size);
+    let compress = use_signal(|| saved.compress);
+    let compression_level = use_signal(|| saved.compression_level);
+    let mut prompting = use_signal(|| false);
+    let mut running = use_signal(|| None::<Operation>);
+
+    // Keep the active profile up to date with any edits
+    use_effect(move || {
+        let backup = BackupProfile {
+            include_paths: include_paths(),
+            output_path: output_path(),
+            exclude_globs: exclude_globs
+                .read()
+                .iter()
+                .map(|pattern| match pattern {
+                    Ok(pat) => pat.as_str().to_owned(),
+                    Err((invalid_pat, _)) => invalid_pat.clone(),
+                })
+                .collect(),
+            chunk_size_magnitude: chunk_size_magnitude(),
+            pool_size: pool_size(),
+            compress: compress(),
+            compression_level: compression_level(),
+        };
+
+        if profiles.peek().active().backup != backup {
+            profiles.with_mut(|profiles| profiles.active_mut().backup = backup);
+        }
+    });
+
+    // The include paths are scanned again in the background whenever they or
+    // the exclusions change
+    let scanned_size = use_resource(move || {
+        let include_paths = include_paths();
+        let exclude_globs = exclude_globs
+            .read()
+            .iter()
+            .filter_map(|pattern| pattern.as_ref().ok().cloned())
+            .collect::<Vec<_>>();
+
+        async move {
+            tokio::task::spawn_blocking(move || scan_size(&include_paths, &exclude_globs))
+                .await
+                .ok()
+        }
+    });
+    let estimate_info = match scanned_size().flatten() {
+        _ if include_paths.read().is_empty() => None,
+        Some(size) => {
+            let estimate = profiles.with(|profiles| profiles.throughput().backup.estimate(size));
+            Some(format!(
+                "{} to back up. {}",
+                format_size(size),
+                format_estimate(estimate)
+            ))
+        }
+        None => Some("Scanning include paths...".to_owned()),
+    };
+
+    let can_start = !include_paths.read().is_empty()
+        && output_path.read().is_some()
+        && exclude_globs.read().iter().all(Result::is_ok);
+
+    let compression_info = if compress() {
+        "The backup will be compressed before it is encrypted, so its size will depend on how compressible the files are"
+    } else {
+        "The backup will be slightly larger than the files being backed up, due to encryption overhead"
+    };
 
     rsx! {
         div {
@@ -29,6 +121,13 @@ pub fn BackupConfig() -> Element {
                 state: include_paths,
             }
 
+            if let Some(estimate_info) = estimate_info {
+                span {
+                    class: "info",
+                    "{estimate_info}"
+                }
+            }
+
             // output_path: PathBuf
             FileSelect {
                 state: output_path,
@@ -70,8 +169,69 @@ pub fn BackupConfig() -> Element {
                 step: 1,
             }
 
-            // PROMPT IN POPUP ON BACKUP START
-            // password: Option<String>,
+            // compression_level: Option<i32>,
+            Checkbox {
+                state: compress,
+                label: "Compress",
+                info: compression_info,
+            }
+
+            Slider {
+                state: compression_level,
+                label: "Compression level",
+                info: "Higher levels produce smaller backups, but take longer to create",
+                min: *COMPRESSION_LEVELS.start(),
+                max: *COMPRESSION_LEVELS.end(),
+                step: 1,
+                disabled: !compress(),
+            }
+
+            button {
+                class: "button primary config-start",
+                disabled: !can_start,
+                onclick: move |_| {
+                    prompting.set(true);
+                },
+                "Back up"
+            }
+
+            if prompting() {
+                PasswordPrompt {
+                    submit_label: "Back up",
+                    confirm: true,
+                    onsubmit: move |password| {
+                        prompting.set(false);
+
+                        if let Some(output_dir) = output_path() {
+                            running.set(Some(Operation::Backup {
+                                include_paths: include_paths(),
+                                exclude_globs: exclude_globs
+                                    .read()
+                                    .iter()
+                                    .filter_map(|pattern| pattern.as_ref().ok().cloned())
+                                    .collect(),
+                                output_path: output_dir.join(backup_file_name()),
+                                password,
+                                chunk_size: 1 << chunk_size_magnitude(),
+                                pool_size: pool_size(),
+                                compression_level: compress().then_some(compression_level()),
+                            }));
+                        }
+                    },
+                    oncancel: move |_| {
+                        prompting.set(false);
+                    },
+                }
+            }
+
+            if let Some(operation) = running() {
+                RunningOperation {
+                    operation,
+                    onclose: move |_| {
+                        running.set(None);
+                    },
+                }
+            }
 
             // REMOVE OPTION AND DISPLAY CONFIRMATION POPUP IF OVER SUGGESTED MEMORY LIMIT:
             // override_memory_limit: bool,
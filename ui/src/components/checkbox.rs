@@ -0,0 +1,57 @@
+//! Checkbox UI component.
+
+use crate::classes::*;
+use crate::hooks::*;
+use dioxus::prelude::*;
+
+/// A checkbox component.
+#[component]
+pub fn Checkbox(
+    /// The checkbox state.
+    state: Signal<bool>,
+    /// The checkbox label.
+    label: Option<String>,
+    /// Optional info to display under the checkbox.
+    info: Option<String>,
+    /// Whether the checkbox is disabled.
+    #[props(default = false)]
+    disabled: bool,
+    /// An optional class name.
+    class: Option<String>,
+) -> Element {
+    let id = use_id();
+    let label = label.unwrap_or_default();
+    let info = info.unwrap_or_default();
+
+    rsx! {
+        div {
+            class: classes!("checkbox-container", disabled.then_some("checkbox-container-disabled"), class),
+
+            div {
+                class: "checkbox-control",
+
+                input {
+                    id: "{id}",
+                    class: "checkbox",
+                    r#type: "checkbox",
+                    disabled: disabled,
+                    checked: state(),
+                    oninput: move |event| {
+                        state.set(event.checked());
+                    }
+                }
+
+                label {
+                    class: "checkbox-label",
+                    r#for: "{id}",
+                    "{label}"
+                }
+            }
+
+            span {
+                class: "info",
+                "{info}"
+            }
+        }
+    }
+}
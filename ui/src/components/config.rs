@@ -1,8 +1,14 @@
 //! Backup/extraction operation configuration.
 
 use crate::classes::*;
-use crate::components::{BackupConfig, ExtractionConfig};
+use crate::components::{BackupConfig, ExtractionConfig, ProfileSelect};
+use crate::services::Profiles;
 use dioxus::prelude::*;
+use std::time::Duration;
+
+/// How long to wait after the last edit before saving profiles, so that
+/// typing does not write the file on every keystroke.
+const SAVE_DELAY: Duration = Duration::from_millis(500);
 
 /// The currently selected operation.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -17,6 +23,30 @@ enum OperationType {
 #[component]
 pub fn Config() -> Element {
     let mut operation_type = use_signal(|| OperationType::Backup);
+    let profiles = use_context_provider(|| Signal::new(Profiles::load()));
+    let mut pending_save = use_signal(|| None::<Task>);
+
+    // Save the profiles once they stop changing, replacing any save that is
+    // still waiting
+    use_effect(move || {
+        let snapshot = profiles();
+
+        if let Some(task) = pending_save.take() {
+            task.cancel();
+        }
+
+        pending_save.set(Some(spawn(async move {
+            tokio::time::sleep(SAVE_DELAY).await;
+
+            if let Err(err) = snapshot.save() {
+                eprintln!("Failed to save profiles: {err}");
+            }
+        })));
+    });
+
+    // The configuration components are recreated when the active profile
+    // changes, so that they load its values
+    let revision = profiles.with(Profiles::revision);
 
     let backup_tab_class = classes!(
         "config-tab",
@@ -31,6 +61,18 @@ pub fn Config() -> Element {
         div {
             class: "config",
 
+            div {
+                class: "config-profile",
+
+                div {
+                    class: "config-profile-inner",
+
+                    ProfileSelect {
+                        state: profiles,
+                    }
+                }
+            }
+
             div {
                 class: "config-tabs",
 
@@ -65,10 +107,14 @@ pub fn Config() -> Element {
 
                     match operation_type() {
                         OperationType::Backup => rsx! {
-                            BackupConfig {}
+                            BackupConfig {
+                                key: "{revision}",
+                            }
                         },
                         OperationType::Extraction => rsx! {
-                            ExtractionConfig {}
+                            ExtractionConfig {
+                                key: "{revision}",
+                            }
                         },
                     }
                 }
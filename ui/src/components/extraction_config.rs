@@ -1,25 +1,200 @@
 //! Extraction operation configuration.
 
+use super::{ControlError, FileSelect, PasswordPrompt, RunningOperation, Slider};
+use crate::constants::BACKUP_FILE_EXTENSION;
+use crate::format::format_estimate;
+use crate::services::{backup_summary, ExtractionProfile, Operation, PasswordBackoff, Profiles};
 use dioxus::prelude::*;
+use std::fs;
 
 /// The extraction operation configuration component.
 #[component]
 pub fn ExtractionConfig() -> Element {
+    let mut profiles = use_context::<Signal<Profiles>>();
+    let saved = use_hook(|| profiles.peek().active().extraction.clone());
+
+    let backup_path = use_signal(|| saved.backup_path.clone());
+    let output_path = use_signal(|| saved.output_path.clone());
+    let output_path_error = use_signal(|| None::<String>);
+    let pool_size = use_signal(|| saved.pool_size);
+    let mut password_backoff = use_signal(PasswordBackoff::default);
+    let mut retry_held = use_signal(|| false);
+    let mut prompting = use_signal(|| false);
+    let mut running = use_signal(|| None::<Operation>);
+
+    // Keep the active profile up to date with any edits
+    use_effect(move || {
+        let extraction = ExtractionProfile {
+            backup_path: backup_path(),
+            output_path: output_path(),
+            pool_size: pool_size(),
+        };
+
+        if profiles.peek().active().extraction != extraction {
+            profiles.with_mut(|profiles| profiles.active_mut().extraction = extraction);
+        }
+    });
+
+    // Wrong password attempts only count against the backup they were made
+    // on, so selecting a different one allows an attempt straight away
+    use_effect(move || {
+        backup_path.read();
+        password_backoff.with_mut(PasswordBackoff::reset);
+        retry_held.set(false);
+    });
+
+    // The header is read again whenever a different backup is selected
+    let summary = use_memo(move || {
+        backup_path().map(|path| backup_summary(&path).map_err(|err| err.to_string()))
+    });
+    let backup_path_error = summary().and_then(Result::err);
+    let summary_rows = summary().and_then(Result::ok).unwrap_or_default();
+    let estimate_info = backup_path()
+        .filter(|_| !summary_rows.is_empty())
+        .and_then(|path| fs::metadata(path).ok())
+        .map(|metadata| {
+            format_estimate(
+                profiles.with(|profiles| profiles.throughput().extraction.estimate(metadata.len())),
+            )
+        });
+    let can_start = backup_path.read().is_some()
+        && output_path.read().is_some()
+        && backup_path_error.is_none()
+        && !retry_held();
+    let retry_error =
+        retry_held().then(|| "Wrong password, wait a moment before trying again".to_owned());
+
     rsx! {
         div {
             class: "extraction-config",
 
-            "Extraction configuration"
+            // BASIC CONFIG OPTIONS
+            h2 {
+                class: "config-title",
+                "Basic configuration"
+            }
+
+            // backup_path: PathBuf
+            FileSelect {
+                state: backup_path,
+                label: "Backup path",
+                info: "This is the encrypted backup file to extract",
+                empty_text: "No backup selected",
+                extensions: vec![BACKUP_FILE_EXTENSION.to_owned()],
+                error: backup_path_error,
+            }
+
+            if !summary_rows.is_empty() {
+                div {
+                    class: "backup-summary",
+
+                    for (label, value) in summary_rows {
+                        div {
+                            class: "backup-summary-row",
 
-            // BASIC CONFIG OPTIONS:
-            // backup_path: PathBuf,
-            // output_path: PathBuf,
+                            span {
+                                class: "backup-summary-label",
+                                "{label}"
+                            }
+
+                            span {
+                                class: "backup-summary-value",
+                                "{value}"
+                            }
+                        }
+                    }
+                }
+            }
+
+            if let Some(estimate_info) = estimate_info {
+                span {
+                    class: "info",
+                    "{estimate_info}"
+                }
+            }
+
+            // output_path: PathBuf
+            FileSelect {
+                state: output_path,
+                label: "Output path",
+                info: "This is the directory in which the backup will be extracted",
+                empty_text: "No output path selected",
+                directory: true,
+                error: output_path_error(),
+            }
+
+            // ADVANCED CONFIG OPTIONS
+            h2 {
+                class: "config-title",
+                "Advanced configuration"
+            }
 
-            // ADVANCED CONFIG OPTIONS:
             // pool_size: u8,
+            Slider {
+                state: pool_size,
+                label: "Pool size",
+                info: "This determines how many workers to spawn in a pool that will perform cryptographic operations in parallel",
+                min: 1,
+                max: 24,
+                step: 1,
+            }
+
+            button {
+                class: "button primary config-start",
+                disabled: !can_start,
+                onclick: move |_| {
+                    prompting.set(true);
+                },
+                "Extract"
+            }
+
+            ControlError {
+                message: retry_error,
+            }
+
+            if prompting() {
+                PasswordPrompt {
+                    submit_label: "Extract",
+                    onsubmit: move |password| {
+                        prompting.set(false);
+
+                        if let (Some(backup_path), Some(output_path)) = (backup_path(), output_path()) {
+                            running.set(Some(Operation::Extraction {
+                                backup_path,
+                                output_path,
+                                password,
+                                pool_size: pool_size(),
+                            }));
+                        }
+                    },
+                    oncancel: move |_| {
+                        prompting.set(false);
+                    },
+                }
+            }
+
+            if let Some(operation) = running() {
+                RunningOperation {
+                    operation,
+                    onfinish: move |result| {
+                        password_backoff.with_mut(|backoff| backoff.record(&result));
+
+                        // Wrong passwords hold back the next attempt for a
+                        // while
+                        if let Some(remaining) = password_backoff.peek().remaining() {
+                            retry_held.set(true);
 
-            // PROMPT IN POPUP ON EXTRACTION START:
-            // password: Option<String>,
+                            spawn(async move {
+                                tokio::time::sleep(remaining).await;
+                                retry_held.set(password_backoff.peek().remaining().is_some());
+                            });
+                        }
+                    },
+                    onclose: move |_| {
+                        running.set(None);
+                    },
+                }
+            }
 
             // REMOVE OPTION AND DISPLAY CONFIRMATION POPUP IF OVER SUGGESTED MEMORY LIMIT:
             // override_memory_limit: bool,
@@ -1,7 +1,7 @@
 //! File selection dialog component.
 
 use crate::classes::*;
-use crate::hooks::*;
+use crate::services::*;
 use dioxus::prelude::*;
 use std::path::PathBuf;
 
@@ -11,6 +11,12 @@ pub fn FileDialog(
     /// Whether the selection should allow directories instead of files.
     #[props(default = false)]
     directory: bool,
+    /// The path at which to start the selection.
+    start_path: Option<PathBuf>,
+    /// The file extensions the selection is limited to, such as `txt`. All
+    /// files can be selected if this is empty.
+    #[props(default)]
+    extensions: Vec<String>,
     /// The dialog open button children.
     children: Element,
     /// An optional class name.
@@ -18,33 +24,19 @@ pub fn FileDialog(
     /// Event handler for when a file is selected.
     onselect: EventHandler<Option<PathBuf>>,
 ) -> Element {
-    let id = use_id();
-
     rsx! {
         div {
             class: classes!("file-dialog", class),
+            onclick: move |_| {
+                let start_path = start_path.clone();
+                let extensions = extensions.clone();
 
-            label {
-                class: "file-dialog-label",
-                r#for: "{id}",
-                {children}
-            }
-
-            input {
-                id: "{id}",
-                class: "file-dialog-input",
-                r#type: "file",
-                directory: directory,
-                onchange: move |event| {
-                    if let Some(file_engine) = event.files() {
-                        if let Some(path) = file_engine.files().first() {
-                            onselect.call(Some(PathBuf::from(path)));
-                        } else {
-                            onselect.call(None);
-                        }
-                    }
-                }
-            }
+                spawn(async move {
+                    let path = pick_path(directory, start_path.as_deref(), &extensions).await;
+                    onselect.call(path);
+                });
+            },
+            {children}
         }
     }
 }
@@ -2,7 +2,7 @@
 
 use super::ControlError;
 use crate::classes::*;
-use crate::hooks::*;
+use crate::services::*;
 use dioxus::prelude::*;
 use std::path::PathBuf;
 
@@ -24,13 +24,16 @@ pub fn FileSelect(
     /// Whether the selection should allow directories instead of files.
     #[props(default = false)]
     directory: bool,
+    /// The file extensions the selection is limited to, such as `txt`. All
+    /// files can be selected if this is empty.
+    #[props(default)]
+    extensions: Vec<String>,
     /// An optional class name.
     class: Option<String>,
     /// An optional error message.
     #[props(!optional, default)]
     error: Option<String>,
 ) -> Element {
-    let id = use_id();
     let label = label.unwrap_or_default();
     let info = info.unwrap_or_default();
     let display_text = state.with(|maybe_path| match maybe_path {
@@ -38,6 +41,7 @@ pub fn FileSelect(
         None => empty_text.unwrap_or_else(|| "No path selected".to_owned()),
     });
     let browse_label = browse_label.unwrap_or_else(|| "Browse".to_owned());
+    let start_path = start_path.or_else(|| state.cloned());
 
     rsx! {
         div {
@@ -56,24 +60,21 @@ pub fn FileSelect(
                     "{display_text}"
                 }
 
-                label {
+                div {
                     class: "file-select-button primary",
-                    r#for: "{id}",
-                    "{browse_label}"
-                }
+                    onclick: move |_| {
+                        let start_path = start_path.clone();
+                        let extensions = extensions.clone();
 
-                input {
-                    id: "{id}",
-                    class: "file-select-input",
-                    r#type: "file",
-                    directory: directory,
-                    onchange: move |event| {
-                        if let Some(file_engine) = event.files() {
-                            if let Some(path) = file_engine.files().first() {
-                                state.set(Some(PathBuf::from(path)));
+                        spawn(async move {
+                            let path = pick_path(directory, start_path.as_deref(), &extensions).await;
+
+                            if let Some(path) = path {
+                                state.set(Some(path));
                             }
-                        }
-                    }
+                        });
+                    },
+                    "{browse_label}"
                 }
             }
 
@@ -2,6 +2,7 @@
 
 mod app;
 mod backup_config;
+mod checkbox;
 mod config;
 mod control_error;
 mod exclude_globs;
@@ -12,11 +13,15 @@ mod icon;
 mod icon_button;
 mod include_paths_select;
 mod loading;
+mod password_prompt;
 mod path_display;
+mod profile_select;
+mod running_operation;
 mod slider;
 
 pub use app::*;
 pub use backup_config::*;
+pub use checkbox::*;
 pub use config::*;
 pub use control_error::*;
 pub use exclude_globs::*;
@@ -27,5 +32,8 @@ pub use icon::*;
 pub use icon_button::*;
 pub use include_paths_select::*;
 pub use loading::*;
+pub use password_prompt::*;
 pub use path_display::*;
+pub use profile_select::*;
+pub use running_operation::*;
 pub use slider::*;
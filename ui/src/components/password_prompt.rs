@@ -0,0 +1,101 @@
+//! Password prompt component.
+
+use super::ControlError;
+use dioxus::prelude::*;
+
+/// A popup prompting for the password of a backup before an operation starts.
+#[component]
+pub fn PasswordPrompt(
+    /// The label of the button that submits the password.
+    submit_label: String,
+    /// Whether the password has to be entered twice, so that a typo does not
+    /// lock the user out of a new backup.
+    #[props(default = false)]
+    confirm: bool,
+    /// Event handler for when the password is submitted.
+    onsubmit: EventHandler<String>,
+    /// Event handler for when the prompt is dismissed.
+    oncancel: EventHandler<()>,
+) -> Element {
+    let mut password = use_signal(String::new);
+    let mut confirmation = use_signal(String::new);
+
+    let mismatch = confirm && password() != confirmation();
+    let can_submit = !password.read().is_empty() && !mismatch;
+    let error = (mismatch && !confirmation.read().is_empty())
+        .then(|| "The passwords do not match".to_owned());
+
+    rsx! {
+        div {
+            class: "modal-backdrop",
+
+            div {
+                class: "modal",
+
+                div {
+                    class: "text-input-container",
+
+                    span {
+                        class: "text-input-label",
+                        "Password"
+                    }
+
+                    input {
+                        class: "text-input",
+                        r#type: "password",
+                        autofocus: true,
+                        value: "{password}",
+                        oninput: move |event| {
+                            password.set(event.value());
+                        },
+                    }
+                }
+
+                if confirm {
+                    div {
+                        class: "text-input-container",
+
+                        span {
+                            class: "text-input-label",
+                            "Confirm password"
+                        }
+
+                        input {
+                            class: "text-input",
+                            r#type: "password",
+                            value: "{confirmation}",
+                            oninput: move |event| {
+                                confirmation.set(event.value());
+                            },
+                        }
+
+                        ControlError {
+                            message: error,
+                        }
+                    }
+                }
+
+                div {
+                    class: "modal-actions",
+
+                    button {
+                        class: "button secondary",
+                        onclick: move |_| {
+                            oncancel.call(());
+                        },
+                        "Cancel"
+                    }
+
+                    button {
+                        class: "button primary",
+                        disabled: !can_submit,
+                        onclick: move |_| {
+                            onsubmit.call(password());
+                        },
+                        "{submit_label}"
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! UI component for managing configuration profiles.
+
+use super::IconButton;
+use crate::icons::{PLUS, XMARK};
+use crate::services::Profiles;
+use dioxus::prelude::*;
+
+/// UI component to create, rename, delete, and select configuration
+/// profiles.
+#[component]
+pub fn ProfileSelect(
+    /// The profiles state.
+    state: Signal<Profiles>,
+) -> Element {
+    let active_index = state.with(Profiles::active_index);
+    let active_name = state.with(|profiles| profiles.active().name.clone());
+    let can_delete = state.with(Profiles::len) > 1;
+
+    rsx! {
+        div {
+            class: "profile-select-container",
+
+            span {
+                class: "profile-select-label",
+                "Profile"
+            }
+
+            div {
+                class: "profile-select",
+
+                select {
+                    class: "profile-select-dropdown",
+                    onchange: move |event| {
+                        if let Ok(index) = event.value().parse() {
+                            state.with_mut(|profiles| profiles.select(index));
+                        }
+                    },
+
+                    for (index, profile) in state.read().iter().enumerate() {
+                        option {
+                            value: "{index}",
+                            selected: index == active_index,
+                            "{profile.name}"
+                        }
+                    }
+                }
+
+                input {
+                    class: "profile-select-name",
+                    r#type: "text",
+                    value: "{active_name}",
+                    oninput: move |event| {
+                        state.with_mut(|profiles| profiles.rename(active_index, event.value()));
+                    }
+                }
+
+                IconButton {
+                    data: PLUS,
+                    onclick: move |_| {
+                        state.with_mut(Profiles::create);
+                    }
+                }
+
+                IconButton {
+                    data: XMARK,
+                    disabled: !can_delete,
+                    onclick: move |_| {
+                        state.with_mut(|profiles| profiles.delete(active_index));
+                    }
+                }
+            }
+
+            span {
+                class: "profile-select-info",
+                "Each profile keeps its own backup and extraction configuration, which is saved as it is edited"
+            }
+        }
+    }
+}
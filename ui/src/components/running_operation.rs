@@ -0,0 +1,211 @@
+//! Running operation component.
+
+use super::ControlError;
+use crate::format::format_size;
+use crate::services::{Operation, Outcome, Profiles};
+use backup::{BackupError, BackupResult, PauseToken, Progress, ProgressStage};
+use dioxus::prelude::*;
+use std::io;
+use tokio::sync::mpsc;
+
+/// The state of a running operation.
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    /// The operation is running, along with its latest progress report, if
+    /// it has made one.
+    Running(Option<Progress>),
+    /// The operation completed.
+    Done(Outcome),
+    /// The operation failed with the given error message.
+    Failed(String),
+}
+
+/// Describes what an operation is doing in a stage.
+const fn stage_description(stage: ProgressStage) -> &'static str {
+    match stage {
+        ProgressStage::Archiving => "Archiving and encrypting",
+        ProgressStage::Decrypting => "Decrypting",
+        ProgressStage::Unpacking => "Unpacking",
+    }
+}
+
+/// A popup that runs an operation, showing its progress and allowing it to
+/// be paused, and then its result.
+#[component]
+pub fn RunningOperation(
+    /// The operation to run. It is started when the component is created.
+    operation: Operation,
+    /// Event handler for when the operation finishes, successfully or not.
+    onfinish: Option<EventHandler<BackupResult<Outcome>>>,
+    /// Event handler for when the popup is closed after the operation
+    /// finishes.
+    onclose: EventHandler<()>,
+) -> Element {
+    let mut profiles = use_context::<Signal<Profiles>>();
+    let pause = use_hook(PauseToken::new);
+    let mut paused = use_signal(|| false);
+    let mut status = use_signal(|| Status::Running(None));
+    let is_backup = matches!(operation, Operation::Backup { .. });
+
+    use_hook(|| {
+        let operation = operation.clone();
+        let pause = pause.clone();
+
+        spawn(async move {
+            let (progress_sender, mut progress_receiver) = mpsc::unbounded_channel();
+            let task =
+                tokio::task::spawn_blocking(move || operation.execute(progress_sender, pause));
+
+            // The channel closes once the operation is done reporting
+            while let Some(progress) = progress_receiver.recv().await {
+                status.set(Status::Running(Some(progress)));
+            }
+
+            let result = task.await.unwrap_or_else(|err| {
+                Err(BackupError::IoError(io::Error::other(format!(
+                    "the operation did not complete: {err}"
+                ))))
+            });
+
+            match &result {
+                Ok(outcome) => {
+                    // Completed operations make the estimates of future ones
+                    // more accurate
+                    profiles.with_mut(|profiles| {
+                        let throughput = profiles.throughput_mut();
+                        let history = if is_backup {
+                            &mut throughput.backup
+                        } else {
+                            &mut throughput.extraction
+                        };
+                        history.record(outcome.bytes, outcome.elapsed);
+                    });
+                    status.set(Status::Done(outcome.clone()));
+                }
+                Err(err) => status.set(Status::Failed(err.to_string())),
+            }
+
+            if let Some(onfinish) = onfinish {
+                onfinish.call(result);
+            }
+        })
+    });
+
+    let title = if is_backup { "Backup" } else { "Extraction" };
+
+    rsx! {
+        div {
+            class: "modal-backdrop",
+
+            div {
+                class: "modal running-operation",
+
+                h2 {
+                    class: "config-title",
+                    "{title}"
+                }
+
+                match status() {
+                    Status::Running(progress) => {
+                        let description = progress.map_or("Starting", |progress| stage_description(progress.stage));
+                        let fraction = progress.and_then(|progress| progress.fraction());
+                        let processed = progress.map_or(0, |progress| progress.bytes_processed);
+                        let amount = match progress.and_then(|progress| progress.total_bytes) {
+                            Some(total) => format!("{} of {}", format_size(processed), format_size(total)),
+                            None => format_size(processed),
+                        };
+                        let width = fraction.map_or(100., |fraction| fraction * 100.);
+                        let bar_class = if fraction.is_some() {
+                            "running-operation-progress-bar"
+                        } else {
+                            "running-operation-progress-bar running-operation-progress-unknown"
+                        };
+                        let pause_label = if paused() { "Resume" } else { "Pause" };
+
+                        rsx! {
+                            span {
+                                class: "running-operation-stage",
+                                if paused() {
+                                    "Paused"
+                                } else {
+                                    "{description}"
+                                }
+                            }
+
+                            div {
+                                class: "running-operation-progress",
+
+                                div {
+                                    class: bar_class,
+                                    style: "width: {width:.1}%",
+                                }
+                            }
+
+                            span {
+                                class: "info",
+                                "{amount}"
+                            }
+
+                            div {
+                                class: "modal-actions",
+
+                                button {
+                                    class: "button secondary",
+                                    onclick: move |_| {
+                                        if paused() {
+                                            pause.resume();
+                                        } else {
+                                            pause.pause();
+                                        }
+
+                                        paused.toggle();
+                                    },
+                                    "{pause_label}"
+                                }
+                            }
+                        }
+                    }
+                    Status::Done(outcome) => {
+                        let verb = if is_backup { "Backed up" } else { "Extracted" };
+                        let path = outcome.path.display().to_string();
+
+                        rsx! {
+                            span {
+                                "{verb} to {path}"
+                            }
+
+                            div {
+                                class: "modal-actions",
+
+                                button {
+                                    class: "button primary",
+                                    onclick: move |_| {
+                                        onclose.call(());
+                                    },
+                                    "Close"
+                                }
+                            }
+                        }
+                    }
+                    Status::Failed(message) => rsx! {
+                        ControlError {
+                            message: Some(message),
+                        }
+
+                        div {
+                            class: "modal-actions",
+
+                            button {
+                                class: "button primary",
+                                onclick: move |_| {
+                                    onclose.call(());
+                                },
+                                "Close"
+                            }
+                        }
+                    },
+                }
+            }
+        }
+    }
+}
@@ -6,5 +6,8 @@ pub const DEBUG: bool = cfg!(debug_assertions);
 /// The application window title.
 pub const WINDOW_TITLE: &str = "Encrypted Backup";
 
+/// The extension given to encrypted backup files.
+pub const BACKUP_FILE_EXTENSION: &str = "ebk";
+
 // /// The application window icon.
 // pub const WINDOW_ICON: &[u8] = include_bytes!("../assets/img/icon.ico");
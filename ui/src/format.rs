@@ -1,5 +1,7 @@
 //! Formatting utilities.
 
+use std::time::Duration;
+
 /// Formats a size in bytes to be human-readable.
 pub fn format_size(size: u64) -> String {
     if size < (1 << 10) {
@@ -12,3 +14,26 @@ pub fn format_size(size: u64) -> String {
         format!("{} GB", size >> 30)
     }
 }
+
+/// Formats an estimate of how long an operation will take, or explains that
+/// there is no estimate yet.
+pub fn format_estimate(estimate: Option<Duration>) -> String {
+    let Some(estimate) = estimate else {
+        return "Estimate unavailable".to_owned();
+    };
+
+    let seconds = estimate.as_secs();
+
+    if seconds < 60 {
+        return "Estimated under a minute".to_owned();
+    }
+
+    let (amount, unit) = if seconds < 60 * 60 {
+        (seconds.div_ceil(60), "minute")
+    } else {
+        (seconds.div_ceil(60 * 60), "hour")
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+
+    format!("Estimated ~{amount} {unit}{plural}")
+}
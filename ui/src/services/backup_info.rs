@@ -0,0 +1,38 @@
+//! Backup header inspection.
+
+use crate::format::*;
+use backup::{BackupResult, ChecksumAlgorithm};
+use std::path::Path;
+
+/// Reads the metadata stored in the header of an encrypted backup, without
+/// the password, and formats it for display as a list of labeled values.
+pub fn backup_summary(backup_path: &Path) -> BackupResult<Vec<(&'static str, String)>> {
+    let info = backup::backup_info(backup_path)?;
+
+    let format_version = info.format_version.map_or_else(
+        || "Legacy (no header)".to_owned(),
+        |version| version.to_string(),
+    );
+    let checksum = match info.checksum_algorithm {
+        Some(ChecksumAlgorithm::Sha256) => "SHA-256",
+        Some(ChecksumAlgorithm::Blake3) => "BLAKE3",
+        None => "None",
+    };
+    let compressed = if info.compressed { "Yes" } else { "No" };
+    let created = info.created.map_or_else(
+        || "Unknown".to_owned(),
+        |created| created.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    );
+    let tool_version = info.tool_version.unwrap_or_else(|| "Unknown".to_owned());
+
+    Ok(vec![
+        ("Created", created),
+        ("Created with version", tool_version),
+        ("Backup size", format_size(info.size)),
+        ("Format version", format_version),
+        ("Chunk size", format_size(info.chunk_size)),
+        ("Passwords", info.key_slots.to_string()),
+        ("Checksum", checksum.to_owned()),
+        ("Compressed", compressed.to_owned()),
+    ])
+}
@@ -0,0 +1,141 @@
+//! Duration estimates from the throughput of past operations.
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The number of past operations of each kind that throughput is averaged
+/// over.
+const HISTORY_LEN: usize = 10;
+
+/// The amount of data processed by a past operation and how long it took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThroughputSample {
+    /// The number of bytes processed.
+    pub bytes: u64,
+    /// The time taken, in milliseconds.
+    pub millis: u64,
+}
+
+/// The throughput of the most recent operations of one kind.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThroughputHistory {
+    /// The most recent samples, oldest first.
+    samples: VecDeque<ThroughputSample>,
+}
+
+impl ThroughputHistory {
+    /// Records a completed operation, forgetting the oldest one if the
+    /// history is full. Operations that processed nothing or finished
+    /// instantly say nothing about throughput, so they are ignored.
+    pub fn record(&mut self, bytes: u64, elapsed: Duration) {
+        let millis = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+
+        if bytes == 0 || millis == 0 {
+            return;
+        }
+
+        self.samples.push_back(ThroughputSample { bytes, millis });
+
+        while self.samples.len() > HISTORY_LEN {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns the average throughput of the recorded operations, in bytes
+    /// per second, weighted by how long each took. Returns `None` if nothing
+    /// has been recorded yet.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn bytes_per_second(&self) -> Option<f64> {
+        let (bytes, millis) = self
+            .samples
+            .iter()
+            .fold((0u64, 0u64), |(bytes, millis), sample| {
+                (
+                    bytes.saturating_add(sample.bytes),
+                    millis.saturating_add(sample.millis),
+                )
+            });
+
+        (millis > 0).then(|| bytes as f64 * 1000. / millis as f64)
+    }
+
+    /// Estimates how long an operation on the given number of bytes will
+    /// take. Returns `None` if nothing has been recorded yet.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn estimate(&self, bytes: u64) -> Option<Duration> {
+        self.bytes_per_second()
+            .map(|rate| Duration::from_secs_f64(bytes as f64 / rate))
+    }
+}
+
+/// The throughput of past backups and extractions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Throughput {
+    /// The throughput of past backups, in bytes archived.
+    pub backup: ThroughputHistory,
+    /// The throughput of past extractions, in bytes of backup read.
+    pub extraction: ThroughputHistory,
+}
+
+/// Adds up the size of the files that a backup of the given paths would
+/// include, for estimating how long it will take. Paths are matched against
+/// the exclude globs relative to the parent of their include path, as they
+/// are during a backup. Anything that cannot be read is left out, as are
+/// linked directories, so the result is a lower bound.
+pub fn scan_size(include_paths: &[PathBuf], exclude_globs: &[Pattern]) -> u64 {
+    let mut size = 0u64;
+    let mut worklist = include_paths
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name()?;
+            Some((path.clone(), PathBuf::from(name)))
+        })
+        .collect::<Vec<_>>();
+
+    while let Some((path, relative_path)) = worklist.pop() {
+        if excluded(&relative_path, exclude_globs) {
+            continue;
+        }
+
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            // Linked directories inside the include paths are not descended
+            // into, in case they lead back to one of their parents
+            let is_include_path = relative_path.components().count() == 1;
+
+            if !is_include_path && path.is_symlink() {
+                continue;
+            }
+
+            let Ok(entries) = fs::read_dir(&path) else {
+                continue;
+            };
+
+            worklist.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| (entry.path(), relative_path.join(entry.file_name()))),
+            );
+        } else {
+            size = size.saturating_add(metadata.len());
+        }
+    }
+
+    size
+}
+
+/// Checks if an archive-relative path is excluded by any of the globs.
+fn excluded(relative_path: &Path, exclude_globs: &[Pattern]) -> bool {
+    exclude_globs
+        .iter()
+        .any(|glob| glob.matches_path(relative_path))
+}
@@ -0,0 +1,45 @@
+//! Native file and directory selection dialogs.
+
+use rfd::AsyncFileDialog;
+use std::path::{Path, PathBuf};
+
+/// Opens a native dialog to choose a file, or a directory if `directory` is
+/// set, returning the chosen path or `None` if the dialog was dismissed.
+///
+/// The dialog opens in `start_path`, or in its parent directory if it is a
+/// file. When choosing a file, `extensions` limits the files shown to those
+/// with one of the given extensions, written without the leading dot. An
+/// empty list shows every file.
+pub async fn pick_path(
+    directory: bool,
+    start_path: Option<&Path>,
+    extensions: &[String],
+) -> Option<PathBuf> {
+    let mut dialog = AsyncFileDialog::new();
+
+    if let Some(start_dir) = start_path.and_then(start_directory) {
+        dialog = dialog.set_directory(start_dir);
+    }
+
+    let handle = if directory {
+        dialog.pick_folder().await
+    } else {
+        if !extensions.is_empty() {
+            dialog = dialog.add_filter(extensions.join(", "), extensions);
+        }
+
+        dialog.pick_file().await
+    }?;
+
+    Some(handle.path().to_path_buf())
+}
+
+/// Returns the directory a dialog should open in to show the given path, if
+/// it still exists.
+fn start_directory(path: &Path) -> Option<&Path> {
+    if path.is_dir() {
+        Some(path)
+    } else {
+        path.parent().filter(|parent| parent.is_dir())
+    }
+}
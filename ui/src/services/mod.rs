@@ -1 +1,15 @@
 //! Application services.
+
+mod backup_info;
+mod estimate;
+mod file_selection;
+mod operation;
+mod password_backoff;
+mod profiles;
+
+pub use backup_info::*;
+pub use estimate::*;
+pub use file_selection::*;
+pub use operation::*;
+pub use password_backoff::*;
+pub use profiles::*;
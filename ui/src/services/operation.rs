@@ -0,0 +1,127 @@
+//! Backup and extraction operations.
+
+use backup::{BackupOptions, BackupResult, ExtractOptions, PauseToken, Progress, ProgressHandler};
+use glob::Pattern;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The result of a completed operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The path of the backup file or extracted directory.
+    pub path: PathBuf,
+    /// The number of bytes processed: those archived by a backup, or those
+    /// read from the backup by an extraction.
+    pub bytes: u64,
+    /// How long the operation took.
+    pub elapsed: Duration,
+}
+
+/// A fully configured backup or extraction operation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// An encrypted backup.
+    Backup {
+        /// Paths to include in the backup.
+        include_paths: Vec<PathBuf>,
+        /// Globs to exclude from the backup.
+        exclude_globs: Vec<Pattern>,
+        /// Output path of the backup.
+        output_path: PathBuf,
+        /// Password for the backup.
+        password: String,
+        /// Size of each chunk of the backup.
+        chunk_size: usize,
+        /// Number of workers performing crypto operations in parallel.
+        pool_size: u8,
+        /// The zstd level to compress the backup with, if any.
+        compression_level: Option<i32>,
+    },
+    /// An extraction of an encrypted backup.
+    Extraction {
+        /// Path to the encrypted backup.
+        backup_path: PathBuf,
+        /// Path to extract the backup to.
+        output_path: PathBuf,
+        /// Password for the backup.
+        password: String,
+        /// Number of workers performing crypto operations in parallel.
+        pool_size: u8,
+    },
+}
+
+impl Operation {
+    /// Executes the operation, blocking until it is complete. Progress
+    /// reports are forwarded to `progress` as the operation runs, and are
+    /// dropped if nothing is receiving them anymore. The operation can be
+    /// paused and resumed from another thread with `pause`. Returns the path
+    /// of the backup file or extracted directory, along with the amount of
+    /// data processed and how long it took, for estimating future operations.
+    pub fn execute(
+        self,
+        progress: UnboundedSender<Progress>,
+        pause: PauseToken,
+    ) -> BackupResult<Outcome> {
+        let progress = Some(ProgressHandler::new(move |report| {
+            _ = progress.send(report);
+        }));
+
+        match self {
+            Self::Backup {
+                include_paths,
+                exclude_globs,
+                output_path,
+                password,
+                chunk_size,
+                pool_size,
+                compression_level,
+            } => backup::backup(
+                &include_paths,
+                &exclude_globs,
+                output_path,
+                &password,
+                chunk_size,
+                pool_size,
+                &BackupOptions {
+                    compression_level,
+                    progress,
+                    pause: Some(pause),
+                    ..Default::default()
+                },
+            )
+            .map(|stats| Outcome {
+                path: stats.path,
+                bytes: stats.archive_size,
+                elapsed: stats.elapsed,
+            }),
+            Self::Extraction {
+                backup_path,
+                output_path,
+                password,
+                pool_size,
+            } => {
+                let start = Instant::now();
+                let bytes = fs::metadata(&backup_path)?.len();
+
+                backup::extract(
+                    backup_path,
+                    output_path,
+                    &password,
+                    pool_size,
+                    &ExtractOptions {
+                        progress,
+                        pause: Some(pause),
+                        ..Default::default()
+                    },
+                )
+                .map(|path| Outcome {
+                    path,
+                    bytes,
+                    elapsed: start.elapsed(),
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! A growing delay between wrong password attempts.
+
+use backup::{BackupError, BackupResult};
+use std::time::{Duration, Instant};
+
+/// How long to wait after the first wrong password.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest to wait between attempts, however many have failed.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Tracks consecutive wrong password attempts at opening a backup, and how
+/// long to wait before allowing another. The delay doubles with each wrong
+/// password, up to [`MAX_DELAY`].
+///
+/// This does nothing against offline attacks on the backup file itself. It
+/// only discourages rapid guessing at the keyboard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PasswordBackoff {
+    /// The number of wrong passwords entered in a row.
+    failures: u32,
+    /// When the next attempt is allowed, if it is being held back.
+    retry_at: Option<Instant>,
+}
+
+impl PasswordBackoff {
+    /// Records the result of an attempt. A wrong password extends the delay,
+    /// and any success clears it. Other errors say nothing about the
+    /// password, so they leave it as it is.
+    pub fn record<T>(&mut self, result: &BackupResult<T>) {
+        match result {
+            Ok(_) => self.reset(),
+            Err(BackupError::IncorrectPassword) => {
+                self.failures = self.failures.saturating_add(1);
+                self.retry_at = Some(Instant::now() + Self::delay(self.failures));
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Forgets all previous attempts, such as when a different backup is
+    /// selected.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns how long to wait after the given number of wrong passwords in
+    /// a row.
+    pub fn delay(failures: u32) -> Duration {
+        match failures {
+            0 => Duration::ZERO,
+            failures => BASE_DELAY
+                .checked_mul(1 << (failures - 1).min(31))
+                .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY)),
+        }
+    }
+
+    /// Returns how long remains until another attempt is allowed, or `None`
+    /// if one is allowed now.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.retry_at
+            .map(|retry_at| retry_at.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(PasswordBackoff::delay(0), Duration::ZERO);
+        assert_eq!(PasswordBackoff::delay(1), BASE_DELAY);
+        assert_eq!(PasswordBackoff::delay(2), BASE_DELAY * 2);
+        assert_eq!(PasswordBackoff::delay(3), BASE_DELAY * 4);
+        assert_eq!(PasswordBackoff::delay(5), BASE_DELAY * 16);
+
+        // The delay stops growing at the cap
+        assert_eq!(PasswordBackoff::delay(6), MAX_DELAY);
+        assert_eq!(PasswordBackoff::delay(20), MAX_DELAY);
+
+        // Shifts past the width of the multiplier are clamped rather than
+        // overflowing
+        assert_eq!(PasswordBackoff::delay(32), MAX_DELAY);
+        assert_eq!(PasswordBackoff::delay(33), MAX_DELAY);
+        assert_eq!(PasswordBackoff::delay(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut backoff = PasswordBackoff::default();
+        assert_eq!(backoff.remaining(), None);
+
+        // Wrong passwords hold back the next attempt, longer each time
+        backoff.record::<()>(&Err(BackupError::IncorrectPassword));
+        assert_eq!(backoff.failures, 1);
+        assert!(backoff
+            .remaining()
+            .is_some_and(|remaining| remaining <= BASE_DELAY));
+        backoff.record::<()>(&Err(BackupError::IncorrectPassword));
+        assert_eq!(backoff.failures, 2);
+        assert!(backoff
+            .remaining()
+            .is_some_and(|remaining| remaining > BASE_DELAY));
+
+        // Other errors leave the backoff as it is
+        let before = backoff;
+        backoff.record::<()>(&Err(BackupError::IoError(io::Error::other("failed"))));
+        assert_eq!(backoff, before);
+
+        // A success clears it
+        backoff.record(&Ok(()));
+        assert_eq!(backoff, PasswordBackoff::default());
+        assert_eq!(backoff.remaining(), None);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut backoff = PasswordBackoff::default();
+        backoff.record::<()>(&Err(BackupError::IncorrectPassword));
+        assert!(backoff.remaining().is_some());
+
+        backoff.reset();
+        assert_eq!(backoff, PasswordBackoff::default());
+        assert_eq!(backoff.remaining(), None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Saved configuration profiles.
+
+use super::Throughput;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// The name of the file, within the user's home directory, in which profiles
+/// are saved.
+const PROFILES_FILE_NAME: &str = ".encrypted-backup.json";
+
+/// The name given to the profile created when none have been saved.
+const DEFAULT_PROFILE_NAME: &str = "Default";
+
+/// The saved backup operation configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupProfile {
+    /// Paths to include in the backup.
+    pub include_paths: Vec<PathBuf>,
+    /// The directory in which the backup file will be created.
+    pub output_path: Option<PathBuf>,
+    /// Glob patterns to exclude from the backup. Patterns are kept as they
+    /// were typed, even if they are invalid, so that they can be corrected.
+    pub exclude_globs: Vec<String>,
+    /// The chunk size, as an order of magnitude.
+    pub chunk_size_magnitude: u8,
+    /// The number of workers performing cryptographic operations.
+    pub pool_size: u8,
+    /// Whether to compress the backup.
+    pub compress: bool,
+    /// The compression level, if compressing.
+    pub compression_level: i32,
+}
+
+impl Default for BackupProfile {
+    fn default() -> Self {
+        Self {
+            include_paths: Vec::new(),
+            output_path: None,
+            exclude_globs: Vec::new(),
+            chunk_size_magnitude: 16,
+            pool_size: 4,
+            compress: false,
+            compression_level: 3,
+        }
+    }
+}
+
+/// The saved extraction operation configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtractionProfile {
+    /// The encrypted backup file to extract.
+    pub backup_path: Option<PathBuf>,
+    /// The directory in which the backup will be extracted.
+    pub output_path: Option<PathBuf>,
+    /// The number of workers performing cryptographic operations.
+    pub pool_size: u8,
+}
+
+impl Default for ExtractionProfile {
+    fn default() -> Self {
+        Self {
+            backup_path: None,
+            output_path: None,
+            pool_size: 4,
+        }
+    }
+}
+
+/// A named set of backup and extraction configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamedProfile {
+    /// The name of the profile.
+    pub name: String,
+    /// The backup configuration.
+    #[serde(default)]
+    pub backup: BackupProfile,
+    /// The extraction configuration.
+    #[serde(default)]
+    pub extraction: ExtractionProfile,
+}
+
+impl NamedProfile {
+    /// Creates a profile with the default configuration.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            backup: BackupProfile::default(),
+            extraction: ExtractionProfile::default(),
+        }
+    }
+}
+
+/// All saved profiles, one of which is active at a time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profiles {
+    /// The saved profiles. There is always at least one.
+    entries: Vec<NamedProfile>,
+    /// The index of the active profile.
+    active: usize,
+    /// The throughput of past operations, shared by all profiles, for
+    /// estimating how long new ones will take.
+    #[serde(default)]
+    throughput: Throughput,
+    /// A counter incremented whenever a different profile becomes active,
+    /// which the index alone does not reveal when profiles are deleted.
+    #[serde(skip)]
+    revision: usize,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self {
+            entries: vec![NamedProfile::new(DEFAULT_PROFILE_NAME)],
+            active: 0,
+            throughput: Throughput::default(),
+            revision: 0,
+        }
+    }
+}
+
+impl Profiles {
+    /// Returns the path of the file in which profiles are saved.
+    fn path() -> Option<PathBuf> {
+        home::home_dir().map(|home| home.join(PROFILES_FILE_NAME))
+    }
+
+    /// Loads the saved profiles. If none have been saved, or they cannot be
+    /// read, a single default profile is returned.
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|contents| serde_json::from_str::<Self>(&contents).ok())
+            .map(Self::normalized)
+            .unwrap_or_default()
+    }
+
+    /// Saves the profiles, replacing any previously saved.
+    pub fn save(&self) -> io::Result<()> {
+        let path = Self::path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "home directory not found"))?;
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)
+    }
+
+    /// Ensures there is at least one profile and that the active index
+    /// refers to one of them.
+    fn normalized(mut self) -> Self {
+        if self.entries.is_empty() {
+            return Self::default();
+        }
+
+        self.active = self.active.min(self.entries.len() - 1);
+        self
+    }
+
+    /// Returns an iterator over the profiles.
+    pub fn iter(&self) -> impl Iterator<Item = &NamedProfile> {
+        self.entries.iter()
+    }
+
+    /// Returns the number of profiles.
+    pub const fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the index of the active profile.
+    pub const fn active_index(&self) -> usize {
+        self.active
+    }
+
+    /// Returns a value that changes whenever a different profile becomes
+    /// active.
+    pub const fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Returns the active profile.
+    pub fn active(&self) -> &NamedProfile {
+        &self.entries[self.active]
+    }
+
+    /// Returns the active profile, mutably.
+    pub fn active_mut(&mut self) -> &mut NamedProfile {
+        &mut self.entries[self.active]
+    }
+
+    /// Returns the throughput of past operations.
+    pub const fn throughput(&self) -> &Throughput {
+        &self.throughput
+    }
+
+    /// Returns the throughput of past operations, mutably, for recording
+    /// completed ones.
+    pub const fn throughput_mut(&mut self) -> &mut Throughput {
+        &mut self.throughput
+    }
+
+    /// Makes the profile at the given index active.
+    pub const fn select(&mut self, index: usize) {
+        if index < self.entries.len() && index != self.active {
+            self.active = index;
+            self.revision += 1;
+        }
+    }
+
+    /// Creates a profile with the default configuration and makes it active.
+    pub fn create(&mut self) {
+        // There are more candidate numbers than profiles, so one is free
+        let count = self.entries.len();
+        let name = (count + 1..=2 * count + 1)
+            .map(|number| format!("Profile {number}"))
+            .find(|name| self.entries.iter().all(|profile| &profile.name != name))
+            .unwrap();
+
+        self.entries.push(NamedProfile::new(name));
+        self.active = self.entries.len() - 1;
+        self.revision += 1;
+    }
+
+    /// Renames the profile at the given index.
+    pub fn rename(&mut self, index: usize, name: impl Into<String>) {
+        if let Some(profile) = self.entries.get_mut(index) {
+            profile.name = name.into();
+        }
+    }
+
+    /// Deletes the profile at the given index. The last remaining profile
+    /// cannot be deleted.
+    pub fn delete(&mut self, index: usize) {
+        if self.entries.len() <= 1 || index >= self.entries.len() {
+            return;
+        }
+
+        self.entries.remove(index);
+
+        if index == self.active {
+            // The profile after the deleted one becomes active, unless the
+            // deleted one was last
+            self.active = self.active.min(self.entries.len() - 1);
+            self.revision += 1;
+        } else if index < self.active {
+            self.active -= 1;
+        }
+    }
+}